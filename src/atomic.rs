@@ -0,0 +1,87 @@
+// A extension, the reservation taken by LR and the memory operations of atomics
+use crate::instr::AInstr;
+use crate::mem::Size;
+use crate::program_state::{ProgramState, Exceptions};
+use crate::reg::RegData;
+use crate::trace::MemAccess;
+
+impl <T : RegData> ProgramState<T> {
+  // Checks where an atomic goes in memory, which must be naturally aligned. LR faults as a load
+  // and everything else as a store.
+  pub(crate) fn atomic_addr(&self, var: AInstr, addr: T, sz: Size) -> Result<usize, Exceptions> {
+    match var {
+      AInstr::LR => self.load(addr, sz, false).map(|_| addr.as_usize()),
+      _ => self.store_addr(addr, sz),
+    }
+  }
+
+  // Any store that overlaps the reserved bytes makes the next SC fail
+  pub(crate) fn void_reservation(&mut self, loc: usize, sz: Size) {
+    if let Some((start, size)) = self.reservation {
+      if loc < start + size.bytes() && start < loc + sz.bytes() { self.reservation = None };
+    };
+  }
+
+  // Writes memory for a store that has been checked by store_addr
+  pub(crate) fn store(&mut self, loc: usize, v: T, sz: Size) {
+    self.void_reservation(loc, sz);
+    self.mem.write(loc, v, sz).expect("Checked store failed");
+  }
+
+  // Runs an atomic on the memory at addr with the value of rs2, returning what goes in rd and
+  // what was accessed. Words are sign extended into rd and compared as 32 bit values.
+  pub(crate) fn atomic(&mut self, var: AInstr, addr: T, sz: Size, src: T)
+    -> Result<(T, Option<MemAccess<T>>), Exceptions> {
+    use AInstr::*;
+    let loc = self.atomic_addr(var, addr, sz)?;
+    let old = self.load(addr, sz, true).expect("Checked atomic failed");
+    let word = sz == Size::WORD;
+    let (old_u, src_s, src_u) = if word { (old.zext_w(), src.sext_w(), src.zext_w()) }
+                                else { (old, src, src) };
+    let new = match var {
+      LR => {
+        self.reservation = Some((loc, sz));
+        return Ok((old, Some(MemAccess::load(addr, sz))))
+      },
+      // the reservation is gone whether or not the store happens, and rd is 0 if it did
+      SC => {
+        if self.reservation.take() != Some((loc, sz)) { return Ok((T::one(), None)) };
+        self.store(loc, src, sz);
+        return Ok((T::zero(), Some(MemAccess::store(addr, sz, src))))
+      },
+      AMOSWAP => src,
+      AMOADD => old.wrapping_add(&src),
+      AMOXOR => old ^ src,
+      AMOAND => old & src,
+      AMOOR => old | src,
+      AMOMIN => if old.to_signed() < src_s.to_signed() { old } else { src },
+      AMOMAX => if old.to_signed() > src_s.to_signed() { old } else { src },
+      AMOMINU => if old_u < src_u { old } else { src },
+      AMOMAXU => if old_u > src_u { old } else { src },
+    };
+    self.store(loc, new, sz);
+    Ok((old, Some(MemAccess::store(addr, sz, new))))
+  }
+}
+
+#[test]
+fn test_atomic() {
+  let mut ps = ProgramState::<u64>::new(crate::mem::Memory::new(0x20));
+  ps.mem.write(8, 0xffff_fffe, Size::WORD).unwrap();
+  // -2 as a word is smaller than 1 signed but larger unsigned, the upper half of rs2 is ignored
+  assert_eq!(ps.atomic(AInstr::AMOMIN, 8, Size::WORD, 0x1_0000_0001).unwrap().0, u64::MAX - 1);
+  assert_eq!(ps.mem.read(8, Size::WORD).unwrap(), 0xffff_fffe);
+  ps.atomic(AInstr::AMOMAXU, 8, Size::WORD, 1).unwrap();
+  assert_eq!(ps.mem.read(8, Size::WORD).unwrap(), 0xffff_fffe);
+  ps.atomic(AInstr::AMOADD, 8, Size::WORD, 3).unwrap();
+  assert_eq!(ps.mem.read(8, Size::DOUBLE).unwrap(), 1);
+
+  assert_eq!(ps.atomic(AInstr::LR, 8, Size::DOUBLE, 0).unwrap().0, 1);
+  ps.store(12, 0, Size::BYTE);
+  assert_eq!(ps.atomic(AInstr::SC, 8, Size::DOUBLE, 5).unwrap(), (1, None));
+  ps.atomic(AInstr::LR, 8, Size::DOUBLE, 0).unwrap();
+  assert_eq!(ps.atomic(AInstr::SC, 8, Size::DOUBLE, 5).unwrap().0, 0);
+  assert_eq!(ps.mem.read(8, Size::DOUBLE).unwrap(), 5);
+  assert_eq!(ps.atomic(AInstr::SC, 4, Size::DOUBLE, 5), Err(Exceptions::StoreMisaligned));
+  assert_eq!(ps.atomic(AInstr::LR, 0x20, Size::WORD, 0), Err(Exceptions::LoadAccessFault));
+}
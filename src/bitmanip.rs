@@ -0,0 +1,106 @@
+// Zba, Zbb, Zbs and Zbc, worked out on the low xlen bits of a u64 so RV32 and RV64 share them
+use crate::instr::ZbInstr;
+use crate::reg::RegData;
+
+// Result of a bit manipulation instruction given rs1, rs2 and the immediate kept in its rs2 field
+pub(crate) fn exec<T : RegData>(var: ZbInstr, a: T, b: T, shamt: u32) -> T {
+  use ZbInstr::*;
+  let xlen = T::BYTE_SIZE as u32 * 8;
+  let (a, b) = (a.as_usize() as u64, b.as_usize() as u64);
+  let word = |v: u64| v & 0xffff_ffff;
+  let signed = |v: u64, bits: u32| (v << (64 - bits)) as i64 >> (64 - bits);
+  // bit indexes and rotations take the immediate, or the low log2(xlen) bits of rs2
+  let index = if var.immediate() { shamt } else { b as u32 & (xlen - 1) };
+  let result = match var {
+    SH1ADD => (a << 1).wrapping_add(b),
+    SH2ADD => (a << 2).wrapping_add(b),
+    SH3ADD => (a << 3).wrapping_add(b),
+    ADDUW => word(a).wrapping_add(b),
+    SH1ADDUW => (word(a) << 1).wrapping_add(b),
+    SH2ADDUW => (word(a) << 2).wrapping_add(b),
+    SH3ADDUW => (word(a) << 3).wrapping_add(b),
+    SLLIUW => word(a) << shamt,
+    ANDN => a & !b,
+    ORN => a | !b,
+    XNOR => !(a ^ b),
+    CLZ => ((a << (64 - xlen)).leading_zeros().min(xlen)) as u64,
+    CTZ => a.trailing_zeros().min(xlen) as u64,
+    CPOP => a.count_ones() as u64,
+    CLZW => (a as u32).leading_zeros() as u64,
+    CTZW => (a as u32).trailing_zeros() as u64,
+    CPOPW => (a as u32).count_ones() as u64,
+    MIN => if signed(a, xlen) < signed(b, xlen) { a } else { b },
+    MAX => if signed(a, xlen) > signed(b, xlen) { a } else { b },
+    MINU => a.min(b),
+    MAXU => a.max(b),
+    SEXTB => signed(a, 8) as u64,
+    SEXTH => signed(a, 16) as u64,
+    ZEXTH => a & 0xffff,
+    ROL => rotate_left(a, index, xlen),
+    ROR | RORI => rotate_left(a, (xlen - index) % xlen, xlen),
+    // the *W rotations sign extend their 32 bit result
+    ROLW => (a as u32).rotate_left(b as u32) as i32 as u64,
+    RORW => (a as u32).rotate_right(b as u32) as i32 as u64,
+    RORIW => (a as u32).rotate_right(shamt) as i32 as u64,
+    ORCB => (0..8).map(|i| 0xff << (8 * i)).filter(|byte| a & byte != 0).fold(0, |r, byte| r | byte),
+    REV8 => a.swap_bytes() >> (64 - xlen),
+    BSET | BSETI => a | 1 << index,
+    BCLR | BCLRI => a & !(1 << index),
+    BINV | BINVI => a ^ 1 << index,
+    BEXT | BEXTI => a >> index & 1,
+    // the 2 * xlen bit product, of which clmulh takes the high half and clmulr bits 2 * xlen - 2
+    // down to xlen - 1
+    CLMUL => clmul(a, b, xlen) as u64,
+    CLMULH => (clmul(a, b, xlen) >> xlen) as u64,
+    CLMULR => (clmul(a, b, xlen) >> (xlen - 1)) as u64,
+  };
+  T::from_u64(result)
+}
+
+fn rotate_left(v: u64, n: u32, xlen: u32) -> u64 {
+  if n == 0 { return v };
+  let mask = u64::MAX >> (64 - xlen);
+  (v << n | v >> (xlen - n)) & mask
+}
+
+// Multiplication where partial products are xored rather than added
+fn clmul(a: u64, b: u64, xlen: u32) -> u128 {
+  (0..xlen).filter(|i| b >> i & 1 == 1).fold(0, |product, i| product ^ (a as u128) << i)
+}
+
+#[test]
+fn test_bitmanip() {
+  use ZbInstr::*;
+  let rv32 = |var, a: u32, b: u32, shamt| exec(var, a, b, shamt);
+  let rv64 = |var, a: u64, b: u64, shamt| exec(var, a, b, shamt);
+  assert_eq!(rv32(SH3ADD, 0x1000_0001, 4, 0), 0x8000_000c);
+  assert_eq!(rv64(SH2ADDUW, 0xffff_ffff_8000_0001, 1, 0), 0x2_0000_0005);
+  assert_eq!(rv64(SLLIUW, 0xffff_ffff_ffff_ffff, 0, 4), 0xf_ffff_fff0);
+  assert_eq!(rv32(ANDN, 0xff, 0x0f, 0), 0xf0);
+  assert_eq!(rv32(XNOR, 0xff00_ff00, 0x0ff0_0ff0, 0), 0x0f0f_0f0f);
+  // counting bits of 0 gives xlen, or 32 for the *W forms
+  assert_eq!([rv32(CLZ, 0, 0, 0), rv32(CTZ, 0, 0, 0), rv32(CLZ, 1, 0, 0)], [32, 32, 31]);
+  assert_eq!([rv64(CLZ, 0, 0, 0), rv64(CTZ, 0, 0, 0), rv64(CLZW, 1 << 40, 0, 0)], [64, 64, 32]);
+  assert_eq!([rv64(CTZW, 1 << 40, 0, 0), rv64(CPOP, u64::MAX, 0, 0), rv64(CPOPW, u64::MAX, 0, 0)],
+    [32, 64, 32]);
+  assert_eq!([rv32(MIN, 0xffff_ffff, 1, 0), rv32(MINU, 0xffff_ffff, 1, 0)], [0xffff_ffff, 1]);
+  assert_eq!([rv64(MAX, u64::MAX, 1, 0), rv64(MAXU, u64::MAX, 1, 0)], [1, u64::MAX]);
+  assert_eq!([rv32(SEXTB, 0x80, 0, 0), rv32(SEXTH, 0x1_7fff, 0, 0), rv32(ZEXTH, 0xabcd_1234, 0, 0)],
+    [0xffff_ff80, 0x7fff, 0x1234]);
+  // rotations take the low log2(xlen) bits of rs2
+  assert_eq!([rv32(ROL, 0x8000_0001, 33, 0), rv32(ROR, 0x8000_0001, 1, 0), rv32(RORI, 3, 0, 0)],
+    [0x0000_0003, 0xc000_0000, 3]);
+  assert_eq!([rv64(ROR, 1, 64, 0), rv64(RORI, 1, 0, 63), rv64(ROLW, 0x8000_0000, 1, 0)], [1, 2, 1]);
+  assert_eq!(rv64(RORIW, 1, 0, 1), 0xffff_ffff_8000_0000);
+  assert_eq!(rv32(ORCB, 0x0010_8000, 0, 0), 0x00ff_ff00);
+  assert_eq!([rv32(REV8, 0x0102_0304, 0, 24) as u64, rv64(REV8, 0x0102_0304, 0, 56)],
+    [0x0403_0201, 0x0403_0201_0000_0000]);
+  assert_eq!([rv32(BSET, 0, 33, 0), rv32(BCLRI, u32::MAX, 0, 31), rv32(BINV, 5, 2, 0)],
+    [2, 0x7fff_ffff, 1]);
+  assert_eq!([rv64(BEXT, 1 << 63, 63, 0), rv64(BEXTI, 1 << 63, 0, 62), rv64(BSETI, 0, 0, 40)],
+    [1, 0, 1 << 40]);
+  // 0b11 * 0b11 is 0b101 carry-less
+  assert_eq!([rv32(CLMUL, 3, 3, 0), rv32(CLMULH, 0x8000_0000, 3, 0), rv32(CLMULR, 0x8000_0000, 3, 0)],
+    [5, 1, 0x3]);
+  assert_eq!(rv64(CLMULH, u64::MAX, u64::MAX, 0), 0x5555_5555_5555_5555);
+}
@@ -1,27 +1,409 @@
-use crate::mem::Memory;
-// are u32s sufficient to store in cache?
+// Set associative caches in front of memory. They only keep the tags of the lines they hold, as
+// memory always has the data, so they find out which accesses would hit, what would be written
+// back and how long each access would take, without changing what a program sees.
+use std::fmt;
+use crate::mem::WORD_SIZE;
+use crate::program_state::ProgramState;
+use crate::reg::RegData;
+use crate::rvc;
+use crate::trace::MemAccess;
 
-// todo implement Caches
+#[derive(Clone, Copy, Debug, Default)]
 pub struct CacheRow {
-  data: Vec<u32>,
-  tag: u32,
+  tag: usize,
   valid: bool,
   dirty: bool,
+  // when the line was last accessed and when it was filled, in accesses to the cache
+  used: u64,
+  filled: u64,
 }
 
-enum Associativity {
-  N(u32),
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Associativity {
+  // ways in each set, 1 is direct mapped
+  N(usize),
   Full,
 }
 
-pub struct Cache<'a, RegData> {
-  assoc: Associativity,
-  data: Vec<CacheRow>,
-  backing: &'a Memory
+// Which line of a full set is evicted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Replacement {
+  LRU,
+  FIFO,
+  Random,
+  // tree pseudo-LRU, which needs a power of two ways
+  PLRU,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WritePolicy {
+  // writes stay in the cache until the line is evicted
+  WriteBack,
+  // every write also goes on to the next level
+  WriteThrough,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CacheConfig {
+  // in bytes
+  pub size: usize,
+  pub line_size: usize,
+  pub assoc: Associativity,
+  pub replacement: Replacement,
+  pub write: WritePolicy,
+  // whether a write that misses brings the line in
+  pub write_allocate: bool,
+  // cycles to find out whether an access hits
+  pub latency: u64,
+}
+
+impl Default for CacheConfig {
+  fn default() -> Self { CacheConfig::level(1) }
+}
+
+fn parse_size(v: &str) -> Option<usize> {
+  let (digits, scale) = match v.strip_suffix(['k', 'K']) {
+    Some(digits) => (digits, 1024),
+    None => (v, 1),
+  };
+  digits.parse::<usize>().ok().map(|n| n * scale)
+}
+
+impl CacheConfig {
+  // Typical settings for each level, with the lower levels bigger and slower
+  pub fn level(level: usize) -> Self {
+    let (size, ways, latency) = match level { 1 => (32, 4, 1), 2 => (256, 8, 10), _ => (2048, 16, 30) };
+    CacheConfig{ size: size * 1024, line_size: 64, assoc: Associativity::N(ways), replacement: Replacement::LRU,
+      write: WritePolicy::WriteBack, write_allocate: true, latency }
+  }
+  // Reads a comma separated list of settings, such as size=32k,line=64,ways=4,replace=lru,
+  // write=back,allocate=yes,latency=1, where anything left out keeps its default for the level
+  pub fn parse(level: usize, spec: &str) -> Result<Self, String> {
+    let mut config = CacheConfig::level(level);
+    for setting in spec.split(',').filter(|s| !s.is_empty()) {
+      let (key, v) = setting.split_once('=').ok_or(format!("Expected key=value, found {}", setting))?;
+      let invalid = || format!("Invalid {} {}", key, v);
+      match key {
+        "size" => config.size = parse_size(v).ok_or_else(invalid)?,
+        "line" => config.line_size = parse_size(v).ok_or_else(invalid)?,
+        "ways" => config.assoc = match v {
+          "full" => Associativity::Full,
+          n => Associativity::N(n.parse().map_err(|_| invalid())?),
+        },
+        "replace" => config.replacement = match v {
+          "lru" => Replacement::LRU,
+          "fifo" => Replacement::FIFO,
+          "random" => Replacement::Random,
+          "plru" => Replacement::PLRU,
+          _ => return Err(invalid()),
+        },
+        "write" => config.write = match v {
+          "back" => WritePolicy::WriteBack,
+          "through" => WritePolicy::WriteThrough,
+          _ => return Err(invalid()),
+        },
+        "allocate" => config.write_allocate = match v {
+          "yes" => true,
+          "no" => false,
+          _ => return Err(invalid()),
+        },
+        "latency" => config.latency = v.parse().map_err(|_| invalid())?,
+        _ => return Err(format!("Unknown cache setting {}", key)),
+      };
+    };
+    Ok(config)
+  }
+  fn lines(&self) -> usize { self.size / self.line_size }
+  fn ways(&self) -> usize {
+    match self.assoc { Associativity::N(n) => n, Associativity::Full => self.lines() }
+  }
+}
+
+// Counts of what a cache did, every write through or line written back goes to the next level
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CacheStats {
+  pub reads: u64,
+  pub read_misses: u64,
+  pub writes: u64,
+  pub write_misses: u64,
+  pub writebacks: u64,
+  pub write_throughs: u64,
+}
+
+impl CacheStats {
+  pub fn accesses(&self) -> u64 { self.reads + self.writes }
+  pub fn misses(&self) -> u64 { self.read_misses + self.write_misses }
+  pub fn hit_rate(&self) -> f64 {
+    if self.accesses() == 0 { return 100.0 };
+    100.0 * (self.accesses() - self.misses()) as f64 / self.accesses() as f64
+  }
+}
+
+impl fmt::Display for CacheStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} of {} hit ({:.2}%), {} read and {} write misses, {} writebacks, {} write throughs",
+      self.accesses() - self.misses(), self.accesses(), self.hit_rate(), self.read_misses,
+      self.write_misses, self.writebacks, self.write_throughs)
+  }
+}
+
+// What happened to an access, for the level below
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Lookup {
+  pub hit: bool,
+  // the address of a dirty line evicted to make room
+  pub writeback: Option<usize>,
+  // whether the write also went on to the next level
+  pub write_through: bool,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+  config: CacheConfig,
+  sets: Vec<Vec<CacheRow>>,
+  // for PLRU, a tree per set with a bit at each node pointing towards the half to evict from
+  trees: Vec<Vec<bool>>,
+  accesses: u64,
+  // xorshift state for random replacement, so runs are repeatable
+  seed: u64,
+  pub stats: CacheStats,
 }
 
 impl Cache {
-  pub fn new() -> Self {
+  pub fn new(config: CacheConfig) -> Result<Self, String> {
+    let ways = config.ways();
+    if !config.line_size.is_power_of_two() || config.line_size < WORD_SIZE {
+      return Err(format!("Cache lines must be a power of two of at least {} bytes", WORD_SIZE));
+    };
+    if ways == 0 || config.size == 0 || !config.size.is_multiple_of(config.line_size * ways) {
+      return Err("Cache size must be a multiple of the line size times the ways".to_string());
+    };
+    let sets = config.lines() / ways;
+    if !sets.is_power_of_two() { return Err("Caches must have a power of two sets".to_string()) };
+    if config.replacement == Replacement::PLRU && !ways.is_power_of_two() {
+      return Err("PLRU needs a power of two ways".to_string());
+    };
+    Ok(Cache{ config, sets: vec![vec![CacheRow::default(); ways]; sets], trees: vec![vec![false; ways - 1]; sets],
+      accesses: 0, seed: 0x2545f4914f6cdd1d, stats: CacheStats::default() })
+  }
 
+  // An L1 cache described by spec, for tests
+  #[cfg(test)]
+  pub(crate) fn l1(spec: &str) -> Self { Cache::new(CacheConfig::parse(1, spec).unwrap()).unwrap() }
+
+  pub fn config(&self) -> CacheConfig { self.config }
+
+  // Looks up the line holding addr, bringing it in on a miss unless it is a write that does not
+  // allocate
+  pub fn access(&mut self, addr: usize, write: bool) -> Lookup {
+    self.accesses += 1;
+    let line = addr / self.config.line_size;
+    let (set, tag) = (line % self.sets.len(), line / self.sets.len());
+    let through = write && self.config.write == WritePolicy::WriteThrough;
+    let mut lookup = Lookup{ hit: true, writeback: None, write_through: through };
+    let way = match self.sets[set].iter().position(|r| r.valid && r.tag == tag) {
+      Some(way) => Some(way),
+      None => {
+        lookup.hit = false;
+        if write && !self.config.write_allocate { None } else {
+          let way = self.victim(set);
+          let old = self.sets[set][way];
+          if old.valid && old.dirty {
+            lookup.writeback = Some((old.tag * self.sets.len() + set) * self.config.line_size);
+          };
+          self.sets[set][way] = CacheRow{ tag, valid: true, dirty: false, used: 0, filled: self.accesses };
+          Some(way)
+        }
+      },
+    };
+    if let Some(way) = way {
+      let row = &mut self.sets[set][way];
+      row.used = self.accesses;
+      row.dirty |= write && !through;
+      self.touch(set, way);
+    };
+    let stats = &mut self.stats;
+    match write {
+      false => { stats.reads += 1; stats.read_misses += !lookup.hit as u64 },
+      true => { stats.writes += 1; stats.write_misses += !lookup.hit as u64 },
+    };
+    stats.writebacks += lookup.writeback.is_some() as u64;
+    stats.write_throughs += through as u64;
+    lookup
+  }
+
+  // The way in a set to fill, an empty one if there is one
+  fn victim(&mut self, set: usize) -> usize {
+    let rows = &self.sets[set];
+    if let Some(way) = rows.iter().position(|r| !r.valid) { return way };
+    let oldest = |key: fn(&CacheRow) -> u64| (0..rows.len()).min_by_key(|&w| key(&rows[w])).unwrap_or(0);
+    match self.config.replacement {
+      Replacement::LRU => oldest(|r| r.used),
+      Replacement::FIFO => oldest(|r| r.filled),
+      Replacement::Random => {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed % rows.len() as u64) as usize
+      },
+      Replacement::PLRU => {
+        let tree = &self.trees[set];
+        let mut node = 0;
+        while node < tree.len() { node = 2 * node + 1 + tree[node] as usize };
+        node - tree.len()
+      },
+    }
+  }
+
+  // Points the PLRU tree away from a way that was just used
+  fn touch(&mut self, set: usize, way: usize) {
+    let tree = &mut self.trees[set];
+    let mut node = way + tree.len();
+    while node > 0 {
+      let parent = (node - 1) / 2;
+      // the left child is odd, so the parent points right at the other half
+      tree[parent] = node % 2 == 1;
+      node = parent;
+    };
+  }
+}
+
+// L1 instruction and data caches, then the levels they share and memory
+#[derive(Debug)]
+pub struct Hierarchy {
+  pub icache: Option<Cache>,
+  pub dcache: Option<Cache>,
+  // L2 then L3
+  pub lower: Vec<Cache>,
+  // cycles for memory to answer an access that missed every level. Accesses with no cache to
+  // look in are answered straight away, as though memory were as fast as a register.
+  pub dram_latency: u64,
+}
+
+impl Default for Hierarchy {
+  fn default() -> Self { Hierarchy{ icache: None, dcache: None, lower: Vec::new(), dram_latency: 100 } }
+}
+
+impl Hierarchy {
+  // Looks an access up in each level until one hits, returning the cycles it took
+  fn access(&mut self, instr: bool, addr: usize, mut write: bool) -> u64 {
+    let l1 = if instr { self.icache.as_mut() } else { self.dcache.as_mut() };
+    let mut levels: Vec<&mut Cache> = l1.into_iter().chain(self.lower.iter_mut()).collect();
+    if levels.is_empty() { return 1 };
+    let mut cycles = 0;
+    for i in 0..levels.len() {
+      let (level, below) = levels[i..].split_first_mut().expect("Level in range");
+      cycles += level.config.latency;
+      let lookup = level.access(addr, write);
+      let allocate = level.config.write_allocate;
+      // writes to the next level are buffered, so do not hold the access up
+      if let Some(line) = lookup.writeback { write_below(below, line) };
+      if lookup.write_through || (write && !lookup.hit && !allocate) { write_below(below, addr) };
+      if lookup.hit || (write && !allocate) { return cycles };
+      // the line is read in from the level below
+      write = false;
+    };
+    cycles + self.dram_latency
   }
 }
+
+// Passes a write on to the first of levels, and whatever it writes on in turn
+fn write_below(levels: &mut [&mut Cache], addr: usize) {
+  let (level, below) = match levels.split_first_mut() { Some(split) => split, None => return };
+  let lookup = level.access(addr, true);
+  if let Some(line) = lookup.writeback { write_below(below, line) };
+  if lookup.write_through || (!lookup.hit && !level.config.write_allocate) { write_below(below, addr) };
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Cycles to fetch the instruction at pc. A 4 byte instruction after a compressed one can
+  // straddle two lines, which are looked up together so the slower one decides.
+  pub(crate) fn fetch_latency(&mut self, pc: T) -> u64 {
+    let first = pc.as_usize();
+    let last = first + self.mem.read_instr(first).map_or(2, rvc::instr_len) - 1;
+    let cycles = self.caches.access(true, first, false);
+    let caches = &self.caches;
+    let line = caches.icache.as_ref().or(caches.lower.first()).map_or(usize::MAX, |c| c.config.line_size);
+    if first / line == last / line { return cycles };
+    cycles.max(self.caches.access(true, last, false))
+  }
+  // Cycles for a load or store to access memory
+  pub(crate) fn data_latency(&mut self, access: MemAccess<T>) -> u64 {
+    self.caches.access(false, access.addr.as_usize(), access.store.is_some())
+  }
+  // Whether the instruction at pc has arrived, starting to fetch it if fetch was waiting on
+  // something else. Fetch asks again each cycle until it has.
+  pub(crate) fn instr_arrived(&mut self, waiting: &mut Option<(T, u64)>, pc: T) -> bool {
+    let ready = match *waiting {
+      Some((from, ready)) if from == pc => ready,
+      _ => self.csrs.cycle + self.fetch_latency(pc).saturating_sub(1),
+    };
+    *waiting = Some((pc, ready));
+    if self.csrs.cycle < ready {
+      self.stats.fetch_stalls += 1;
+      return false
+    };
+    *waiting = None;
+    true
+  }
+}
+
+#[test]
+fn test_cache() {
+  let misses = |spec: &str, lines: &[usize]| {
+    let mut cache = Cache::l1(spec);
+    lines.iter().for_each(|&l| { cache.access(l * 16, false); });
+    cache.stats.read_misses
+  };
+  // A B A C A in one set of two ways, FIFO evicts A for C even though it was just used
+  let abaca = [0, 1, 0, 2, 0];
+  assert_eq!(misses("size=32,line=16,ways=2,replace=lru", &abaca), 3);
+  assert_eq!(misses("size=32,line=16,ways=2,replace=fifo", &abaca), 4);
+  assert_eq!(misses("size=32,line=16,ways=full,replace=plru", &abaca), 3);
+  // after A B C D A the tree points at C, while B is least recently used
+  let plru = [0, 1, 2, 3, 0, 4, 1];
+  assert_eq!(misses("size=64,line=16,ways=4,replace=lru", &plru), 6);
+  assert_eq!(misses("size=64,line=16,ways=4,replace=plru", &plru), 5);
+  assert!(misses("size=64,line=16,ways=4,replace=random", &plru) >= 5);
+  // direct mapped lines 4 apart conflict, and the other sets are untouched
+  assert_eq!(misses("size=64,line=16,ways=1", &[0, 4, 0, 1, 2, 1, 2]), 5);
+
+  let run = |spec: &str| {
+    let mut cache = Cache::l1(spec);
+    let lookups = [cache.access(0, true), cache.access(64, false), cache.access(0, false)];
+    (lookups, cache.stats)
+  };
+  let (back, stats) = run("size=64,line=64,ways=1");
+  assert_eq!(back[1].writeback, Some(0));
+  assert_eq!((stats.writebacks, stats.write_throughs, stats.misses()), (1, 0, 3));
+  let (through, stats) = run("size=64,line=64,ways=1,write=through");
+  assert!(through[0].write_through && through[1].writeback.is_none());
+  assert_eq!((stats.writebacks, stats.write_throughs), (0, 1));
+  // without allocating, the write leaves the line out, so the read of 64 evicts nothing
+  let (_, stats) = run("size=128,line=64,ways=1,allocate=no,write=through");
+  assert_eq!((stats.write_misses, stats.read_misses), (1, 2));
+
+  assert!(CacheConfig::parse(1, "size=64,colour=red").is_err());
+  assert!(Cache::new(CacheConfig::parse(1, "size=96,line=16,ways=2").unwrap()).is_err());
+  assert!(Cache::new(CacheConfig::parse(1, "size=96,line=16,ways=3,replace=plru").unwrap()).is_err());
+
+  // A misses everywhere, then hits in L1, and after B takes its place in L1 still hits in L2
+  let level = |level, spec| Cache::new(CacheConfig::parse(level, spec).unwrap()).unwrap();
+  let mut caches = Hierarchy{ dcache: Some(level(1, "size=16,line=16,ways=1")),
+    lower: vec![level(2, "size=64,line=16,ways=4")], ..Hierarchy::default() };
+  let latencies: Vec<u64> = [0, 0, 16, 0].iter().map(|&a| caches.access(false, a, false)).collect();
+  assert_eq!(latencies, [1 + 10 + 100, 1, 1 + 10 + 100, 1 + 10]);
+  // without an I-cache fetches go straight to L2
+  assert_eq!(caches.access(true, 0, false), 10);
+  assert_eq!(Hierarchy::default().access(true, 0, false), 1);
+
+  // c.nop fills the first half of a line and an addi straddles the next two
+  let mut mem = crate::mem::Memory::<u32>::new(0x40);
+  [0x0001u32, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001, 0x0513, 0x0015].iter().enumerate()
+    .for_each(|(i, &h)| mem.write(i * 2, h, crate::mem::Size::HALF).unwrap());
+  let mut ps = ProgramState::new(mem);
+  ps.caches.icache = Some(level(1, "size=32,line=16,ways=1"));
+  assert_eq!([0, 2, 14, 14].map(|pc| ps.fetch_latency(pc)), [101, 1, 101, 1]);
+  let stats = ps.caches.icache.unwrap().stats;
+  assert_eq!((stats.accesses(), stats.read_misses), (6, 2));
+}
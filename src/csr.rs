@@ -0,0 +1,467 @@
+// Control and status registers (Zicsr) for a single hart with M, S and U modes
+use crate::instr::IInstr;
+use crate::program_state::{ProgramState, Exceptions, Privilege};
+use crate::reg::RegData;
+
+// Floating point, fcsr holds frm above fflags
+pub const FFLAGS: u32 = 0x001;
+pub const FRM: u32 = 0x002;
+pub const FCSR: u32 = 0x003;
+
+// Vector, vl, vtype and vlenb are read-only and only change through vset{i}vl{i}
+pub const VSTART: u32 = 0x008;
+pub const VL: u32 = 0xC20;
+pub const VTYPE: u32 = 0xC21;
+pub const VLENB: u32 = 0xC22;
+
+// Unprivileged counters, the *H variants hold the upper 32 bits on RV32
+pub const CYCLE: u32 = 0xC00;
+pub const TIME: u32 = 0xC01;
+pub const INSTRET: u32 = 0xC02;
+pub const CYCLEH: u32 = 0xC80;
+pub const TIMEH: u32 = 0xC81;
+pub const INSTRETH: u32 = 0xC82;
+
+// Supervisor mode, sstatus, sie and sip are views of the machine mode registers
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+
+// Machine mode
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MCYCLE: u32 = 0xB00;
+pub const MINSTRET: u32 = 0xB02;
+pub const MCYCLEH: u32 = 0xB80;
+pub const MINSTRETH: u32 = 0xB82;
+pub const MHARTID: u32 = 0xF14;
+
+// mstatus fields. SUM is read-only 0 as satp can only be Bare.
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_MPP: u32 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP
+  | MSTATUS_MPRV | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+// the fields sstatus shows and can write
+const SSTATUS_FIELDS: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_MXR;
+
+// Software, timer and external interrupt bits of mie, mip and mideleg. Only the supervisor ones
+// can be delegated, and only SSIP can be written through sip.
+const S_INTERRUPTS: u32 = 1 << 1 | 1 << 5 | 1 << 9;
+const M_INTERRUPTS: u32 = 1 << 3 | 1 << 7 | 1 << 11;
+const SSIP: u32 = 1 << 1;
+// Exceptions that can be delegated, every cause but ecalls from M mode and the reserved ones
+const MEDELEG_WRITABLE: u32 = 0xb3ff;
+// The cycle, time and instret bits of mcounteren and scounteren
+const COUNTEREN_WRITABLE: u32 = 0b111;
+
+// Extensions reported in misa, one bit per letter
+const MISA_EXTENSIONS: u32 = 1 << (b'I' - b'A') | 1 << (b'M' - b'A') | 1 /* A */ | 1 << (b'F' - b'A')
+  | 1 << (b'D' - b'A') | 1 << (b'C' - b'A') | 1 << (b'B' - b'A') | 1 << (b'S' - b'A')
+  | 1 << (b'U' - b'A');
+
+// Assembler name of a CSR
+pub fn name(csr: u32) -> Option<&'static str> {
+  let name = match csr {
+    FFLAGS => "fflags", FRM => "frm", FCSR => "fcsr",
+    VSTART => "vstart", VL => "vl", VTYPE => "vtype", VLENB => "vlenb",
+    CYCLE => "cycle", TIME => "time", INSTRET => "instret",
+    CYCLEH => "cycleh", TIMEH => "timeh", INSTRETH => "instreth",
+    SSTATUS => "sstatus", SIE => "sie", STVEC => "stvec", SCOUNTEREN => "scounteren",
+    SSCRATCH => "sscratch", SEPC => "sepc", SCAUSE => "scause", STVAL => "stval", SIP => "sip",
+    SATP => "satp",
+    MSTATUS => "mstatus", MISA => "misa", MEDELEG => "medeleg", MIDELEG => "mideleg",
+    MIE => "mie", MTVEC => "mtvec", MCOUNTEREN => "mcounteren", MSCRATCH => "mscratch",
+    MEPC => "mepc", MCAUSE => "mcause", MTVAL => "mtval", MIP => "mip",
+    MCYCLE => "mcycle", MINSTRET => "minstret", MCYCLEH => "mcycleh", MINSTRETH => "minstreth",
+    MHARTID => "mhartid",
+    _ => return None,
+  };
+  Some(name)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Csrs<T : RegData> {
+  pub cycle: u64,
+  pub instret: u64,
+  pub mstatus: T,
+  pub mtvec: T,
+  pub mepc: T,
+  pub mcause: T,
+  pub mtval: T,
+  pub mscratch: T,
+  // exceptions and interrupts handled in S mode rather than M mode, one bit per cause
+  pub medeleg: T,
+  pub mideleg: T,
+  // interrupts enabled and pending, which nothing in the simulator raises
+  pub mie: T,
+  pub mip: T,
+  // the counters S and U mode can read
+  pub mcounteren: u32,
+  pub scounteren: u32,
+  pub stvec: T,
+  // set once the program writes mtvec or stvec, before that exceptions end the simulation
+  // instead of trapping to whatever address the vector holds
+  pub handler_installed: bool,
+  pub sepc: T,
+  pub scause: T,
+  pub stval: T,
+  pub sscratch: T,
+  pub satp: T,
+  // accrued exceptions and the dynamic rounding mode
+  pub fflags: u32,
+  pub frm: u32,
+  // the element vector instructions start from, and how many elements they work on
+  pub vstart: T,
+  pub vl: T,
+  pub vtype: T,
+}
+
+impl <T : RegData> Default for Csrs<T> {
+  fn default() -> Self { Csrs::new() }
+}
+
+impl <T : RegData> Csrs<T> {
+  pub fn new() -> Self {
+    Csrs{
+      cycle: 0,
+      instret: 0,
+      // an mret before any trap stays in M mode
+      mstatus: T::from(MSTATUS_MPP),
+      mtvec: T::zero(),
+      mepc: T::zero(),
+      mcause: T::zero(),
+      mtval: T::zero(),
+      mscratch: T::zero(),
+      medeleg: T::zero(),
+      mideleg: T::zero(),
+      mie: T::zero(),
+      mip: T::zero(),
+      mcounteren: 0,
+      scounteren: 0,
+      stvec: T::zero(),
+      handler_installed: false,
+      sepc: T::zero(),
+      scause: T::zero(),
+      stval: T::zero(),
+      sscratch: T::zero(),
+      satp: T::zero(),
+      fflags: 0,
+      frm: 0,
+      vstart: T::zero(),
+      vl: T::zero(),
+      // vector instructions are illegal until a vsetvl
+      vtype: crate::vector::vill(),
+    }
+  }
+
+  fn rv32() -> bool { T::BYTE_SIZE == 4 }
+
+  // The level MPP returns to
+  pub fn mpp(&self) -> Privilege {
+    Privilege::from_bits(self.mstatus.as_usize() as u32 >> MSTATUS_MPP_SHIFT)
+  }
+
+  // UXL and, in mstatus, SXL, which in RV64 read as 64 bits and cannot be changed
+  fn xl(s_mode: bool) -> T {
+    if Self::rv32() { return T::zero() };
+    T::from_u64(if s_mode { 2 << 32 | 2 << 34 } else { 2 << 32 })
+  }
+
+  // Reads a CSR, failing for ones that do not exist
+  pub fn read(&self, csr: u32) -> Result<T, Exceptions> {
+    let v = match csr {
+      FFLAGS => T::from(self.fflags),
+      FRM => T::from(self.frm),
+      FCSR => T::from(self.frm << 5 | self.fflags),
+      VSTART => self.vstart,
+      VL => self.vl,
+      VTYPE => self.vtype,
+      // time advances once per cycle
+      CYCLE | MCYCLE | TIME => T::from_u64(self.cycle),
+      INSTRET | MINSTRET => T::from_u64(self.instret),
+      CYCLEH | MCYCLEH | TIMEH if Self::rv32() => T::from_u64(self.cycle >> 32),
+      INSTRETH | MINSTRETH if Self::rv32() => T::from_u64(self.instret >> 32),
+      SSTATUS => (self.mstatus & T::from(SSTATUS_FIELDS)) | Self::xl(false),
+      SIE => self.mie & self.mideleg,
+      STVEC => self.stvec,
+      SCOUNTEREN => T::from(self.scounteren),
+      SSCRATCH => self.sscratch,
+      SEPC => self.sepc,
+      SCAUSE => self.scause,
+      STVAL => self.stval,
+      SIP => self.mip & self.mideleg,
+      SATP => self.satp,
+      MSTATUS => self.mstatus | Self::xl(true),
+      MISA => {
+        let mxl = if Self::rv32() { 1 } else { 2 };
+        (T::from(mxl as u32) << T::from((T::BYTE_SIZE * 8 - 2) as u32)) | T::from(MISA_EXTENSIONS)
+      },
+      MEDELEG => self.medeleg,
+      MIDELEG => self.mideleg,
+      MIE => self.mie,
+      MTVEC => self.mtvec,
+      MCOUNTEREN => T::from(self.mcounteren),
+      MSCRATCH => self.mscratch,
+      MEPC => self.mepc,
+      MCAUSE => self.mcause,
+      MTVAL => self.mtval,
+      MIP => self.mip,
+      MHARTID => T::zero(),
+      _ => return Err(Exceptions::IllegalInstruction),
+    };
+    Ok(v)
+  }
+
+  // Writes a CSR, ignoring bits that are not writable, and failing for read-only CSRs
+  pub fn write(&mut self, csr: u32, v: T) -> Result<(), Exceptions> {
+    // the top two address bits being set marks a CSR as read-only
+    if csr >> 10 == 0b11 { return Err(Exceptions::IllegalInstruction) };
+    let v64 = v.as_usize() as u64;
+    match csr {
+      FFLAGS => self.fflags = v64 as u32 & 0x1f,
+      FRM => self.frm = v64 as u32 & 0b111,
+      FCSR => {
+        self.fflags = v64 as u32 & 0x1f;
+        self.frm = (v64 >> 5) as u32 & 0b111;
+      },
+      VSTART => self.vstart = v,
+      MCYCLE if Self::rv32() => self.cycle = (self.cycle & !0xffffffff) | v64,
+      MCYCLE => self.cycle = v64,
+      MINSTRET if Self::rv32() => self.instret = (self.instret & !0xffffffff) | v64,
+      MINSTRET => self.instret = v64,
+      MCYCLEH if Self::rv32() => self.cycle = (self.cycle & 0xffffffff) | (v64 << 32),
+      MINSTRETH if Self::rv32() => self.instret = (self.instret & 0xffffffff) | (v64 << 32),
+      SSTATUS => {
+        let fields = T::from(SSTATUS_FIELDS);
+        self.mstatus = (self.mstatus & !fields) | (v & fields);
+      },
+      // only delegated interrupts can be seen or changed from S mode
+      SIE => self.mie = (self.mie & !self.mideleg) | (v & self.mideleg),
+      SIP => {
+        let writable = self.mideleg & T::from(SSIP);
+        self.mip = (self.mip & !writable) | (v & writable);
+      },
+      STVEC => {
+        self.stvec = tvec(self.stvec, v);
+        self.handler_installed = true;
+      },
+      SCOUNTEREN => self.scounteren = v64 as u32 & COUNTEREN_WRITABLE,
+      SSCRATCH => self.sscratch = v,
+      SEPC => self.sepc = v & !T::one(),
+      SCAUSE => self.scause = v,
+      STVAL => self.stval = v,
+      // only Bare is supported, and writes asking for any other mode are ignored entirely
+      SATP => if v64 >> (if Self::rv32() { 31 } else { 60 }) == 0 { self.satp = v },
+      // MPP is WARL, and the reserved H level goes to U
+      MSTATUS => {
+        let mpp = Privilege::from_bits((v64 >> MSTATUS_MPP_SHIFT) as u32) as u32;
+        self.mstatus = (v & T::from(MSTATUS_WRITABLE)) | T::from(mpp << MSTATUS_MPP_SHIFT);
+      },
+      // misa is WARL and the extensions cannot be turned off
+      MISA => (),
+      MEDELEG => self.medeleg = v & T::from(MEDELEG_WRITABLE),
+      MIDELEG => self.mideleg = v & T::from(S_INTERRUPTS),
+      MIE => self.mie = v & T::from(S_INTERRUPTS | M_INTERRUPTS),
+      MTVEC => {
+        self.mtvec = tvec(self.mtvec, v);
+        self.handler_installed = true;
+      },
+      MCOUNTEREN => self.mcounteren = v64 as u32 & COUNTEREN_WRITABLE,
+      MSCRATCH => self.mscratch = v,
+      // compressed instructions can trap on any 2 byte boundary
+      MEPC => self.mepc = v & !T::one(),
+      MCAUSE => self.mcause = v,
+      MTVAL => self.mtval = v,
+      // the machine level bits are only set by interrupt sources
+      MIP => self.mip = (self.mip & !T::from(S_INTERRUPTS)) | (v & T::from(S_INTERRUPTS)),
+      _ => return Err(Exceptions::IllegalInstruction),
+    };
+    Ok(())
+  }
+}
+
+// mtvec and stvec, where only direct and vectored modes exist and other modes leave the old
+// mode in place
+fn tvec<T : RegData>(old: T, v: T) -> T {
+  let mode = T::from(0b11u32);
+  let keep = if (v & mode) > T::one() { old & mode } else { v & mode };
+  (v & !mode) | keep
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Whether the current privilege level can use a CSR. Bits 9:8 of its address are the lowest
+  // level that can, mstatus.TVM keeps satp from S mode, and the user counters need their bit in
+  // mcounteren to be read below M mode, and in scounteren too to be read in U mode.
+  fn csr_allowed(&self, csr: u32) -> bool {
+    let prv = self.privilege;
+    let enabled = |counteren: u32| counteren >> (csr & 0x1f) & 1 == 1;
+    match (csr, prv) {
+      _ if (csr >> 8) & 0b11 > prv as u32 => false,
+      (_, Privilege::Machine) => true,
+      (SATP, _) => (self.csrs.mstatus & T::from(MSTATUS_TVM)) == T::zero(),
+      (CYCLE..=INSTRET | CYCLEH..=INSTRETH, Privilege::Supervisor) => enabled(self.csrs.mcounteren),
+      (CYCLE..=INSTRET | CYCLEH..=INSTRETH, _) =>
+        enabled(self.csrs.mcounteren) && enabled(self.csrs.scounteren),
+      _ => true,
+    }
+  }
+
+  // Executes a Zicsr instruction, returning the old value of the CSR to be written to rd.
+  // rs1 is the register index, or the immediate for the *I variants, and rs1_val its value.
+  pub(crate) fn csr_op(&mut self, var: IInstr, csr: u32, rs1: u32, rs1_val: T) -> Result<T, Exceptions> {
+    let src = match var {
+      IInstr::CSRRWI | IInstr::CSRRSI | IInstr::CSRRCI => T::from(rs1),
+      _ => rs1_val,
+    };
+    if !self.csr_allowed(csr) { return Err(Exceptions::IllegalInstruction) };
+    // vlenb is the only CSR that depends on the vector registers
+    let old = if csr == VLENB { T::from(self.vregs.vlenb() as u32) } else { self.csrs.read(csr)? };
+    match var {
+      IInstr::CSRRW | IInstr::CSRRWI => self.csrs.write(csr, src)?,
+      // set and clear with x0 or a zero immediate do not write, so they can read counters
+      IInstr::CSRRS | IInstr::CSRRSI if rs1 != 0 => self.csrs.write(csr, old | src)?,
+      IInstr::CSRRC | IInstr::CSRRCI if rs1 != 0 => self.csrs.write(csr, old & !src)?,
+      IInstr::CSRRS | IInstr::CSRRSI | IInstr::CSRRC | IInstr::CSRRCI => (),
+      v => panic!("csr_op() called with non-CSR instruction {:?}", v),
+    };
+    Ok(old)
+  }
+}
+
+#[test]
+fn test_csr_ops() {
+  let mut ps = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  assert_eq!(ps.csr_op(IInstr::CSRRW, MSCRATCH, 5, 0xf0), Ok(0));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, MSCRATCH, 5, 0x0f), Ok(0xf0));
+  assert_eq!(ps.csr_op(IInstr::CSRRC, MSCRATCH, 5, 0x11), Ok(0xff));
+  assert_eq!(ps.csr_op(IInstr::CSRRCI, MSCRATCH, 0b1110, 0), Ok(0xee));
+  assert_eq!(ps.csr_op(IInstr::CSRRSI, MSCRATCH, 1, 0), Ok(0xe0));
+  assert_eq!(ps.csr_op(IInstr::CSRRWI, MSCRATCH, 31, 0), Ok(0xe1));
+  assert_eq!(ps.csrs.mscratch, 31);
+}
+
+#[test]
+fn test_csr_read_only() {
+  let mut ps = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  ps.csrs.cycle = 0x1_0000_0002;
+  // csrr is csrrs with x0, which must not count as a write
+  assert_eq!(ps.csr_op(IInstr::CSRRS, CYCLE, 0, 0xff), Ok(2));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, CYCLEH, 0, 0), Ok(1));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, CYCLE, 1, 0xff), Err(Exceptions::IllegalInstruction));
+  assert_eq!(ps.csr_op(IInstr::CSRRW, MHARTID, 0, 0), Err(Exceptions::IllegalInstruction));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, 0x7ff, 0, 0), Err(Exceptions::IllegalInstruction));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, MISA, 0, 0), Ok(0x4014_112f));
+  assert_eq!(ps.csr_op(IInstr::CSRRW, MCYCLEH, 1, 7), Ok(1));
+  assert_eq!(ps.csrs.cycle, 0x7_0000_0002);
+}
+
+#[test]
+fn test_csr_warl() {
+  let mut csrs = Csrs::<u32>::new();
+  csrs.write(MSTATUS, u32::MAX).unwrap();
+  assert_eq!(csrs.mstatus, MSTATUS_WRITABLE | MSTATUS_MPP);
+  // MPP cannot be H
+  csrs.write(MSTATUS, 2 << MSTATUS_MPP_SHIFT).unwrap();
+  assert_eq!(csrs.mpp(), Privilege::User);
+  assert!(!csrs.handler_installed);
+  csrs.write(MTVEC, 0x1001).unwrap();
+  csrs.write(MTVEC, 0x2002).unwrap();
+  assert_eq!(csrs.mtvec, 0x2001);
+  assert!(csrs.handler_installed);
+  csrs.write(MEPC, 0x1003).unwrap();
+  assert_eq!(csrs.mepc, 0x1002);
+  csrs.write(FCSR, 0xfff).unwrap();
+  assert_eq!((csrs.frm, csrs.fflags), (0b111, 0x1f));
+  csrs.write(FFLAGS, 0).unwrap();
+  assert_eq!(csrs.read(FCSR), Ok(0xe0));
+  let csrs = Csrs::<u64>::new();
+  assert_eq!(csrs.read(MISA), Ok(2 << 62 | 0x14_112f));
+  assert_eq!(csrs.read(MSTATUS), Ok(0xa_0000_0000 | MSTATUS_MPP as u64));
+  assert_eq!(csrs.read(CYCLEH), Err(Exceptions::IllegalInstruction));
+}
+
+#[test]
+fn test_csr_supervisor() {
+  let mut csrs = Csrs::<u64>::new();
+  // sstatus only shows the supervisor fields of mstatus, and UXL
+  csrs.write(SSTATUS, u64::MAX).unwrap();
+  assert_eq!(csrs.mstatus, (SSTATUS_FIELDS | MSTATUS_MPP) as u64);
+  assert_eq!(csrs.read(SSTATUS), Ok(2 << 32 | SSTATUS_FIELDS as u64));
+  // only supervisor interrupts are delegated, and sie and sip only see delegated ones
+  csrs.write(MIE, u64::MAX).unwrap();
+  csrs.write(MIDELEG, u64::MAX).unwrap();
+  assert_eq!((csrs.mie, csrs.mideleg), (0xaaa, 0x222));
+  csrs.write(MIDELEG, 1 << 5).unwrap();
+  assert_eq!(csrs.read(SIE), Ok(1 << 5));
+  csrs.write(SIE, 0).unwrap();
+  assert_eq!(csrs.mie, 0xa8a);
+  csrs.write(SIP, u64::MAX).unwrap();
+  assert_eq!(csrs.mip, 0);
+  csrs.write(MIP, u64::MAX).unwrap();
+  assert_eq!((csrs.mip, csrs.read(SIP)), (0x222, Ok(1 << 5)));
+  // ecalls from M mode always stay in M mode
+  csrs.write(MEDELEG, u64::MAX).unwrap();
+  assert_eq!(csrs.medeleg, 0xb3ff);
+  // satp is Bare only, so Sv39 is ignored
+  csrs.write(SATP, 8 << 60 | 0x1234).unwrap();
+  assert_eq!(csrs.satp, 0);
+  csrs.write(SATP, 0x1234).unwrap();
+  assert_eq!(csrs.satp, 0x1234);
+  csrs.write(STVEC, 0x1003).unwrap();
+  csrs.write(SEPC, 0x1003).unwrap();
+  assert_eq!((csrs.stvec, csrs.sepc), (0x1000, 0x1002));
+}
+
+#[test]
+fn test_csr_privilege() {
+  let mut ps = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  let illegal = Err(Exceptions::IllegalInstruction);
+  ps.privilege = Privilege::Supervisor;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, MSTATUS, 0, 0), illegal);
+  assert_eq!(ps.csr_op(IInstr::CSRRW, SSCRATCH, 1, 5), Ok(0));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, SATP, 0, 0), Ok(0));
+  ps.csrs.mstatus |= MSTATUS_TVM;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, SATP, 0, 0), illegal);
+  ps.privilege = Privilege::User;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, SSCRATCH, 0, 0), illegal);
+  assert_eq!(ps.csr_op(IInstr::CSRRS, FCSR, 0, 0), Ok(0));
+  // the counters need mcounteren below M mode, and scounteren as well in U mode
+  ps.csrs.instret = 9;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, INSTRET, 0, 0), illegal);
+  ps.csrs.mcounteren = 0b100;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, INSTRET, 0, 0), illegal);
+  ps.privilege = Privilege::Supervisor;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, INSTRET, 0, 0), Ok(9));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, CYCLE, 0, 0), illegal);
+  ps.csr_op(IInstr::CSRRWI, SCOUNTEREN, 0b111, 0).unwrap();
+  ps.privilege = Privilege::User;
+  assert_eq!(ps.csr_op(IInstr::CSRRS, INSTRETH, 0, 0), Ok(0));
+  assert_eq!(ps.csr_op(IInstr::CSRRS, TIME, 0, 0), illegal);
+}
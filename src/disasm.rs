@@ -0,0 +1,462 @@
+// Renders decoded instructions as assembly
+use std::fmt;
+use crate::csr;
+use crate::fp;
+use crate::instr::{self, InstrType, RInstr, ZbInstr, IInstr, BInstr, JInstr, AInstr, FInstr, VInstr, VSrc};
+use crate::reg::{RegData, ABI_NAMES};
+use crate::rvc;
+
+// How registers and instructions are spelled
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Syntax {
+  // a0 rather than x10
+  pub abi_names: bool,
+  // fold instructions into the pseudo-instructions they implement, e.g. li, mv, j and ret
+  pub pseudo: bool,
+}
+
+impl Default for Syntax {
+  fn default() -> Self { Syntax{ abi_names: true, pseudo: true } }
+}
+
+// An instruction ready to be printed. Jump and branch targets are shown as addresses when the
+// pc of the instruction is known, and as offsets otherwise.
+pub(crate) struct Disasm<T : RegData> {
+  instr: InstrType,
+  pc: Option<T>,
+  syntax: Syntax,
+}
+
+// Disassembles the instruction at pc, words that do not decode are shown as data. Compressed
+// instructions are shown as the ones they expand to.
+pub fn disassemble<T : RegData>(raw: u32, pc: T, syntax: Syntax) -> String {
+  match instr::decode_parcel(raw, T::BYTE_SIZE * 8) {
+    Ok(instr) => instr.disasm(Some(pc), syntax).to_string(),
+    Err(_) if rvc::is_compressed(raw) => format!(".half {:#06x}", raw),
+    Err(_) => format!(".word {:#010x}", raw),
+  }
+}
+
+impl InstrType {
+  pub(crate) fn disasm<T : RegData>(self, pc: Option<T>, syntax: Syntax) -> Disasm<T> {
+    Disasm{ instr: self, pc, syntax }
+  }
+
+  fn mnemonic(&self) -> String {
+    use InstrType::*;
+    let name = match self {
+      R{ var: RInstr::Zb(zb), .. } => {
+        use ZbInstr::*;
+        let name = format!("{:?}", zb);
+        match zb {
+          ADDUW | SH1ADDUW | SH2ADDUW | SH3ADDUW | SLLIUW => format!("{}.uw", &name[..name.len() - 2]),
+          SEXTB | SEXTH | ZEXTH | ORCB => format!("{}.{}", &name[..name.len() - 1], &name[name.len() - 1..]),
+          _ => name,
+        }
+      },
+      R{ var, .. } => format!("{:?}", var),
+      I{ var: IInstr::FENCEI, .. } => String::from("fence.i"),
+      I{ var: IInstr::SFENCEVMA, .. } => String::from("sfence.vma"),
+      // fm 1000 orders everything but stores before loads
+      I{ var: IInstr::FENCE, zx_imm, .. } if zx_imm >> 8 == 0b1000 => String::from("fence.tso"),
+      I{ var, .. } => format!("{:?}", var),
+      S{ var, .. } => format!("{:?}", var),
+      B{ var, .. } => format!("{:?}", var),
+      U{ var, .. } => format!("{:?}", var),
+      J{ var, .. } => format!("{:?}", var),
+      A{ var, size, aq, rl, .. } => {
+        let width = if *size == crate::mem::Size::DOUBLE { "d" } else { "w" };
+        let order = match (aq, rl) { (true, true) => ".aqrl", (true, false) => ".aq",
+          (false, true) => ".rl", (false, false) => "" };
+        format!("{:?}.{}{}", var, width, order)
+      },
+      F{ var, double, .. } => {
+        use FInstr::*;
+        let (f, other) = if *double { ("d", "s") } else { ("s", "d") };
+        match var {
+          FL | FS => format!("{:?}{}", var, if *double { "d" } else { "w" }),
+          FCVTFF => format!("fcvt.{}.{}", f, other),
+          FCVTWF | FCVTWUF | FCVTLF | FCVTLUF => {
+            let int = &format!("{:?}", var)[4..];
+            format!("fcvt.{}.{}", &int[..int.len() - 1], f)
+          },
+          FCVTFW | FCVTFWU | FCVTFL | FCVTFLU => format!("fcvt.{}.{}", f, &format!("{:?}", var)[5..]),
+          FMVXF => format!("fmv.x.{}", if *double { "d" } else { "w" }),
+          FMVFX => format!("fmv.{}.x", if *double { "d" } else { "w" }),
+          _ => format!("{:?}.{}", var, f),
+        }
+      },
+      V{ var, src, imm, .. } => {
+        use VInstr::*;
+        let name = format!("{:?}", var);
+        let kind = match src { VSrc::VV => "v", VSrc::VX => "x", VSrc::VI => "i" };
+        match var {
+          VSETVLI | VSETIVLI | VSETVL => name,
+          VLE | VLSE | VSE | VSSE => format!("{}{}.v", name, imm * 8),
+          VMERGE => format!("vmerge.v{}m", kind),
+          VMV => format!("vmv.v.{}", kind),
+          VMVXS => String::from("vmv.x.s"),
+          VMVSX => String::from("vmv.s.x"),
+          VCPOP | VFIRST => format!("{}.m", name),
+          _ if var.reduction() => format!("{}.vs", name),
+          _ if var.mask_logical() => format!("{}.mm", name),
+          _ => format!("{}.v{}", name, kind),
+        }
+      },
+      Halt => String::from("halt"),
+    };
+    name.to_lowercase()
+  }
+}
+
+impl fmt::Display for InstrType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.disasm::<u32>(None, Syntax::default()).fmt(f)
+  }
+}
+
+impl <T : RegData> Disasm<T> {
+  fn reg(&self, r: u32) -> String {
+    if self.syntax.abi_names { String::from(ABI_NAMES[r as usize]) } else { format!("x{}", r) }
+  }
+
+  fn freg(&self, r: u32) -> String {
+    if self.syntax.abi_names { String::from(fp::ABI_NAMES[r as usize]) } else { format!("f{}", r) }
+  }
+
+  fn target(&self, offset: i32) -> String {
+    match self.pc {
+      Some(pc) => format!("{:#x}", pc.offset(T::Signed::from(offset))),
+      None => format!("{}", offset),
+    }
+  }
+
+  // Mnemonic and operands
+  fn parts(&self) -> (String, Vec<String>) {
+    use InstrType::*;
+    let p = self.syntax.pseudo;
+    let op = self.instr.mnemonic();
+    let ops = |mnemonic: &str, operands: Vec<String>| (String::from(mnemonic), operands);
+    match self.instr {
+      R{ var: RInstr::SLLI | RInstr::SRLI | RInstr::SRAI | RInstr::SLLIW | RInstr::SRLIW | RInstr::SRAIW,
+          rs1, rs2: shamt, rd } =>
+        (op, vec![self.reg(rd), self.reg(rs1), shamt.to_string()]),
+      R{ var: RInstr::SUB, rs1: 0, rs2, rd } if p => ops("neg", vec![self.reg(rd), self.reg(rs2)]),
+      R{ var: RInstr::SLTU, rs1: 0, rs2, rd } if p => ops("snez", vec![self.reg(rd), self.reg(rs2)]),
+      R{ var: RInstr::SLT, rs1, rs2: 0, rd } if p => ops("sltz", vec![self.reg(rd), self.reg(rs1)]),
+      R{ var: RInstr::SLT, rs1: 0, rs2, rd } if p => ops("sgtz", vec![self.reg(rd), self.reg(rs2)]),
+      R{ var: RInstr::Zb(ZbInstr::ADDUW), rs1, rs2: 0, rd } if p =>
+        ops("zext.w", vec![self.reg(rd), self.reg(rs1)]),
+      R{ var: RInstr::Zb(zb), rs1, rd, .. } if zb.unary() => (op, vec![self.reg(rd), self.reg(rs1)]),
+      R{ var: RInstr::Zb(zb), rs1, rs2: shamt, rd } if zb.immediate() =>
+        (op, vec![self.reg(rd), self.reg(rs1), shamt.to_string()]),
+      R{ rs1, rs2, rd, .. } => (op, vec![self.reg(rd), self.reg(rs1), self.reg(rs2)]),
+
+      I{ var: IInstr::JALR, rs1, rd, sx_imm: 0, .. } if p && rd <= 1 => match (rd, rs1) {
+        (0, 1) => ops("ret", vec![]),
+        (0, _) => ops("jr", vec![self.reg(rs1)]),
+        _ => ops("jalr", vec![self.reg(rs1)]),
+      },
+      I{ var: IInstr::JALR | IInstr::LB | IInstr::LH | IInstr::LW | IInstr::LBU | IInstr::LHU
+          | IInstr::LWU | IInstr::LD, rs1, rd, sx_imm, .. } =>
+        (op, vec![self.reg(rd), format!("{}({})", sx_imm, self.reg(rs1))]),
+      I{ var: IInstr::ADDI, rs1: 0, rd: 0, sx_imm: 0, .. } if p => ops("nop", vec![]),
+      I{ var: IInstr::ADDI, rs1: 0, rd, sx_imm, .. } if p =>
+        ops("li", vec![self.reg(rd), sx_imm.to_string()]),
+      I{ var: IInstr::ADDI, rs1, rd, sx_imm: 0, .. } if p =>
+        ops("mv", vec![self.reg(rd), self.reg(rs1)]),
+      I{ var: IInstr::ADDIW, rs1, rd, sx_imm: 0, .. } if p =>
+        ops("sext.w", vec![self.reg(rd), self.reg(rs1)]),
+      I{ var: IInstr::XORI, rs1, rd, sx_imm: -1, .. } if p =>
+        ops("not", vec![self.reg(rd), self.reg(rs1)]),
+      I{ var: IInstr::SLTIU, rs1, rd, sx_imm: 1, .. } if p =>
+        ops("seqz", vec![self.reg(rd), self.reg(rs1)]),
+      I{ var: IInstr::FENCE, zx_imm, .. } if op == "fence" && zx_imm & 0xff != 0xff => {
+        // predecessor and successor sets, with iorw left out when it is both
+        let set = |bits: u32| "iorw".chars().enumerate().filter(|&(i, _)| bits & (8 >> i) != 0)
+          .map(|(_, c)| c).collect::<String>();
+        (op, vec![format!("{},{}", set((zx_imm >> 4) & 0xf), set(zx_imm & 0xf))])
+      },
+      // the address and ASID are left off when they are x0
+      I{ var: IInstr::SFENCEVMA, rs1, zx_imm, .. } => {
+        let regs = match (rs1, zx_imm & 0x1f) {
+          (0, 0) => vec![],
+          (rs1, 0) => vec![rs1],
+          (rs1, rs2) => vec![rs1, rs2],
+        };
+        (op, regs.into_iter().map(|r| self.reg(r)).collect())
+      },
+      I{ var: IInstr::ECALL | IInstr::EBREAK | IInstr::MRET | IInstr::SRET | IInstr::WFI
+        | IInstr::FENCE | IInstr::FENCEI, .. } => (op, vec![]),
+      I{ var, rs1, rd, zx_imm, .. } if self.instr.serializes() => self.csr_parts(var, rs1, rd, zx_imm),
+      I{ rs1, rd, sx_imm, .. } =>
+        (op, vec![self.reg(rd), self.reg(rs1), sx_imm.to_string()]),
+
+      S{ rs1, rs2, imm, .. } =>
+        (op, vec![self.reg(rs2), format!("{}({})", imm, self.reg(rs1))]),
+
+      B{ var, rs1, rs2: 0, imm } if p && var != BInstr::BLTU && var != BInstr::BGEU => {
+        let mnemonic = match var { BInstr::BEQ => "beqz", BInstr::BNE => "bnez",
+          BInstr::BLT => "bltz", _ => "bgez" };
+        ops(mnemonic, vec![self.reg(rs1), self.target(imm)])
+      },
+      B{ var: BInstr::BLT, rs1: 0, rs2, imm } if p => ops("bgtz", vec![self.reg(rs2), self.target(imm)]),
+      B{ var: BInstr::BGE, rs1: 0, rs2, imm } if p => ops("blez", vec![self.reg(rs2), self.target(imm)]),
+      B{ rs1, rs2, imm, .. } => (op, vec![self.reg(rs1), self.reg(rs2), self.target(imm)]),
+
+      U{ rd, imm, .. } => (op, vec![self.reg(rd), format!("{:#x}", imm >> 12)]),
+
+      J{ var: JInstr::JAL, rd: 0, offset } if p => ops("j", vec![self.target(offset)]),
+      J{ var: JInstr::JAL, rd: 1, offset } if p => ops("jal", vec![self.target(offset)]),
+      J{ rd, offset, .. } => (op, vec![self.reg(rd), self.target(offset)]),
+
+      A{ var: AInstr::LR, rs1, rd, .. } => (op, vec![self.reg(rd), format!("({})", self.reg(rs1))]),
+      A{ rs1, rs2, rd, .. } =>
+        (op, vec![self.reg(rd), self.reg(rs2), format!("({})", self.reg(rs1))]),
+
+      F{ .. } => self.float_parts(op),
+
+      V{ .. } => self.vector_parts(op),
+
+      Halt => ops("halt", vec![]),
+    }
+  }
+
+  fn float_parts(&self, op: String) -> (String, Vec<String>) {
+    use FInstr::*;
+    let (var, rd, rs1, rs2, rs3, imm, double, rm) = match self.instr {
+      InstrType::F{ var, rd, rs1, rs2, rs3, imm, double, rm } => (var, rd, rs1, rs2, rs3, imm, double, rm),
+      _ => unreachable!(),
+    };
+    let p = self.syntax.pseudo;
+    let f = if double { "d" } else { "s" };
+    let mut operands = match var {
+      FL => vec![self.freg(rd), format!("{}({})", imm, self.reg(rs1))],
+      FS => vec![self.freg(rs2), format!("{}({})", imm, self.reg(rs1))],
+      FSGNJ | FSGNJN | FSGNJX if p && rs1 == rs2 => {
+        let name = match var { FSGNJ => "fmv", FSGNJN => "fneg", _ => "fabs" };
+        return (format!("{}.{}", name, f), vec![self.freg(rd), self.freg(rs1)]);
+      },
+      FMADD | FMSUB | FNMSUB | FNMADD =>
+        vec![self.freg(rd), self.freg(rs1), self.freg(rs2), self.freg(rs3)],
+      FSQRT | FCVTFF => vec![self.freg(rd), self.freg(rs1)],
+      FCVTWF | FCVTWUF | FCVTLF | FCVTLUF | FCLASS | FMVXF => vec![self.reg(rd), self.freg(rs1)],
+      FCVTFW | FCVTFWU | FCVTFL | FCVTFLU | FMVFX => vec![self.freg(rd), self.reg(rs1)],
+      FEQ | FLT | FLE => vec![self.reg(rd), self.freg(rs1), self.freg(rs2)],
+      _ => vec![self.freg(rd), self.freg(rs1), self.freg(rs2)],
+    };
+    // the rounding mode is left out where it cannot matter, and when it is frm unless spelling
+    // everything out
+    let exact = double && matches!(var, FCVTFF | FCVTFW | FCVTFWU);
+    let rounds = !matches!(var, FL | FS | FSGNJ | FSGNJN | FSGNJX | FMIN | FMAX | FEQ | FLT | FLE
+      | FCLASS | FMVXF | FMVFX);
+    if rounds && !exact && (rm != 7 || !p) {
+      let mode = ["rne", "rtz", "rdn", "rup", "rmm", "", "", "dyn"][rm as usize];
+      operands.push(String::from(mode));
+    };
+    (op, operands)
+  }
+
+  fn vector_parts(&self, op: String) -> (String, Vec<String>) {
+    use VInstr::*;
+    let (var, vd, rs1, vs2, src, vm, imm) = match self.instr {
+      InstrType::V{ var, vd, rs1, vs2, src, vm, imm } => (var, vd, rs1, vs2, src, vm, imm),
+      _ => unreachable!(),
+    };
+    let p = self.syntax.pseudo;
+    let v = |r: u32| format!("v{}", r);
+    let ops = |mnemonic: &str, operands: Vec<String>| (String::from(mnemonic), operands);
+    // immediates are sign extended other than shift amounts
+    let other = match src {
+      VSrc::VV => v(rs1),
+      VSrc::VX => self.reg(rs1),
+      VSrc::VI if matches!(var, VSLL | VSRL | VSRA) => rs1.to_string(),
+      VSrc::VI => ((rs1 as i32) << 27 >> 27).to_string(),
+    };
+    let (op, mut operands) = match var {
+      VSETVLI => (op, vec![self.reg(vd), self.reg(rs1), vtype_name(imm)]),
+      VSETIVLI => (op, vec![self.reg(vd), rs1.to_string(), vtype_name(imm)]),
+      VSETVL => (op, vec![self.reg(vd), self.reg(rs1), self.reg(vs2)]),
+      VLE | VSE => (op, vec![v(vd), format!("({})", self.reg(rs1))]),
+      VLSE | VSSE => (op, vec![v(vd), format!("({})", self.reg(rs1)), self.reg(vs2)]),
+      VMV => (op, vec![v(vd), other]),
+      VMVXS | VCPOP | VFIRST => (op, vec![self.reg(vd), v(vs2)]),
+      VMVSX => (op, vec![v(vd), self.reg(rs1)]),
+      VMAND if p && rs1 == vs2 => ops("vmmv.m", vec![v(vd), v(vs2)]),
+      VMNAND if p && rs1 == vs2 => ops("vmnot.m", vec![v(vd), v(vs2)]),
+      VMXOR if p && rs1 == vd && vs2 == vd => ops("vmclr.m", vec![v(vd)]),
+      VMXNOR if p && rs1 == vd && vs2 == vd => ops("vmset.m", vec![v(vd)]),
+      VRSUB if p && src == VSrc::VX && rs1 == 0 => ops("vneg.v", vec![v(vd), v(vs2)]),
+      _ => (op, vec![v(vd), v(vs2), other]),
+    };
+    // vmerge picks with v0 rather than being masked by it
+    if var == VMERGE { operands.push(v(0)) } else if !vm { operands.push(String::from("v0.t")) };
+    (op, operands)
+  }
+
+  fn csr_parts(&self, var: IInstr, rs1: u32, rd: u32, csr: u32) -> (String, Vec<String>) {
+    use IInstr::*;
+    let name = csr::name(csr).map(String::from).unwrap_or_else(|| format!("{:#x}", csr));
+    let src = match var { CSRRWI | CSRRSI | CSRRCI => rs1.to_string(), _ => self.reg(rs1) };
+    let counter = match csr {
+      csr::CYCLE => Some("rdcycle"), csr::TIME => Some("rdtime"), csr::INSTRET => Some("rdinstret"),
+      csr::CYCLEH => Some("rdcycleh"), csr::TIMEH => Some("rdtimeh"),
+      csr::INSTRETH => Some("rdinstreth"),
+      _ => None,
+    };
+    if self.syntax.pseudo {
+      // reads that leave the CSR alone
+      if let (CSRRS, 0) = (var, rs1) {
+        return match counter {
+          Some(read) => (String::from(read), vec![self.reg(rd)]),
+          None => (String::from("csrr"), vec![self.reg(rd), name]),
+        };
+      };
+      // writes that discard the old value
+      if rd == 0 {
+        let mnemonic = match var {
+          CSRRW => "csrw", CSRRS => "csrs", CSRRC => "csrc",
+          CSRRWI => "csrwi", CSRRSI => "csrsi", _ => "csrci",
+        };
+        return (String::from(mnemonic), vec![name, src]);
+      };
+    };
+    (self.instr.mnemonic(), vec![self.reg(rd), name, src])
+  }
+}
+
+// The settings in a vtype as the assembler spells them, or the number for reserved ones
+fn vtype_name(vtype: u32) -> String {
+  let (vsew, vlmul) = ((vtype >> 3) & 0b111, vtype & 0b111);
+  if vtype >> 8 != 0 || vsew > 3 || vlmul == 4 { return vtype.to_string() };
+  let lmul = if vlmul < 4 { format!("m{}", 1 << vlmul) } else { format!("mf{}", 1 << (8 - vlmul)) };
+  let tail = if vtype & 1 << 6 != 0 { "ta" } else { "tu" };
+  let mask = if vtype & 1 << 7 != 0 { "ma" } else { "mu" };
+  format!("e{}, {}, {}, {}", 8 << vsew, lmul, tail, mask)
+}
+
+impl <T : RegData> fmt::Display for Disasm<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (mnemonic, operands) = self.parts();
+    if operands.is_empty() { return write!(f, "{}", mnemonic) };
+    write!(f, "{} {}", mnemonic, operands.join(", "))
+  }
+}
+
+#[test]
+fn test_disassemble() {
+  let abi = Syntax::default();
+  let cases: [(u32, u32, &str); 95] = [
+    (0x01010513, 0, "addi a0, sp, 16"),
+    (0x04028063, 0, "beqz t0, 0x40"),
+    (0xfe029ee3, 0x20, "bnez t0, 0x1c"),
+    (0x00a5c463, 0, "blt a1, a0, 0x8"),
+    (0x00000013, 0, "nop"),
+    (0xfff00513, 0, "li a0, -1"),
+    (0x00058513, 0, "mv a0, a1"),
+    (0x00008067, 0, "ret"),
+    (0x00050067, 0, "jr a0"),
+    (0x000500e7, 0, "jalr a0"),
+    (0x00c50567, 0, "jalr a0, 12(a0)"),
+    (0xff5ff06f, 0x10, "j 0x4"),
+    (0x008000ef, 0, "jal 0x8"),
+    (0xffc12583, 0, "lw a1, -4(sp)"),
+    (0x00b12423, 0, "sw a1, 8(sp)"),
+    (0x12345537, 0, "lui a0, 0x12345"),
+    (0x40b50533, 0, "sub a0, a0, a1"),
+    (0x40b00533, 0, "neg a0, a1"),
+    (0x41f55513, 0, "srai a0, a0, 31"),
+    (0xc0002573, 0, "rdcycle a0"),
+    (0x30529073, 0, "csrw mtvec, t0"),
+    (0x00000073, 0, "ecall"),
+    (0x0ff0000f, 0, "fence"),
+    (0x0330000f, 0, "fence rw,rw"),
+    (0x8330000f, 0, "fence.tso"),
+    (0x0000100f, 0, "fence.i"),
+    (0x12000073, 0, "sfence.vma"),
+    (0x12b50073, 0, "sfence.vma a0, a1"),
+    (0x00813503, 0, "ld a0, 8(sp)"),
+    (0xfeb13c23, 0, "sd a1, -8(sp)"),
+    (0x0005851b, 0, "sext.w a0, a1"),
+    (0x03f51513, 0, "slli a0, a0, 63"),
+    (0x40b5053b, 0, "subw a0, a0, a1"),
+    (0x100422af, 0, "lr.w t0, (s0)"),
+    (0x1854232f, 0, "sc.w t1, t0, (s0)"),
+    (0x0cb6252f, 0, "amoswap.w.aq a0, a1, (a2)"),
+    (0x00812507, 0, "flw fa0, 8(sp)"),
+    (0xfe853827, 0, "fsd fs0, -16(a0)"),
+    (0x00c5f553, 0, "fadd.s fa0, fa1, fa2"),
+    (0x02c59553, 0, "fadd.d fa0, fa1, fa2, rtz"),
+    (0x1a20f043, 0, "fmadd.d ft0, ft1, ft2, ft3"),
+    (0x68c5854b, 0, "fnmsub.s fa0, fa1, fa2, fa3, rne"),
+    (0x20b58553, 0, "fmv.s fa0, fa1"),
+    (0x22b59553, 0, "fneg.d fa0, fa1"),
+    (0xc0051553, 0, "fcvt.w.s a0, fa0, rtz"),
+    (0xc2157553, 0, "fcvt.wu.d a0, fa0"),
+    (0x42058553, 0, "fcvt.d.s fa0, fa1"),
+    (0xa2b51553, 0, "flt.d a0, fa0, fa1"),
+    (0xe2051553, 0, "fclass.d a0, fa0"),
+    (0xf0050553, 0, "fmv.w.x fa0, a0"),
+    (0x20c5a533, 0, "sh1add a0, a1, a2"),
+    (0x40c5f533, 0, "andn a0, a1, a2"),
+    (0x40c5c533, 0, "xnor a0, a1, a2"),
+    (0x60059513, 0, "clz a0, a1"),
+    (0x60259513, 0, "cpop a0, a1"),
+    (0x0ac5d533, 0, "minu a0, a1, a2"),
+    (0x60459513, 0, "sext.b a0, a1"),
+    (0x0805c533, 0, "zext.h a0, a1"),
+    (0x61f5d513, 0, "rori a0, a1, 31"),
+    (0x2875d513, 0, "orc.b a0, a1"),
+    (0x6985d513, 0, "rev8 a0, a1"),
+    (0x29f59513, 0, "bseti a0, a1, 31"),
+    (0x48c59533, 0, "bclr a0, a1, a2"),
+    (0x4915d513, 0, "bexti a0, a1, 17"),
+    (0x0ac5a533, 0, "clmulr a0, a1, a2"),
+    (0x08c5853b, 0, "add.uw a0, a1, a2"),
+    (0x0805853b, 0, "zext.w a0, a1"),
+    (0x0a85951b, 0, "slli.uw a0, a1, 40"),
+    (0x6005951b, 0, "clzw a0, a1"),
+    (0x6025951b, 0, "cpopw a0, a1"),
+    (0x61f5d51b, 0, "roriw a0, a1, 31"),
+    (0x6b85d513, 0, "rev8 a0, a1"),
+    (0x0805c53b, 0, "zext.h a0, a1"),
+    (0x0db57357, 0, "vsetvli t1, a0, e64, m8, ta, ma"),
+    (0x00557357, 0, "vsetvli t1, a0, e8, mf8, tu, mu"),
+    (0x10057357, 0, "vsetvli t1, a0, 256"),
+    (0xcd027057, 0, "vsetivli zero, 4, e32, m1, ta, ma"),
+    (0x80b57357, 0, "vsetvl t1, a0, a1"),
+    (0x0205f407, 0, "vle64.v v8, (a1)"),
+    (0x0bc5e627, 0, "vsse32.v v12, (a1), t3"),
+    (0x00840857, 0, "vadd.vv v16, v8, v8, v0.t"),
+    (0x5d01b457, 0, "vmerge.vim v8, v16, 3, v0"),
+    (0x662120d7, 0, "vmmv.m v1, v2"),
+    (0x6e31a1d7, 0, "vmclr.m v3"),
+    (0x0c504257, 0, "vneg.v v4, v5, v0.t"),
+    (0x965fb257, 0, "vsll.vi v4, v5, 31"),
+    (0x9b056457, 0, "vmulhsu.vx v8, v16, a0"),
+    (0x728fb057, 0, "vmsleu.vi v0, v8, -1"),
+    (0x188120d7, 0, "vredmaxu.vs v1, v8, v2, v0.t"),
+    (0x4218a757, 0, "vfirst.m a4, v1"),
+    (0x10200073, 0, "sret"),
+    (0x10500073, 0, "wfi"),
+    (0x14129073, 0, "csrw sepc, t0"),
+    (0x18002573, 0, "csrr a0, satp"),
+    (0x30229073, 0, "csrw medeleg, t0"),
+  ];
+  for &(raw, pc, text) in cases.iter() {
+    assert_eq!(disassemble(raw, pc, abi), text, "{:#010x}", raw);
+  }
+  let raw = Syntax{ abi_names: false, pseudo: false };
+  assert_eq!(disassemble(0x04028063, 0u32, raw), "beq x5, x0, 0x40");
+  assert_eq!(disassemble(0xc0002573, 0u32, raw), "csrrs x10, cycle, x0");
+  assert_eq!(disassemble(0x111dffd3, 0u32, raw), "fmul.s f31, f27, f17, dyn");
+  assert_eq!(disassemble(0xc2257553, 0u32, abi), "fcvt.l.d a0, fa0");
+  assert_eq!(disassemble(0xd2357553, 0u32, abi), "fcvt.d.lu fa0, a0");
+  assert_eq!(disassemble(0xffffffff, 0u32, raw), ".word 0xffffffff");
+  // c.jal on RV32 is c.addiw on RV64
+  assert_eq!(disassemble(0x3001, 0x100u32, abi), "jal 0xfffff900");
+  assert_eq!(disassemble(0x337d, 0u64, abi), "addiw t1, t1, -1");
+  assert_eq!(disassemble(0x8082, 0u32, abi), "ret");
+  assert_eq!(disassemble(0x0000, 0u32, abi), ".half 0x0000");
+  assert_eq!(instr::decode(0xff5ff06f).unwrap().to_string(), "j -12");
+}
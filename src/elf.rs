@@ -0,0 +1,288 @@
+// Loader for statically linked RISC-V ELF executables
+use crate::mem::Memory;
+use crate::program_state::ProgramState;
+use crate::reg::RegData;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+// register the stack pointer is passed in by the loader
+const SP: u32 = 2;
+// memory is mapped from the page holding the lowest segment
+const PAGE_SIZE: u64 = 0x1000;
+// largest span of addresses the segments and the space after them may cover
+const MAX_MEMORY: u64 = 1 << 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Class { Elf32, Elf64, }
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Segment {
+  pub vaddr: u64,
+  // bytes backed by the file, anything past this up to mem_size is zero filled (.bss)
+  pub data: Vec<u8>,
+  pub mem_size: u64,
+  pub executable: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Symbol {
+  pub name: String,
+  pub value: u64,
+  pub size: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Elf {
+  pub class: Class,
+  pub entry: u64,
+  pub segments: Vec<Segment>,
+  pub symbols: Vec<Symbol>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool { bytes.starts_with(&MAGIC) }
+
+// Reads little endian fields out of the file, where addresses depend on the class
+struct Reader<'a> { bytes: &'a [u8], class: Class, }
+
+impl <'a> Reader<'a> {
+  fn slice(&self, off: u64, len: u64) -> Result<&'a [u8], String> {
+    let end = off.checked_add(len)
+      .ok_or_else(|| format!("ELF offset {:#x} + {:#x} overflows", off, len))?;
+    self.bytes.get(off as usize..end as usize)
+      .ok_or_else(|| format!("ELF file truncated, wanted bytes {:#x}..{:#x}", off, end))
+  }
+  fn u8(&self, off: u64) -> Result<u8, String> { Ok(self.slice(off, 1)?[0]) }
+  fn u16(&self, off: u64) -> Result<u16, String> {
+    let mut b = [0; 2];
+    b.copy_from_slice(self.slice(off, 2)?);
+    Ok(u16::from_le_bytes(b))
+  }
+  fn u32(&self, off: u64) -> Result<u32, String> {
+    let mut b = [0; 4];
+    b.copy_from_slice(self.slice(off, 4)?);
+    Ok(u32::from_le_bytes(b))
+  }
+  fn u64(&self, off: u64) -> Result<u64, String> {
+    let mut b = [0; 8];
+    b.copy_from_slice(self.slice(off, 8)?);
+    Ok(u64::from_le_bytes(b))
+  }
+  // Address sized field
+  fn addr(&self, off: u64) -> Result<u64, String> {
+    match self.class {
+      Class::Elf32 => self.u32(off).map(u64::from),
+      Class::Elf64 => self.u64(off),
+    }
+  }
+  fn c_str(&self, off: u64) -> Result<String, String> {
+    let rest = self.bytes.get(off as usize..).ok_or("ELF string out of bounds")?;
+    let len = rest.iter().position(|&b| b == 0).ok_or("Unterminated ELF string")?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+  }
+}
+
+impl Elf {
+  pub fn parse(bytes: &[u8]) -> Result<Elf, String> {
+    if !is_elf(bytes) { return Err(String::from("Missing ELF magic")) };
+    let class = match bytes.get(4) {
+      Some(1) => Class::Elf32,
+      Some(2) => Class::Elf64,
+      c => return Err(format!("Unexpected ELF class {:?}", c)),
+    };
+    let r = Reader{ bytes, class };
+    if r.u8(5)? != 1 { return Err(String::from("Only little endian ELF files are supported")) };
+    let machine = r.u16(18)?;
+    if machine != EM_RISCV { return Err(format!("ELF machine {} is not RISC-V", machine)) };
+
+    let (entry, phoff, shoff, sizes) = match class {
+      Class::Elf32 => (r.addr(24)?, r.addr(28)?, r.addr(32)?, 42),
+      Class::Elf64 => (r.addr(24)?, r.addr(32)?, r.addr(40)?, 54),
+    };
+    let (phentsize, phnum) = (r.u16(sizes)? as u64, r.u16(sizes + 2)? as u64);
+    let (shentsize, shnum) = (r.u16(sizes + 4)? as u64, r.u16(sizes + 6)? as u64);
+
+    // entries have to start inside the file, so the small field offsets added to them cannot
+    // overflow
+    let entry_at = |table: u64, i: u64, size: u64| i.checked_mul(size)
+      .and_then(|off| table.checked_add(off))
+      .filter(|&at| at <= bytes.len() as u64)
+      .ok_or_else(|| format!("ELF table at {:#x} runs past the end of the file", table));
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+      let ph = entry_at(phoff, i, phentsize)?;
+      if r.u32(ph)? != PT_LOAD { continue };
+      let (offset, vaddr, file_size, mem_size, flags) = match class {
+        Class::Elf32 => (r.addr(ph + 4)?, r.addr(ph + 8)?, r.addr(ph + 16)?, r.addr(ph + 20)?,
+          r.u32(ph + 24)?),
+        Class::Elf64 => (r.addr(ph + 8)?, r.addr(ph + 16)?, r.addr(ph + 32)?, r.addr(ph + 40)?,
+          r.u32(ph + 4)?),
+      };
+      if file_size > mem_size {
+        return Err(format!("Segment at {:#x} has more file bytes than memory", vaddr));
+      };
+      if vaddr.checked_add(mem_size).is_none() {
+        return Err(format!("Segment at {:#x} runs past the end of the address space", vaddr));
+      };
+      let data = r.slice(offset, file_size)?.to_vec();
+      segments.push(Segment{ vaddr, data, mem_size, executable: flags & PF_X != 0 });
+    }
+
+    let section = |i: u64| -> Result<(u32, u64, u64, u32), String> {
+      let sh = entry_at(shoff, i, shentsize)?;
+      match class {
+        Class::Elf32 =>
+          Ok((r.u32(sh + 4)?, r.addr(sh + 16)?, r.addr(sh + 20)?, r.u32(sh + 24)?)),
+        Class::Elf64 =>
+          Ok((r.u32(sh + 4)?, r.addr(sh + 24)?, r.addr(sh + 32)?, r.u32(sh + 40)?)),
+      }
+    };
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+      let (kind, offset, size, link) = section(i)?;
+      if kind != SHT_SYMTAB { continue };
+      let (_, strtab, _, _) = section(link as u64)?;
+      let entsize = match class { Class::Elf32 => 16, Class::Elf64 => 24 };
+      // first entry is always the undefined symbol
+      for j in 1..size / entsize {
+        let sym = entry_at(offset, j, entsize)?;
+        let (value, size) = match class {
+          Class::Elf32 => (r.addr(sym + 4)?, r.addr(sym + 8)?),
+          Class::Elf64 => (r.addr(sym + 8)?, r.addr(sym + 16)?),
+        };
+        let name = strtab.checked_add(r.u32(sym)? as u64).ok_or("ELF string out of bounds")?;
+        let name = r.c_str(name)?;
+        if !name.is_empty() { symbols.push(Symbol{ name, value, size }) };
+      }
+    }
+    Ok(Elf{ class, entry, segments, symbols })
+  }
+
+  // One past the highest address occupied by a segment
+  pub fn end_addr(&self) -> u64 {
+    self.segments.iter().map(|s| s.vaddr.saturating_add(s.mem_size)).max().unwrap_or(0)
+  }
+
+  // Start of the page holding the lowest segment
+  pub fn base_addr(&self) -> u64 {
+    self.segments.iter().map(|s| s.vaddr).min().unwrap_or(0) & !(PAGE_SIZE - 1)
+  }
+
+  // Memory covering every segment with extra bytes after the last one, refusing images spread
+  // over more than MAX_MEMORY instead of allocating for them
+  pub fn memory<T : RegData>(&self, extra: usize) -> Result<Memory<T>, String> {
+    let size = (self.end_addr() - self.base_addr()).saturating_add(extra as u64);
+    if size > MAX_MEMORY {
+      return Err(format!("Segments from {:#x} to {:#x} need more than {:#x} bytes of memory",
+        self.base_addr(), self.end_addr(), MAX_MEMORY));
+    };
+    Ok(Memory::with_base(self.base_addr() as usize, size as usize))
+  }
+
+  pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+    self.symbols.iter().find(|s| s.name == name)
+  }
+
+  // Copies every segment to its virtual address, points the pc at the entry and the stack
+  // pointer at the top of memory
+  pub fn load<T : RegData>(&self, ps: &mut ProgramState<T>) -> Result<(), String> {
+    let xlen = match self.class { Class::Elf32 => 32, Class::Elf64 => 64 };
+    if xlen != T::BYTE_SIZE * 8 {
+      return Err(format!("ELF file is for RV{}, but registers are {} bits", xlen, T::BYTE_SIZE * 8));
+    };
+    for seg in self.segments.iter() {
+      if !ps.mem.in_bounds(seg.vaddr as usize, seg.mem_size as usize) {
+        return Err(format!("Segment at {:#x} does not fit in memory, use --mem", seg.vaddr));
+      };
+      let mut bytes = seg.data.clone();
+      bytes.resize(seg.mem_size as usize, 0);
+      ps.mem.write_bytes(seg.vaddr as usize, &bytes).expect("Segment was checked to fit");
+    }
+    ps.regs.assign_pc(T::from_u64(self.entry));
+    let stack_top = (ps.mem.end() & !0xf) as u64;
+    ps.regs.force_assign(SP, T::from_u64(stack_top));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+fn test_elf32() -> Vec<u8> {
+  fn push(out: &mut Vec<u8>, fields: &[(u32, usize)]) {
+    fields.iter().for_each(|&(v, sz)| out.extend_from_slice(&v.to_le_bytes()[..sz]));
+  }
+  let mut out = Vec::new();
+  // file header, one program header at 52, section headers at 132
+  out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+  push(&mut out, &[(2, 2), (243, 2), (1, 4), (0x1000, 4), (52, 4), (132, 4), (0, 4),
+    (52, 2), (32, 2), (1, 2), (40, 2), (3, 2), (0, 2)]);
+  // PT_LOAD with 8 bytes of text and 8 bytes of bss at 0x1000
+  push(&mut out, &[(1, 4), (84, 4), (0x1000, 4), (0x1000, 4), (8, 4), (16, 4), (5, 4), (4, 4)]);
+  push(&mut out, &[(0x00700513, 4), (0xfeedfeed, 4)]);
+  // strtab at 92, symtab at 100
+  out.extend_from_slice(b"\0_start\0");
+  push(&mut out, &[(0, 4), (0, 4), (0, 4), (0, 4)]);
+  push(&mut out, &[(1, 4), (0x1000, 4), (8, 4), (0x10, 1), (0, 1), (1, 2)]);
+  // null, symtab, strtab section headers
+  push(&mut out, &[(0, 4); 10]);
+  push(&mut out, &[(0, 4), (2, 4), (0, 4), (0, 4), (100, 4), (32, 4), (2, 4), (1, 4), (4, 4),
+    (16, 4)]);
+  push(&mut out, &[(0, 4), (3, 4), (0, 4), (0, 4), (92, 4), (8, 4), (0, 4), (0, 4), (1, 4),
+    (0, 4)]);
+  out
+}
+
+#[test]
+fn test_parse_elf32() {
+  let elf = Elf::parse(&test_elf32()).unwrap();
+  assert_eq!(elf.class, Class::Elf32);
+  assert_eq!(elf.entry, 0x1000);
+  assert_eq!(elf.segments.len(), 1);
+  assert_eq!(elf.segments[0].data.len(), 8);
+  assert!(elf.segments[0].executable);
+  assert_eq!(elf.end_addr(), 0x1010);
+  let start = Symbol{ name: String::from("_start"), value: 0x1000, size: 8 };
+  assert_eq!(elf.symbol("_start"), Some(&start));
+  assert!(Elf::parse(&[0x7f, b'E', b'L', b'F', 1]).is_err());
+}
+
+#[test]
+fn test_load_elf32() {
+  use crate::mem::{Memory, Size};
+  let elf = Elf::parse(&test_elf32()).unwrap();
+  let mut mem = Memory::<u32>::new(0x2000);
+  mem.write(0x100c, 0xdeadbeef, Size::WORD).unwrap();
+  let mut ps = ProgramState::new(mem);
+  elf.load(&mut ps).unwrap();
+  assert_eq!(ps.regs.pc(), 0x1000);
+  assert_eq!(ps.regs[SP], 0x2000);
+  assert_eq!(ps.mem.read_instr(0x1000).unwrap(), 0x00700513);
+  assert_eq!(ps.mem.read(0x100c, Size::WORD).unwrap(), 0);
+
+  let mut small = ProgramState::new(Memory::<u32>::new(0x1000));
+  assert!(elf.load(&mut small).is_err());
+
+  // memory starts at the page of the lowest segment
+  let mut ps = ProgramState::new(elf.memory::<u32>(0x1000).unwrap());
+  assert_eq!((ps.mem.base(), ps.mem.end()), (0x1000, 0x2010));
+  elf.load(&mut ps).unwrap();
+  assert_eq!(ps.regs[SP], 0x2010);
+}
+
+#[test]
+fn test_malformed_elf32() {
+  let patch = |at: usize, v: u32| {
+    let mut bytes = test_elf32();
+    bytes[at..at + 4].copy_from_slice(&v.to_le_bytes());
+    bytes
+  };
+  // program and section header tables past the end of the file
+  assert!(Elf::parse(&patch(28, u32::MAX)).is_err());
+  assert!(Elf::parse(&patch(32, u32::MAX)).is_err());
+  // a 4 GiB bss is refused before anything is allocated for it
+  let elf = Elf::parse(&patch(72, u32::MAX)).unwrap();
+  assert!(elf.memory::<u32>(0x10000).is_err());
+  let mut ps = ProgramState::new(crate::mem::Memory::<u32>::new(0x2000));
+  assert!(elf.load(&mut ps).is_err());
+}
@@ -0,0 +1,571 @@
+// F and D extensions, the FP register file and IEEE-754 arithmetic. Values are worked out exactly
+// on integers and then rounded, so every rounding mode and exception flag matches the spec rather
+// than whatever the host does.
+use std::cmp::Ordering;
+use std::fmt;
+use crate::instr::{InstrType, FInstr};
+use crate::mem::Size;
+use crate::program_state::{ProgramState, Exceptions};
+use crate::reg::RegData;
+use crate::trace::MemAccess;
+
+// fflags, accrued until software clears them
+pub const NX: u32 = 1; // inexact
+pub const UF: u32 = 1 << 1; // underflow
+pub const OF: u32 = 1 << 2; // overflow
+pub const DZ: u32 = 1 << 3; // divide by zero
+pub const NV: u32 = 1 << 4; // invalid operation
+
+// Numbered as in frm and the rm field of instructions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rounding { RNE, RTZ, RDN, RUP, RMM }
+
+impl Rounding {
+  // The rounding mode from an rm field, where 7 picks frm. Reserved modes are illegal.
+  pub fn new(rm: u32, frm: u32) -> Option<Self> {
+    let mode = match if rm == 7 { frm } else { rm } {
+      0 => Rounding::RNE, 1 => Rounding::RTZ, 2 => Rounding::RDN, 3 => Rounding::RUP, 4 => Rounding::RMM,
+      _ => return None,
+    };
+    Some(mode)
+  }
+}
+
+// Bits of the exponent and mantissa in an encoding
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format { exp_bits: u32, mant_bits: u32 }
+
+pub const SINGLE: Format = Format{ exp_bits: 8, mant_bits: 23 };
+pub const DOUBLE: Format = Format{ exp_bits: 11, mant_bits: 52 };
+
+// A finite value is sig * 2^exp, kept apart from the sign
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class { Zero, Finite(u128, i32), Inf, NaN{ signaling: bool } }
+
+// Drops the low shift bits of sig and rounds what is kept, returning whether anything was lost
+fn shift_round(sig: u128, shift: i32, sign: bool, rm: Rounding) -> (u128, bool) {
+  if shift <= 0 { return (sig << -shift, false) };
+  let (kept, rest, half) = if shift >= 128 { (0, sig, u128::MAX) }
+    else { (sig >> shift, sig & ((1 << shift) - 1), 1 << (shift - 1)) };
+  let up = match rm {
+    Rounding::RNE => rest > half || (rest == half && kept & 1 == 1),
+    Rounding::RTZ => false,
+    Rounding::RDN => sign && rest != 0,
+    Rounding::RUP => !sign && rest != 0,
+    Rounding::RMM => rest >= half,
+  };
+  (kept + up as u128, rest != 0)
+}
+
+fn isqrt(n: u128) -> u128 {
+  let (mut n, mut root, mut bit) = (n, 0u128, 1u128 << 126);
+  while bit > n { bit >>= 2 };
+  while bit != 0 {
+    if n >= root + bit {
+      n -= root + bit;
+      root = (root >> 1) + bit;
+    } else {
+      root >>= 1;
+    };
+    bit >>= 2;
+  };
+  root
+}
+
+impl Format {
+  fn width(self) -> u32 { 1 + self.exp_bits + self.mant_bits }
+  fn bias(self) -> i32 { (1 << (self.exp_bits - 1)) - 1 }
+  fn max_exp(self) -> u64 { (1 << self.exp_bits) - 1 }
+  fn sign_bit(self) -> u64 { 1 << (self.width() - 1) }
+  fn zero(self, sign: bool) -> u64 { if sign { self.sign_bit() } else { 0 } }
+  fn inf(self, sign: bool) -> u64 { self.zero(sign) | self.max_exp() << self.mant_bits }
+  pub fn canonical_nan(self) -> u64 { self.inf(false) | 1 << (self.mant_bits - 1) }
+
+  fn unpack(self, bits: u64) -> (bool, Class) {
+    let sign = bits & self.sign_bit() != 0;
+    let exp = (bits >> self.mant_bits) & self.max_exp();
+    let mant = bits & ((1 << self.mant_bits) - 1);
+    let min_exp = 1 - self.bias() - self.mant_bits as i32;
+    let class = match (exp, mant) {
+      (0, 0) => Class::Zero,
+      (0, _) => Class::Finite(mant as u128, min_exp),
+      (e, 0) if e == self.max_exp() => Class::Inf,
+      (e, _) if e == self.max_exp() => Class::NaN{ signaling: mant >> (self.mant_bits - 1) == 0 },
+      (e, _) => Class::Finite((mant | 1 << self.mant_bits) as u128, min_exp + e as i32 - 1),
+    };
+    (sign, class)
+  }
+
+  // Rounds (-1)^sign * (sig + sticky) * 2^exp, where sticky stands for a fraction of the last
+  // bit of sig that is not zero. Tininess is detected after rounding.
+  fn round(self, sign: bool, sig: u128, exp: i32, sticky: bool, rm: Rounding, flags: &mut u32) -> u64 {
+    if sig == 0 && !sticky { return self.zero(sign) };
+    // the fraction becomes a bit below anything that can be kept
+    let (sig, exp) = if sticky { (sig << 2 | 1, exp - 2) } else { (sig, exp) };
+    let m = self.mant_bits as i32;
+    let emin = 1 - self.bias();
+    // exponent of the leading bit, and of the last bit the result can keep
+    let top = exp + 127 - sig.leading_zeros() as i32;
+    let mut lsb = (top - m).max(emin - m);
+    let (mut kept, inexact) = shift_round(sig, lsb - exp, sign, rm);
+    let tiny = top < emin
+      && !(top == emin - 1 && shift_round(sig, top - m - exp, sign, rm).0 >> (m + 1) != 0);
+    if kept >> (m + 1) != 0 {
+      kept >>= 1;
+      lsb += 1;
+    };
+    let biased = if kept >> m != 0 { (lsb + m + self.bias()) as u64 } else { 0 };
+    if biased >= self.max_exp() {
+      *flags |= OF | NX;
+      let to_inf = match rm {
+        Rounding::RNE | Rounding::RMM => true,
+        Rounding::RTZ => false,
+        Rounding::RDN => sign,
+        Rounding::RUP => !sign,
+      };
+      // the largest finite value is just below infinity
+      return if to_inf { self.inf(sign) } else { self.inf(sign) - 1 };
+    };
+    if inexact { *flags |= NX };
+    if inexact && tiny { *flags |= UF };
+    self.zero(sign) | biased << self.mant_bits | (kept as u64 & ((1 << self.mant_bits) - 1))
+  }
+
+  // Adds two exact values, given as (sign, sig, exp), and rounds the sum
+  fn add_exact(self, a: (bool, u128, i32), b: (bool, u128, i32), rm: Rounding, flags: &mut u32) -> u64 {
+    let top = |(_, sig, exp): (bool, u128, i32)| exp - sig.leading_zeros() as i32;
+    let ((sx, mut x, mut ex), (sy, mut y, ey)) = if top(a) >= top(b) { (a, b) } else { (b, a) };
+    // x moves up as far as it can while leaving room for a carry and the sticky bits, and y is
+    // lined up with it, with anything below x only kept as sticky
+    let room = (x.leading_zeros() as i32 - 4).min(ex - ey).max(0);
+    x <<= room;
+    ex -= room;
+    let sticky = if ex > ey {
+      let d = ex - ey;
+      let lost = if d >= 128 { y } else { y & ((1 << d) - 1) };
+      y = if d >= 128 { 0 } else { y >> d };
+      lost != 0
+    } else {
+      y <<= ey - ex;
+      false
+    };
+    if sx == sy { return self.round(sx, x + y, ex, sticky, rm, flags) };
+    // what y lost is taken from x as a borrow, x is always larger when anything was lost
+    if sticky { return self.round(sx, x - y - 1, ex, true, rm, flags) };
+    match x.cmp(&y) {
+      Ordering::Equal => self.zero(rm == Rounding::RDN),
+      Ordering::Greater => self.round(sx, x - y, ex, false, rm, flags),
+      Ordering::Less => self.round(sy, y - x, ex, false, rm, flags),
+    }
+  }
+
+  // The canonical NaN, which is invalid if any operand was a signaling NaN
+  fn nan(self, ops: &[Class], flags: &mut u32) -> u64 {
+    if ops.contains(&Class::NaN{ signaling: true }) { *flags |= NV };
+    self.canonical_nan()
+  }
+
+  fn invalid(self, flags: &mut u32) -> u64 {
+    *flags |= NV;
+    self.canonical_nan()
+  }
+
+  // Zero from adding two zeros, or two values that cancel out
+  fn zero_sum(self, a: bool, b: bool, rm: Rounding) -> u64 {
+    if a == b { self.zero(a) } else { self.zero(rm == Rounding::RDN) }
+  }
+
+  pub fn add(self, a: u64, b: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    let ((sa, ca), (sb, cb)) = (self.unpack(a), self.unpack(b));
+    match (ca, cb) {
+      (Class::NaN{ .. }, _) | (_, Class::NaN{ .. }) => self.nan(&[ca, cb], flags),
+      (Class::Inf, Class::Inf) if sa != sb => self.invalid(flags),
+      (Class::Inf, _) => a,
+      (_, Class::Inf) => b,
+      (Class::Zero, Class::Zero) => self.zero_sum(sa, sb, rm),
+      (Class::Zero, _) => b,
+      (_, Class::Zero) => a,
+      (Class::Finite(x, ex), Class::Finite(y, ey)) => self.add_exact((sa, x, ex), (sb, y, ey), rm, flags),
+    }
+  }
+
+  pub fn sub(self, a: u64, b: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    self.add(a, b ^ self.sign_bit(), rm, flags)
+  }
+
+  pub fn mul(self, a: u64, b: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    let ((sa, ca), (sb, cb)) = (self.unpack(a), self.unpack(b));
+    let sign = sa != sb;
+    match (ca, cb) {
+      (Class::NaN{ .. }, _) | (_, Class::NaN{ .. }) => self.nan(&[ca, cb], flags),
+      (Class::Inf, Class::Zero) | (Class::Zero, Class::Inf) => self.invalid(flags),
+      (Class::Inf, _) | (_, Class::Inf) => self.inf(sign),
+      (Class::Zero, _) | (_, Class::Zero) => self.zero(sign),
+      (Class::Finite(x, ex), Class::Finite(y, ey)) => self.round(sign, x * y, ex + ey, false, rm, flags),
+    }
+  }
+
+  pub fn div(self, a: u64, b: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    let ((sa, ca), (sb, cb)) = (self.unpack(a), self.unpack(b));
+    let sign = sa != sb;
+    match (ca, cb) {
+      (Class::NaN{ .. }, _) | (_, Class::NaN{ .. }) => self.nan(&[ca, cb], flags),
+      (Class::Inf, Class::Inf) | (Class::Zero, Class::Zero) => self.invalid(flags),
+      (Class::Inf, _) => self.inf(sign),
+      (_, Class::Zero) => {
+        *flags |= DZ;
+        self.inf(sign)
+      },
+      (Class::Zero, _) | (_, Class::Inf) => self.zero(sign),
+      (Class::Finite(x, ex), Class::Finite(y, ey)) => {
+        // both have their leading bit at 63, so the quotient has at least 64 bits
+        let (xs, ys) = (x.leading_zeros() as i32 - 64, y.leading_zeros() as i32 - 64);
+        let (x, y) = (x << xs << 64, y << ys);
+        self.round(sign, x / y, (ex - xs) - (ey - ys) - 64, x % y != 0, rm, flags)
+      },
+    }
+  }
+
+  pub fn sqrt(self, a: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    match self.unpack(a) {
+      (_, c @ Class::NaN{ .. }) => self.nan(&[c], flags),
+      (_, Class::Zero) => a,
+      (true, _) => self.invalid(flags),
+      (false, Class::Inf) => a,
+      (false, Class::Finite(sig, exp)) => {
+        // an even exponent halves exactly, and the root keeps at least 62 bits
+        let shift = sig.leading_zeros() as i32 - 3;
+        let (sig, exp) = (sig << shift, exp - shift);
+        let (sig, exp) = if exp & 1 != 0 { (sig << 1, exp - 1) } else { (sig, exp) };
+        let root = isqrt(sig);
+        self.round(false, root, exp / 2, root * root != sig, rm, flags)
+      },
+    }
+  }
+
+  // a * b + c with a single rounding. Multiplying infinity by zero is invalid even when c is a
+  // quiet NaN.
+  pub fn fma(self, a: u64, b: u64, c: u64, rm: Rounding, flags: &mut u32) -> u64 {
+    let ((sa, ca), (sb, cb), (sc, cc)) = (self.unpack(a), self.unpack(b), self.unpack(c));
+    let sign = sa != sb;
+    let inf_zero = matches!((ca, cb), (Class::Inf, Class::Zero) | (Class::Zero, Class::Inf));
+    if [ca, cb, cc].iter().any(|c| matches!(c, Class::NaN{ .. })) {
+      if inf_zero { *flags |= NV };
+      return self.nan(&[ca, cb, cc], flags);
+    };
+    if inf_zero { return self.invalid(flags) };
+    if ca == Class::Inf || cb == Class::Inf {
+      return if cc == Class::Inf && sc != sign { self.invalid(flags) } else { self.inf(sign) };
+    };
+    match (ca, cb, cc) {
+      (_, _, Class::Inf) => c,
+      (Class::Finite(x, ex), Class::Finite(y, ey), Class::Finite(z, ez)) =>
+        self.add_exact((sign, x * y, ex + ey), (sc, z, ez), rm, flags),
+      (Class::Finite(x, ex), Class::Finite(y, ey), _) => self.round(sign, x * y, ex + ey, false, rm, flags),
+      (_, _, Class::Zero) => self.zero_sum(sign, sc, rm),
+      _ => c,
+    }
+  }
+
+  // Converts to a signed or unsigned integer of bits bits, returned as a 64 bit two's complement
+  // value. NaN and values out of range are invalid and give the nearest limit, NaN the largest.
+  pub fn to_int(self, a: u64, signed: bool, bits: u32, rm: Rounding, flags: &mut u32) -> u64 {
+    let (min, max): (i128, i128) = if signed { (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) }
+                                   else { (0, (1 << bits) - 1) };
+    let (sign, class) = self.unpack(a);
+    let limit = |flags: &mut u32, sign: bool| {
+      *flags |= NV;
+      (if sign { min } else { max }) as u64
+    };
+    match class {
+      Class::NaN{ .. } => limit(flags, false),
+      Class::Inf => limit(flags, sign),
+      Class::Zero => 0,
+      Class::Finite(_, exp) if exp >= 64 => limit(flags, sign),
+      Class::Finite(sig, exp) => {
+        let (mag, inexact) = shift_round(sig, -exp, sign, rm);
+        let v = if sign { -(mag as i128) } else { mag as i128 };
+        if v < min || v > max { return limit(flags, sign) };
+        if inexact { *flags |= NX };
+        v as u64
+      },
+    }
+  }
+
+  // Converts the low bits bits of v, as a signed or unsigned integer
+  pub fn from_int(self, v: u64, signed: bool, bits: u32, rm: Rounding, flags: &mut u32) -> u64 {
+    let v = if bits < 64 { v & ((1 << bits) - 1) } else { v };
+    let (sign, mag) = if signed {
+      let v = ((v << (64 - bits)) as i64) >> (64 - bits);
+      (v < 0, v.unsigned_abs())
+    } else {
+      (false, v)
+    };
+    self.round(sign, mag as u128, 0, false, rm, flags)
+  }
+
+  // Converts to the other format
+  pub fn convert(self, a: u64, to: Format, rm: Rounding, flags: &mut u32) -> u64 {
+    match self.unpack(a) {
+      (_, c @ Class::NaN{ .. }) => to.nan(&[c], flags),
+      (sign, Class::Inf) => to.inf(sign),
+      (sign, Class::Zero) => to.zero(sign),
+      (sign, Class::Finite(sig, exp)) => to.round(sign, sig, exp, false, rm, flags),
+    }
+  }
+
+  // Orders values by their sign and magnitude, with -0 below +0 only if zero_signed
+  fn key(self, bits: u64, zero_signed: bool) -> i128 {
+    let mag = (bits & !self.sign_bit()) as i128;
+    if bits & self.sign_bit() != 0 { -mag - zero_signed as i128 } else { mag }
+  }
+
+  // None if either is NaN, which is invalid unless quiet and both are quiet NaNs
+  pub fn compare(self, a: u64, b: u64, quiet: bool, flags: &mut u32) -> Option<Ordering> {
+    let (ca, cb) = (self.unpack(a).1, self.unpack(b).1);
+    let signaling = |c| c == Class::NaN{ signaling: true };
+    if matches!(ca, Class::NaN{ .. }) || matches!(cb, Class::NaN{ .. }) {
+      if !quiet || signaling(ca) || signaling(cb) { *flags |= NV };
+      return None;
+    };
+    Some(self.key(a, false).cmp(&self.key(b, false)))
+  }
+
+  // The smaller or larger of the two, where -0 is below +0 and NaNs are only returned if both are
+  pub fn min_max(self, a: u64, b: u64, max: bool, flags: &mut u32) -> u64 {
+    let (ca, cb) = (self.unpack(a).1, self.unpack(b).1);
+    let nan = |c| matches!(c, Class::NaN{ .. });
+    if [ca, cb].contains(&Class::NaN{ signaling: true }) { *flags |= NV };
+    match (nan(ca), nan(cb)) {
+      (true, true) => self.canonical_nan(),
+      (true, false) => b,
+      (false, true) => a,
+      _ if (self.key(a, true) < self.key(b, true)) != max => a,
+      _ => b,
+    }
+  }
+
+  // FCLASS, a single bit for the kind of value
+  pub fn class(self, a: u64) -> u32 {
+    let (sign, class) = self.unpack(a);
+    let subnormal = (a >> self.mant_bits) & self.max_exp() == 0;
+    let bit = match class {
+      Class::Inf => if sign { 0 } else { 7 },
+      Class::Finite(..) if subnormal => if sign { 2 } else { 5 },
+      Class::Finite(..) => if sign { 1 } else { 6 },
+      Class::Zero => if sign { 3 } else { 4 },
+      Class::NaN{ signaling: true } => 8,
+      Class::NaN{ signaling: false } => 9,
+    };
+    1 << bit
+  }
+}
+
+pub const ABI_NAMES: [&str; 32] = [
+  "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3",
+  "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10",
+  "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+// f0-f31, wide enough for doubles. Singles are NaN-boxed in the upper 32 bits.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct FRegister {
+  data: [u64; 32],
+}
+
+const BOX: u64 = 0xffffffff_00000000;
+
+impl FRegister {
+  // A single that is not properly boxed reads as the canonical NaN
+  pub fn read(&self, r: u32, fmt: Format) -> u64 {
+    let v = self.data[r as usize];
+    match fmt {
+      SINGLE if v & BOX != BOX => SINGLE.canonical_nan(),
+      SINGLE => v & !BOX,
+      _ => v,
+    }
+  }
+  pub fn write(&mut self, r: u32, v: u64, fmt: Format) {
+    self.data[r as usize] = if fmt == SINGLE { v | BOX } else { v };
+  }
+}
+
+impl std::ops::Index<u32> for FRegister {
+  type Output = u64;
+  fn index(&self, i: u32) -> &u64 { &self.data[i as usize] }
+}
+
+// Boxed singles are shown as singles, and everything else as a double
+impl fmt::Display for FRegister {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, &v) in self.data.iter().enumerate() {
+      if v & BOX == BOX { writeln!(f, "[ f{:02}: {:016x} | {} ]", i, v, f32::from_bits(v as u32))? }
+      else { writeln!(f, "[ f{:02}: {:016x} | {} ]", i, v, f64::from_bits(v))? };
+    }
+    Ok(())
+  }
+}
+
+// What goes in the integer rd, and the memory accessed
+type Executed<T> = (Option<T>, Option<MemAccess<T>>);
+
+impl <T : RegData> ProgramState<T> {
+  fn read_float(&self, addr: T, size: Size) -> Result<u64, Exceptions> {
+    let loc = addr.as_usize();
+    if !loc.is_multiple_of(size.bytes()) { return Err(Exceptions::LoadMisaligned) };
+    let bytes = self.mem.read_bytes(loc, size.bytes()).map_err(|_| Exceptions::LoadAccessFault)?;
+    let mut v = [0u8; 8];
+    v[..size.bytes()].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(v))
+  }
+
+  // Runs an F or D instruction given the value of its integer source. The FP registers, fflags
+  // and memory are updated as it goes, and what goes in the integer rd is returned along with
+  // the memory accessed. Faults return the exception and mtval, which is 0 for illegal ones.
+  pub(crate) fn float(&mut self, instr: &InstrType, src: T)
+    -> Result<Executed<T>, (Exceptions, T)> {
+    use FInstr::*;
+    let (var, rd, rs1, rs2, rs3, imm, double, rm) = match *instr {
+      InstrType::F{ var, rd, rs1, rs2, rs3, imm, double, rm } => (var, rd, rs1, rs2, rs3, imm, double, rm),
+      _ => panic!("float() called with {:?}", instr),
+    };
+    let (fmt, other, size) = if double { (DOUBLE, SINGLE, Size::DOUBLE) } else { (SINGLE, DOUBLE, Size::WORD) };
+    let rm = Rounding::new(rm, self.csrs.frm).ok_or((Exceptions::IllegalInstruction, T::zero()));
+    let (a, b, c) = (self.fregs.read(rs1, fmt), self.fregs.read(rs2, fmt), self.fregs.read(rs3, fmt));
+    let sign = fmt.sign_bit();
+    let mut flags = 0;
+    let fl = &mut flags;
+    let bool = |v: bool| if v { T::one() } else { T::zero() };
+    // results that go in an FP register, or are returned for the integer rd
+    let (f, x) = match var {
+      FL => {
+        let addr = src.offset(T::Signed::from(imm));
+        let v = self.read_float(addr, size).map_err(|e| (e, addr))?;
+        self.fregs.write(rd, v, fmt);
+        return Ok((None, Some(MemAccess::load(addr, size))));
+      },
+      // the bits are stored as they are, boxed or not
+      FS => {
+        let addr = src.offset(T::Signed::from(imm));
+        let loc = self.store_addr(addr, size).map_err(|e| (e, addr))?;
+        let v = self.fregs[rs2];
+        self.void_reservation(loc, size);
+        self.mem.write_bytes(loc, &v.to_le_bytes()[..size.bytes()]).expect("Checked store failed");
+        return Ok((None, Some(MemAccess::store_bits(addr, size, v))));
+      },
+      FMADD => (Some(fmt.fma(a, b, c, rm?, fl)), None),
+      FMSUB => (Some(fmt.fma(a, b, c ^ sign, rm?, fl)), None),
+      FNMSUB => (Some(fmt.fma(a ^ sign, b, c, rm?, fl)), None),
+      FNMADD => (Some(fmt.fma(a ^ sign, b, c ^ sign, rm?, fl)), None),
+      FADD => (Some(fmt.add(a, b, rm?, fl)), None),
+      FSUB => (Some(fmt.sub(a, b, rm?, fl)), None),
+      FMUL => (Some(fmt.mul(a, b, rm?, fl)), None),
+      FDIV => (Some(fmt.div(a, b, rm?, fl)), None),
+      FSQRT => (Some(fmt.sqrt(a, rm?, fl)), None),
+      FSGNJ => (Some((a & !sign) | (b & sign)), None),
+      FSGNJN => (Some((a & !sign) | (!b & sign)), None),
+      FSGNJX => (Some(a ^ (b & sign)), None),
+      FMIN => (Some(fmt.min_max(a, b, false, fl)), None),
+      FMAX => (Some(fmt.min_max(a, b, true, fl)), None),
+      FCVTFF => (Some(other.convert(self.fregs.read(rs1, other), fmt, rm?, fl)), None),
+      // words are sign extended into rd, even when unsigned
+      FCVTWF => (None, Some(T::from_u64(fmt.to_int(a, true, 32, rm?, fl)).sext_w())),
+      FCVTWUF => (None, Some(T::from_u64(fmt.to_int(a, false, 32, rm?, fl)).sext_w())),
+      FCVTLF => (None, Some(T::from_u64(fmt.to_int(a, true, 64, rm?, fl)))),
+      FCVTLUF => (None, Some(T::from_u64(fmt.to_int(a, false, 64, rm?, fl)))),
+      FCVTFW => (Some(fmt.from_int(src.as_usize() as u64, true, 32, rm?, fl)), None),
+      FCVTFWU => (Some(fmt.from_int(src.as_usize() as u64, false, 32, rm?, fl)), None),
+      FCVTFL => (Some(fmt.from_int(src.as_usize() as u64, true, 64, rm?, fl)), None),
+      FCVTFLU => (Some(fmt.from_int(src.as_usize() as u64, false, 64, rm?, fl)), None),
+      FEQ => (None, Some(bool(fmt.compare(a, b, true, fl) == Some(Ordering::Equal)))),
+      FLT => (None, Some(bool(fmt.compare(a, b, false, fl) == Some(Ordering::Less)))),
+      FLE => (None, Some(bool(matches!(fmt.compare(a, b, false, fl), Some(Ordering::Less | Ordering::Equal))))),
+      FCLASS => (None, Some(T::from(fmt.class(a)))),
+      // the raw bits, with singles sign extended
+      FMVXF if double => (None, Some(T::from_u64(self.fregs[rs1]))),
+      FMVXF => (None, Some(T::from_u64(self.fregs[rs1]).sext_w())),
+      FMVFX => (Some(src.as_usize() as u64), None),
+    };
+    if let Some(v) = f { self.fregs.write(rd, v, fmt) };
+    self.csrs.fflags |= flags;
+    Ok((x, None))
+  }
+}
+
+#[cfg(test)]
+fn bits(v: f64) -> u64 { if v.is_nan() { DOUBLE.canonical_nan() } else { v.to_bits() } }
+
+#[cfg(test)]
+fn single(v: f32) -> u64 { if v.is_nan() { SINGLE.canonical_nan() } else { v.to_bits() as u64 } }
+
+#[test]
+fn test_rounding() {
+  let mut flags = 0;
+  let third = DOUBLE.div(bits(1.0), bits(3.0), Rounding::RNE, &mut flags);
+  assert_eq!(third, bits(1.0 / 3.0));
+  assert_eq!(flags, NX);
+  // 1/3 is below its nearest double, so rounding up or away from zero lands one above it
+  assert_eq!(DOUBLE.div(bits(1.0), bits(3.0), Rounding::RUP, &mut flags), third + 1);
+  assert_eq!(DOUBLE.div(bits(1.0), bits(3.0), Rounding::RTZ, &mut flags), third);
+  assert_eq!(DOUBLE.div(bits(-1.0), bits(3.0), Rounding::RDN, &mut flags), bits(-1.0 / 3.0) + 1);
+  // ties
+  let one = SINGLE.from_int(1, false, 32, Rounding::RNE, &mut flags);
+  assert_eq!(one, 1f32.to_bits() as u64);
+  let odd = (1 << 24) + 1;
+  assert_eq!(SINGLE.from_int(odd, false, 32, Rounding::RNE, &mut flags), 16777216f32.to_bits() as u64);
+  assert_eq!(SINGLE.from_int(odd, false, 32, Rounding::RMM, &mut flags), 16777218f32.to_bits() as u64);
+  assert_eq!(SINGLE.to_int(2.5f32.to_bits() as u64, true, 32, Rounding::RNE, &mut flags), 2);
+  assert_eq!(SINGLE.to_int(2.5f32.to_bits() as u64, true, 32, Rounding::RMM, &mut flags), 3);
+  assert_eq!(SINGLE.to_int((-2.5f32).to_bits() as u64, true, 32, Rounding::RDN, &mut flags), -3i64 as u64);
+  // the host agrees on everything rounded to nearest
+  let values = [1.5, -0.1, 3.0e-308, 1.0e308, 7.0, -2.0e-320, 0.3];
+  for &x in values.iter() {
+    for &y in values.iter() {
+      let mut flags = 0;
+      assert_eq!(DOUBLE.add(bits(x), bits(y), Rounding::RNE, &mut flags), bits(x + y), "{} + {}", x, y);
+      assert_eq!(DOUBLE.mul(bits(x), bits(y), Rounding::RNE, &mut flags), bits(x * y), "{} * {}", x, y);
+      assert_eq!(DOUBLE.div(bits(x), bits(y), Rounding::RNE, &mut flags), bits(x / y), "{} / {}", x, y);
+      assert_eq!(DOUBLE.fma(bits(x), bits(y), bits(0.3), Rounding::RNE, &mut flags),
+        bits(x.mul_add(y, 0.3)), "{} * {} + 0.3", x, y);
+      let (fx, fy) = (x as f32, y as f32);
+      let (sx, sy) = (fx.to_bits() as u64, fy.to_bits() as u64);
+      assert_eq!(SINGLE.add(sx, sy, Rounding::RNE, &mut flags), single(fx + fy), "{} + {}", fx, fy);
+      assert_eq!(SINGLE.div(sx, sy, Rounding::RNE, &mut flags), single(fx / fy), "{} / {}", fx, fy);
+    };
+    let mut flags = 0;
+    assert_eq!(DOUBLE.sqrt(bits(x), Rounding::RNE, &mut flags), bits(x.sqrt()), "sqrt {}", x);
+    assert_eq!(DOUBLE.convert(bits(x), SINGLE, Rounding::RNE, &mut flags), single(x as f32));
+  };
+}
+
+#[test]
+fn test_exceptions() {
+  let mut flags = 0;
+  assert_eq!(DOUBLE.div(bits(1.0), 0, Rounding::RNE, &mut flags), bits(f64::INFINITY));
+  assert_eq!(flags, DZ);
+  flags = 0;
+  assert_eq!(DOUBLE.sub(bits(f64::INFINITY), bits(f64::INFINITY), Rounding::RNE, &mut flags),
+    DOUBLE.canonical_nan());
+  assert_eq!(flags, NV);
+  flags = 0;
+  assert_eq!(DOUBLE.mul(bits(1e300), bits(1e300), Rounding::RTZ, &mut flags), bits(f64::MAX));
+  assert_eq!(flags, OF | NX);
+  flags = 0;
+  DOUBLE.mul(bits(1e-300), bits(1e-300), Rounding::RNE, &mut flags);
+  assert_eq!(flags, UF | NX);
+  flags = 0;
+  // quiet NaNs only make comparisons other than equality invalid
+  let qnan = SINGLE.canonical_nan();
+  assert_eq!(SINGLE.compare(qnan, 0, true, &mut flags), None);
+  assert_eq!(flags, 0);
+  SINGLE.compare(qnan, 0, false, &mut flags);
+  assert_eq!(flags, NV);
+  flags = 0;
+  assert_eq!(SINGLE.to_int(qnan, true, 32, Rounding::RNE, &mut flags), i32::MAX as u64);
+  assert_eq!(SINGLE.to_int((-1.0f32).to_bits() as u64, false, 32, Rounding::RNE, &mut flags), 0);
+  assert_eq!(flags, NV);
+  assert_eq!(SINGLE.min_max(qnan, 1f32.to_bits() as u64, false, &mut flags), 1f32.to_bits() as u64);
+  assert_eq!(DOUBLE.min_max(bits(-0.0), 0, false, &mut flags), bits(-0.0));
+  assert_eq!(SINGLE.class(qnan), 1 << 9);
+  assert_eq!(DOUBLE.class(bits(-5e-324)), 1 << 2);
+}
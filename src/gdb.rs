@@ -0,0 +1,410 @@
+// GDB remote serial protocol stub, driving the normal simulator one instruction at a time
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::program_state::{ProgramState, Status, Exceptions};
+use crate::reg::{RegData, ABI_NAMES};
+use crate::sim::step;
+use crate::trace::Retired;
+
+// gdb numbers the pc right after the integer registers
+const PC_REGNUM: usize = 32;
+// how many instructions run between checks for a ctrl-c from gdb
+const INTERRUPT_POLL: usize = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum WatchKind { Write, Read, Access, }
+
+impl WatchKind {
+  // name used for the watchpoint in stop replies
+  fn name(self) -> &'static str {
+    match self { WatchKind::Write => "watch", WatchKind::Read => "rwatch", WatchKind::Access => "awatch" }
+  }
+  fn triggered_by(self, write: bool) -> bool {
+    match self { WatchKind::Write => write, WatchKind::Read => !write, WatchKind::Access => true }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Watchpoint { kind: WatchKind, addr: usize, len: usize, }
+
+struct GdbStub {
+  stream: TcpStream,
+  // acks are sent until gdb asks for no-ack mode
+  ack: bool,
+  breakpoints: HashSet<usize>,
+  watchpoints: Vec<Watchpoint>,
+}
+
+// Waits for gdb to connect, then serves it until it kills or detaches from the program
+pub fn serve<T : RegData>(mut ps: ProgramState<T>, listener: TcpListener)
+  -> Result<ProgramState<T>, String> {
+  let (stream, _) = listener.accept().map_err(|e| format!("Failed to accept gdb: {}", e))?;
+  stream.set_nodelay(true).map_err(|e| e.to_string())?;
+  let mut stub = GdbStub{ stream, ack: true, breakpoints: HashSet::new(), watchpoints: Vec::new() };
+  stub.run(&mut ps).map_err(|e| format!("Lost connection to gdb: {}", e))?;
+  Ok(ps)
+}
+
+impl GdbStub {
+  fn run<T : RegData>(&mut self, ps: &mut ProgramState<T>) -> io::Result<()> {
+    while let Some(packet) = self.read_packet()? {
+      match packet.as_slice() {
+        b"k" => break,
+        b"D" => {
+          self.send("OK")?;
+          break
+        },
+        _ => {
+          let reply = self.handle(ps, &packet)?;
+          self.send(&reply)?;
+        },
+      };
+    }
+    Ok(())
+  }
+
+  // Reads the next packet, acking it, or None once gdb disconnects
+  fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+    loop {
+      // anything before the start of a packet is an ack or a stray interrupt
+      match self.read_byte()? {
+        None => return Ok(None),
+        Some(b'$') => (),
+        Some(_) => continue,
+      };
+      let mut data = Vec::new();
+      loop {
+        match self.read_byte()? {
+          None => return Ok(None),
+          Some(b'#') => break,
+          Some(b) => data.push(b),
+        };
+      }
+      let mut sum = [0; 2];
+      self.stream.read_exact(&mut sum)?;
+      let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+      let valid = expected == Some(checksum(&data));
+      if self.ack { self.stream.write_all(if valid { b"+" } else { b"-" })? };
+      if valid { return Ok(Some(unescape(&data))) };
+    }
+  }
+
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    let mut b = [0];
+    match self.stream.read(&mut b)? {
+      0 => Ok(None),
+      _ => Ok(Some(b[0])),
+    }
+  }
+
+  fn send(&mut self, reply: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", reply, checksum(reply.as_bytes()));
+    loop {
+      self.stream.write_all(packet.as_bytes())?;
+      if !self.ack { return Ok(()) };
+      // resend until gdb acknowledges it
+      match self.read_byte()? {
+        Some(b'+') | None => return Ok(()),
+        Some(_) => continue,
+      };
+    }
+  }
+
+  // Checks whether gdb sent a ctrl-c while the program was running
+  fn interrupted(&mut self) -> io::Result<bool> {
+    self.stream.set_nonblocking(true)?;
+    let mut b = [0];
+    let read = self.stream.read(&mut b);
+    self.stream.set_nonblocking(false)?;
+    match read {
+      Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+      Ok(_) => Ok(b[0] == 0x03),
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+      Err(e) => Err(e),
+    }
+  }
+
+  fn handle<T : RegData>(&mut self, ps: &mut ProgramState<T>, packet: &[u8])
+    -> io::Result<String> {
+    if packet.first().is_none_or(|b| !b.is_ascii()) { return Ok(String::new()) };
+    // only X carries binary data, after the ':'
+    let text = String::from_utf8_lossy(packet);
+    let (cmd, args) = (&text[..1], &text[1..]);
+    let reply = match cmd {
+      "?" => format!("S{:02x}", SIGTRAP),
+      "g" => (0..=PC_REGNUM).map(|i| hex(&reg(ps, i).to_le_bytes())).collect(),
+      "G" => {
+        let width = T::BYTE_SIZE * 2;
+        for i in 0..=PC_REGNUM {
+          match args.get(i * width..(i + 1) * width).and_then(unhex) {
+            Some(bytes) => set_reg(ps, i, T::from_le_bytes(bytes.into_boxed_slice())),
+            None => return Ok(String::from("E16")),
+          };
+        }
+        String::from("OK")
+      },
+      "p" => match parse_hex(args) {
+        Some(i) if i <= PC_REGNUM => hex(&reg(ps, i).to_le_bytes()),
+        _ => String::from("E16"),
+      },
+      "P" => {
+        let parsed = args.split_once('=')
+          .and_then(|(i, v)| Some((parse_hex(i)?, unhex(v)?)))
+          .filter(|(i, v)| *i <= PC_REGNUM && v.len() == T::BYTE_SIZE);
+        match parsed {
+          Some((i, v)) => {
+            set_reg(ps, i, T::from_le_bytes(v.into_boxed_slice()));
+            String::from("OK")
+          },
+          None => String::from("E16"),
+        }
+      },
+      "m" => match parse_pair(args).and_then(|(addr, len)| ps.mem.read_bytes(addr, len).ok()) {
+        Some(bytes) => hex(bytes),
+        None => String::from("E0e"),
+      },
+      "M" | "X" => {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+          let (addr, len) = parse_pair(range)?;
+          let bytes = if cmd == "M" { unhex(data)? }
+                      else { packet[packet.iter().position(|&b| b == b':')? + 1..].to_vec() };
+          if bytes.len() == len { Some((addr, bytes)) } else { None }
+        });
+        match parsed.map(|(addr, bytes)| ps.mem.write_bytes(addr, &bytes).is_ok()) {
+          Some(true) => String::from("OK"),
+          _ => String::from("E0e"),
+        }
+      },
+      "s" | "c" => {
+        if let Some(addr) = parse_hex(args) { ps.regs.assign_pc(T::from_u64(addr as u64)) };
+        self.resume(ps, cmd == "s")?
+      },
+      "Z" | "z" => self.set_point(cmd == "Z", args),
+      "H" | "T" => String::from("OK"),
+      "q" | "Q" => self.query::<T>(&text),
+      _ => String::new(),
+    };
+    Ok(reply)
+  }
+
+  fn query<T : RegData>(&mut self, packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+      return String::from("PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+");
+    };
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+      return match parse_pair(range) {
+        Some((off, len)) => {
+          let xml = target_xml(T::BYTE_SIZE * 8);
+          let end = off.saturating_add(len);
+          let chunk = xml.get(off.min(xml.len())..end.min(xml.len())).unwrap_or("");
+          format!("{}{}", if end >= xml.len() { 'l' } else { 'm' }, chunk)
+        },
+        None => String::from("E16"),
+      };
+    };
+    match packet {
+      "QStartNoAckMode" => {
+        self.ack = false;
+        String::from("OK")
+      },
+      "qAttached" => String::from("1"),
+      "qC" => String::from("QC1"),
+      "qfThreadInfo" => String::from("m1"),
+      "qsThreadInfo" => String::from("l"),
+      _ => String::new(),
+    }
+  }
+
+  // Z/z packets, with the type, address and length or kind
+  fn set_point(&mut self, insert: bool, args: &str) -> String {
+    let mut fields = args.split(',');
+    let kind = fields.next();
+    let (addr, len) = match (fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) {
+      (Some(addr), Some(len)) => (addr, len),
+      _ => return String::from("E16"),
+    };
+    let watch = match kind {
+      // hardware breakpoints behave just like software ones here
+      Some("0") | Some("1") => {
+        if insert { self.breakpoints.insert(addr); } else { self.breakpoints.remove(&addr); };
+        return String::from("OK")
+      },
+      Some("2") => WatchKind::Write,
+      Some("3") => WatchKind::Read,
+      Some("4") => WatchKind::Access,
+      _ => return String::new(),
+    };
+    let w = Watchpoint{ kind: watch, addr, len };
+    if insert { self.watchpoints.push(w) } else { self.watchpoints.retain(|&v| v != w) };
+    String::from("OK")
+  }
+
+  // Runs until a breakpoint, watchpoint, exception or exit, or for one instruction, returning
+  // the stop reply
+  fn resume<T : RegData>(&mut self, ps: &mut ProgramState<T>, single: bool) -> io::Result<String> {
+    // continuing after an exception retries the faulting instruction
+    if let Status::Exception(_) = ps.status { ps.status = Status::Running };
+    let mut first = true;
+    for steps in 1.. {
+      if !first && self.breakpoints.contains(&ps.regs.pc().as_usize()) {
+        return Ok(format!("T{:02x}swbreak:;", SIGTRAP))
+      };
+      first = false;
+      let retired = if ps.status == Status::Running { step(ps) } else { None };
+      match ps.status {
+        Status::Running => (),
+        Status::Done => return Ok(String::from("W00")),
+        Status::Exit(code) => return Ok(format!("W{:02x}", code as u8)),
+        Status::Exception(e) => return Ok(format!("S{:02x}", signal(e))),
+        Status::Diverged => return Ok(format!("S{:02x}", SIGTRAP)),
+      };
+      let access = match retired { Some(Retired::Commit{ access, .. }) => access, _ => None };
+      let hit = access.and_then(|a| self.watchpoints.iter().find(|w| {
+        let (addr, size) = (a.addr.as_usize(), a.size.bytes());
+        w.kind.triggered_by(a.store.is_some()) && addr < w.addr.saturating_add(w.len)
+          && w.addr < addr.saturating_add(size)
+      }));
+      if let Some(w) = hit { return Ok(format!("T{:02x}{}:{:x};", SIGTRAP, w.kind.name(), w.addr)) };
+      if single { break };
+      if steps % INTERRUPT_POLL == 0 && self.interrupted()? { return Ok(format!("S{:02x}", SIGINT)) };
+    }
+    Ok(format!("S{:02x}", SIGTRAP))
+  }
+}
+
+fn signal(e: Exceptions) -> u8 {
+  match e {
+    Exceptions::IllegalInstruction => SIGILL,
+    Exceptions::InstrMisaligned | Exceptions::LoadMisaligned | Exceptions::StoreMisaligned =>
+      SIGBUS,
+    Exceptions::InstrAccessFault | Exceptions::LoadAccessFault | Exceptions::StoreAccessFault =>
+      SIGSEGV,
+    Exceptions::Breakpoint | Exceptions::EcallU | Exceptions::EcallS | Exceptions::EcallM => SIGTRAP,
+  }
+}
+
+fn reg<T : RegData>(ps: &ProgramState<T>, i: usize) -> T {
+  if i == PC_REGNUM { ps.regs.pc() } else { ps.regs[i as u32] }
+}
+
+fn set_reg<T : RegData>(ps: &mut ProgramState<T>, i: usize, v: T) {
+  if i == PC_REGNUM { ps.regs.assign_pc(v) } else { ps.regs.force_assign(i as u32, v) }
+}
+
+// Describes the registers so gdb does not have to guess the XLEN
+fn target_xml(xlen: usize) -> String {
+  let regs: String = ABI_NAMES.iter().enumerate()
+    .map(|(i, name)| format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", name,
+      xlen, if i == 2 { "data_ptr" } else { "int" }, i))
+    .collect();
+  format!("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+    <target version=\"1.0\"><architecture>riscv:rv{}</architecture>\
+    <feature name=\"org.gnu.gdb.riscv.cpu\">{}\
+    <reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"{}\"/></feature></target>",
+    xlen, regs, xlen, PC_REGNUM)
+}
+
+fn checksum(data: &[u8]) -> u8 { data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) }
+
+// Undoes the escaping of '#', '$', '}' and '*' in binary packet data
+fn unescape(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(data.len());
+  let mut bytes = data.iter();
+  while let Some(&b) = bytes.next() {
+    match b {
+      b'}' => if let Some(&n) = bytes.next() { out.push(n ^ 0x20) },
+      b => out.push(b),
+    };
+  }
+  out
+}
+
+fn hex(bytes: &[u8]) -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() }
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) { return None };
+  (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<usize> { usize::from_str_radix(s, 16).ok() }
+
+// "addr,len" as used by memory packets
+fn parse_pair(s: &str) -> Option<(usize, usize)> {
+  let (a, b) = s.split_once(',')?;
+  Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+#[cfg(test)]
+fn exchange(stream: &mut TcpStream, packet: &str) -> String {
+  write!(stream, "${}#{:02x}", packet, checksum(packet.as_bytes())).unwrap();
+  let mut reply = Vec::new();
+  let mut b = [0];
+  // skip the ack, then read up to the checksum
+  while stream.read(&mut b).unwrap() == 1 && b[0] != b'$' {}
+  while stream.read(&mut b).unwrap() == 1 && b[0] != b'#' { reply.push(b[0]) }
+  stream.read_exact(&mut [0; 2]).unwrap();
+  stream.write_all(b"+").unwrap();
+  String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn test_gdb_session() {
+  use crate::instr::InstrType;
+  use crate::mem::{Memory, Size, WORD_SIZE};
+  let program = [
+    0x04000513, // li a0, 64
+    0x00500293, // li t0, 5
+    0x00552023, // sw t0, 0(a0)
+    0x00052303, // lw t1, 0(a0)
+    0x00158593, // addi a1, a1, 1
+    InstrType::halt_val(),
+  ];
+  let mut mem = Memory::new(0x100);
+  program.iter().enumerate()
+    .for_each(|(i, &w)| mem.write(i * WORD_SIZE, w, Size::WORD).unwrap());
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  // the program state is not Send, so the client runs on the other thread
+  let client = std::thread::spawn(move || {
+    let mut gdb = TcpStream::connect(addr).unwrap();
+    gdb.set_nodelay(true).unwrap();
+
+    assert!(exchange(&mut gdb, "qSupported:swbreak+").contains("swbreak+"));
+    assert!(exchange(&mut gdb, "qXfer:features:read:target.xml:0,1000").contains("riscv:rv32"));
+    assert_eq!(exchange(&mut gdb, "g").len(), 33 * 8);
+    assert_eq!(exchange(&mut gdb, "s"), "S05");
+    assert_eq!(exchange(&mut gdb, "p20"), "04000000");
+    assert_eq!(exchange(&mut gdb, "Z0,10,4"), "OK");
+    assert_eq!(exchange(&mut gdb, "Z2,40,4"), "OK");
+    // a watchpoint running past the end of the address space, which no access reaches
+    assert_eq!(exchange(&mut gdb, "Z2,ffffffffffffff00,ffff"), "OK");
+    // stops after the store, then on the breakpoint after the load
+    assert_eq!(exchange(&mut gdb, "c"), "T05watch:40;");
+    assert_eq!(exchange(&mut gdb, "p20"), "0c000000");
+    assert_eq!(exchange(&mut gdb, "c"), "T05swbreak:;");
+    assert_eq!(exchange(&mut gdb, "m40,4"), "05000000");
+    assert_eq!(exchange(&mut gdb, "M40,4:07000000"), "OK");
+    assert_eq!(exchange(&mut gdb, "X44,2:ab"), "OK");
+    assert_eq!(exchange(&mut gdb, "m44,2"), "6162");
+    assert_eq!(exchange(&mut gdb, "P5=09000000"), "OK");
+    assert_eq!(exchange(&mut gdb, "m1000,4"), "E0e");
+    // lengths running past the end of the address space
+    assert_eq!(exchange(&mut gdb, "qXfer:features:read:target.xml:10,ffffffffffffffff").get(..1),
+      Some("l"));
+    assert_eq!(exchange(&mut gdb, "c"), "W00");
+    write!(gdb, "$k#6b").unwrap();
+  });
+  let ps = serve(ProgramState::<u32>::new(mem), listener).unwrap();
+  client.join().unwrap();
+  assert_eq!(ps.regs[5], 9);
+  assert_eq!(ps.regs[6], 5);
+  assert_eq!(ps.regs[11], 1);
+  assert_eq!(ps.mem.read(0x40, Size::WORD).unwrap(), 7);
+}
@@ -1,53 +1,166 @@
 #![allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+use crate::mem::Size;
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum RInstr {
   SLLI, SRLI, SRAI, ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND,
   // M-extension
-  // MUL, MULH, MULHSU, MULHU, DIV, DIVU, REM, REMU,
+  MUL, MULH, MULHSU, MULHU, DIV, DIVU, REM, REMU,
+  // RV64, operating on the low 32 bits and sign extending the result
+  SLLIW, SRLIW, SRAIW, ADDW, SUBW, SLLW, SRLW, SRAW,
+  MULW, DIVW, DIVUW, REMW, REMUW,
+  // Zba, Zbb, Zbs and Zbc
+  Zb(ZbInstr),
 }
 
-#[derive(Clone, Copy, Debug)]
+// Bit manipulation. Unary instructions keep the rest of their encoding in rs2, and those taking
+// an immediate keep it there as a shift amount.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum ZbInstr {
+  // Zba, address generation
+  SH1ADD, SH2ADD, SH3ADD,
+  // Zbb, basic bit manipulation
+  ANDN, ORN, XNOR, CLZ, CTZ, CPOP, MIN, MINU, MAX, MAXU, SEXTB, SEXTH, ZEXTH, ROL, ROR, RORI,
+  ORCB, REV8,
+  // Zbs, single bit instructions
+  BSET, BSETI, BCLR, BCLRI, BINV, BINVI, BEXT, BEXTI,
+  // Zbc, carry-less multiplication
+  CLMUL, CLMULH, CLMULR,
+  // RV64, where the *UW instructions zero extend the low 32 bits of rs1 first
+  ADDUW, SH1ADDUW, SH2ADDUW, SH3ADDUW, SLLIUW, CLZW, CTZW, CPOPW, ROLW, RORW, RORIW,
+}
+
+impl ZbInstr {
+  // Reads only rs1
+  pub fn unary(self) -> bool {
+    use ZbInstr::*;
+    matches!(self, CLZ | CTZ | CPOP | SEXTB | SEXTH | ZEXTH | ORCB | REV8 | CLZW | CTZW | CPOPW)
+  }
+  // Takes a shift amount or bit index from the instruction instead of rs2
+  pub fn immediate(self) -> bool {
+    use ZbInstr::*;
+    matches!(self, RORI | BSETI | BCLRI | BINVI | BEXTI | SLLIUW | RORIW)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum IInstr {
   JALR, LB, LH, LW, LBU, LHU, ADDI, SLTI, SLTIU, XORI, ORI, ANDI,
-
-  // unimplemented
-  ECALL, EBREAK, CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI,
+  ECALL, EBREAK, MRET, SRET, WFI,
+  // rs2 is in the bottom of zx_imm, there is no TLB so it only checks it is allowed
+  SFENCEVMA,
+  // MISC-MEM, which a single hart has nothing to order with
+  FENCE, FENCEI,
+  // RV64
+  LD, LWU, ADDIW,
+  // Zicsr, the CSR address is in zx_imm and the *I variants use rs1 as an immediate
+  CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum BInstr {
   BEQ, BNE, BLT, BGE, BLTU, BGEU,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum JInstr {
   JAL,
 }
 
-// not sure what to do with these
-#[derive(Clone, Copy, Debug)]
-pub(crate) enum Instr {
-  FENCE, FENCEI,
-}
-
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum SInstr {
   SB, SH, SW,
+  // RV64
+  SD,
 }
 
-#[derive(Clone, Copy, Debug)]
+// A-extension, LR and SC reserve memory and AMOs read, modify and write it in one go
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum AInstr {
+  LR, SC, AMOSWAP, AMOADD, AMOXOR, AMOAND, AMOOR, AMOMIN, AMOMAX, AMOMINU, AMOMAXU,
+}
+
+// F and D extensions, FCVTFF converts between S and D and the rest name the integer side as
+// W, WU, L or LU before or after the F
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum FInstr {
+  FL, FS, FMADD, FMSUB, FNMSUB, FNMADD, FADD, FSUB, FMUL, FDIV, FSQRT, FSGNJ, FSGNJN, FSGNJX,
+  FMIN, FMAX, FCVTFF, FCVTWF, FCVTWUF, FCVTLF, FCVTLUF, FCVTFW, FCVTFWU, FCVTFL, FCVTFLU,
+  FEQ, FLT, FLE, FCLASS, FMVXF, FMVFX,
+}
+
+// V extension, the integer subset. VLE and VSE are unit-stride, VLSE and VSSE strided, and VMV
+// is vmv.v.* while VMVXS and VMVSX move element 0 to and from an x register.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum VInstr {
+  VSETVLI, VSETIVLI, VSETVL,
+  VLE, VLSE, VSE, VSSE,
+  VADD, VSUB, VRSUB, VAND, VOR, VXOR, VSLL, VSRL, VSRA, VMINU, VMIN, VMAXU, VMAX,
+  VMUL, VMULH, VMULHU, VMULHSU, VMERGE, VMV,
+  // compares, writing a mask
+  VMSEQ, VMSNE, VMSLTU, VMSLT, VMSLEU, VMSLE, VMSGTU, VMSGT,
+  // reductions into element 0, starting from element 0 of vs1
+  VREDSUM, VREDAND, VREDOR, VREDXOR, VREDMINU, VREDMIN, VREDMAXU, VREDMAX,
+  // mask instructions
+  VMAND, VMNAND, VMANDN, VMXOR, VMOR, VMNOR, VMORN, VMXNOR, VCPOP, VFIRST,
+  VMVXS, VMVSX,
+}
+
+impl VInstr {
+  pub fn compare(self) -> bool {
+    use VInstr::*;
+    matches!(self, VMSEQ | VMSNE | VMSLTU | VMSLT | VMSLEU | VMSLE | VMSGTU | VMSGT)
+  }
+  pub fn reduction(self) -> bool {
+    use VInstr::*;
+    matches!(self, VREDSUM | VREDAND | VREDOR | VREDXOR | VREDMINU | VREDMIN | VREDMAXU | VREDMAX)
+  }
+  // Bitwise operations between whole masks
+  pub fn mask_logical(self) -> bool {
+    use VInstr::*;
+    matches!(self, VMAND | VMNAND | VMANDN | VMXOR | VMOR | VMNOR | VMORN | VMXNOR)
+  }
+}
+
+// Where the second operand of a V instruction comes from, as in the .vv, .vx and .vi forms
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum VSrc {
+  VV, VX, VI,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum UInstr {
   LUI, AUIPC,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub(crate) enum InstrType {
   R{ var: RInstr, rs1: u32, rs2: u32, rd: u32 },
   I{ var: IInstr, rs1: u32, rd: u32, sx_imm: i32, zx_imm: u32 },
-  S{ var: SInstr, rs1: u32, rs2: u32, imm: u32 },
+  S{ var: SInstr, rs1: u32, rs2: u32, imm: i32 },
   B{ var: BInstr, rs1: u32, rs2: u32, imm: i32 },
   U{ var: UInstr, rd: u32, imm: u32 },
   J{ var: JInstr, rd: u32, offset: i32 } ,
+  // the address is in rs1, aq orders later memory accesses after it and rl earlier ones before it
+  A{ var: AInstr, rs1: u32, rs2: u32, rd: u32, size: Size, aq: bool, rl: bool },
+  // registers are f registers unless they hold an integer or address, double picks D over S and
+  // rm is the rounding mode, 7 meaning frm
+  F{ var: FInstr, rd: u32, rs1: u32, rs2: u32, rs3: u32, imm: i32, double: bool, rm: u32 },
+  // rs1 is a vector register, x register or 5 bit immediate as src says, and vm clear masks the
+  // instruction by v0. Loads and stores take the address from rs1, the stride from the x register
+  // in vs2 and the element width in bytes from imm, which holds the vtype asked for by vsetvli and
+  // vsetivli. vd is the data stored by stores, and the integer rd of instructions that have one.
+  V{ var: VInstr, vd: u32, rs1: u32, vs2: u32, src: VSrc, vm: bool, imm: u32 },
 
   Halt,
 }
@@ -57,13 +170,18 @@ impl InstrType {
     use self::r::*;
     InstrType::R{ var: r, rs1: rs1(v), rs2: rs2(v), rd: rd(v) }
   }
+  // Shifts by an immediate keep the shift amount in rs2, which is 6 bits wide in RV64
+  pub fn shift(r: RInstr, v: u32) -> InstrType {
+    use self::r::*;
+    InstrType::R{ var: r, rs1: rs1(v), rs2: i::shamt(v), rd: rd(v) }
+  }
   pub fn i(i: IInstr, v: u32) -> InstrType {
     use self::i::*;
     InstrType::I{ var: i, rs1: rs1(v), rd: rd(v), sx_imm: sx_imm(v), zx_imm: zx_imm(v) }
   }
   pub fn s(s: SInstr, v: u32) -> InstrType {
     use self::s::*;
-    InstrType::S{ var: s, rs1: rs1(v), rs2: rs2(v), imm: imm(v) }
+    InstrType::S{ var: s, rs1: rs1(v), rs2: rs2(v), imm: sx_imm(v) }
   }
   pub fn b(b: BInstr, v: u32) -> InstrType {
     use self::b::*;
@@ -77,37 +195,129 @@ impl InstrType {
     use self::j::*;
     InstrType::J{ var: j, rd: rd(v), offset: offset(v) }
   }
+  pub fn a(a: AInstr, size: Size, v: u32) -> InstrType {
+    use self::r::*;
+    InstrType::A{ var: a, rs1: rs1(v), rs2: rs2(v), rd: rd(v), size, aq: (v >> 26) & 1 == 1,
+      rl: (v >> 25) & 1 == 1 }
+  }
+  // rm is kept for the instructions that round and is 0 otherwise
+  pub fn f(f: FInstr, double: bool, v: u32) -> InstrType {
+    use self::r::*;
+    use FInstr::*;
+    let imm = match f { FL => i::sx_imm(v), FS => s::sx_imm(v), _ => 0 };
+    let rm = match f {
+      FL | FS | FSGNJ | FSGNJN | FSGNJX | FMIN | FMAX | FEQ | FLT | FLE | FCLASS | FMVXF | FMVFX => 0,
+      _ => funct3(v),
+    };
+    InstrType::F{ var: f, rd: rd(v), rs1: rs1(v), rs2: rs2(v), rs3: v >> 27, imm, double, rm }
+  }
+  pub fn v(var: VInstr, src: VSrc, imm: u32, v: u32) -> InstrType {
+    use self::r::*;
+    InstrType::V{ var, vd: rd(v), rs1: rs1(v), vs2: rs2(v), src, vm: (v >> 25) & 1 == 1, imm }
+  }
   pub const fn halt_val() -> u32 { 0xfeedfeedu32 }
-  pub fn depends_on(&self, on: &InstrType) -> bool {
+  // Instructions with side effects outside the register file and memory
+  pub fn serializes(&self) -> bool {
+    use IInstr::*;
+    matches!(self, InstrType::I{
+      var: ECALL | EBREAK | MRET | SRET | WFI | SFENCEVMA | CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI
+        | CSRRCI, ..
+    })
+  }
+  // Registers read, other than by a system call
+  pub fn sources(&self) -> [Option<u32>; 2] {
     use InstrType::*;
-    match self {
-      Halt => false,
-      U{ var: UInstr::AUIPC, .. } => match on {
-        J{..} | B{ .. } | I{ var: IInstr::JALR, .. } | Halt => true,
-        _ => false,
-      },
-      J{ .. } | U{ .. } => false,
-      R{ rs1, rs2, ..} | S{rs1,rs2, ..} | B{ rs1, rs2, ..} => match on {
-        S{..} | B{..} => false,
-        R{rd, ..} | I{rd, ..} | U{rd, ..} | J{rd, ..} => rd == rs1 || rd == rs2,
-        Halt => true,
-      }
-      I{ var: IInstr::JALR, rs1, .. } => match on {
-        J{..} | B{ .. } | I{ var: IInstr::JALR, .. } => true,
-        S{..} => false,
-        R{rd, ..} | I{rd, ..} | U{rd, ..} => rd == rs1,
-        Halt => true,
+    let regs = match *self {
+      R{ var: RInstr::SLLI | RInstr::SRLI | RInstr::SRAI
+        | RInstr::SLLIW | RInstr::SRLIW | RInstr::SRAIW, rs1, .. } => [rs1, 0],
+      R{ var: RInstr::Zb(zb), rs1, .. } if zb.unary() || zb.immediate() => [rs1, 0],
+      R{ rs1, rs2, .. } | S{ rs1, rs2, .. } | B{ rs1, rs2, .. } => [rs1, rs2],
+      I{ var: IInstr::ECALL | IInstr::EBREAK | IInstr::MRET | IInstr::SRET | IInstr::WFI
+        | IInstr::SFENCEVMA | IInstr::FENCE | IInstr::FENCEI | IInstr::CSRRWI | IInstr::CSRRSI | IInstr::CSRRCI, .. } => [0, 0],
+      I{ rs1, .. } | A{ var: AInstr::LR, rs1, .. } => [rs1, 0],
+      A{ rs1, rs2, .. } => [rs1, rs2],
+      F{ var: FInstr::FL | FInstr::FS | FInstr::FCVTFW | FInstr::FCVTFWU | FInstr::FCVTFL
+        | FInstr::FCVTFLU | FInstr::FMVFX, rs1, .. } => [rs1, 0],
+      F{ .. } => [0, 0],
+      V{ var: VInstr::VSETVL | VInstr::VLSE | VInstr::VSSE, rs1, vs2, .. } => [rs1, vs2],
+      V{ src: VSrc::VX, rs1, .. } => [rs1, 0],
+      V{ .. } => [0, 0],
+      U{ .. } | J{ .. } | Halt => [0, 0],
+    };
+    // x0 is never written, so nothing waits on it
+    regs.map(|r| if r == 0 { None } else { Some(r) })
+  }
+  // Register written, system calls return their result in a0
+  pub fn dest(&self) -> Option<u32> {
+    use InstrType::*;
+    let rd = match *self {
+      I{ var: IInstr::ECALL, .. } => crate::syscall::A0,
+      I{ var: IInstr::EBREAK | IInstr::MRET | IInstr::SRET | IInstr::WFI | IInstr::SFENCEVMA
+        | IInstr::FENCE | IInstr::FENCEI, .. } => 0,
+      R{ rd, .. } | I{ rd, .. } | U{ rd, .. } | J{ rd, .. } | A{ rd, .. } => rd,
+      F{ rd, .. } if self.fdest().is_none() => rd,
+      V{ var: VInstr::VSETVLI | VInstr::VSETIVLI | VInstr::VSETVL | VInstr::VCPOP | VInstr::VFIRST
+        | VInstr::VMVXS, vd, .. } => vd,
+      S{ .. } | B{ .. } | F{ .. } | V{ .. } | Halt => 0,
+    };
+    if rd == 0 { None } else { Some(rd) }
+  }
+  // F register written, which unlike x0 includes f0
+  pub fn fdest(&self) -> Option<u32> {
+    use FInstr::*;
+    match *self {
+      InstrType::F{ var: FS | FEQ | FLT | FLE | FCLASS | FMVXF | FCVTWF | FCVTWUF | FCVTLF | FCVTLUF, .. } =>
+        None,
+      InstrType::F{ rd, .. } => Some(rd),
+      _ => None,
+    }
+  }
+  // Instructions that only exist in RV64, including shifts by 32 or more
+  pub fn rv64_only(&self) -> bool {
+    use RInstr::*;
+    match *self {
+      InstrType::R{ var: SLLI | SRLI | SRAI, rs2: shamt, .. } => shamt >= 32,
+      // rev8 has a separate encoding for each xlen, whose shift amount is 24 or 56
+      InstrType::R{ var: Zb(ZbInstr::REV8), rs2: shamt, .. } => shamt == 56,
+      InstrType::R{ var: Zb(var), rs2: shamt, .. } if var.immediate() && var != ZbInstr::RORIW => shamt >= 32,
+      InstrType::R{ var: Zb(var), .. } => {
+        use ZbInstr::*;
+        matches!(var, ADDUW | SH1ADDUW | SH2ADDUW | SH3ADDUW | CLZW | CTZW | CPOPW | ROLW | RORW)
       },
-      I{ rs1, .. } => match on {
-        // TODO loads can be dependent depending on where the stores are
-        S{..} | B{..} => false,
-        R{rd, ..} | I{rd, ..} | U{rd, ..} | J{rd, ..} => rd == rs1,
-        Halt => true,
-      }
+      InstrType::R{ var, .. } => matches!(var, SLLIW | SRLIW | SRAIW | ADDW | SUBW | SLLW | SRLW
+        | SRAW | MULW | DIVW | DIVUW | REMW | REMUW),
+      InstrType::I{ var, .. } => matches!(var, IInstr::LD | IInstr::LWU | IInstr::ADDIW),
+      InstrType::S{ var, .. } => var == SInstr::SD,
+      InstrType::A{ size, .. } => size == Size::DOUBLE,
+      InstrType::F{ var: FInstr::FMVXF | FInstr::FMVFX, double, .. } => double,
+      InstrType::F{ var, .. } => matches!(var, FInstr::FCVTLF | FInstr::FCVTLUF | FInstr::FCVTFL
+        | FInstr::FCVTFLU),
+      _ => false,
     }
   }
 }
 
+// Decodes an instruction for a core with xlen bit registers, where RV64 instructions are illegal
+// in RV32
+pub(crate) fn decode_xlen(instr: u32, xlen: usize) -> Result<InstrType, String> {
+  let decoded = decode_parcel(instr, xlen)?;
+  if xlen < 64 && decoded.rv64_only() { return Err(format!("{:?} is only in RV64", decoded)) };
+  // zext.h is in OP on RV32 and in OP-32 on RV64, and each encoding is reserved in the other
+  if let InstrType::R{ var: RInstr::Zb(ZbInstr::ZEXTH), .. } = decoded {
+    if (opcode(instr) == 0b0111011) != (xlen == 64) {
+      return Err(format!("{:?} is not encoded this way in RV{}", decoded, xlen))
+    };
+  };
+  Ok(decoded)
+}
+
+// Decodes a 32 bit instruction, or a compressed one, which needs xlen to be expanded. RV64
+// instructions are still decoded in RV32, for tools that only look at them.
+pub(crate) fn decode_parcel(instr: u32, xlen: usize) -> Result<InstrType, String> {
+  if !crate::rvc::is_compressed(instr) { return decode(instr) };
+  decode(crate::rvc::expand(instr as u16, xlen)?)
+}
+
 pub(crate) fn decode(instr: u32) -> Result<InstrType, String> {
   let v = instr; // just for aliasing
   if v == InstrType::halt_val() { return Ok(InstrType::Halt) }
@@ -116,6 +326,12 @@ pub(crate) fn decode(instr: u32) -> Result<InstrType, String> {
     0b0110111 => InstrType::u(UInstr::LUI, v),
     0b0010111 => InstrType::u(UInstr::AUIPC, v),
     0b1100111 if i::funct3(instr) == 0 => InstrType::i(IInstr::JALR, v),
+    // the fields other than funct3 are reserved for finer grained fences, which fall back to these
+    0b0001111 => match i::funct3(instr) {
+      0b000 => InstrType::i(IInstr::FENCE, v),
+      0b001 => InstrType::i(IInstr::FENCEI, v),
+      funct3 => return Err(format!("Unexpected funct3 for opcode 0b0001111: {}", funct3)),
+    },
     0b1100011 => match s::funct3(instr) {
       0b000 => InstrType::b(BInstr::BEQ, v),
       0b001 => InstrType::b(BInstr::BNE, v),
@@ -131,12 +347,15 @@ pub(crate) fn decode(instr: u32) -> Result<InstrType, String> {
       0b010 => InstrType::i(IInstr::LW, v),
       0b100 => InstrType::i(IInstr::LBU, v),
       0b101 => InstrType::i(IInstr::LHU, v),
+      0b011 => InstrType::i(IInstr::LD, v),
+      0b110 => InstrType::i(IInstr::LWU, v),
       funct3 => return Err(format!("Unexpected funct3 for opcode 0b0000011: {}", funct3)),
     },
     0b0100011 => match s::funct3(instr) {
       0b000 => InstrType::s(SInstr::SB, v),
       0b001 => InstrType::s(SInstr::SH, v),
       0b010 => InstrType::s(SInstr::SW, v),
+      0b011 => InstrType::s(SInstr::SD, v),
       funct3 => return Err(format!("Unexpected funct3 for opcode 0b0100011: {}", funct3)),
     },
     0b0010011 => match i::funct3(instr) {
@@ -146,10 +365,121 @@ pub(crate) fn decode(instr: u32) -> Result<InstrType, String> {
       0b100 => InstrType::i(IInstr::XORI, v),
       0b110 => InstrType::i(IInstr::ORI, v),
       0b111 => InstrType::i(IInstr::ANDI, v),
-      0b001 => InstrType::r(RInstr::SLLI, v),
-      0b101 if r::funct7(instr) == 0 => InstrType::r(RInstr::SRLI, v),
-      0b101 => InstrType::r(RInstr::SRAI, v),
-      funct3 => return Err(format!("Unexpected funct3 for opcode 0b0010111: {}", funct3)),
+      // the top bit of the shift amount is the bottom bit of funct7
+      0b001 if r::funct7(instr) >> 1 == 0 => InstrType::shift(RInstr::SLLI, v),
+      0b101 if r::funct7(instr) >> 1 == 0 => InstrType::shift(RInstr::SRLI, v),
+      0b101 if r::funct7(instr) >> 1 == 0b010000 => InstrType::shift(RInstr::SRAI, v),
+      funct3 => return decode_zb_imm(v, funct3),
+    },
+    0b0011011 => match (r::funct7(instr), i::funct3(instr)) {
+      (_, 0b000) => InstrType::i(IInstr::ADDIW, v),
+      (0, 0b001) => InstrType::r(RInstr::SLLIW, v),
+      (0, 0b101) => InstrType::r(RInstr::SRLIW, v),
+      (32, 0b101) => InstrType::r(RInstr::SRAIW, v),
+      (0b0110000, 0b001) if r::rs2(instr) == 0 => InstrType::r(RInstr::Zb(ZbInstr::CLZW), v),
+      (0b0110000, 0b001) if r::rs2(instr) == 1 => InstrType::r(RInstr::Zb(ZbInstr::CTZW), v),
+      (0b0110000, 0b001) if r::rs2(instr) == 2 => InstrType::r(RInstr::Zb(ZbInstr::CPOPW), v),
+      (0b0110000, 0b101) => InstrType::r(RInstr::Zb(ZbInstr::RORIW), v),
+      (f7, 0b001) if f7 >> 1 == 0b000010 => InstrType::shift(RInstr::Zb(ZbInstr::SLLIUW), v),
+      (f7, f3) =>
+        return Err(format!("Unexpected funct7 & funct3 for opcode 0b0011011: {}, {}", f7, f3)),
+    },
+    0b0111011 => match (r::funct7(instr), r::funct3(instr)) {
+      (0, 0b000) => InstrType::r(RInstr::ADDW, v),
+      (32, 0b000) => InstrType::r(RInstr::SUBW, v),
+      (0, 0b001) => InstrType::r(RInstr::SLLW, v),
+      (0, 0b101) => InstrType::r(RInstr::SRLW, v),
+      (32, 0b101) => InstrType::r(RInstr::SRAW, v),
+      (1, 0b000) => InstrType::r(RInstr::MULW, v),
+      (1, 0b100) => InstrType::r(RInstr::DIVW, v),
+      (1, 0b101) => InstrType::r(RInstr::DIVUW, v),
+      (1, 0b110) => InstrType::r(RInstr::REMW, v),
+      (1, 0b111) => InstrType::r(RInstr::REMUW, v),
+      (0b0000100, 0b000) => InstrType::r(RInstr::Zb(ZbInstr::ADDUW), v),
+      (0b0000100, 0b100) if r::rs2(instr) == 0 => InstrType::r(RInstr::Zb(ZbInstr::ZEXTH), v),
+      (0b0010000, 0b010) => InstrType::r(RInstr::Zb(ZbInstr::SH1ADDUW), v),
+      (0b0010000, 0b100) => InstrType::r(RInstr::Zb(ZbInstr::SH2ADDUW), v),
+      (0b0010000, 0b110) => InstrType::r(RInstr::Zb(ZbInstr::SH3ADDUW), v),
+      (0b0110000, 0b001) => InstrType::r(RInstr::Zb(ZbInstr::ROLW), v),
+      (0b0110000, 0b101) => InstrType::r(RInstr::Zb(ZbInstr::RORW), v),
+      (f7, f3) =>
+        return Err(format!("Unexpected funct7 & funct3 for opcode 0b0111011: {}, {}", f7, f3)),
+    },
+    0b0101111 => {
+      let size = match r::funct3(instr) {
+        0b010 => Size::WORD,
+        0b011 => Size::DOUBLE,
+        funct3 => return Err(format!("Unexpected funct3 for opcode 0b0101111: {}", funct3)),
+      };
+      // the bottom two bits of funct7 are aq and rl
+      let var = match r::funct7(instr) >> 2 {
+        0b00010 if r::rs2(instr) == 0 => AInstr::LR,
+        0b00011 => AInstr::SC,
+        0b00001 => AInstr::AMOSWAP,
+        0b00000 => AInstr::AMOADD,
+        0b00100 => AInstr::AMOXOR,
+        0b01100 => AInstr::AMOAND,
+        0b01000 => AInstr::AMOOR,
+        0b10000 => AInstr::AMOMIN,
+        0b10100 => AInstr::AMOMAX,
+        0b11000 => AInstr::AMOMINU,
+        0b11100 => AInstr::AMOMAXU,
+        funct5 => return Err(format!("Unexpected funct5 for opcode 0b0101111: {}", funct5)),
+      };
+      InstrType::a(var, size, v)
+    },
+    0b0000111 | 0b0100111 => {
+      let double = match i::funct3(instr) {
+        0b010 => false,
+        0b011 => true,
+        0b000 | 0b101 | 0b110 | 0b111 => return decode_vmem(v),
+        funct3 => return Err(format!("Unexpected funct3 for opcode {:07b}: {}", opcode(v), funct3)),
+      };
+      InstrType::f(if opcode(v) == 0b0000111 { FInstr::FL } else { FInstr::FS }, double, v)
+    },
+    0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 | 0b1010011 => {
+      use FInstr::*;
+      let (funct7, funct3, rs2) = (r::funct7(instr), r::funct3(instr), r::rs2(instr));
+      // the bottom two bits of funct7 are the format, only S and D exist
+      let double = match funct7 & 0b11 {
+        0b00 => false,
+        0b01 => true,
+        fmt => return Err(format!("Unexpected format for opcode {:07b}: {}", opcode(v), fmt)),
+      };
+      let var = match (opcode(v), funct7 >> 2, funct3) {
+        (0b1000011, ..) => FMADD,
+        (0b1000111, ..) => FMSUB,
+        (0b1001011, ..) => FNMSUB,
+        (0b1001111, ..) => FNMADD,
+        (_, 0b00000, _) => FADD,
+        (_, 0b00001, _) => FSUB,
+        (_, 0b00010, _) => FMUL,
+        (_, 0b00011, _) => FDIV,
+        (_, 0b01011, _) if rs2 == 0 => FSQRT,
+        (_, 0b00100, 0b000) => FSGNJ,
+        (_, 0b00100, 0b001) => FSGNJN,
+        (_, 0b00100, 0b010) => FSGNJX,
+        (_, 0b00101, 0b000) => FMIN,
+        (_, 0b00101, 0b001) => FMAX,
+        // rs2 holds the format converted from
+        (_, 0b01000, _) if rs2 == !double as u32 => FCVTFF,
+        (_, 0b10100, 0b010) => FEQ,
+        (_, 0b10100, 0b001) => FLT,
+        (_, 0b10100, 0b000) => FLE,
+        (_, 0b11000, _) if rs2 < 4 => [FCVTWF, FCVTWUF, FCVTLF, FCVTLUF][rs2 as usize],
+        (_, 0b11010, _) if rs2 < 4 => [FCVTFW, FCVTFWU, FCVTFL, FCVTFLU][rs2 as usize],
+        (_, 0b11100, 0b000) if rs2 == 0 => FMVXF,
+        (_, 0b11100, 0b001) if rs2 == 0 => FCLASS,
+        (_, 0b11110, 0b000) if rs2 == 0 => FMVFX,
+        (_, funct5, funct3) =>
+          return Err(format!("Unexpected funct5 & funct3 for opcode 0b1010011: {}, {}", funct5, funct3)),
+      };
+      let instr = InstrType::f(var, double, v);
+      // rounding modes 5 and 6 are reserved
+      if let InstrType::F{ rm: 5 | 6, .. } = instr {
+        return Err(format!("Unexpected rounding mode for {:?}", var));
+      };
+      instr
     },
     0b0110011 => match (r::funct7(instr), r::funct3(instr)) {
       (0, 0b000) => InstrType::r(RInstr::ADD, v),
@@ -163,37 +493,332 @@ pub(crate) fn decode(instr: u32) -> Result<InstrType, String> {
       (0, 0b110) => InstrType::r(RInstr::OR, v),
       (0, 0b111) => InstrType::r(RInstr::AND, v),
       // Multiplication extension
-      //(1, 0b000) => InstrType::R(RInstr::MUL),
-      //(1, 0b001) => InstrType::R(RInstr::MULH),
-      //(1, 0b010) => InstrType::R(RInstr::MULHSU),
-      //(1, 0b011) => InstrType::R(RInstr::MULHU),
-      //(1, 0b100) => InstrType::R(RInstr::DIV),
-      //(1, 0b101) => InstrType::R(RInstr::DIVU),
-      //(1, 0b110) => InstrType::R(RInstr::REM),
-      //(1, 0b111) => InstrType::R(RInstr::REMU),
-      (f7, f3) =>
-        return Err(format!("Unexpected funct7 & funct3 for opcode 0b0110011: {}, {}", f7, f3)),
+      (1, 0b000) => InstrType::r(RInstr::MUL, v),
+      (1, 0b001) => InstrType::r(RInstr::MULH, v),
+      (1, 0b010) => InstrType::r(RInstr::MULHSU, v),
+      (1, 0b011) => InstrType::r(RInstr::MULHU, v),
+      (1, 0b100) => InstrType::r(RInstr::DIV, v),
+      (1, 0b101) => InstrType::r(RInstr::DIVU, v),
+      (1, 0b110) => InstrType::r(RInstr::REM, v),
+      (1, 0b111) => InstrType::r(RInstr::REMU, v),
+      (f7, f3) => match decode_zb(f7, f3, r::rs2(instr)) {
+        Some(zb) => InstrType::r(RInstr::Zb(zb), v),
+        None =>
+          return Err(format!("Unexpected funct7 & funct3 for opcode 0b0110011: {}, {}", f7, f3)),
+      },
     },
     0b1110011 => match i::funct3(instr) {
-      0b000 => match i::zx_imm(instr) {
-        0 => InstrType::i(IInstr::ECALL, v),
-        1 => InstrType::i(IInstr::EBREAK, v),
-        v =>
-          return Err(format!("Unexpected immediate for opcode: 0b1110011, funct3: 0b000, {}",v)),
+      // rs1 and rd are reserved and must be 0, other than the address sfence.vma takes in rs1
+      0b000 => match (i::zx_imm(instr), i::rs1(instr), i::rd(instr)) {
+        (imm, _, 0) if imm >> 5 == 0b0001001 => InstrType::i(IInstr::SFENCEVMA, v),
+        (0, 0, 0) => InstrType::i(IInstr::ECALL, v),
+        (1, 0, 0) => InstrType::i(IInstr::EBREAK, v),
+        (0x102, 0, 0) => InstrType::i(IInstr::SRET, v),
+        (0x105, 0, 0) => InstrType::i(IInstr::WFI, v),
+        (0x302, 0, 0) => InstrType::i(IInstr::MRET, v),
+        (imm, rs1, rd) => return Err(format!(
+          "Unexpected immediate, rs1 or rd for opcode: 0b1110011, funct3: 0b000, {}, {}, {}",
+          imm, rs1, rd)),
       },
       0b001 => InstrType::i(IInstr::CSRRW, v),
       0b010 => InstrType::i(IInstr::CSRRS, v),
       0b011 => InstrType::i(IInstr::CSRRC, v),
-      0b101 => InstrType::i(IInstr::CSRRW, v),
-      0b110 => InstrType::i(IInstr::CSRRS, v),
-      0b111 => InstrType::i(IInstr::CSRRC, v),
+      0b101 => InstrType::i(IInstr::CSRRWI, v),
+      0b110 => InstrType::i(IInstr::CSRRSI, v),
+      0b111 => InstrType::i(IInstr::CSRRCI, v),
       v => return Err(format!("Unexpected funct3 for opcode: 0b1110011, funct3: {}", v)),
     },
+    0b1010111 => return decode_v(v),
     v => return Err(format!("Unexpected Opcode {:b} for instr {:b}", v, instr)),
   };
   Ok(instr)
 }
 
+// Vector loads and stores in the LOAD-FP and STORE-FP opcodes, where funct3 is the element width.
+// Only unit-stride and strided accesses without segments exist here.
+fn decode_vmem(v: u32) -> Result<InstrType, String> {
+  use VInstr::*;
+  let eew = match i::funct3(v) { 0b000 => 1, 0b101 => 2, 0b110 => 4, _ => 8 };
+  // nf and mew are above mop, and unit-stride accesses keep lumop or sumop in rs2
+  let (nf_mew, mop, lumop) = (v >> 28, (v >> 26) & 0b11, r::rs2(v));
+  let var = match (opcode(v), mop) {
+    (0b0000111, 0b00) if lumop == 0 => VLE,
+    (0b0000111, 0b10) => VLSE,
+    (0b0100111, 0b00) if lumop == 0 => VSE,
+    (0b0100111, 0b10) => VSSE,
+    (op, mop) => return Err(format!("Unexpected mop for vector opcode {:07b}: {}, {}", op, mop, lumop)),
+  };
+  if nf_mew != 0 { return Err(format!("Unexpected nf or mew for {:?}: {}", var, nf_mew)) };
+  Ok(InstrType::v(var, VSrc::VX, eew, v))
+}
+
+// The OP-V opcode, where funct3 picks the operands and funct6 the operation
+fn decode_v(v: u32) -> Result<InstrType, String> {
+  use VInstr::*;
+  use VSrc::*;
+  let (funct6, funct3, vm) = (v >> 26, r::funct3(v), (v >> 25) & 1 == 1);
+  let (vs1, vs2) = (r::rs1(v), r::rs2(v));
+  let src = match funct3 {
+    0b000 | 0b010 => VV,
+    0b100 | 0b110 => VX,
+    0b011 => VI,
+    0b111 => return decode_vset(v),
+    _ => return Err(format!("Unexpected funct3 for opcode 0b1010111: {}", funct3)),
+  };
+  let var = match (funct3, funct6) {
+    // OPIVV, OPIVX and OPIVI
+    (0b000 | 0b100 | 0b011, _) => match (funct6, src) {
+      (0b000000, _) => VADD,
+      (0b000010, VV | VX) => VSUB,
+      (0b000011, VX | VI) => VRSUB,
+      (0b000100, VV | VX) => VMINU,
+      (0b000101, VV | VX) => VMIN,
+      (0b000110, VV | VX) => VMAXU,
+      (0b000111, VV | VX) => VMAX,
+      (0b001001, _) => VAND,
+      (0b001010, _) => VOR,
+      (0b001011, _) => VXOR,
+      // the mask picks between vs2 and the other operand, vmv.v.* has no vs2
+      (0b010111, _) if !vm => VMERGE,
+      (0b010111, _) if vs2 == 0 => VMV,
+      (0b011000, _) => VMSEQ,
+      (0b011001, _) => VMSNE,
+      (0b011010, VV | VX) => VMSLTU,
+      (0b011011, VV | VX) => VMSLT,
+      (0b011100, _) => VMSLEU,
+      (0b011101, _) => VMSLE,
+      (0b011110, VX | VI) => VMSGTU,
+      (0b011111, VX | VI) => VMSGT,
+      (0b100101, _) => VSLL,
+      (0b101000, _) => VSRL,
+      (0b101001, _) => VSRA,
+      _ => return Err(format!("Unexpected funct6 for opcode 0b1010111, funct3 {}: {:#08b}", funct3, funct6)),
+    },
+    // OPMVV
+    (0b010, 0b000000..=0b000111) =>
+      [VREDSUM, VREDAND, VREDOR, VREDXOR, VREDMINU, VREDMIN, VREDMAXU, VREDMAX][funct6 as usize],
+    (0b010, 0b010000) if vs1 == 0 && vm => VMVXS,
+    (0b010, 0b010000) if vs1 == 0b10000 => VCPOP,
+    (0b010, 0b010000) if vs1 == 0b10001 => VFIRST,
+    (0b010, 0b011000..=0b011111) if vm =>
+      [VMANDN, VMAND, VMOR, VMXOR, VMORN, VMNAND, VMNOR, VMXNOR][funct6 as usize - 0b011000],
+    // OPMVX
+    (0b110, 0b010000) if vs2 == 0 && vm => VMVSX,
+    (_, 0b100100) => VMULHU,
+    (_, 0b100101) => VMUL,
+    (_, 0b100110) => VMULHSU,
+    (_, 0b100111) => VMULH,
+    _ => return Err(format!("Unexpected funct6 for opcode 0b1010111, funct3 {}: {:#08b}", funct3, funct6)),
+  };
+  Ok(InstrType::v(var, src, 0, v))
+}
+
+// vsetvli and vsetivli keep the vtype they ask for in the top bits, vsetivli taking the AVL as
+// an immediate in rs1 and vsetvl taking the vtype from rs2
+fn decode_vset(v: u32) -> Result<InstrType, String> {
+  let (var, src, vtype) = match v >> 30 {
+    0b00 | 0b01 => (VInstr::VSETVLI, VSrc::VX, (v >> 20) & 0x7ff),
+    0b11 => (VInstr::VSETIVLI, VSrc::VI, (v >> 20) & 0x3ff),
+    _ if r::funct7(v) == 0b1000000 => (VInstr::VSETVL, VSrc::VX, 0),
+    _ => return Err(format!("Unexpected funct7 for vsetvl: {:#09b}", r::funct7(v))),
+  };
+  // vm is part of the vtype
+  Ok(InstrType::V{ var, vd: r::rd(v), rs1: r::rs1(v), vs2: r::rs2(v), src, vm: true, imm: vtype })
+}
+
+// Bit manipulation between registers, in the OP opcode
+fn decode_zb(funct7: u32, funct3: u32, rs2: u32) -> Option<ZbInstr> {
+  use ZbInstr::*;
+  Some(match (funct7, funct3) {
+    (0b0010000, 0b010) => SH1ADD,
+    (0b0010000, 0b100) => SH2ADD,
+    (0b0010000, 0b110) => SH3ADD,
+    (0b0100000, 0b111) => ANDN,
+    (0b0100000, 0b110) => ORN,
+    (0b0100000, 0b100) => XNOR,
+    (0b0000101, 0b100) => MIN,
+    (0b0000101, 0b101) => MINU,
+    (0b0000101, 0b110) => MAX,
+    (0b0000101, 0b111) => MAXU,
+    (0b0000101, 0b001) => CLMUL,
+    (0b0000101, 0b011) => CLMULH,
+    (0b0000101, 0b010) => CLMULR,
+    (0b0110000, 0b001) => ROL,
+    (0b0110000, 0b101) => ROR,
+    (0b0010100, 0b001) => BSET,
+    (0b0100100, 0b001) => BCLR,
+    (0b0110100, 0b001) => BINV,
+    (0b0100100, 0b101) => BEXT,
+    // the RV32 encoding, RV64 has its own in OP-32
+    (0b0000100, 0b100) if rs2 == 0 => ZEXTH,
+    _ => return None,
+  })
+}
+
+// Bit manipulation with an immediate in OP-IMM, where the shift amount or bit index takes the
+// low 6 bits of imm and the rest says which instruction it is
+fn decode_zb_imm(v: u32, funct3: u32) -> Result<InstrType, String> {
+  use ZbInstr::*;
+  let (funct6, shamt) = (r::funct7(v) >> 1, i::shamt(v));
+  let var = match (funct3, funct6) {
+    (0b001, 0b011000) if shamt == 0 => CLZ,
+    (0b001, 0b011000) if shamt == 1 => CTZ,
+    (0b001, 0b011000) if shamt == 2 => CPOP,
+    (0b001, 0b011000) if shamt == 4 => SEXTB,
+    (0b001, 0b011000) if shamt == 5 => SEXTH,
+    (0b001, 0b001010) => BSETI,
+    (0b001, 0b010010) => BCLRI,
+    (0b001, 0b011010) => BINVI,
+    (0b101, 0b010010) => BEXTI,
+    (0b101, 0b011000) => RORI,
+    (0b101, 0b001010) if shamt == 7 => ORCB,
+    (0b101, 0b011010) if shamt == 24 || shamt == 56 => REV8,
+    _ => return Err(format!("Unexpected funct3 & imm for opcode 0b0010011: {}, {:#x}", funct3,
+      i::zx_imm(v))),
+  };
+  Ok(InstrType::shift(RInstr::Zb(var), v))
+}
+
+#[test]
+fn test_decode_m_extension() {
+  match decode(0x02b50633).unwrap() {
+    InstrType::R{ var: RInstr::MUL, rs1: 10, rs2: 11, rd: 12 } => (),
+    v => panic!("Decoded mul a2, a0, a1 as {:?}", v),
+  };
+  match decode(0x020578b3).unwrap() {
+    InstrType::R{ var: RInstr::REMU, rs1: 10, rs2: 0, rd: 17 } => (),
+    v => panic!("Decoded remu a7, a0, zero as {:?}", v),
+  };
+}
+
+#[test]
+fn test_decode_rv64() {
+  match decode(0x03f51513).unwrap() {
+    InstrType::R{ var: RInstr::SLLI, rs1: 10, rs2: 63, rd: 10 } => (),
+    v => panic!("Decoded slli a0, a0, 63 as {:?}", v),
+  };
+  match decode(0x40b5053b).unwrap() {
+    InstrType::R{ var: RInstr::SUBW, rs1: 10, rs2: 11, rd: 10 } => (),
+    v => panic!("Decoded subw a0, a0, a1 as {:?}", v),
+  };
+  assert!(decode_xlen(0x00813503, 32).is_err()); // ld a0, 8(sp)
+  assert!(decode_xlen(0x03f51513, 32).is_err());
+  assert!(decode_xlen(0x01f51513, 32).is_ok()); // slli a0, a0, 31
+  assert!(decode(0x04051513).is_err()); // funct7 of 2 is not a shift
+}
+
+#[test]
+fn test_decode_a_extension() {
+  match decode(0x100422af).unwrap() {
+    InstrType::A{ var: AInstr::LR, rs1: 8, rd: 5, size: Size::WORD, aq: false, rl: false, .. } => (),
+    v => panic!("Decoded lr.w t0, (s0) as {:?}", v),
+  };
+  match decode(0x26b6252f).unwrap() {
+    InstrType::A{ var: AInstr::AMOXOR, rs1: 12, rs2: 11, rd: 10, aq: true, rl: true, .. } => (),
+    v => panic!("Decoded amoxor.w.aqrl a0, a1, (a2) as {:?}", v),
+  };
+  assert!(decode_xlen(0x00b6352f, 32).is_err()); // amoadd.d a0, a1, (a2)
+  assert!(decode(0x10b422af).is_err()); // lr.w with an rs2
+  // the fences compilers put around seq_cst atomics
+  assert_eq!(decode(0x0ff0000f), Ok(InstrType::i(IInstr::FENCE, 0x0ff0000f)));
+  assert_eq!(decode(0x0000100f), Ok(InstrType::i(IInstr::FENCEI, 0x0000100f)));
+  assert!(decode(0x0000200f).is_err());
+}
+
+#[test]
+fn test_decode_zb() {
+  match decode(0x61f5d513).unwrap() {
+    InstrType::R{ var: RInstr::Zb(ZbInstr::RORI), rs1: 11, rs2: 31, rd: 10 } => (),
+    v => panic!("Decoded rori a0, a1, 31 as {:?}", v),
+  };
+  assert_eq!(decode(0x60459513).unwrap().sources(), [Some(11), None]); // sext.b a0, a1
+  // RV64 only: rev8, slli.uw, bseti by 63 and cpopw
+  for &raw in [0x6b85d513, 0x0a85951b, 0x2bf59513, 0x6025951b].iter() {
+    assert!(decode_xlen(raw, 32).is_err(), "{:#010x}", raw);
+    assert!(decode_xlen(raw, 64).is_ok(), "{:#010x}", raw);
+  }
+  assert!(decode_xlen(0x6985d513, 32).is_ok()); // rev8 a0, a1
+  // zext.h a0, a1 in OP and then OP-32
+  assert!(decode_xlen(0x0805c533, 32).is_ok());
+  assert!(decode_xlen(0x0805c533, 64).is_err());
+  assert!(decode_xlen(0x0805c53b, 32).is_err());
+  assert!(decode_xlen(0x0805c53b, 64).is_ok());
+  assert!(decode(0x60359513).is_err()); // clz with an rs2 of 3
+}
+
+#[test]
+fn test_decode_v_extension() {
+  match decode(0x0d12f357).unwrap() {
+    i @ InstrType::V{ var: VInstr::VSETVLI, vd: 6, rs1: 5, imm: 0xd1, .. } => {
+      assert_eq!((i.dest(), i.sources()), (Some(6), [Some(5), None]));
+    },
+    v => panic!("Decoded vsetvli t1, t0, e32, m2, ta, ma as {:?}", v),
+  };
+  match decode(0x0bc5e627).unwrap() {
+    i @ InstrType::V{ var: VInstr::VSSE, vd: 12, rs1: 11, vs2: 28, imm: 4, vm: true, .. } => {
+      assert_eq!((i.dest(), i.sources()), (None, [Some(11), Some(28)]));
+    },
+    v => panic!("Decoded vsse32.v v12, (a1), t3 as {:?}", v),
+  };
+  match decode(0x00840857).unwrap() {
+    InstrType::V{ var: VInstr::VADD, src: VSrc::VV, vd: 16, rs1: 8, vs2: 8, vm: false, .. } => (),
+    v => panic!("Decoded vadd.vv v16, v8, v8, v0.t as {:?}", v),
+  };
+  assert_eq!(decode(0x6e83c057).unwrap().sources(), [Some(7), None]); // vmslt.vx v0, v8, t2
+  assert_eq!(decode(0x43002657).unwrap().dest(), Some(12)); // vmv.x.s a2, v16
+  // vmsbf.m, vle8ff.v, vlseg2e8.v, vluxei8.v and vfadd.vv are not supported
+  for &raw in [0x5220a0d7, 0x03050407, 0x22050407, 0x07050407, 0x030c1457].iter() {
+    assert!(decode(raw).is_err(), "{:#010x}", raw);
+  }
+}
+
+#[test]
+fn test_decode_f_extension() {
+  match decode(0x78f070c3).unwrap() {
+    InstrType::F{ var: FInstr::FMADD, rd: 1, rs1: 0, rs2: 15, rs3: 15, double: false, rm: 7, .. } => (),
+    v => panic!("Decoded fmadd.s ft1, ft0, fa5, fa5 as {:?}", v),
+  };
+  match decode(0xfe853827).unwrap() {
+    InstrType::F{ var: FInstr::FS, rs1: 10, rs2: 8, imm: -16, double: true, .. } => (),
+    v => panic!("Decoded fsd fs0, -16(a0) as {:?}", v),
+  };
+  match decode(0xc0117953).unwrap() {
+    i @ InstrType::F{ var: FInstr::FCVTWUF, rd: 18, rs1: 2, rm: 7, .. } => {
+      assert_eq!((i.dest(), i.fdest(), i.sources()), (Some(18), None, [None, None]));
+    },
+    v => panic!("Decoded fcvt.wu.s s2, ft2 as {:?}", v),
+  };
+  assert_eq!(decode(0x00a00053).unwrap().fdest(), Some(0)); // fadd.s ft0, ft0, fa0, rne
+  assert!(decode(0x00005053).is_err()); // a reserved rounding mode
+  assert!(decode(0x04000053).is_err()); // fadd.h
+  assert!(decode(0x40200053).is_err()); // fcvt.s.s
+  assert!(decode_xlen(0xc2257553, 32).is_err()); // fcvt.l.d a0, fa0
+  assert!(decode_xlen(0xe2050553, 32).is_err()); // fmv.x.d a0, fa0
+}
+
+#[test]
+fn test_decode_csr() {
+  // csrrs a0, cycle, zero and csrrwi zero, mscratch, 5
+  match decode(0xc0002573).unwrap() {
+    InstrType::I{ var: IInstr::CSRRS, rs1: 0, rd: 10, zx_imm: 0xc00, .. } => (),
+    v => panic!("Decoded csrr a0, cycle as {:?}", v),
+  };
+  match decode(0x3402d073).unwrap() {
+    InstrType::I{ var: IInstr::CSRRWI, rs1: 5, rd: 0, zx_imm: 0x340, .. } => (),
+    v => panic!("Decoded csrwi mscratch, 5 as {:?}", v),
+  };
+  match decode(0x12b50073).unwrap() {
+    InstrType::I{ var: IInstr::SFENCEVMA, rs1: 10, rd: 0, .. } => (),
+    v => panic!("Decoded sfence.vma a0, a1 as {:?}", v),
+  };
+  assert_eq!(decode(0x30200073), Ok(InstrType::i(IInstr::MRET, 0x30200073)));
+  // rs1 and rd of the other system instructions are reserved
+  assert!(decode(0x302000f3).is_err()); // mret with rd
+  assert!(decode(0x10208073).is_err()); // sret with rs1
+  assert!(decode(0x10500573).is_err()); // wfi with rd
+  assert!(decode(0x00050073).is_err()); // ecall with rs1
+  assert!(decode(0x120000f3).is_err()); // sfence.vma with rd
+}
 
 const OPCODE_MASK: u32 = 0b1111111;
 fn opcode(v: u32) -> u32 { v & OPCODE_MASK }
@@ -211,11 +836,10 @@ pub(crate) mod r {
 
 pub(crate) mod i {
   pub fn sx_imm(v: u32) -> i32 {
-    use std::mem::transmute;
-    let v = unsafe { transmute::<u32, i32>(v) };
-    v >> 20
+    (v as i32) >> 20
   }
   pub fn zx_imm(v: u32) -> u32 { v >> 20 }
+  pub fn shamt(v: u32) -> u32 { (v >> 20) & 0b111111 }
   pub use crate::instr::r::{rs1, funct3, rd};
 }
 
@@ -225,10 +849,11 @@ pub(crate) mod s {
   pub fn imm(v: u32) -> u32 {
     ((v >> 25) << 5) | rd(v)
   }
+  pub fn sx_imm(v: u32) -> i32 { (((v as i32) >> 25) << 5) | rd(v) as i32 }
 }
 
 pub(crate) mod b {
-  pub use crate::instr::s::{rs2, rs1, funct3};
+  pub use crate::instr::s::{rs2, rs1};
   use crate::instr::s::imm as s_imm;
   const SIGN_MASK: u32 = 0xFFFFF000;
   pub fn imm(v: u32) -> i32 {
@@ -236,15 +861,12 @@ pub(crate) mod b {
     let sign_bit = s >> 11;
     let out = ((s & 0b1) << 11) |
       (((v >> 25) & 0b111111) << 5) |
-      (((v >> 8) & 0b11111) << 1);
-    unsafe {
-      std::mem::transmute::<u32, i32>(
-        if sign_bit == 1 { SIGN_MASK | out } else { out }
-      )
-    }
+      (((v >> 8) & 0b1111) << 1);
+    (if sign_bit == 1 { SIGN_MASK | out } else { out }) as i32
   }
 
   #[test]
+  #[allow(clippy::unusual_byte_groupings)]
   fn b_imm_test() {
     let v: u32 = 0b1111111_00000_00000_000_11111_0000000;
     assert_eq!(imm(v), -2);
@@ -278,14 +900,11 @@ pub(crate) mod j {
   pub fn offset(v: u32) -> i32 {
     let sign_bit = v >> 31;
     let  v = (((v >> 20) & 0b1) << 11) | ((v >> 20) & 0x7fe) | (v & 0xFF000);
-    unsafe {
-      std::mem::transmute::<u32, i32>(
-        if sign_bit == 1 { SIGN_MASK | v } else { v }
-      )
-    }
+    (if sign_bit == 1 { SIGN_MASK | v } else { v }) as i32
   }
 
   #[test]
+  #[allow(clippy::unusual_byte_groupings)]
   fn test_imm() {
     let v = 0b1_1111111111_1_11111111_000000000000;
     assert_eq!(offset(v), -2, "0b{:b}",  offset(v));
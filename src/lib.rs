@@ -3,3 +3,18 @@ pub mod sim;
 pub mod instr;
 pub mod reg;
 pub mod program_state;
+pub mod elf;
+pub mod syscall;
+pub mod csr;
+pub mod trap;
+pub mod atomic;
+pub mod fp;
+pub mod rvc;
+pub mod bitmanip;
+pub mod vector;
+pub mod gdb;
+pub mod disasm;
+pub mod trace;
+pub mod stats;
+pub mod predictor;
+pub mod cache;
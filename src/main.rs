@@ -1,80 +1,341 @@
-use std::io::{BufReader, Read};
-use std::u32;
-use riscv::{mem};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use riscv::{mem, elf, gdb, predictor, rvc};
+use riscv::cache::{Cache, CacheConfig};
+use riscv::disasm::{disassemble, Syntax};
 use riscv::program_state::{ProgramState, Status};
-use riscv::sim::{normal, in_order, out_of_order};
-use riscv::reg::Register;
+use riscv::reg::RegData;
+use riscv::stats::Report;
+use riscv::syscall::LinuxSyscalls;
+use riscv::trace::Tracer;
+use riscv::vector::{VRegister, DEFAULT_VLEN};
+use riscv::sim::{normal, in_order_with, out_of_order_with, InOrderConfig, OutOfOrderConfig,
+  BranchStage, Lockstep, MAX_BRANCHES};
 
 #[derive(Debug, Clone, Copy)]
 enum RunType {
   Normal, Inorder, OutOfOrder
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReportFormat {
+  Table, Json
+}
+
 struct Config {
   run_type: RunType,
   mem_size: usize,
+  // register width, taken from the class of ELF files and 32 for raw binaries when not given
+  xlen: Option<usize>,
+  // bits in each vector register
+  vlen: usize,
   display_regs: bool,
+  // host directory that programs may open files in
+  sandbox: Option<PathBuf>,
+  // port to wait for gdb on instead of running straight away
+  gdb: Option<u16>,
+  // file to log retired instructions to, and whether to include their disassembly
+  trace: Option<(String, bool)>,
+  // run the normal simulator alongside to check every retired instruction
+  check: bool,
+  in_order: InOrderConfig,
+  out_of_order: OutOfOrderConfig,
+  // branch predictor for the pipelined simulators, which fall through without one
+  predictor: Option<String>,
+  // caches to model, checked when the flags are read, and the latency of memory behind them
+  icache: Option<CacheConfig>,
+  dcache: Option<CacheConfig>,
+  l2: Option<CacheConfig>,
+  l3: Option<CacheConfig>,
+  dram_latency: Option<u64>,
+  // how to print the statistics at the end of the run, a table with -v by default
+  report: Option<ReportFormat>,
 }
 
 impl Config {
   fn new() -> Config {
-    Config{ run_type: RunType::Normal, mem_size: 0x10000, display_regs: false }
+    Config{ run_type: RunType::Normal, mem_size: 0x10000, xlen: None, vlen: DEFAULT_VLEN,
+      display_regs: false, sandbox: None, gdb: None, trace: None, check: false,
+      in_order: InOrderConfig::default(), out_of_order: OutOfOrderConfig::default(),
+      predictor: None, icache: None, dcache: None, l2: None, l3: None, dram_latency: None,
+      report: None }
   }
 }
 
 fn main() {
+  let mut args = std::env::args().skip(1).peekable();
+  if args.peek().map(String::as_str) == Some("disasm") {
+    args.next();
+    return disasm_files(args);
+  };
   let mut config = Config::new();
   let mut files: Vec<String> = Vec::new();
-  let args = std::env::args().skip(1).collect::<Vec<_>>();
-  for mut v in 0..args.len() {
-    match args[v].as_str() {
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
       "-m" | "--mem" => {
-        v += 1;
-        config.mem_size = args.get(v)
+        config.mem_size = args.next()
           .expect("Must pass memory after --mem")
           .parse::<usize>()
           .expect("Expected Integer after --mem");
       },
+      "--xlen" => {
+        config.xlen = match args.next().as_deref() {
+          Some("32") => Some(32),
+          Some("64") => Some(64),
+          _ => panic!("Expected 32 or 64 after --xlen"),
+        };
+      },
+      "--vlen" => {
+        config.vlen = args.next()
+          .and_then(|n| n.parse::<usize>().ok())
+          .expect("Expected an integer after --vlen");
+        if let Err(e) = VRegister::new(config.vlen) { panic!("{}", e) };
+      },
+      "--sandbox" => {
+        let dir = args.next().expect("Must pass directory after --sandbox");
+        config.sandbox = Some(PathBuf::from(dir));
+      },
+      "--gdb" => {
+        config.gdb = Some(args.next()
+          .expect("Must pass port after --gdb")
+          .parse::<u16>()
+          .expect("Expected port number after --gdb"));
+      },
+      "--trace" | "--log-commits" => {
+        let file = args.next().unwrap_or_else(|| panic!("Must pass file after {}", arg));
+        config.trace = Some((file, arg == "--trace"));
+      },
       "-io" | "--inorder" => config.run_type = RunType::Inorder,
       "-ooo" | "--outoforder" => config.run_type = RunType::OutOfOrder,
       "--normal" => config.run_type = RunType::Normal,
       "-v" | "--verbose" => config.display_regs = true,
+      "--check" => config.check = true,
+      "--stats" => {
+        config.report = match args.next().as_deref() {
+          Some("table") => Some(ReportFormat::Table),
+          Some("json") => Some(ReportFormat::Json),
+          _ => panic!("Expected table or json after --stats"),
+        };
+      },
+      "--predictor" => {
+        let name = args.next().unwrap_or_default();
+        if predictor::from_name::<u32>(&name).is_none() {
+          panic!("Expected one of {} after --predictor", predictor::NAMES.join(", "));
+        };
+        config.predictor = Some(name);
+      },
+      "--no-forwarding" => config.in_order.forwarding = false,
+      "--branch-stage" => {
+        config.in_order.branch_stage = match args.next().as_deref() {
+          Some("id") => BranchStage::ID,
+          Some("ex") => BranchStage::EX,
+          _ => panic!("Expected id or ex after --branch-stage"),
+        };
+      },
+      "--width" | "--rob" | "--rs" | "--branches" | "--lq" | "--sq" => {
+        let n = args.next()
+          .and_then(|n| n.parse::<usize>().ok())
+          .filter(|&n| n > 0)
+          .unwrap_or_else(|| panic!("Expected a positive integer after {}", arg));
+        match arg.as_str() {
+          "--width" => config.out_of_order.width = n,
+          "--rob" => config.out_of_order.rob_size = n,
+          "--branches" if n > MAX_BRANCHES =>
+            panic!("At most {} branches can be in flight", MAX_BRANCHES),
+          "--branches" => config.out_of_order.branches = n,
+          "--lq" => config.out_of_order.load_queue = n,
+          "--sq" => config.out_of_order.store_queue = n,
+          _ => config.out_of_order.stations = n,
+        };
+      },
+      "--icache" | "--dcache" | "--l2" | "--l3" => {
+        let spec = args.next().unwrap_or_else(|| panic!("Must pass cache settings after {}", arg));
+        let level = match arg.as_str() { "--l2" => 2, "--l3" => 3, _ => 1 };
+        let cache = Some(CacheConfig::parse(level, &spec).and_then(|c| Cache::new(c).map(|_| c))
+          .unwrap_or_else(|e| panic!("{} after {}", e, arg)));
+        match level {
+          2 => config.l2 = cache,
+          3 => config.l3 = cache,
+          _ if arg == "--icache" => config.icache = cache,
+          _ => config.dcache = cache,
+        };
+      },
+      "--dram-latency" => {
+        config.dram_latency = Some(args.next()
+          .and_then(|n| n.parse::<u64>().ok())
+          .filter(|&n| n > 0)
+          .expect("Expected a positive integer after --dram-latency"));
+      },
       flag if flag.starts_with("-") => println!("Unsupported flag: {}", flag),
       f => files.push(f.to_string()),
     }
   }
   println!("{:?}", config.run_type);
+  let mut exit_code = 0;
   for file in files.iter() {
     println!("Running: {:?}", file);
-    run(file.to_string(), &config).expect(&format!("Failed on file {:?}", file));
+    let code = run(file.to_string(), &config)
+      .unwrap_or_else(|_| panic!("Failed on file {:?}", file));
+    if code != 0 { exit_code = code };
   };
+  std::process::exit(exit_code);
 }
 
-fn run(s: String, c: &Config) -> Result<(), ()> {
-  let f = std::fs::File::open(s).expect("Failed to open file");
-  let len = f.metadata().expect("Could not read metadata").len() as usize;
-  assert!(len % 4 == 0, "Input File is not word-aligned");
-  let mut reader = BufReader::new(f);
-  let mut buffer: [u8;4] = [0,0,0,0];
-  let mut memory = mem::Memory::new(c.mem_size);
-  for v in 0..(len/4) {
-    reader.read_exact(&mut buffer).expect("Failed to write to memory");
-    memory.write(
-      v * mem::WORD_SIZE,
-      u32::from_ne_bytes(buffer).to_le(),
-      mem::Size::WORD).expect("Initial write was not in bounds, allocate more memory");
+// Runs a program to completion, returning the code it exited with
+fn run(s: String, c: &Config) -> Result<i32, ()> {
+  let bytes = std::fs::read(s).expect("Failed to open file");
+  let elf = if elf::is_elf(&bytes) {
+    Some(elf::Elf::parse(&bytes).unwrap_or_else(|e| panic!("Invalid ELF file: {}", e)))
+  } else { None };
+  let class = elf.as_ref()
+    .map(|elf| match elf.class { elf::Class::Elf32 => 32, elf::Class::Elf64 => 64 });
+  match c.xlen.or(class).unwrap_or(32) {
+    64 => simulate::<u64>(&bytes, elf.as_ref(), c),
+    _ => simulate::<u32>(&bytes, elf.as_ref(), c),
+  }
+}
+
+fn simulate<T : RegData + 'static>(bytes: &[u8], elf: Option<&elf::Elf>, c: &Config)
+  -> Result<i32, ()> {
+  let (mut ps, heap_start): (ProgramState<T>, _) =
+    match elf { Some(elf) => load_elf(elf, c), None => load_flat(bytes, c) };
+  ps.syscalls = Some(Box::new(LinuxSyscalls::new(heap_start, c.sandbox.clone())));
+  ps.vregs = VRegister::new(c.vlen).unwrap();
+  if let Some(name) = &c.predictor { ps.predictor = predictor::from_name(name).unwrap() };
+  let cache = |c: Option<CacheConfig>| c.map(|c| Cache::new(c).unwrap());
+  ps.caches.icache = cache(c.icache);
+  ps.caches.dcache = cache(c.dcache);
+  ps.caches.lower = [c.l2, c.l3].iter().copied().filter_map(cache).collect();
+  if let Some(latency) = c.dram_latency { ps.caches.dram_latency = latency };
+  if let Some((file, disasm)) = &c.trace {
+    let out: Box<dyn Write> = if file == "-" { Box::new(std::io::stdout()) } else {
+      Box::new(BufWriter::new(File::create(file).expect("Failed to create trace file")))
+    };
+    ps.hooks.push(Box::new(Tracer::new(out, *disasm)));
   };
-  let ps = ProgramState::<u32> {
-    regs: Register::new(32),
-    mem: memory,
-    status: Status::Running,
+  let divergence = if c.check {
+    let (checker, divergence) = Lockstep::new(&ps);
+    ps.hooks.push(Box::new(checker));
+    Some(divergence)
+  } else { None };
+  let output_state = match (c.gdb, c.run_type) {
+    (Some(port), run_type) => {
+      if let RunType::Inorder | RunType::OutOfOrder = run_type {
+        println!("gdb always steps the normal executor");
+      };
+      let listener = TcpListener::bind(("127.0.0.1", port)).expect("Failed to listen for gdb");
+      println!("Waiting for gdb on 127.0.0.1:{}", port);
+      gdb::serve(ps, listener).unwrap_or_else(|e| panic!("{}", e))
+    },
+    (None, RunType::Normal) => normal(ps)?,
+    (None, RunType::Inorder) => in_order_with(ps, c.in_order)?,
+    (None, RunType::OutOfOrder) => out_of_order_with(ps, c.out_of_order)?,
   };
-  let output_state = match c.run_type {
-    RunType::Normal => normal(ps)?,
-    RunType::Inorder => in_order(ps)?,
-    RunType::OutOfOrder => out_of_order(ps)?,
+  if c.display_regs {
+    println!("{}{}", output_state.regs, output_state.fregs);
+    // the vector registers only once something has used them
+    if output_state.stats.mix.vector > 0 { println!("{}", output_state.vregs) };
   };
-  if c.display_regs { println!("{}", output_state.regs) };
-  Ok(())
+  let report = c.report.or(if c.display_regs { Some(ReportFormat::Table) } else { None });
+  match report {
+    Some(ReportFormat::Table) => print!("{}", Report::new(&output_state)),
+    Some(ReportFormat::Json) => println!("{}", Report::new(&output_state).json()),
+    None if c.predictor.is_some() => {
+      let stats = &output_state.stats;
+      println!("Branch prediction accuracy: {:.2}% of {} branches", stats.accuracy(),
+        stats.branches);
+    },
+    None => (),
+  };
+  match output_state.status {
+    Status::Exit(code) => Ok(code),
+    Status::Exception(e) => {
+      println!("Stopped on exception: {:?}", e);
+      Ok(1)
+    },
+    Status::Diverged => {
+      let divergence = divergence.expect("Diverged without a check");
+      println!("{}", divergence.borrow().as_deref().unwrap_or_default());
+      Ok(1)
+    },
+    Status::Running | Status::Done => Ok(0),
+  }
 }
 
+// `riscv disasm <file>`, printing every instruction in the executable segments of ELF files or
+// all of a raw .text dump
+fn disasm_files(mut args: impl Iterator<Item=String>) {
+  let mut syntax = Syntax::default();
+  let mut files: Vec<String> = Vec::new();
+  // compressed instructions mean different things in RV32 and RV64, ELF files say which they are
+  let mut rv64 = false;
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--numeric" => syntax.abi_names = false,
+      "--no-pseudo" => syntax.pseudo = false,
+      "--xlen" => rv64 = match args.next().as_deref() {
+        Some("32") => false,
+        Some("64") => true,
+        _ => panic!("Expected 32 or 64 after --xlen"),
+      },
+      flag if flag.starts_with("-") => println!("Unsupported flag: {}", flag),
+      f => files.push(f.to_string()),
+    }
+  }
+  for file in files.iter() {
+    let bytes = std::fs::read(file).expect("Failed to open file");
+    if !elf::is_elf(&bytes) {
+      if rv64 { disasm_bytes(0u64, &bytes, &[], syntax) }
+      else { disasm_bytes(0u32, &bytes, &[], syntax) };
+      continue;
+    };
+    let elf = elf::Elf::parse(&bytes).unwrap_or_else(|e| panic!("Invalid ELF file: {}", e));
+    for seg in elf.segments.iter().filter(|s| s.executable) {
+      match elf.class {
+        elf::Class::Elf32 => disasm_bytes(seg.vaddr as u32, &seg.data, &elf.symbols, syntax),
+        elf::Class::Elf64 => disasm_bytes(seg.vaddr, &seg.data, &elf.symbols, syntax),
+      };
+    }
+  }
+}
+
+// Walks the bytes an instruction at a time, which is 2 bytes for compressed ones
+fn disasm_bytes<T : RegData>(start: T, bytes: &[u8], symbols: &[elf::Symbol], syntax: Syntax) {
+  let mut i = 0;
+  while let Some(raw) = rvc::read_parcel(start.as_usize() + i, &bytes[i..]) {
+    let addr = start.wrapping_add(&T::from(i as u32));
+    // mapping symbols like $x and assembler locals are not worth a label
+    let label = symbols.iter().find(|s| s.value == addr.as_usize() as u64
+      && !s.name.starts_with('$') && !s.name.starts_with(".L"));
+    if let Some(sym) = label { println!("\n{:08x} <{}>:", addr, sym.name) };
+    let len = rvc::instr_len(raw);
+    let hex = format!("{:0w$x}", raw, w = len * 2);
+    println!("{:8x}:  {:<8}  {}", addr, hex, disassemble(raw, addr, syntax));
+    i += len;
+  }
+}
+
+// Raw .text dumps are copied to address 0 and start executing there, the heap starts right
+// after them
+fn load_flat<T : RegData>(bytes: &[u8], c: &Config) -> (ProgramState<T>, usize) {
+  assert!(bytes.len().is_multiple_of(2), "Input File is not halfword-aligned");
+  let mut memory = mem::Memory::new(c.mem_size);
+  for (v, half) in bytes.chunks_exact(2).enumerate() {
+    memory.write(
+      v * 2,
+      T::from(u16::from_le_bytes([half[0], half[1]]) as u32),
+      mem::Size::HALF).expect("Initial write was not in bounds, allocate more memory");
+  };
+  (ProgramState::new(memory), bytes.len())
+}
+
+// ELF files are placed at their linked addresses, with memory starting at the lowest segment
+// and at least --mem bytes free past the last one for the heap and stack
+fn load_elf<T : RegData>(elf: &elf::Elf, c: &Config) -> (ProgramState<T>, usize) {
+  let memory = elf.memory(c.mem_size).unwrap_or_else(|e| panic!("Failed to load ELF file: {}", e));
+  let mut ps = ProgramState::new(memory);
+  elf.load(&mut ps).unwrap_or_else(|e| panic!("Failed to load ELF file: {}", e));
+  (ps, elf.end_addr() as usize)
+}
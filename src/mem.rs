@@ -1,10 +1,11 @@
 use crate::reg::RegData;
+use crate::rvc;
 use std::collections::VecDeque;
 use std::ops::Range;
-use std::default::Default;
 
 pub const WORD_SIZE: usize = 4;
 #[derive(PartialEq, Eq, Copy, Clone, Debug, Hash)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Size {
   DOUBLE, // 8 bytes
   WORD, // 4 bytes
@@ -12,9 +13,17 @@ pub enum Size {
   BYTE, // 1 byte
 }
 
+impl Size {
+  pub fn bytes(self) -> usize {
+    match self { Size::DOUBLE => 8, Size::WORD => 4, Size::HALF => 2, Size::BYTE => 1, }
+  }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Memory <T : RegData> {
   pub data: Vec<u8>,
+  // address of data[0], so programs linked high up do not need memory below them
+  base: usize,
   size: usize,
   write_queue: VecDeque<(usize, T, Size)>,
 }
@@ -25,51 +34,61 @@ pub struct MemView <'a, T : RegData> {
 }
 
 impl <T : RegData> Memory<T> {
-  pub fn new(size: usize) -> Memory<T> {
-    Memory { data: vec![0; size], size: size, write_queue: VecDeque::new(), }
+  pub fn new(size: usize) -> Memory<T> { Memory::with_base(0, size) }
+  pub fn with_base(base: usize, size: usize) -> Memory<T> {
+    Memory { data: vec![0; size], base, size, write_queue: VecDeque::new(), }
   }
   pub fn write(&mut self, loc: usize, data: T, s: Size) -> Result<(), &str> {
-    if loc > self.size { return Err("Mem write out of bounds") };
+    if !self.in_bounds(loc, s.bytes()) { return Err("Mem write out of bounds") };
     let bytes = data.to_le_bytes();
     match s {
-      Size::BYTE => (0..1),
-      Size::HALF => (0..2),
-      Size::WORD => (0..4),
+      Size::BYTE => 0..1,
+      Size::HALF => 0..2,
+      Size::WORD => 0..4,
       Size::DOUBLE if T::BYTE_SIZE < 8 => return Err("Not sufficient size to write word"),
-      Size::DOUBLE => (0..8)
-    }.for_each(|i| self.data[loc + i] = bytes[i]);
+      Size::DOUBLE => 0..8
+    }.for_each(|i| self.data[loc - self.base + i] = bytes[i]);
     Ok(())
   }
   pub fn read(&self, loc: usize, s: Size) -> Result<T, &str> {
-    if loc > self.size { return Err("mem.read() out of bounds") };
-    let mut bytes : [u8; 4] = Default::default();
-    match s {
-      Size::BYTE => (0..1),
-      Size::HALF => (0..2),
-      Size::WORD => (0..4),
-      Size::DOUBLE if T::BYTE_SIZE < 8 => return Err("Not sufficient size to read double"),
-      Size::DOUBLE => {
-        let mut bytes: [u8; 8] = Default::default();
-        (0..8).for_each(|i| bytes[i] = self.data[loc+1]);
-        return Ok(T::from_le_bytes(Box::new(bytes)));
-      },
-    }.for_each(|i| bytes[i] = self.data[loc+1]);
-    Ok(T::from_le_bytes(Box::new(bytes)))
+    if !self.in_bounds(loc, s.bytes()) { return Err("mem.read() out of bounds") };
+    if s.bytes() > T::BYTE_SIZE { return Err("Not sufficient size to read double") };
+    // zero extended up to the register width
+    let mut bytes = vec![0; T::BYTE_SIZE];
+    let i = loc - self.base;
+    bytes[..s.bytes()].copy_from_slice(&self.data[i..i + s.bytes()]);
+    Ok(T::from_le_bytes(bytes.into_boxed_slice()))
+  }
+  pub fn size(&self) -> usize { self.size }
+  pub fn base(&self) -> usize { self.base }
+  // One past the highest address
+  pub fn end(&self) -> usize { self.base + self.size }
+  pub fn in_bounds(&self, loc: usize, len: usize) -> bool {
+    loc >= self.base && loc - self.base <= self.size && len <= self.size - (loc - self.base)
   }
+  pub fn write_bytes(&mut self, loc: usize, bytes: &[u8]) -> Result<(), &str> {
+    if !self.in_bounds(loc, bytes.len()) { return Err("mem.write_bytes() out of bounds") };
+    let i = loc - self.base;
+    self.data[i..i + bytes.len()].copy_from_slice(bytes);
+    Ok(())
+  }
+  pub fn read_bytes(&self, loc: usize, len: usize) -> Result<&[u8], &str> {
+    if !self.in_bounds(loc, len) { return Err("mem.read_bytes() out of bounds") };
+    let i = loc - self.base;
+    Ok(&self.data[i..i + len])
+  }
+  // Reads 2 bytes for a compressed instruction and 4 for any other
   pub fn read_instr(&self, loc: usize) -> Result<u32, &str> {
-    if loc > self.size { return Err("mem.read_instr() out of bounds") };
-    let mut bytes: [u8; 4] = [0;4];
-    (0..4).for_each(|i| bytes[i] = self.data[loc+i]);
-    Ok(u32::from_le_bytes(bytes))
+    loc.checked_sub(self.base).and_then(|i| self.data.get(i..))
+      .and_then(|bytes| rvc::read_parcel(loc, bytes))
+      .ok_or("mem.read_instr() out of bounds")
   }
   pub fn read_signed(&self, loc: usize, s: Size) -> Result<T::Signed, &str> {
-    if loc > self.size { return Err("mem.read_signed() out of bounds") };
-    use std::{i8, i16, i32};
+    if !self.in_bounds(loc, s.bytes()) { return Err("mem.read_signed() out of bounds") };
+    let loc = loc - self.base;
     let v = match s {
       Size::BYTE => {
-        unsafe {
-          T::Signed::from(std::mem::transmute::<u8, i8>(self.data[loc]) as i32)
-        }
+        T::Signed::from(self.data[loc] as i8 as i32)
       },
       Size::HALF => {
         let mut bytes : [u8; 2] = [0, 0];
@@ -82,20 +101,16 @@ impl <T : RegData> Memory<T> {
         T::Signed::from(i32::from_le_bytes(bytes))
       },
       Size::DOUBLE if T::BYTE_SIZE < 8 => return Err("Not sufficient size to read signed double"),
-      Size::DOUBLE => {
-        let mut bytes : [u8; 8] = Default::default();
-        (0..8).for_each(|i| bytes[i] = self.data[loc+i]);
-        unimplemented!()
-        // TODO create trait for FromLeBytes for signed
-        // T::Signed::from(i64::from_le_bytes(bytes))
-      },
+      // fills the register, so there is nothing to extend
+      Size::DOUBLE => self.read(loc + self.base, s)?.to_signed(),
     };
     Ok(v)
   }
   // queues a write to memory TODO return hit or miss
   // will overwrite things in queue which have same location and memory
+  #[allow(clippy::result_unit_err)]
   pub fn queue_write(&mut self, loc: usize, data: T, s: Size) -> Result<(), ()> {
-    if loc > self.size { return Err(()) };
+    if !self.in_bounds(loc, s.bytes()) { return Err(()) };
     self.write_queue.push_back((loc, data, s));
     Ok(())
   }
@@ -105,11 +120,12 @@ impl <T : RegData> Memory<T> {
       None => Err("No writes remaining"),
     }
   }
-  pub fn view(&self, range: Range<usize>) -> MemView<T> {
-    assert!(range.start % 4 == 0, "View range start must be word aligned");
-    assert!(range.end % 4 == 0, "View range end must be word aligned");
-    assert!(range.end < self.size, "View range cannot pass end of memory");
-    MemView{ range: range, m: &self, }
+  pub fn view(&self, range: Range<usize>) -> MemView<'_, T> {
+    assert!(range.start.is_multiple_of(4), "View range start must be word aligned");
+    assert!(range.end.is_multiple_of(4), "View range end must be word aligned");
+    assert!(range.start >= self.base, "View range cannot start before memory");
+    assert!(range.end < self.end(), "View range cannot pass end of memory");
+    MemView{ range, m: self, }
   }
 }
 
@@ -125,47 +141,97 @@ impl <'a, T : RegData> std::fmt::Display for MemView<'a, T> {
 
 #[test]
 fn test_memory_word() {
-  let mut mem = create_memory(0x8000usize);
+  let mut mem = Memory::<u32>::new(0x8000usize);
   let data = 0x12345678u32;
   mem.write(0usize, data, Size::WORD).expect("Failed to write memory correctly");
-  assert_eq!(mem.read::<u32>(0usize, Size::WORD).unwrap(), data);
+  assert_eq!(mem.read(0usize, Size::WORD).unwrap(), data);
 }
 
 #[test]
 fn test_memory_half() {
-  let mut mem = create_memory(0x8000usize);
+  let mut mem = Memory::<u32>::new(0x8000usize);
   let data = 0x12345678u32;
   mem.write(0usize, data, Size::HALF).expect("Failed to write memory correctly");
-  let read = mem.read::<u32>(0usize, Size::HALF).unwrap();
+  let read = mem.read(0usize, Size::HALF).unwrap();
   assert_eq!(read, data & 0xffff, "read = 0x{:x}, expected = 0x{:x}", read, data);
 }
 
 #[test]
 fn test_memory_byte() {
-  let mut mem = create_memory(0x8000usize);
+  let mut mem = Memory::<u32>::new(0x8000usize);
   let data = 0x12345678u32;
   mem.write(0usize, data, Size::BYTE).expect("Failed to write memory correctly");
-  let read = mem.read::<u32>(0usize, Size::BYTE).unwrap();
+  let read = mem.read(0usize, Size::BYTE).unwrap();
   assert_eq!(read, data & 0xff, "read = 0x{:x}, expected = 0x{:x}", read, data);
 }
 
+#[test]
+fn test_memory_bounds() {
+  let mem = Memory::<u32>::new(0x8usize);
+  assert!(mem.read(4, Size::WORD).is_ok());
+  assert!(mem.read(6, Size::WORD).is_err());
+  assert!(mem.read_signed(7, Size::HALF).is_err());
+  assert!(mem.read_instr(8).is_err());
+}
+
+#[test]
+fn test_memory_base() {
+  let mut mem = Memory::<u32>::with_base(0x8000_0000, 0x8);
+  mem.write(0x8000_0004, 0x12345673, Size::WORD).unwrap();
+  assert_eq!(mem.read(0x8000_0004, Size::WORD).unwrap(), 0x12345673);
+  assert_eq!(mem.read_signed(0x8000_0007, Size::BYTE).unwrap(), 0x12);
+  assert_eq!(mem.read_instr(0x8000_0004), Ok(0x12345673));
+  assert_eq!(mem.end(), 0x8000_0008);
+  assert!(mem.read(0x4, Size::WORD).is_err());
+  assert!(mem.read_instr(0x7fff_fffe).is_err());
+  assert!(mem.write(0x8000_0008, 0, Size::BYTE).is_err());
+}
+
 #[test]
 fn test_signed_byte() {
-  let mut mem = create_memory(0x4usize);
+  let mut mem = Memory::<u32>::new(0x4usize);
   let data = 0xffu32;
   mem.write(0usize, data, Size::BYTE).expect("Failed to write memory correctly");
-  let read = u32::from_signed(mem.read_signed::<u32>(0usize, Size::BYTE).unwrap());
+  let read = u32::from_signed(mem.read_signed(0usize, Size::BYTE).unwrap());
   assert_eq!(read, 0xffffffff);
 }
 
 #[test]
 fn test_signed_half() {
-  let mut mem = create_memory(0x4usize);
+  let mut mem = Memory::<u32>::new(0x4usize);
   let data = 0xffffu32;
   mem.write(0usize, data, Size::HALF).expect("Failed to write memory correctly");
-  let read = u32::from_signed(mem.read_signed::<u32>(0usize, Size::HALF).unwrap());
+  let read = u32::from_signed(mem.read_signed(0usize, Size::HALF).unwrap());
   assert_eq!(read, 0xffffffff);
 }
 
 
 
+#[test]
+fn test_memory_double() {
+  let mut mem = Memory::<u64>::new(0x10usize);
+  mem.write(8, 0x8765432112345678, Size::DOUBLE).expect("Failed to write memory correctly");
+  assert_eq!(mem.read(8, Size::DOUBLE).unwrap(), 0x8765432112345678);
+  assert_eq!(mem.read(12, Size::WORD).unwrap(), 0x87654321);
+  assert_eq!(mem.read_signed(12, Size::WORD).unwrap(), 0x87654321u32 as i32 as i64);
+  assert_eq!(mem.read_signed(8, Size::DOUBLE).unwrap(), 0x8765432112345678u64 as i64);
+  assert!(Memory::<u32>::new(0x10).read(8, Size::DOUBLE).is_err());
+}
+
+#[test]
+fn test_read_instr() {
+  use crate::instr::InstrType;
+  let mut mem = Memory::<u32>::new(0x8usize);
+  // c.li a0, 1, then an addi straddling the word boundary, then c.ret in the last two bytes
+  [0x4505u32, 0x0513, 0x0015, 0x8082].iter().enumerate()
+    .for_each(|(i, &h)| mem.write(i * 2, h, Size::HALF).unwrap());
+  assert_eq!(mem.read_instr(0), Ok(0x4505));
+  assert_eq!(mem.read_instr(2), Ok(0x00150513));
+  assert_eq!(mem.read_instr(6), Ok(0x8082));
+  assert!(mem.read_instr(8).is_err());
+  mem.write(0, InstrType::halt_val(), Size::WORD).unwrap();
+  assert_eq!(mem.read_instr(0), Ok(InstrType::halt_val()));
+  // off a word boundary the same bytes are a pair of c.bnez
+  mem.write(4, InstrType::halt_val(), Size::HALF).unwrap();
+  assert_eq!(mem.read_instr(2), Ok(0xfeed));
+}
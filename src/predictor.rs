@@ -0,0 +1,315 @@
+// Branch predictors for the fetch stages of the pipelined simulators. Fetch decodes enough of
+// the instruction to know the targets of branches and JAL, while JALR needs a BTB or return
+// address stack. Predictors only learn once a jump or branch resolves.
+use std::fmt;
+use crate::instr::{decode_parcel, InstrType, IInstr, JInstr};
+use crate::mem::WORD_SIZE;
+use crate::program_state::ProgramState;
+use crate::reg::RegData;
+use crate::rvc;
+
+// entries in the pattern tables
+const TABLE_BITS: usize = 10;
+// branches kept in the global history
+const HISTORY_BITS: usize = 10;
+const BTB_BITS: usize = 8;
+const RAS_DEPTH: usize = 16;
+
+pub trait BranchPredictor<T : RegData> : fmt::Debug {
+  // Where the jump or branch raw at pc goes, None to fall through
+  fn predict(&self, pc: T, raw: u32) -> Option<T>;
+  // Learns where it went once it resolves
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>);
+}
+
+// Names accepted by --predictor
+pub const NAMES: [&str; 7] = ["not-taken", "btfn", "1-bit", "2-bit", "gshare", "tournament", "btb"];
+
+pub fn from_name<T : RegData + 'static>(name: &str) -> Option<Box<dyn BranchPredictor<T>>> {
+  Some(match name {
+    "not-taken" => Box::new(NotTaken),
+    "btfn" => Box::new(Btfn),
+    "1-bit" => Box::new(OneBit::new()),
+    "2-bit" => Box::new(TwoBit::new()),
+    "gshare" => Box::new(Gshare::new()),
+    "tournament" => Box::new(Tournament::new()),
+    "btb" => Box::new(Btb::new()),
+    _ => return None,
+  })
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Where fetch goes after the instruction at pc
+  pub(crate) fn predict_next(&self, pc: T, raw: u32) -> T {
+    let next = rvc::next_pc(pc, raw);
+    if !is_control(&decoded::<T>(raw)) { return next };
+    match self.predictor.predict(pc, raw) {
+      // misaligned targets trap when the jump resolves, so are not worth fetching from
+      Some(target) if target.as_usize().is_multiple_of(rvc::IALIGN) => target,
+      _ => next,
+    }
+  }
+  // Trains the predictor on a jump or branch that resolved, returning whether fetch went
+  // somewhere else
+  pub(crate) fn resolved(&mut self, pc: T, raw: u32, target: Option<T>, predicted: T) -> bool {
+    self.predictor.update(pc, raw, target);
+    let next = target.unwrap_or_else(|| rvc::next_pc(pc, raw));
+    self.stats.branches += 1;
+    if next != predicted { self.stats.mispredicts += 1 };
+    next != predicted
+  }
+}
+
+fn is_control(instr: &InstrType) -> bool {
+  matches!(instr, InstrType::B{ .. } | InstrType::J{ .. } | InstrType::I{ var: IInstr::JALR, .. })
+}
+
+// Target of a branch or JAL, which is encoded in the instruction
+fn direct_target<T : RegData>(pc: T, instr: &InstrType) -> Option<T> {
+  match *instr {
+    InstrType::B{ imm, .. } => Some(pc.offset(T::Signed::from(imm))),
+    InstrType::J{ var: JInstr::JAL, offset, .. } => Some(pc.offset(T::Signed::from(offset))),
+    _ => None,
+  }
+}
+
+// Follows a prediction of whether a branch is taken, JAL is always taken and JALR never is
+fn direction<T : RegData>(pc: T, instr: &InstrType, taken: impl FnOnce() -> bool) -> Option<T> {
+  match instr {
+    InstrType::B{ .. } => if taken() { direct_target(pc, instr) } else { None },
+    _ => direct_target(pc, instr),
+  }
+}
+
+// Only jumps and branches are given to predictors, so anything else is never seen here
+fn decoded<T : RegData>(raw: u32) -> InstrType {
+  decode_parcel(raw, T::BYTE_SIZE * 8).unwrap_or(InstrType::Halt)
+}
+
+fn index<T : RegData>(pc: T, bits: usize) -> usize { (pc.as_usize() / WORD_SIZE) & ((1 << bits) - 1) }
+
+// 2-bit saturating counters, predicting taken from 2 up
+fn train(counter: &mut u8, taken: bool) {
+  *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+}
+
+// Falls through every time, which is what fetch did before there were predictors
+#[derive(Debug)]
+pub struct NotTaken;
+
+impl <T : RegData> BranchPredictor<T> for NotTaken {
+  fn predict(&self, _: T, _: u32) -> Option<T> { None }
+  fn update(&mut self, _: T, _: u32, _: Option<T>) {}
+}
+
+// Backward branches taken and forward ones not, as loops usually go round again
+#[derive(Debug)]
+pub struct Btfn;
+
+impl <T : RegData> BranchPredictor<T> for Btfn {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    let instr = decoded::<T>(raw);
+    direction(pc, &instr, || matches!(instr, InstrType::B{ imm, .. } if imm < 0))
+  }
+  fn update(&mut self, _: T, _: u32, _: Option<T>) {}
+}
+
+// Whether each branch was taken last time
+#[derive(Debug)]
+pub struct OneBit {
+  taken: Vec<bool>,
+}
+
+impl OneBit {
+  pub fn new() -> Self { OneBit{ taken: vec![false; 1 << TABLE_BITS] } }
+}
+
+impl Default for OneBit {
+  fn default() -> Self { Self::new() }
+}
+
+impl <T : RegData> BranchPredictor<T> for OneBit {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    direction(pc, &decoded::<T>(raw), || self.taken[index(pc, TABLE_BITS)])
+  }
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>) {
+    if let InstrType::B{ .. } = decoded::<T>(raw) { self.taken[index(pc, TABLE_BITS)] = target.is_some() };
+  }
+}
+
+// A saturating counter per branch, so one odd outcome does not change the prediction
+#[derive(Debug)]
+pub struct TwoBit {
+  counters: Vec<u8>,
+}
+
+impl TwoBit {
+  // counters start weakly not taken
+  pub fn new() -> Self { TwoBit{ counters: vec![1; 1 << TABLE_BITS] } }
+}
+
+impl Default for TwoBit {
+  fn default() -> Self { Self::new() }
+}
+
+impl <T : RegData> BranchPredictor<T> for TwoBit {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    direction(pc, &decoded::<T>(raw), || self.counters[index(pc, TABLE_BITS)] >= 2)
+  }
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>) {
+    if let InstrType::B{ .. } = decoded::<T>(raw) {
+      train(&mut self.counters[index(pc, TABLE_BITS)], target.is_some())
+    };
+  }
+}
+
+// Counters indexed by the pc xored with the outcomes of the last branches
+#[derive(Debug)]
+pub struct Gshare {
+  counters: Vec<u8>,
+  history: usize,
+}
+
+impl Gshare {
+  pub fn new() -> Self { Gshare{ counters: vec![1; 1 << TABLE_BITS], history: 0 } }
+  fn slot<T : RegData>(&self, pc: T) -> usize { index(pc, TABLE_BITS) ^ self.history }
+}
+
+impl Default for Gshare {
+  fn default() -> Self { Self::new() }
+}
+
+impl <T : RegData> BranchPredictor<T> for Gshare {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    direction(pc, &decoded::<T>(raw), || self.counters[self.slot(pc)] >= 2)
+  }
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>) {
+    if let InstrType::B{ .. } = decoded::<T>(raw) {
+      let slot = self.slot(pc);
+      train(&mut self.counters[slot], target.is_some());
+      self.history = ((self.history << 1) | target.is_some() as usize) & ((1 << HISTORY_BITS) - 1);
+    };
+  }
+}
+
+// Picks between per-branch counters and gshare, by which has been right more for the branch
+#[derive(Debug)]
+pub struct Tournament {
+  local: TwoBit,
+  global: Gshare,
+  // from 2 up gshare is used
+  choosers: Vec<u8>,
+}
+
+impl Tournament {
+  pub fn new() -> Self {
+    Tournament{ local: TwoBit::new(), global: Gshare::new(), choosers: vec![1; 1 << TABLE_BITS] }
+  }
+}
+
+impl Default for Tournament {
+  fn default() -> Self { Self::new() }
+}
+
+impl <T : RegData> BranchPredictor<T> for Tournament {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    if self.choosers[index(pc, TABLE_BITS)] >= 2 { self.global.predict(pc, raw) }
+    else { self.local.predict(pc, raw) }
+  }
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>) {
+    let local = self.local.predict(pc, raw) == target;
+    let global = self.global.predict(pc, raw) == target;
+    if local != global { train(&mut self.choosers[index(pc, TABLE_BITS)], global) };
+    self.local.update(pc, raw, target);
+    self.global.update(pc, raw, target);
+  }
+}
+
+// Remembers where jumps and taken branches went, with a counter for whether branches are taken
+// again, and predicts returns from a stack of the calls that have not returned yet
+#[derive(Debug)]
+pub struct Btb<T : RegData> {
+  // pc, target and counter of the branch last seen in each slot
+  entries: Vec<Option<(T, T, u8)>>,
+  returns: Vec<T>,
+}
+
+impl <T : RegData> Btb<T> {
+  pub fn new() -> Self { Btb{ entries: vec![None; 1 << BTB_BITS], returns: Vec::new() } }
+}
+
+impl <T : RegData> Default for Btb<T> {
+  fn default() -> Self { Self::new() }
+}
+
+// ra and t0 are the link registers of the calling convention
+fn is_link(r: u32) -> bool { r == 1 || r == 5 }
+
+fn is_return(instr: &InstrType) -> bool {
+  matches!(*instr, InstrType::I{ var: IInstr::JALR, rd: 0, rs1, .. } if is_link(rs1))
+}
+
+impl <T : RegData> BranchPredictor<T> for Btb<T> {
+  fn predict(&self, pc: T, raw: u32) -> Option<T> {
+    let instr = &decoded::<T>(raw);
+    if is_return(instr) {
+      if let Some(&ret) = self.returns.last() { return Some(ret) };
+    };
+    match self.entries[index(pc, BTB_BITS)] {
+      Some((tag, target, counter)) if tag == pc
+        && (counter >= 2 || !matches!(instr, InstrType::B{ .. })) => Some(target),
+      _ => None,
+    }
+  }
+  fn update(&mut self, pc: T, raw: u32, target: Option<T>) {
+    let instr = &decoded::<T>(raw);
+    match *instr {
+      _ if is_return(instr) => { self.returns.pop(); },
+      InstrType::J{ rd, .. } | InstrType::I{ rd, .. } if is_link(rd) => {
+        if self.returns.len() == RAS_DEPTH { self.returns.remove(0); };
+        self.returns.push(rvc::next_pc(pc, raw));
+      },
+      _ => (),
+    };
+    let entry = &mut self.entries[index(pc, BTB_BITS)];
+    match (entry.as_mut(), target) {
+      (Some((tag, to, counter)), _) if *tag == pc => {
+        train(counter, target.is_some());
+        if let Some(target) = target { *to = target };
+      },
+      // only taken branches are worth a slot
+      (_, Some(target)) => *entry = Some((pc, target, 2)),
+      (_, None) => (),
+    };
+  }
+}
+
+#[test]
+fn test_predictors() {
+  // counts how often each predictor gets a sequence of outcomes wrong
+  let mispredicts = |name: &str, outcomes: &[(u32, u32, Option<u32>)]| {
+    let mut p = from_name::<u32>(name).unwrap();
+    outcomes.iter().filter(|&&(pc, raw, target)| {
+      let wrong = p.predict(pc, raw) != target;
+      p.update(pc, raw, target);
+      wrong
+    }).count()
+  };
+  // bne t0, t1, -32 closing a loop that goes round 8 times, run 10 times
+  let loops: Vec<_> = (0..10)
+    .flat_map(|_| (0..8).map(|i| (0x40, 0xfe6290e3, (i < 7).then_some(0x20))))
+    .collect();
+  let counts: Vec<_> = NAMES.iter().map(|name| mispredicts(name, &loops)).collect();
+  // gshare takes longer to learn the exit, having to see it under each history, and the
+  // tournament gets it from gshare once it has while using the counters until then
+  assert_eq!(counts, [70, 10, 20, 11, 15, 5, 11]);
+
+  // two calls to the same function, twice over
+  let (call, ret) = ((0x10, 0x0f0000ef, Some(0x100)), (0x108, 0x00008067, Some(0x14)));
+  let (call2, ret2) = ((0x30, 0x0d0000ef, Some(0x100)), (0x108, 0x00008067, Some(0x34)));
+  let calls = [call, ret, call2, ret2, call, ret, call2, ret2];
+  // the stack gets every return right, and the BTB only misses each call the first time
+  assert_eq!(mispredicts("btb", &calls), 2);
+  // other predictors know where calls go, but not returns
+  assert_eq!(mispredicts("2-bit", &calls), 4);
+}
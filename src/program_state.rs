@@ -1,30 +1,115 @@
 use crate::mem;
 use crate::reg::{Register, RegData};
+use crate::syscall::SyscallHandler;
+use crate::csr::Csrs;
+use crate::trace::RetireHook;
+use crate::stats::Stats;
+use crate::predictor::{BranchPredictor, NotTaken};
+use crate::cache::Hierarchy;
+use crate::fp::FRegister;
+use crate::vector::VRegister;
 
+// Synchronous exceptions, numbered by their mcause code
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub enum Exceptions {
-  Mem,
+  InstrMisaligned = 0,
+  InstrAccessFault = 1,
+  IllegalInstruction = 2,
+  Breakpoint = 3,
+  LoadMisaligned = 4,
+  LoadAccessFault = 5,
+  StoreMisaligned = 6,
+  StoreAccessFault = 7,
+  EcallU = 8,
+  EcallS = 9,
+  EcallM = 11,
+}
+
+impl Exceptions {
+  pub fn cause(self) -> u32 { self as u32 }
+}
+
+// Privilege levels, numbered as in mstatus.MPP and commit logs
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum Privilege {
+  User = 0,
+  Supervisor = 1,
+  Machine = 3,
+}
+
+impl Privilege {
+  // The level a 2 bit field such as MPP asks for, the reserved 2 meaning U
+  pub fn from_bits(bits: u32) -> Privilege {
+    match bits & 0b11 {
+      3 => Privilege::Machine,
+      1 => Privilege::Supervisor,
+      _ => Privilege::User,
+    }
+  }
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Status {
   Running,
   Done,
+  // the program called exit with the given code
+  Exit(i32),
   Exception(Exceptions),
+  // stopped by a lockstep check after disagreeing with the reference
+  Diverged,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct ProgramState<T : RegData> {
   pub regs: Register<T>,
+  pub fregs: FRegister,
+  pub vregs: VRegister,
   pub mem: mem::Memory<T>,
   pub status: Status,
+  pub syscalls: Option<Box<dyn SyscallHandler<T>>>,
+  pub csrs: Csrs<T>,
+  // the level the hart runs at, programs start in machine mode
+  pub privilege: Privilege,
+  // watch every retired instruction, e.g. to log them
+  pub hooks: Vec<Box<dyn RetireHook<T>>>,
+  pub stats: Stats,
+  // where the pipelined simulators fetch from after jumps and branches
+  pub predictor: Box<dyn BranchPredictor<T>>,
+  // caches that fetches, loads and stores are looked up in, if they are modelled
+  pub caches: Hierarchy,
+  // the bytes reserved by the last LR, until an SC or an overlapping store
+  pub reservation: Option<(usize, mem::Size)>,
 }
 
 
 impl <T : RegData> ProgramState<T> {
+  pub fn new(mem: mem::Memory<T>) -> Self {
+    ProgramState {
+      regs: Register::new(32), fregs: FRegister::default(),
+      vregs: VRegister::default(), mem, status: Status::Running, syscalls: None, csrs: Csrs::new(),
+      privilege: Privilege::Machine, hooks: Vec::new(), stats: Stats::default(),
+      predictor: Box::new(NotTaken), caches: Hierarchy::default(), reservation: None,
+    }
+  }
   // Sign Extend
   pub fn sx(&self, reg: T) -> T::Signed { reg.to_signed() }
   // Zero Extend
   pub fn zx(&self, reg: T) -> T { reg }
+
+  // Reads memory for a load, which must be naturally aligned
+  pub fn load(&self, addr: T, sz: mem::Size, signed: bool) -> Result<T, Exceptions> {
+    let loc = addr.as_usize();
+    if !loc.is_multiple_of(sz.bytes()) { return Err(Exceptions::LoadMisaligned) };
+    let v = if signed { self.mem.read_signed(loc, sz).map(T::from_signed) }
+            else { self.mem.read(loc, sz) };
+    v.map_err(|_| Exceptions::LoadAccessFault)
+  }
+  // Checks that a store can be performed, returning where it goes in memory
+  pub fn store_addr(&self, addr: T, sz: mem::Size) -> Result<usize, Exceptions> {
+    let loc = addr.as_usize();
+    if !loc.is_multiple_of(sz.bytes()) { return Err(Exceptions::StoreMisaligned) };
+    if !self.mem.in_bounds(loc, sz.bytes()) { return Err(Exceptions::StoreAccessFault) };
+    Ok(loc)
+  }
 }
 
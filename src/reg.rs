@@ -1,24 +1,35 @@
 extern crate num;
-use std::ops::{Shr, Shl, BitAnd, BitOr, BitXor, Index};
-use std::mem::transmute;
-use std::collections::VecDeque;
+use std::ops::{Shr, Shl, BitAnd, BitOr, BitXor, Not, Index};
 use std::hash::Hash;
 use std::fmt::{Display, Debug, LowerHex};
+use num::traits::{WrappingAdd, WrappingSub};
 
-// TODO
+// Register names in the standard calling convention
+pub const ABI_NAMES: [&str; 32] = [
+  "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+  "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
 
 pub trait RegData: num::Unsigned + Clone + Copy + From<u32> + From<u8> + Ord + Shl<Output=Self>
-  + Shr<Output=Self> + BitAnd<Output=Self> + BitOr<Output=Self> + BitXor<Output=Self>
-  + LowerHex + Debug + Display + Hash {
+  + Shr<Output=Self> + BitAnd<Output=Self> + BitOr<Output=Self> + BitXor<Output=Self> + Not<Output=Self>
+  + WrappingAdd + WrappingSub + LowerHex + Debug + Display + Hash {
 
   // Corresponding signed type
-  type Signed: num::Signed + From<i32> + Ord + Shl<Output=Self::Signed>
+  type Signed: num::Signed + Copy + From<i32> + Ord + Shl<Output=Self::Signed>
     + Shr<Output=Self::Signed> + BitAnd<Output=Self::Signed> + Display;
 
   fn to_signed(self) -> Self::Signed;
   fn from_signed(v: Self::Signed) -> Self;
   fn offset(&self, offset: Self::Signed) -> Self;
   fn as_usize(&self) -> usize;
+  // Truncates to the register width
+  fn from_u64(v: u64) -> Self;
+  // Only the low log2(XLEN) bits of a register are used as a shift amount
+  fn shamt(self) -> Self { self & Self::from((Self::BYTE_SIZE * 8 - 1) as u32) }
+  // RV64 *W instructions shift by the low 5 bits, and sign extend their 32 bit result
+  fn shamt_w(self) -> Self { self & Self::from(31u32) }
+  fn sext_w(self) -> Self;
+  fn zext_w(self) -> Self;
 
   const BYTE_SIZE: usize = std::mem::size_of::<Self>();
   // Byte Representation of Data
@@ -27,18 +38,32 @@ pub trait RegData: num::Unsigned + Clone + Copy + From<u32> + From<u8> + Ord + S
   // fn to_le_bytes(&self) -> [u8; Self::BYTE_SIZE];
   fn to_le_bytes(&self) -> Box<[u8]>;
   fn from_le_bytes(bytes: Box<[u8]>) -> Self;
+
+  // M-extension arithmetic, division by zero and signed overflow return the values
+  // defined by the spec instead of trapping
+  fn mul_lo(self, o: Self) -> Self;
+  fn mulh(self, o: Self) -> Self;
+  fn mulhsu(self, o: Self) -> Self;
+  fn mulhu(self, o: Self) -> Self;
+  fn div_s(self, o: Self) -> Self;
+  fn div_u(self, o: Self) -> Self;
+  fn rem_s(self, o: Self) -> Self;
+  fn rem_u(self, o: Self) -> Self;
 }
 
 impl RegData for u32 {
   type Signed = i32;
   fn offset(&self, s: i32) -> u32 {
-    if s < 0 { self - (s.abs() as u32) } else { self + (s as u32) }
+    u32::wrapping_add(*self, s as u32)
   }
   fn as_usize(&self) -> usize { *self as usize }
+  fn from_u64(v: u64) -> Self { v as Self }
+  fn sext_w(self) -> Self { self }
+  fn zext_w(self) -> Self { self }
   #[inline]
-  fn to_signed(self) -> Self::Signed { unsafe { transmute::<Self, Self::Signed>(self) } }
+  fn to_signed(self) -> Self::Signed { self as Self::Signed }
   #[inline]
-  fn from_signed(v: Self::Signed) -> Self { unsafe { transmute::<Self::Signed, Self>(v) } }
+  fn from_signed(v: Self::Signed) -> Self { v as Self }
 
   fn to_le_bytes(&self) -> Box<[u8]> { Box::new(u32::to_le_bytes(*self)) }
   fn from_le_bytes(bytes: Box<[u8]>) -> Self {
@@ -46,18 +71,39 @@ impl RegData for u32 {
     temp.copy_from_slice(&bytes);
     Self::from_le_bytes(temp)
   }
+
+  fn mul_lo(self, o: Self) -> Self { self.wrapping_mul(o) }
+  fn mulh(self, o: Self) -> Self {
+    (((self as i32 as i64) * (o as i32 as i64)) >> 32) as Self
+  }
+  fn mulhsu(self, o: Self) -> Self {
+    (((self as i32 as i64) * (o as i64)) >> 32) as Self
+  }
+  fn mulhu(self, o: Self) -> Self { (((self as u64) * (o as u64)) >> 32) as Self }
+  fn div_s(self, o: Self) -> Self {
+    if o == 0 { Self::MAX }
+    else { (self as i32).wrapping_div(o as i32) as Self }
+  }
+  fn div_u(self, o: Self) -> Self { self.checked_div(o).unwrap_or(Self::MAX) }
+  fn rem_s(self, o: Self) -> Self {
+    if o == 0 { self }
+    else { (self as i32).wrapping_rem(o as i32) as Self }
+  }
+  fn rem_u(self, o: Self) -> Self { self.checked_rem(o).unwrap_or(self) }
 }
 impl RegData for u64 {
   type Signed = i64;
   fn offset(&self, s: i64) -> Self {
-    if s < 0 { self - (s.abs() as u64) }
-    else { self + (s as u64) }
+    u64::wrapping_add(*self, s as u64)
   }
   fn as_usize(&self) -> usize { *self as usize }
+  fn from_u64(v: u64) -> Self { v as Self }
+  fn sext_w(self) -> Self { self as i32 as Self }
+  fn zext_w(self) -> Self { self & 0xffffffff }
   #[inline]
-  fn to_signed(self) -> Self::Signed { unsafe { transmute::<Self, Self::Signed>(self) } }
+  fn to_signed(self) -> Self::Signed { self as Self::Signed }
   #[inline]
-  fn from_signed(v: Self::Signed) -> Self { unsafe { transmute::<Self::Signed, Self>(v) } }
+  fn from_signed(v: Self::Signed) -> Self { v as Self }
 
   fn to_le_bytes(&self) -> Box<[u8]> { Box::new(u64::to_le_bytes(*self)) }
   fn from_le_bytes(bytes: Box<[u8]>) -> Self {
@@ -65,21 +111,39 @@ impl RegData for u64 {
     temp.copy_from_slice(&bytes);
     Self::from_le_bytes(temp)
   }
+
+  fn mul_lo(self, o: Self) -> Self { self.wrapping_mul(o) }
+  fn mulh(self, o: Self) -> Self {
+    (((self as i64 as i128) * (o as i64 as i128)) >> 64) as Self
+  }
+  fn mulhsu(self, o: Self) -> Self {
+    (((self as i64 as i128) * (o as i128)) >> 64) as Self
+  }
+  fn mulhu(self, o: Self) -> Self { (((self as u128) * (o as u128)) >> 64) as Self }
+  fn div_s(self, o: Self) -> Self {
+    if o == 0 { Self::MAX }
+    else { (self as i64).wrapping_div(o as i64) as Self }
+  }
+  fn div_u(self, o: Self) -> Self { self.checked_div(o).unwrap_or(Self::MAX) }
+  fn rem_s(self, o: Self) -> Self {
+    if o == 0 { self }
+    else { (self as i64).wrapping_rem(o as i64) as Self }
+  }
+  fn rem_u(self, o: Self) -> Self { self.checked_rem(o).unwrap_or(self) }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Register<T : RegData> {
   data: Vec<T>,
-  unwritten: VecDeque<(usize, T)>,
   pc: T,
 }
 
 impl <T : RegData> std::fmt::Display for Register<T> {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     for i in 0..self.data.len() {
-      writeln!(f, "[ x{:02}: {:08x} | {} ]", i, self.data[i], self.data[i].to_signed())?;
+      writeln!(f, "[ x{:02}: {:0w$x} | {} ]", i, self.data[i], self.data[i].to_signed(), w = T::BYTE_SIZE * 2)?;
     }
-    writeln!(f, "[ pc : {:08x} ]", self.pc())
+    writeln!(f, "[ pc : {:0w$x} ]", self.pc(), w = T::BYTE_SIZE * 2)
   }
 }
 
@@ -87,34 +151,47 @@ impl <T:RegData>Register<T> {
   pub fn new(num_regs: usize) -> Register<T> {
     Register{
       data: vec![T::zero(); num_regs],
-      unwritten: VecDeque::new(),
       pc: T::zero(),
     }
   }
 
   pub fn pc(&self) -> T { self.pc }
-  pub fn inc_pc(&mut self) {
-    self.pc = self.pc + T::from(crate::mem::WORD_SIZE as u32);
-  }
-  pub fn force_assign(&mut self, rd: u32, v: T) { self.data[rd as usize] = v }
-  pub fn assign(&mut self, rd: u32, v: T) {
-    if rd == 0 { self.data[0] = T::zero() }
-    else { self.unwritten.push_back((rd as usize, v)) }
-  }
-  pub fn writeback(&mut self, rd: u32) -> bool {
-    if rd == 0 { return true }
-    else if self.unwritten.len() == 0 { return false };
-    let (rd, v) = self.unwritten.pop_front().expect("Failed writeback");
-    self.data[rd] = v;
-    true
+  // Steps over an instruction of len bytes
+  pub fn inc_pc(&mut self, len: usize) {
+    self.pc = self.pc + T::from(len as u32);
   }
+  pub fn force_assign(&mut self, rd: u32, v: T) { if rd != 0 { self.data[rd as usize] = v } }
   pub fn assign_pc(&mut self, v: T) { self.pc = v }
 }
 
 impl <T: RegData>Index<u32> for Register<T> {
   type Output = T;
-  fn index(&self, i: u32) -> &T {
-    let i = i as usize;
-    self.unwritten.iter().find(|&v| v.0 == i).map(|v| &v.1).unwrap_or(&self.data[i])
-  }
+  fn index(&self, i: u32) -> &T { &self.data[i as usize] }
+}
+
+#[test]
+fn test_mul_high() {
+  assert_eq!(0xffffffffu32.mul_lo(2), 0xfffffffe);
+  assert_eq!(0xffffffffu32.mulh(0xffffffff), 0);
+  assert_eq!(0xffffffffu32.mulhu(0xffffffff), 0xfffffffe);
+  assert_eq!(0xffffffffu32.mulhsu(2), 0xffffffff);
+  assert_eq!(0x80000000u32.mulh(0x80000000), 0x40000000);
+  assert_eq!(u64::MAX.mulhu(u64::MAX), u64::MAX - 1);
+  assert_eq!(u64::MAX.mulhsu(u64::MAX), u64::MAX);
+}
+
+#[test]
+fn test_div_edge_cases() {
+  // division by zero
+  assert_eq!(7u32.div_s(0), u32::MAX);
+  assert_eq!(7u32.div_u(0), u32::MAX);
+  assert_eq!(7u32.rem_s(0), 7);
+  assert_eq!(7u32.rem_u(0), 7);
+  // signed overflow
+  assert_eq!(0x80000000u32.div_s(u32::MAX), 0x80000000);
+  assert_eq!(0x80000000u32.rem_s(u32::MAX), 0);
+  assert_eq!((1u64 << 63).div_s(u64::MAX), 1 << 63);
+  // rounds towards zero
+  assert_eq!(u32::from_signed(-7).div_s(2), u32::from_signed(-3));
+  assert_eq!(u32::from_signed(-7).rem_s(2), u32::from_signed(-1));
 }
@@ -0,0 +1,222 @@
+// C extension, 16 bit instructions expanded into the 32 bit ones they stand for. Some encodings
+// mean different things in RV32 and RV64, so expansion needs the register width.
+use crate::instr::InstrType;
+use crate::reg::RegData;
+
+// Whether raw is a 16 bit instruction, whose low two bits are anything but 11
+pub fn is_compressed(raw: u32) -> bool { raw & 0b11 != 0b11 && raw <= 0xffff }
+
+// Bytes taken by the instruction
+pub fn instr_len(raw: u32) -> usize { if is_compressed(raw) { 2 } else { 4 } }
+
+// Reads the instruction at loc from the start of bytes, which is a compressed one in the low half
+// if its low two bits are not 11. The halt word looks like two c.bnez, so it is only read whole
+// when it is word aligned.
+pub fn read_parcel(loc: usize, bytes: &[u8]) -> Option<u32> {
+  let parcel = u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]) as u32;
+  let word = bytes.get(..4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+  match word {
+    Some(word) if word == InstrType::halt_val() && loc.is_multiple_of(4) => Some(word),
+    _ if is_compressed(parcel) => Some(parcel),
+    word => word,
+  }
+}
+
+// The instruction after the one at pc
+pub fn next_pc<T : RegData>(pc: T, raw: u32) -> T { pc.wrapping_add(&T::from(instr_len(raw) as u32)) }
+
+// Instructions start on any 2 byte boundary, so only odd jump targets are misaligned
+pub const IALIGN: usize = 2;
+
+const LOAD: u32 = 0b0000011;
+const LOAD_FP: u32 = 0b0000111;
+const STORE: u32 = 0b0100011;
+const STORE_FP: u32 = 0b0100111;
+const OP_IMM: u32 = 0b0010011;
+const OP_IMM_32: u32 = 0b0011011;
+const OP: u32 = 0b0110011;
+const OP_32: u32 = 0b0111011;
+const LUI: u32 = 0b0110111;
+const BRANCH: u32 = 0b1100011;
+const JALR: u32 = 0b1100111;
+const JAL: u32 = 0b1101111;
+const EBREAK: u32 = 0x00100073;
+const SP: u32 = 2;
+const RA: u32 = 1;
+
+// Gathers an immediate scattered over the instruction. order names the immediate bit held in each
+// instruction bit, counting down from top, as the spec writes them.
+fn imm(c: u32, top: u32, order: &[u32]) -> u32 {
+  order.iter().enumerate().fold(0, |imm, (k, &bit)| imm | (c >> (top - k as u32) & 1) << bit)
+}
+
+fn sext(v: u32, sign: u32) -> u32 { ((v << (31 - sign)) as i32 >> (31 - sign)) as u32 }
+
+fn r(op: u32, rd: u32, f3: u32, rs1: u32, rs2: u32, f7: u32) -> u32 {
+  f7 << 25 | rs2 << 20 | rs1 << 15 | f3 << 12 | rd << 7 | op
+}
+fn i(op: u32, rd: u32, f3: u32, rs1: u32, imm: u32) -> u32 {
+  (imm & 0xfff) << 20 | rs1 << 15 | f3 << 12 | rd << 7 | op
+}
+fn s(op: u32, f3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+  (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | f3 << 12 | (imm & 0x1f) << 7 | op
+}
+fn b(f3: u32, rs1: u32, imm: u32) -> u32 {
+  (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | rs1 << 15 | f3 << 12 | (imm >> 1 & 0xf) << 8
+    | (imm >> 11 & 1) << 7 | BRANCH
+}
+fn j(rd: u32, imm: u32) -> u32 {
+  (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12
+    | rd << 7 | JAL
+}
+
+// Expands c into the 32 bit instruction it stands for on a core with xlen bit registers. Reserved
+// encodings, including all zeros, are errors. Expansions that only exist in RV64, such as c.subw
+// or shifts by 32, are left to decode_xlen to reject.
+pub fn expand(c: u16, xlen: usize) -> Result<u32, String> {
+  let c = c as u32;
+  let reserved = || Err(format!("Reserved compressed instruction {:#06x}", c));
+  let rv64 = xlen >= 64;
+  // full registers in bits 11:7 and 6:2, and x8-x15 in bits 9:7 and 4:2
+  let (rd, rs2) = (c >> 7 & 0x1f, c >> 2 & 0x1f);
+  let (rd_, rs2_) = (8 + (c >> 7 & 0b111), 8 + (c >> 2 & 0b111));
+  let word_off = imm(c, 12, &[5, 4, 3]) | imm(c, 6, &[2, 6]);
+  let double_off = imm(c, 12, &[5, 4, 3]) | imm(c, 6, &[7, 6]);
+  let ci = sext(imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 2, 1, 0]), 5);
+  let shamt = imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 2, 1, 0]);
+  let expanded = match (c & 0b11, c >> 13) {
+    (0b00, 0b000) => {
+      let nzuimm = imm(c, 12, &[5, 4, 9, 8, 7, 6, 2, 3]);
+      if nzuimm == 0 { return reserved() };
+      i(OP_IMM, rs2_, 0b000, SP, nzuimm)
+    },
+    (0b00, 0b001) => i(LOAD_FP, rs2_, 0b011, rd_, double_off),
+    (0b00, 0b010) => i(LOAD, rs2_, 0b010, rd_, word_off),
+    (0b00, 0b011) if rv64 => i(LOAD, rs2_, 0b011, rd_, double_off),
+    (0b00, 0b011) => i(LOAD_FP, rs2_, 0b010, rd_, word_off),
+    (0b00, 0b101) => s(STORE_FP, 0b011, rd_, rs2_, double_off),
+    (0b00, 0b110) => s(STORE, 0b010, rd_, rs2_, word_off),
+    (0b00, 0b111) if rv64 => s(STORE, 0b011, rd_, rs2_, double_off),
+    (0b00, 0b111) => s(STORE_FP, 0b010, rd_, rs2_, word_off),
+    (0b01, 0b000) => i(OP_IMM, rd, 0b000, rd, ci),
+    (0b01, 0b001) if rv64 => {
+      if rd == 0 { return reserved() };
+      i(OP_IMM_32, rd, 0b000, rd, ci)
+    },
+    (0b01, 0b001) => j(RA, sext(imm(c, 12, &[11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5]), 11)),
+    (0b01, 0b010) => i(OP_IMM, rd, 0b000, 0, ci),
+    (0b01, 0b011) if rd == SP => {
+      let nzimm = sext(imm(c, 12, &[9]) | imm(c, 6, &[4, 6, 8, 7, 5]), 9);
+      if nzimm == 0 { return reserved() };
+      i(OP_IMM, SP, 0b000, SP, nzimm)
+    },
+    (0b01, 0b011) => {
+      let nzimm = sext(imm(c, 12, &[17]) | imm(c, 6, &[16, 15, 14, 13, 12]), 17);
+      if nzimm == 0 { return reserved() };
+      nzimm & 0xfffff000 | rd << 7 | LUI
+    },
+    (0b01, 0b100) => match (c >> 10 & 0b11, c >> 12 & 1, c >> 5 & 0b11) {
+      (0b00, _, _) => i(OP_IMM, rd_, 0b101, rd_, shamt),
+      (0b01, _, _) => i(OP_IMM, rd_, 0b101, rd_, 0x400 | shamt),
+      (0b10, _, _) => i(OP_IMM, rd_, 0b111, rd_, ci),
+      (_, 0, 0b00) => r(OP, rd_, 0b000, rd_, rs2_, 0b0100000),
+      (_, 0, 0b01) => r(OP, rd_, 0b100, rd_, rs2_, 0),
+      (_, 0, 0b10) => r(OP, rd_, 0b110, rd_, rs2_, 0),
+      (_, 0, _) => r(OP, rd_, 0b111, rd_, rs2_, 0),
+      (_, _, 0b00) => r(OP_32, rd_, 0b000, rd_, rs2_, 0b0100000),
+      (_, _, 0b01) => r(OP_32, rd_, 0b000, rd_, rs2_, 0),
+      _ => return reserved(),
+    },
+    (0b01, 0b101) => j(0, sext(imm(c, 12, &[11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5]), 11)),
+    (0b01, f3) => b(f3 & 1, rd_, sext(imm(c, 12, &[8, 4, 3]) | imm(c, 6, &[7, 6, 2, 1, 5]), 8)),
+    (0b10, 0b000) => i(OP_IMM, rd, 0b001, rd, shamt),
+    (0b10, 0b001) => i(LOAD_FP, rd, 0b011, SP, imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 8, 7, 6])),
+    (0b10, 0b010) => {
+      if rd == 0 { return reserved() };
+      i(LOAD, rd, 0b010, SP, imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 2, 7, 6]))
+    },
+    (0b10, 0b011) if rv64 => {
+      if rd == 0 { return reserved() };
+      i(LOAD, rd, 0b011, SP, imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 8, 7, 6]))
+    },
+    (0b10, 0b011) => i(LOAD_FP, rd, 0b010, SP, imm(c, 12, &[5]) | imm(c, 6, &[4, 3, 2, 7, 6])),
+    (0b10, 0b100) => match (c >> 12 & 1, rd, rs2) {
+      (0, 0, 0) => return reserved(),
+      (0, _, 0) => i(JALR, 0, 0b000, rd, 0),
+      (0, _, _) => r(OP, rd, 0b000, 0, rs2, 0),
+      (_, 0, 0) => EBREAK,
+      (_, _, 0) => i(JALR, RA, 0b000, rd, 0),
+      _ => r(OP, rd, 0b000, rd, rs2, 0),
+    },
+    (0b10, 0b101) => s(STORE_FP, 0b011, SP, rs2, imm(c, 12, &[5, 4, 3, 8, 7, 6])),
+    (0b10, 0b110) => s(STORE, 0b010, SP, rs2, imm(c, 12, &[5, 4, 3, 2, 7, 6])),
+    (0b10, 0b111) if rv64 => s(STORE, 0b011, SP, rs2, imm(c, 12, &[5, 4, 3, 8, 7, 6])),
+    (0b10, 0b111) => s(STORE_FP, 0b010, SP, rs2, imm(c, 12, &[5, 4, 3, 2, 7, 6])),
+    _ => return reserved(),
+  };
+  Ok(expanded)
+}
+
+#[test]
+fn test_expand() {
+  // compressed, xlen and the 32 bit instruction it stands for
+  let cases = [
+    (0x1fe8, 32, 0x3fc10513), // c.addi4spn a0, sp, 1020
+    (0x3ffc, 32, 0x0f87b787), // c.fld fa5, 248(a5)
+    (0x5cf0, 32, 0x07c4a603), // c.lw a2, 124(s1)
+    (0x6140, 32, 0x00452407), // c.flw fs0, 4(a0)
+    (0xa408, 32, 0x00a43427), // c.fsd fa0, 8(s0)
+    (0xc33c, 32, 0x04f72023), // c.sw a5, 64(a4)
+    (0xfeac, 32, 0x06b6ac27), // c.fsw fa1, 120(a3)
+    (0x0001, 32, 0x00000013), // c.nop
+    (0x1501, 32, 0xfe050513), // c.addi a0, -32
+    (0x3001, 32, 0x801ff0ef), // c.jal -2048
+    (0x42fd, 32, 0x01f00293), // c.li t0, 31
+    (0x7101, 32, 0xe0010113), // c.addi16sp sp, -512
+    (0x7d81, 32, 0xfffe0db7), // c.lui s11, 0xfffe0
+    (0x6505, 32, 0x00001537), // c.lui a0, 1
+    (0x81fd, 32, 0x01f5d593), // c.srli a1, 31
+    (0x8405, 32, 0x40145413), // c.srai s0, 1
+    (0x9b7d, 32, 0xfff77713), // c.andi a4, -1
+    (0x8c9d, 32, 0x40f484b3), // c.sub s1, a5
+    (0x8d2d, 32, 0x00b54533), // c.xor a0, a1
+    (0x8e55, 32, 0x00d66633), // c.or a2, a3
+    (0x8f61, 32, 0x00877733), // c.and a4, s0
+    (0xaffd, 32, 0x7fe0006f), // c.j 2046
+    (0xd101, 32, 0xf00500e3), // c.beqz a0, -256
+    (0xecfd, 32, 0x0e049f63), // c.bnez s1, 254
+    (0x0fc6, 32, 0x011f9f93), // c.slli t6, 17
+    (0x30fe, 32, 0x1f813087), // c.fldsp ft1, 504(sp)
+    (0x50fe, 32, 0x0fc12083), // c.lwsp ra, 252(sp)
+    (0x6d82, 32, 0x00012d87), // c.flwsp fs11, 0(sp)
+    (0x8282, 32, 0x00028067), // c.jr t0
+    (0x854a, 32, 0x01200533), // c.mv a0, s2
+    (0x9002, 32, 0x00100073), // c.ebreak
+    (0x9782, 32, 0x000780e7), // c.jalr a5
+    (0x9172, 32, 0x01c10133), // c.add sp, t3
+    (0xbfc6, 32, 0x1f113c27), // c.fsdsp fa7, 504(sp)
+    (0xdfce, 32, 0x0f312e23), // c.swsp s3, 252(sp)
+    (0xe202, 32, 0x00012227), // c.fswsp ft0, 4(sp)
+    (0x7fe8, 64, 0x0f87b503), // c.ld a0, 248(a5)
+    (0xe404, 64, 0x00943423), // c.sd s1, 8(s0)
+    (0x337d, 64, 0xfff3031b), // c.addiw t1, -1
+    (0x957d, 64, 0x43f55513), // c.srai a0, 63
+    (0x1602, 64, 0x02061613), // c.slli a2, 32
+    (0x9d0d, 64, 0x40b5053b), // c.subw a0, a1
+    (0x9c3d, 64, 0x00f4043b), // c.addw s0, a5
+    (0x747e, 64, 0x1f813403), // c.ldsp s0, 504(sp)
+    (0xe406, 64, 0x00113423), // c.sdsp ra, 8(sp)
+  ];
+  for &(c, xlen, expanded) in cases.iter() {
+    assert_eq!(expand(c, xlen), Ok(expanded), "{:#06x} on RV{}", c, xlen);
+  }
+  // c.flw and c.fsw on RV32 are c.ld and c.sd on RV64
+  assert_eq!(expand(0x6140, 64), Ok(0x08053403)); // ld s0, 128(a0)
+  assert_eq!(expand(0xe404, 32), Ok(0x00942427)); // fsw fs1, 8(s0)
+  // all zeros, c.addi16sp 0, c.lui 0, c.lwsp and c.addiw to x0, c.jr x0
+  for &c in [0x0000, 0x6101, 0x6501, 0x4002, 0x8002].iter() { assert!(expand(c, 32).is_err()) };
+  assert!(expand(0x2001, 64).is_err());
+  assert_eq!(instr_len(0x8082), 2);
+  assert_eq!(instr_len(0x00008067), 4);
+  assert_eq!(instr_len(0xfeedfeed), 4);
+}
@@ -1,207 +1,564 @@
-use crate::mem;
+use crate::rvc;
+use crate::bitmanip;
 use crate::reg::{RegData};
-use crate::program_state::{ProgramState, Status, Exceptions};
-use crate::instr::{self, InstrType, RInstr, IInstr, BInstr, JInstr, SInstr, UInstr};
+use crate::program_state::{ProgramState, Status, Exceptions, Privilege};
+use crate::instr::{self, InstrType, RInstr, IInstr, BInstr, JInstr, UInstr};
+use crate::trace::{mem_access, MemAccess};
 
-// Pipeline elements can either be exceptions or instructions
+// Where branches and indirect jumps find out where they go. JAL always knows by ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchStage { ID, EX }
+
+// How the pipeline handles data and control hazards
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InOrderConfig {
+  // pass results from EX/MEM and MEM/WB back to the instructions that need them, rather than
+  // stalling until they are written back
+  pub forwarding: bool,
+  pub branch_stage: BranchStage,
+}
+
+impl Default for InOrderConfig {
+  fn default() -> Self { InOrderConfig{ forwarding: true, branch_stage: BranchStage::ID } }
+}
+
+// An instruction in flight. Its sources are read in ID, and replaced by forwarded values up
+// until they are used. The result is what it will write back, and the memory it accessed is kept
+// for the trace. Fetch carries on from predicted, which is checked once control flow resolves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Latch<T : RegData> {
+  raw: u32,
+  pc: T,
+  predicted: T,
+  rs1: T,
+  rs2: T,
+  result: Option<T>,
+  access: Option<MemAccess<T>>,
+  // the cycle the D-cache answers a load or store, once it has started in MEM
+  ready: u64,
+  // the level it was fetched at, which is the one it runs at as everything younger than a
+  // trap, mret or sret is flushed
+  prv: Privilege,
+}
+
+impl <T : RegData> Latch<T> {
+  fn dest(&self) -> Option<u32> { instr::decode_parcel(self.raw, T::BYTE_SIZE * 8).ok().and_then(|i| i.dest()) }
+}
+
+// Pipeline elements can either be exceptions or instructions, along with the pc they came from.
+// Exceptions also carry the value for mtval, and are only taken once they reach writeback.
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum PipelineEntry { Empty, Exc(Exceptions), Instr(u32), }
+enum PipelineEntry<T : RegData> { Empty, Exc(Exceptions, T, T), Instr(Latch<T>), }
 
 const PIPE_SIZE: usize = 5;
 #[derive(Clone, Copy, Debug)]
-struct Pipeline([PipelineEntry;PIPE_SIZE]);
-impl std::ops::Index<Phases> for Pipeline {
-  type Output = PipelineEntry;
-  fn index(&self, p: Phases) -> &PipelineEntry { &self.0[p as usize] }
+struct Pipeline<T : RegData>([PipelineEntry<T>;PIPE_SIZE]);
+impl <T : RegData> std::ops::Index<Phases> for Pipeline<T> {
+  type Output = PipelineEntry<T>;
+  fn index(&self, p: Phases) -> &PipelineEntry<T> { &self.0[p as usize] }
 }
 
-impl Pipeline {
-  fn shift(&mut self) {
-    (0..PIPE_SIZE-1).rev().for_each(|v| self.0[v+1] = self.0[v]);
-    self.0[0] = PipelineEntry::Empty;
+impl <T : RegData> Pipeline<T> {
+  // A stall holds IF and ID in place and sends a bubble down to EX
+  fn shift(&mut self, stalled: bool) {
+    let from = if stalled { Phases::EX as usize } else { 0 };
+    (from..PIPE_SIZE-1).rev().for_each(|v| self.0[v+1] = self.0[v]);
+    self.0[from] = PipelineEntry::Empty;
   }
   // TODO only if there are no jumps ahead?
   fn done(&self) -> bool {
-    self.0.iter().any(|&v| v == PipelineEntry::Instr(InstrType::halt_val()))
+    self.0.iter().any(|v| matches!(v, PipelineEntry::Instr(l) if l.raw == InstrType::halt_val()))
+  }
+  // Whether a load or store in MEM is still waiting on the D-cache
+  fn waiting(&self, cycle: u64) -> bool {
+    matches!(self[Phases::MEM], PipelineEntry::Instr(l) if l.ready > cycle)
+  }
+  // Holds everything up to MEM in place while what was in WB leaves, passing its result on to
+  // EX first as it would otherwise have been forwarded there
+  fn hold(&mut self) {
+    if let PipelineEntry::Instr(mut l) = self[Phases::EX] {
+      let [src1, src2] = instr::decode_parcel(l.raw, T::BYTE_SIZE * 8).map_or([None; 2], |i| i.sources());
+      l.rs1 = self.forward(src1, &[Phases::WB]).unwrap_or(l.rs1);
+      l.rs2 = self.forward(src2, &[Phases::WB]).unwrap_or(l.rs2);
+      self[Phases::EX] = PipelineEntry::Instr(l);
+    };
+    self[Phases::WB] = PipelineEntry::Empty;
+  }
+  // Removes every instruction younger than the one in phase
+  fn flush(&mut self, phase: Phases) {
+    (0..phase as usize).for_each(|v| self.0[v] = PipelineEntry::Empty);
+  }
+  // The result an instruction in one of phases will write to r, searching youngest first
+  fn forward(&self, r: Option<u32>, phases: &[Phases]) -> Option<T> {
+    let r = r?;
+    phases.iter().find_map(|&phase| match self[phase] {
+      PipelineEntry::Instr(l) if l.dest() == Some(r) => Some(l.result),
+      _ => None,
+    }).flatten()
   }
 }
 
-impl std::ops::IndexMut<Phases> for Pipeline {
-  fn index_mut(&mut self, p: Phases) -> &mut PipelineEntry { &mut self.0[p as usize] }
+impl <T : RegData> std::ops::IndexMut<Phases> for Pipeline<T> {
+  fn index_mut(&mut self, p: Phases) -> &mut PipelineEntry<T> { &mut self.0[p as usize] }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum Phases { IF=0, ID=1, EX=2, MEM=3, WB=4, }
 
-pub fn in_order<T : RegData>(mut ps: ProgramState<T>) -> Result<ProgramState<T>, ()> {
-  let mut p: Pipeline = Pipeline([PipelineEntry::Empty; PIPE_SIZE]);
+// Loads, atomics, FP and system instructions only have their result at the end of MEM
+fn result_in_mem(instr: &InstrType) -> bool {
+  use IInstr::*;
+  instr.serializes() || matches!(instr, InstrType::I{ var: LB | LH | LW | LBU | LHU | LWU | LD, .. }
+    | InstrType::A{ .. } | InstrType::F{ .. })
+}
+
+// Whether control flow is resolved in ID, which needs the sources a cycle early
+fn resolves_in_id(instr: &InstrType, config: InOrderConfig) -> bool {
+  match instr {
+    InstrType::J{ .. } => true,
+    InstrType::B{ .. } | InstrType::I{ var: IInstr::JALR, .. } => config.branch_stage == BranchStage::ID,
+    _ => false,
+  }
+}
+
+fn resolves_in_ex(instr: &InstrType, config: InOrderConfig) -> bool {
+  config.branch_stage == BranchStage::EX
+    && matches!(instr, InstrType::B{ .. } | InstrType::I{ var: IInstr::JALR, .. })
+}
+
+// What fetch does after a phase has run
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fetch {
+  Next,
+  // ID is waiting on an operand, so holds IF in place
+  Stall,
+  // fetch was redirected, what it fetched this cycle was on the wrong path
+  Squash,
+}
+
+#[allow(clippy::result_unit_err)]
+pub fn in_order<T : RegData>(ps: ProgramState<T>) -> Result<ProgramState<T>, ()> {
+  in_order_with(ps, InOrderConfig::default())
+}
+
+#[allow(clippy::result_unit_err)]
+pub fn in_order_with<T : RegData>(mut ps: ProgramState<T>, config: InOrderConfig)
+  -> Result<ProgramState<T>, ()> {
+  let mut p: Pipeline<T> = Pipeline([PipelineEntry::Empty; PIPE_SIZE]);
+  // the instruction fetch is waiting on the I-cache for, and when it arrives
+  let mut fetching = None;
   while ps.status == Status::Running {
-    ps = ps.run_phase(&mut p, Phases::WB)
-      .run_phase(&mut p, Phases::MEM)
-      .run_phase(&mut p, Phases::EX)
-      .run_phase(&mut p, Phases::ID);
-    ps.run_if_phase(&mut p);
-    if !p.done() { ps.regs.inc_pc(); }
-    p.shift();
+    ps.csrs.cycle += 1;
+    ps.run_phase(&mut p, Phases::WB, config);
+    ps.run_phase(&mut p, Phases::MEM, config);
+    if p.waiting(ps.csrs.cycle) {
+      ps.stats.memory_stalls += 1;
+      p.hold();
+      continue
+    };
+    let fetch = match ps.run_phase(&mut p, Phases::EX, config) {
+      Fetch::Squash => Fetch::Squash,
+      _ => ps.run_phase(&mut p, Phases::ID, config),
+    };
+    if ps.status != Status::Running { break }
+    if fetch == Fetch::Next { ps.run_if_phase(&mut p, &mut fetching) };
+    p.shift(fetch == Fetch::Stall);
   };
-  println!("{}", ps.regs);
   Ok(ps)
 }
 
 impl <T: RegData>ProgramState<T> {
-  fn run_phase(mut self, p: &mut Pipeline, phase: Phases) -> ProgramState<T> {
+  // Runs the instruction in phase, returning what fetch should do this cycle
+  fn run_phase(&mut self, p: &mut Pipeline<T>, phase: Phases, config: InOrderConfig) -> Fetch {
     use PipelineEntry::*;
-    let instr = match p[phase] {
-      Empty => return self,
-      Instr(raw) => instr::decode(raw).unwrap(),
-      Exc(_) if phase != Phases::WB => return self,
-      Exc(e) => {
-        self.status = Status::Exception(e);
-        return self;
+    let mut l = match p[phase] {
+      Empty => return Fetch::Next,
+      Instr(l) => l,
+      Exc(..) if phase != Phases::WB => return Fetch::Next,
+      Exc(e, epc, tval) => {
+        // everything younger is discarded before the handler starts
+        self.trap(e, epc, tval);
+        p.flush(Phases::WB);
+        self.stats.flushes += 1;
+        return Fetch::Next;
+      },
+    };
+    let (raw, pc) = (l.raw, l.pc);
+    let instr = match instr::decode_xlen(raw, T::BYTE_SIZE * 8) {
+      // vector instructions are only run by the normal simulator
+      Ok(instr) if !matches!(instr, InstrType::V{ .. }) => instr,
+      // only raised if the instruction reaches writeback, it may be on a path not taken
+      _ => {
+        p[phase] = Exc(Exceptions::IllegalInstruction, pc, T::from(raw));
+        return Fetch::Next;
       },
     };
+    let [src1, src2] = instr.sources();
     match phase {
       Phases::IF => panic!("Unexpected run_phase() with Phases::IF, use run_if_phase instead"),
-      Phases::ID => match instr {
-        InstrType::I{ var: IInstr::JALR, rs1, rd, sx_imm, .. } => {
-          let curr_pc = self.regs.pc();
-          let sx = T::Signed::from(sx_imm);
-          self.regs.assign_pc(
-            T::from_signed(self.sx(self.regs[rs1])+sx & T::Signed::from(-2))
-          );
-          self.regs.assign(rd, curr_pc);
-        },
-        InstrType::B{ var, rs1, rs2, imm } => {
-          let branch = match var {
-            BInstr::BEQ => self.regs[rs1] == self.regs[rs2],
-            BInstr::BNE => self.regs[rs1] != self.regs[rs2],
-            BInstr::BLT => self.sx(self.regs[rs1]) < self.sx(self.regs[rs2]),
-            BInstr::BGE => self.sx(self.regs[rs1]) >= self.sx(self.regs[rs2]),
-            BInstr::BLTU => self.zx(self.regs[rs1]) < self.zx(self.regs[rs2]),
-            BInstr::BGEU => self.zx(self.regs[rs1]) >= self.zx(self.regs[rs2]),
-          };
-          if branch {
-            self.regs.assign_pc(self.regs.pc().offset(T::Signed::from(imm))
-              - T::from(mem::WORD_SIZE as u32));
-          };
-        },
-        InstrType::J{ var, rd, offset } => match var {
-          JInstr::JAL => {
-            let curr_pc = self.regs.pc();
-            self.regs.assign(rd, curr_pc);
-            self.regs.assign_pc(self.regs.pc().offset(T::Signed::from(offset))
-              - T::from(mem::WORD_SIZE as u32));
+      Phases::ID => {
+        if self.hazard(p, &instr, config) { return Fetch::Stall };
+        // values only reach ID from EX/MEM, and only for control flow resolved here
+        let early = resolves_in_id(&instr, config);
+        let read = |r: Option<u32>| {
+          let forwarded = if config.forwarding && early { p.forward(r, &[Phases::MEM]) } else { None };
+          forwarded.unwrap_or_else(|| r.map_or(T::zero(), |r| self.regs[r]))
+        };
+        l.rs1 = read(src1);
+        l.rs2 = read(src2);
+        p[phase] = Instr(l);
+        if early { return self.resolve(p, phase, l, &instr) };
+      },
+      Phases::EX => {
+        if config.forwarding {
+          l.rs1 = p.forward(src1, &[Phases::MEM, Phases::WB]).unwrap_or(l.rs1);
+          l.rs2 = p.forward(src2, &[Phases::MEM, Phases::WB]).unwrap_or(l.rs2);
+        };
+        let (rs1, rs2) = (l.rs1, l.rs2);
+        match instr {
+          InstrType::R{ var, rs2: shamt, .. } => l.result = Some(match var {
+            RInstr::ADD => rs1.wrapping_add(&rs2),
+            RInstr::SUB => rs1.wrapping_sub(&rs2),
+            RInstr::SLL => self.zx(rs1) << rs2.shamt(),
+            RInstr::SLT => if self.sx(rs1) < self.sx(rs2) {T::one()} else {T::zero()},
+            RInstr::SLTU => if self.zx(rs1) < self.zx(rs2) {T::one()} else {T::zero()},
+            RInstr::XOR => self.zx(rs1) ^ self.zx(rs2),
+            RInstr::SRL => self.zx(rs1) >> rs2.shamt(),
+            RInstr::SRA => T::from_signed(self.sx(rs1) >> rs2.shamt().to_signed()),
+            RInstr::OR => self.zx(rs1) | self.zx(rs2),
+            RInstr::AND => self.zx(rs1) & self.zx(rs2),
+            RInstr::SLLI => self.zx(rs1) << T::from(shamt),
+            RInstr::SRLI => self.zx(rs1) >> T::from(shamt),
+            RInstr::SRAI => T::from_signed(self.sx(rs1) >> T::from(shamt).to_signed()),
+            RInstr::MUL => rs1.mul_lo(rs2),
+            RInstr::MULH => rs1.mulh(rs2),
+            RInstr::MULHSU => rs1.mulhsu(rs2),
+            RInstr::MULHU => rs1.mulhu(rs2),
+            RInstr::DIV => rs1.div_s(rs2),
+            RInstr::DIVU => rs1.div_u(rs2),
+            RInstr::REM => rs1.rem_s(rs2),
+            RInstr::REMU => rs1.rem_u(rs2),
+            RInstr::ADDW => rs1.wrapping_add(&rs2).sext_w(),
+            RInstr::SUBW => rs1.wrapping_sub(&rs2).sext_w(),
+            RInstr::SLLW => (rs1 << rs2.shamt_w()).sext_w(),
+            RInstr::SRLW => (rs1.zext_w() >> rs2.shamt_w()).sext_w(),
+            RInstr::SRAW => T::from_signed(self.sx(rs1.sext_w()) >> rs2.shamt_w().to_signed()),
+            RInstr::SLLIW => (rs1 << T::from(shamt)).sext_w(),
+            RInstr::SRLIW => (rs1.zext_w() >> T::from(shamt)).sext_w(),
+            RInstr::SRAIW => T::from_signed(self.sx(rs1.sext_w()) >> T::from(shamt).to_signed()),
+            RInstr::MULW => rs1.mul_lo(rs2).sext_w(),
+            RInstr::DIVW => rs1.sext_w().div_s(rs2.sext_w()).sext_w(),
+            RInstr::DIVUW => rs1.zext_w().div_u(rs2.zext_w()).sext_w(),
+            RInstr::REMW => rs1.sext_w().rem_s(rs2.sext_w()).sext_w(),
+            RInstr::REMUW => rs1.zext_w().rem_u(rs2.zext_w()).sext_w(),
+            RInstr::Zb(zb) => bitmanip::exec(zb, rs1, rs2, shamt),
+          }),
+          InstrType::I{ var, sx_imm, .. } => {
+            let sx_imm = T::Signed::from(sx_imm);
+            // the immediate is sign extended even for unsigned comparisons and logical ops
+            let imm = T::from_signed(sx_imm);
+            let result = match var {
+              IInstr::ADDI => Some(rs1.offset(sx_imm)),
+              IInstr::SLTI => Some(if self.sx(rs1) < sx_imm {T::one()} else {T::zero()}),
+              IInstr::SLTIU => Some(if self.zx(rs1) < imm {T::one()} else {T::zero()}),
+              IInstr::XORI => Some(self.zx(rs1) ^ imm),
+              IInstr::ORI => Some(self.zx(rs1) | imm),
+              IInstr::ANDI => Some(self.zx(rs1) & imm),
+              IInstr::ADDIW => Some(rs1.offset(sx_imm).sext_w()),
+              _ => None,
+            };
+            if result.is_some() { l.result = result };
           },
-        },
-        _ => (),
+          InstrType::U{ var, imm, .. } => l.result = Some(match var {
+            UInstr::LUI => T::from_signed(T::Signed::from(imm as i32)),
+            UInstr::AUIPC => pc.offset(T::Signed::from(imm as i32)),
+          }),
+          _ => (),
+        };
+        // addresses are calculated here, and accessed in MEM
+        let rs1_idx = src1.unwrap_or(0);
+        l.access = mem_access(&instr, |r| if r == rs1_idx { rs1 } else { rs2 });
+        p[phase] = Instr(l);
+        if resolves_in_ex(&instr, config) { return self.resolve(p, phase, l, &instr) };
       },
-      Phases::EX => match instr {
-        InstrType::R{ var, rs1, rs2, rd } => {
-          let result = match var {
-            RInstr::ADD => T::from_signed(self.sx(self.regs[rs1]) + self.sx(self.regs[rs2])),
-            RInstr::SUB => T::from_signed(self.sx(self.regs[rs1]) - self.sx(self.regs[rs2])),
-            RInstr::SLL => self.zx(self.regs[rs1]) << self.regs[rs2],
-            RInstr::SLT =>
-              if self.sx(self.regs[rs1]) < self.sx(self.regs[rs2]) {T::one()} else {T::zero()},
-            RInstr::SLTU =>
-              if self.zx(self.regs[rs1]) < self.zx(self.regs[rs2]) {T::one()} else {T::zero()},
-            RInstr::XOR => self.zx(self.regs[rs1]) ^ self.zx(self.regs[rs2]),
-            RInstr::SRL => self.zx(self.regs[rs1]) >> self.regs[rs2],
-            RInstr::SRA => T::from_signed(self.sx(self.regs[rs1]) >> self.regs[rs2].to_signed()),
-            RInstr::OR => self.zx(self.regs[rs1]) | self.zx(self.regs[rs2]),
-            RInstr::AND => self.zx(self.regs[rs1]) & self.zx(self.regs[rs2]),
-            RInstr::SLLI => self.zx(self.regs[rs1]) << T::from(rs2),
-            RInstr::SRLI => self.zx(self.regs[rs1]) >> T::from(rs2),
-            RInstr::SRAI => T::from_signed(self.sx(self.regs[rs1]) >> T::from(rs2).to_signed()),
-          };
-          self.regs.assign(rd, result);
+      // everything older has been written back by the time an instruction is here, so system
+      // instructions run here and produce their result like a load would
+      Phases::MEM => match (instr, l.access) {
+        // already accessed memory, and waiting for the D-cache to answer
+        _ if l.ready > 0 => (),
+        (InstrType::I { var: IInstr::ECALL, .. }, _) => match self.ecall() {
+          Ok(()) => {
+            l.result = Some(self.regs[crate::syscall::A0]);
+            p[phase] = Instr(l);
+            // exiting stops the pipeline, so the ecall retires here rather than in writeback
+            if let Status::Exit(_) = self.status {
+              self.csrs.instret += 1;
+              self.commit(pc, raw, l.prv, &instr, None);
+            };
+          },
+          Err(e) => p[phase] = Exc(e, pc, T::zero()),
         },
-        InstrType::I{ var, rs1, rd, sx_imm, zx_imm } => {
-          let (sx_imm, zx_imm) = (T::Signed::from(sx_imm), T::from(zx_imm));
-          let result = match var {
-            IInstr::ADDI => Some(T::from_signed(self.sx(self.regs[rs1]) + sx_imm)),
-            IInstr::SLTI =>
-              Some(if self.sx(self.regs[rs1]) < sx_imm {T::one()} else {T::zero()}),
-            IInstr::SLTIU =>
-              Some(if self.zx(self.regs[rs1]) < zx_imm {T::one()} else {T::zero()}),
-            IInstr::XORI => Some(self.zx(self.regs[rs1]) ^ zx_imm),
-            IInstr::ORI => Some(self.zx(self.regs[rs1]) | zx_imm),
-            IInstr::ANDI => Some(self.zx(self.regs[rs1]) & zx_imm),
-            _ => None,
+        (InstrType::I { var: IInstr::EBREAK, .. }, _) =>
+          p[phase] = Exc(Exceptions::Breakpoint, pc, pc),
+        (InstrType::I { var: var @ (IInstr::MRET | IInstr::SRET), .. }, _) => {
+          match if var == IInstr::MRET { self.mret() } else { self.sret() } {
+            Ok(()) => {
+              p.flush(phase);
+              self.stats.flushes += 1;
+            },
+            Err(e) => p[phase] = Exc(e, pc, T::from(raw)),
           };
-          if let Some(result) = result { self.regs.assign(rd, result); };
         },
-        InstrType::U{ var, rd, imm } => {
-          let result = match var {
-            UInstr::LUI => T::from(imm),
-            UInstr::AUIPC => T::from(imm) + self.regs.pc(),
+        (InstrType::I { var: var @ (IInstr::WFI | IInstr::SFENCEVMA), .. }, _) =>
+          if let Err(e) = if var == IInstr::WFI { self.wfi() } else { self.sfence_vma() } {
+            p[phase] = Exc(e, pc, T::from(raw));
+          },
+        (InstrType::I { var, rs1, zx_imm, .. }, _) if instr.serializes() =>
+          p[phase] = match self.csr_op(var, zx_imm, rs1, l.rs1) {
+            Ok(old) => Instr(Latch{ result: Some(old), ..l }),
+            Err(e) => Exc(e, pc, T::from(raw)),
+          },
+        (InstrType::I { var, .. }, Some(MemAccess{ addr, size, .. })) => {
+          let signed = matches!(var, IInstr::LW | IInstr::LH | IInstr::LB);
+          p[phase] = match self.load(addr, size, signed) {
+            Ok(v) => {
+              let latency = self.data_latency(MemAccess::load(addr, size));
+              let ready = self.csrs.cycle + latency.saturating_sub(1);
+              Instr(Latch{ result: Some(v), ready, ..l })
+            },
+            Err(e) => Exc(e, pc, addr),
           };
-          self.regs.assign(rd, result);
         },
-        _ => (),
-      },
-      Phases::MEM => match instr {
-        InstrType::I { var, rd, rs1, sx_imm, .. } => {
-          let sx= T::Signed::from(sx_imm);
-          let result = match var {
-            IInstr::LW =>
-              self.mem.read(
-                T::from_signed(self.sx(self.regs[rs1])+sx).as_usize(), mem::Size::WORD),
-            IInstr::LH =>
-              self.mem.read(
-                T::from_signed(self.sx(self.regs[rs1])+sx).as_usize(), mem::Size::HALF),
-            IInstr::LB =>
-              self.mem.read(
-                T::from_signed(self.sx(self.regs[rs1])+sx).as_usize(), mem::Size::BYTE),
-
-            IInstr::LHU =>
-              self.mem.read_signed(
-                T::from_signed(self.sx(self.regs[rs1])+sx).as_usize(), mem::Size::HALF)
-              .map(|s| T::from_signed(s)),
-            IInstr::LBU =>
-              self.mem.read_signed(
-                T::from_signed(self.sx(self.regs[rs1])+sx).as_usize(), mem::Size::BYTE)
-              .map(|s| T::from_signed(s)),
-            _ => return self,
-          };
-          match result {
-            Ok(v) => self.regs.assign(rd, v),
-            Err(_) => p[phase] = PipelineEntry::Exc(Exceptions::Mem),
-          };
+        // memory is only accessed here and in program order, which is all aq and rl ask for
+        (InstrType::A { var, size, .. }, _) => match self.atomic(var, l.rs1, size, l.rs2) {
+          Ok((v, access)) => {
+            let ready = self.csrs.cycle + access.map_or(1, |a| self.data_latency(a)).saturating_sub(1);
+            p[phase] = Instr(Latch{ result: Some(v), access, ready, ..l });
+          },
+          Err(e) => p[phase] = Exc(e, pc, l.rs1),
         },
-        InstrType::S { var, rs1, rs2, imm } => {
-          let sz = match var {
-            SInstr::SW => mem::Size::WORD,
-            SInstr::SH => mem::Size::HALF,
-            SInstr::SB => mem::Size::BYTE,
-          };
-          let result = self.mem
-            .queue_write((self.regs[rs1] + T::from(imm)).as_usize(), self.regs[rs2], sz);
-          if let Err(()) = result {
-            p[phase] = PipelineEntry::Exc(Exceptions::Mem);
-          };
+        // the f registers are only read and written here, in program order, so never need
+        // forwarding
+        (InstrType::F { .. }, _) => match self.float(&instr, l.rs1) {
+          Ok((result, access)) => {
+            let ready = self.csrs.cycle + access.map_or(1, |a| self.data_latency(a)).saturating_sub(1);
+            p[phase] = Instr(Latch{ result, access, ready, ..l });
+          },
+          Err((Exceptions::IllegalInstruction, _)) =>
+            p[phase] = Exc(Exceptions::IllegalInstruction, pc, T::from(raw)),
+          Err((e, tval)) => p[phase] = Exc(e, pc, tval),
         },
+        (InstrType::S { .. }, Some(MemAccess{ addr, size, .. })) =>
+          match self.store_addr(addr, size) {
+            Ok(loc) => {
+              let latency = self.data_latency(MemAccess::store(addr, size, l.rs2));
+              let ready = self.csrs.cycle + latency.saturating_sub(1);
+              p[phase] = Instr(Latch{ ready, ..l });
+              self.void_reservation(loc, size);
+              assert!(self.mem.queue_write(loc, l.rs2, size).is_ok());
+            },
+            Err(e) => p[phase] = Exc(e, pc, addr),
+          },
         _ => (),
       },
-      Phases::WB => match instr {
-        InstrType::Halt => self.status = Status::Done,
-        InstrType::S{ .. } => assert!(self.mem.complete_write().is_ok()),
-        InstrType::B{ .. } => (),
-        InstrType::J{ rd, .. } | InstrType::I{ rd, .. }
-          | InstrType::R{ rd, .. } | InstrType::U{ rd, .. } =>
-          assert!(self.regs.writeback(rd)),
+      Phases::WB => {
+        match instr {
+          InstrType::Halt => self.status = Status::Done,
+          InstrType::S{ .. } => assert!(self.mem.complete_write().is_ok()),
+          _ => if let (Some(rd), Some(v)) = (instr.dest(), l.result) { self.regs.force_assign(rd, v) },
+        };
+        // instructions retire once they are written back
+        if self.status == Status::Running {
+          self.csrs.instret += 1;
+          self.commit(pc, raw, l.prv, &instr, l.access);
+        };
+      },
+    };
+    Fetch::Next
+  }
+
+  // Works out where control goes after a jump or branch in phase, and if fetch went elsewhere
+  // flushes what it fetched since and starts again from there
+  fn resolve(&mut self, p: &mut Pipeline<T>, phase: Phases, mut l: Latch<T>, instr: &InstrType)
+    -> Fetch {
+    let pc = l.pc;
+    let target = match *instr {
+      InstrType::I{ var: IInstr::JALR, sx_imm, .. } => Some(
+        l.rs1.offset(T::Signed::from(sx_imm)) & T::from_signed(T::Signed::from(-2))
+      ),
+      InstrType::B{ var, imm, .. } => {
+        let branch = match var {
+          BInstr::BEQ => l.rs1 == l.rs2,
+          BInstr::BNE => l.rs1 != l.rs2,
+          BInstr::BLT => self.sx(l.rs1) < self.sx(l.rs2),
+          BInstr::BGE => self.sx(l.rs1) >= self.sx(l.rs2),
+          BInstr::BLTU => self.zx(l.rs1) < self.zx(l.rs2),
+          BInstr::BGEU => self.zx(l.rs1) >= self.zx(l.rs2),
+        };
+        if branch { Some(pc.offset(T::Signed::from(imm))) } else { None }
       },
+      InstrType::J{ var: JInstr::JAL, offset, .. } => Some(pc.offset(T::Signed::from(offset))),
+      _ => None,
     };
-    self
+    let next_pc = rvc::next_pc(pc, l.raw);
+    if let Some(target) = target {
+      if !target.as_usize().is_multiple_of(rvc::IALIGN) {
+        p[phase] = PipelineEntry::Exc(Exceptions::InstrMisaligned, pc, target);
+        return Fetch::Next;
+      };
+      if let InstrType::I{ .. } | InstrType::J{ .. } = instr {
+        l.result = Some(next_pc);
+      };
+    };
+    p[phase] = PipelineEntry::Instr(l);
+    if !self.resolved(pc, l.raw, target, l.predicted) { return Fetch::Next };
+    // one cycle was fetched on the wrong path for every phase before this one
+    p.flush(phase);
+    self.stats.flushes += 1;
+    self.regs.assign_pc(target.unwrap_or(next_pc));
+    self.stats.mispredict_penalty += phase as u64;
+    Fetch::Squash
   }
-  fn run_if_phase(&mut self, p: &mut Pipeline) {
-    p[Phases::IF] = if p.done() { PipelineEntry::Empty }
-                    else {
-                      PipelineEntry::Instr(self.mem.read_instr(self.regs.pc().as_usize())
-                        .expect("Failed to read instruction"))
-                    };
+
+  // Whether the instruction in ID needs a result that is not ready yet, counting the stall
+  fn hazard(&mut self, p: &Pipeline<T>, instr: &InstrType, config: InOrderConfig) -> bool {
+    let in_id = resolves_in_id(instr, config);
+    let stall = instr.sources().iter().flatten().find_map(|&r| {
+      // only the youngest older writer of r matters
+      let (phase, raw) = [Phases::EX, Phases::MEM].iter().find_map(|&phase| match p[phase] {
+        PipelineEntry::Instr(l) if l.dest() == Some(r) => Some((phase, l.raw)),
+        _ => None,
+      })?;
+      let late = instr::decode_parcel(raw, T::BYTE_SIZE * 8).is_ok_and(|i| result_in_mem(&i));
+      let waits = !config.forwarding || match phase {
+        // the result is in EX/MEM next cycle, if EX produced it
+        Phases::EX => in_id || late,
+        // the result is in MEM/WB next cycle, which only reaches EX
+        _ => in_id && late,
+      };
+      if waits { Some(late) } else { None }
+    });
+    match stall {
+      Some(true) => self.stats.load_use_stalls += 1,
+      Some(false) => self.stats.raw_stalls += 1,
+      None => (),
+    };
+    stall.is_some()
+  }
+
+  // Fetches the next instruction, and moves the pc on to where the predictor says it goes
+  fn run_if_phase(&mut self, p: &mut Pipeline<T>, fetching: &mut Option<(T, u64)>) {
+    if p.done() { return };
+    let pc = self.regs.pc();
+    if !self.instr_arrived(fetching, pc) { return };
+    p[Phases::IF] = match self.mem.read_instr(pc.as_usize()) {
+      Ok(raw) => {
+        let predicted = self.predict_next(pc, raw);
+        PipelineEntry::Instr(Latch{ raw, pc, predicted, rs1: T::zero(), rs2: T::zero(), result: None,
+          access: None, ready: 0, prv: self.privilege })
+      },
+      Err(_) => PipelineEntry::Exc(Exceptions::InstrAccessFault, pc, pc),
+    };
+    if let PipelineEntry::Instr(l) = p[Phases::IF] {
+      if !p.done() { self.regs.assign_pc(l.predicted) };
+    };
   }
 }
 
+#[cfg(test)]
+use crate::cache::Cache;
+
+#[test]
+fn test_precise_traps() {
+  let ps = in_order(super::load_words(&super::TRAP_PROGRAM)).unwrap();
+  assert_eq!(ps.status, Status::Done);
+  assert_eq!(ps.regs[8], 4 + 2);
+  assert_eq!(ps.regs[9], 3);
+  // the instructions behind each fault were flushed, and ran again after the handler
+  assert_eq!(ps.regs[11], 0);
+  assert_eq!(ps.regs[12], 7);
+  assert_eq!(ps.csrs.instret, 3 + 2 * 8 + 1);
+}
 
+#[test]
+fn test_load_use() {
+  // test/load_use.asm
+  let program = [
+    0x01c00e93, // li t4, 28
+    0x000ea283, // lw t0, 0(t4)
+    0x04428393, // addi t2, t0, 68
+    0x00539663, // bne t2, t0, next
+    0x12c00313, // li t1, 300
+    0x01031313, // slli t1, t1, 16
+    InstrType::halt_val(), // next:
+    0x24,
+  ];
+  let run = |forwarding| {
+    let config = InOrderConfig{ forwarding, ..Default::default() };
+    let ps = in_order_with(super::load_words(&program), config).unwrap();
+    assert_eq!(ps.status, Status::Done);
+    assert_eq!((ps.regs[5], ps.regs[7], ps.regs[6]), (0x24, 0x68, 0));
+    ps
+  };
+  // lw to addi waits for MEM, addi to bne waits for EX
+  let ps = run(true);
+  assert_eq!((ps.stats.load_use_stalls, ps.stats.raw_stalls), (1, 1));
+  // without forwarding everything waits for writeback, including the address of the lw
+  let slow = run(false);
+  assert_eq!((slow.stats.load_use_stalls, slow.stats.raw_stalls), (2, 4));
+  assert_eq!(slow.csrs.cycle, ps.csrs.cycle + 4);
+  // the lw misses in the D-cache, holding everything behind MEM until memory answers
+  let mut cached = super::load_words(&program);
+  cached.caches.dcache = Some(Cache::l1("size=256,line=16,latency=2"));
+  cached.caches.dram_latency = 10;
+  let cached = in_order(cached).unwrap();
+  assert_eq!((cached.regs[5], cached.regs[7]), (0x24, 0x68));
+  assert_eq!(cached.stats.memory_stalls, 2 + 10 - 1);
+  assert_eq!(cached.csrs.cycle, ps.csrs.cycle + 2 + 10 - 1);
+  // caches with no latency add nothing once they hit
+  let mut free = super::load_words(&program);
+  free.caches.icache = Some(Cache::l1("size=256,line=16,latency=0"));
+  free.caches.dcache = Some(Cache::l1("size=256,line=16,latency=0"));
+  free.caches.dram_latency = 0;
+  let free = in_order(free).unwrap();
+  assert_eq!((free.regs[5], free.regs[7]), (0x24, 0x68));
+  assert_eq!(free.csrs.cycle, ps.csrs.cycle);
+}
 
+#[test]
+fn test_control_flow() {
+  // test/control_flow.asm
+  let program = [
+    0x00000293, 0x00188893, 0x00088313, 0x00100493, // main
+    0x02028263, 0x00188893, 0x00088e13, 0x00300993, 0x028000ef, // beg
+    0x00188893, 0x00088f13, 0x00500a93, 0x0280006f,
+    0xfff00293, 0x00188893, 0x00088393, 0x00200913, 0xfcdff06f, // hit
+    0x00188893, 0x00088e93, 0x00400a13, 0x00008067, // fn
+    0x00188893, 0x00088f93, 0x00188893, 0x00600b13, 0x00000013, 0x00000013, // done
+    InstrType::halt_val(),
+  ];
+  let run = |branch_stage, predictor| {
+    let config = InOrderConfig{ branch_stage, ..Default::default() };
+    let mut ps = super::load_words(&program);
+    ps.predictor = crate::predictor::from_name(predictor).unwrap();
+    let ps = in_order_with(ps, config).unwrap();
+    assert_eq!(ps.status, Status::Done);
+    // t1-t6 and s1-s6 are set in order, and nothing on a path not taken ran
+    assert_eq!([6, 7, 28, 29, 30, 31].map(|r| ps.regs[r]), [1, 2, 3, 4, 5, 6]);
+    assert_eq!([9, 18, 19, 20, 21, 22].map(|r| ps.regs[r]), [1, 2, 3, 4, 5, 6]);
+    assert_eq!(ps.regs[17], 7);
+    ps
+  };
+  // the taken beq, j beg, jal fn, ret and j done each fetch one instruction too many
+  let id = run(BranchStage::ID, "not-taken");
+  assert_eq!((id.stats.branches, id.stats.mispredicts, id.stats.mispredict_penalty), (6, 5, 5));
+  // and the beq and ret two when they resolve in EX
+  let ex = run(BranchStage::EX, "not-taken");
+  assert_eq!((ex.stats.mispredicts, ex.stats.mispredict_penalty), (5, 7));
+  assert_eq!(ex.csrs.cycle, id.csrs.cycle + 2);
+  // following jumps from fetch leaves the forward beq and the ret
+  let btfn = run(BranchStage::ID, "btfn");
+  assert_eq!((btfn.stats.mispredicts, btfn.stats.mispredict_penalty), (2, 2));
+  assert_eq!(btfn.csrs.cycle, id.csrs.cycle - 3);
+}
@@ -0,0 +1,124 @@
+// Checks an engine against the normal one, which runs alongside it one retired instruction at a
+// time, stopping the engine at the first difference
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::csr;
+use crate::disasm::{disassemble, Syntax};
+use crate::instr::{decode, InstrType, IInstr};
+use crate::rvc;
+use crate::program_state::{ProgramState, Status};
+use crate::reg::RegData;
+use crate::trace::{Retired, RetireHook};
+use super::step;
+
+#[derive(Debug)]
+pub struct Lockstep<T : RegData> {
+  reference: ProgramState<T>,
+  // description of the first divergence, shared with whoever started the check
+  divergence: Rc<RefCell<Option<String>>>,
+}
+
+impl <T : RegData> Lockstep<T> {
+  // Starts the reference from a copy of ps, returning the checker to install on ps and where
+  // the divergence will be reported
+  pub fn new(ps: &ProgramState<T>) -> (Self, Rc<RefCell<Option<String>>>) {
+    let mut reference = ProgramState::new(ps.mem.clone());
+    reference.regs = ps.regs.clone();
+    reference.fregs = ps.fregs.clone();
+    reference.vregs = ps.vregs.clone();
+    reference.csrs = ps.csrs.clone();
+    reference.privilege = ps.privilege;
+    let divergence = Rc::new(RefCell::new(None));
+    (Lockstep{ reference, divergence: divergence.clone() }, divergence)
+  }
+
+  // What the reference retires next, or None if it stopped
+  fn expect(&mut self, ps: &ProgramState<T>, event: &Retired<T>) -> Option<Retired<T>> {
+    let r = &mut self.reference;
+    if let Retired::Commit{ pc, raw, .. } = *event {
+      // system calls only run on the checked engine, the reference takes on their effects
+      if matches!(decode(raw), Ok(InstrType::I{ var: IInstr::ECALL, .. }))
+        && ps.syscalls.is_some() && r.regs.pc() == pc {
+        (1..32).for_each(|i| r.regs.force_assign(i, ps.regs[i]));
+        r.mem.data.clone_from(&ps.mem.data);
+        r.regs.assign_pc(rvc::next_pc(pc, raw));
+        r.csrs.instret += 1;
+        r.status = ps.status;
+        return Some(*event);
+      };
+    };
+    while r.status == Status::Running {
+      let mut expected = match step(r) { Some(e) => e, None => continue };
+      // cycle counts differ between engines, so counter reads are taken from the engine
+      if let (Retired::Commit{ raw, rd: Some((rd, v)), .. }, Retired::Commit{ rd: want, .. })
+        = (event, &mut expected) {
+        if reads_cycles(*raw) {
+          r.regs.force_assign(*rd, *v);
+          *want = Some((*rd, *v));
+        };
+      };
+      return Some(expected);
+    }
+    None
+  }
+}
+
+fn reads_cycles(raw: u32) -> bool {
+  match decode(raw) {
+    Ok(instr @ InstrType::I{ zx_imm, .. }) if instr.serializes() => matches!(zx_imm,
+      csr::CYCLE | csr::TIME | csr::MCYCLE | csr::CYCLEH | csr::TIMEH | csr::MCYCLEH),
+    _ => false,
+  }
+}
+
+impl <T : RegData> RetireHook<T> for Lockstep<T> {
+  fn retire(&mut self, ps: &mut ProgramState<T>, event: &Retired<T>) {
+    let expected = self.expect(ps, event);
+    if expected.as_ref() == Some(event) { return };
+    let pc = match *event { Retired::Commit{ pc, .. } => pc, Retired::Exception{ epc, .. } => epc };
+    let instr = match ps.mem.read_instr(pc.as_usize()) {
+      Ok(raw) => disassemble(raw, pc, Syntax::default()),
+      Err(_) => String::from("unreadable"),
+    };
+    let expected = match expected {
+      Some(e) => e.to_string(),
+      None => format!("the reference stopped with {:?}", self.reference.status),
+    };
+    *self.divergence.borrow_mut() = Some(format!(
+      "Diverged from the reference at pc {:#x} ({})\nexpected:\n{}\nfound:\n{}",
+      pc, instr, expected, event));
+    ps.status = Status::Diverged;
+  }
+}
+
+#[test]
+fn test_lockstep_agrees() {
+  let mut ps = super::load_words(&super::TRAP_PROGRAM);
+  let (checker, divergence) = Lockstep::new(&ps);
+  ps.hooks.push(Box::new(checker));
+  let ps = super::in_order(ps).unwrap();
+  assert_eq!(*divergence.borrow(), None);
+  assert_eq!(ps.status, Status::Done);
+}
+
+#[test]
+fn test_lockstep_divergence() {
+  use crate::mem::Size;
+  let mut ps = super::load_words(&[
+    0x00100293, // li t0, 1
+    0x04002503, // lw a0, 64(zero)
+    InstrType::halt_val(),
+  ]);
+  let (checker, divergence) = Lockstep::new(&ps);
+  ps.hooks.push(Box::new(checker));
+  // only the checked engine sees this
+  ps.mem.write(0x40, 5, Size::WORD).unwrap();
+  let ps = super::normal(ps).unwrap();
+  assert_eq!(ps.status, Status::Diverged);
+  assert_eq!(divergence.borrow().as_deref(), Some("\
+Diverged from the reference at pc 0x4 (lw a0, 64(zero))
+expected:
+core   0: 3 0x00000004 (0x04002503) x10 0x00000000 mem 0x00000040
+found:
+core   0: 3 0x00000004 (0x04002503) x10 0x00000005 mem 0x00000040"));
+}
@@ -1,7 +1,372 @@
 mod normal;
 mod in_order;
 mod out_of_order;
+mod lockstep;
 
 pub use self::normal::execute as normal;
-pub use self::in_order::in_order;
-pub use self::out_of_order::execute as out_of_order;
+pub use self::normal::step;
+pub use self::in_order::{in_order, in_order_with, InOrderConfig, BranchStage};
+pub use self::out_of_order::{execute as out_of_order, execute_with as out_of_order_with, OutOfOrderConfig,
+  MAX_BRANCHES};
+pub use self::lockstep::Lockstep;
+
+// Places a program at address 0 for the tests of each simulator
+#[cfg(test)]
+fn load_words(program: &[u32]) -> crate::program_state::ProgramState<u32> {
+  load_words_in(program, 0x100)
+}
+
+#[cfg(test)]
+fn load_words_in<T : crate::reg::RegData>(program: &[u32], size: usize)
+  -> crate::program_state::ProgramState<T> {
+  use crate::mem;
+  let mut memory = mem::Memory::new(size);
+  program.iter().enumerate()
+    .for_each(|(i, &w)| memory.write(i * mem::WORD_SIZE, T::from(w), mem::Size::WORD).unwrap());
+  crate::program_state::ProgramState::new(memory)
+}
+
+// The integer registers in rs, to check a run of them at once
+#[cfg(test)]
+fn regs<T : crate::reg::RegData>(ps: &crate::program_state::ProgramState<T>,
+  rs: std::ops::RangeInclusive<u32>) -> Vec<T> {
+  rs.map(|r| ps.regs[r]).collect()
+}
+
+// Runs a program on every simulator, checking the state each one finishes in, and returns the
+// commit log after making sure they all retired the same instructions
+#[cfg(test)]
+fn run_all<T : crate::reg::RegData, F : Fn(crate::program_state::ProgramState<T>)>(program: &[u32],
+  check: F) -> String {
+  use crate::trace::{SharedBuf, Tracer};
+  let runs: [fn(_) -> Result<_, ()>; 3] = [normal, in_order, out_of_order];
+  let logs: Vec<String> = runs.iter().map(|run| {
+    let buf = SharedBuf::default();
+    let mut ps = load_words_in::<T>(program, 0x800);
+    ps.hooks.push(Box::new(Tracer::new(Box::new(buf.clone()), false)));
+    check(run(ps).unwrap());
+    buf.text()
+  }).collect();
+  assert!(logs.iter().all(|log| *log == logs[0]));
+  logs[0].clone()
+}
+
+// Takes a misaligned load and an illegal instruction, with a handler that sums mcause into s0
+// and mtval into s1 before skipping the faulting instruction
+#[cfg(test)]
+const TRAP_PROGRAM: [u32; 15] = [
+  0x01c00293, // li t0, handler
+  0x30529073, // csrw mtvec, t0
+  0x00200513, // li a0, 2
+  0x00152583, // lw a1, 1(a0)
+  0x00000000, // illegal
+  0x00700613, // li a2, 7
+  crate::instr::InstrType::halt_val(),
+  0x34202373, // handler: csrr t1, mcause
+  0x343023f3, // csrr t2, mtval
+  0x00640433, // add s0, s0, t1
+  0x007484b3, // add s1, s1, t2
+  0x34102e73, // csrr t3, mepc
+  0x004e0e13, // addi t3, t3, 4
+  0x341e1073, // csrw mepc, t3
+  0x30200073, // mret
+];
+
+#[test]
+fn test_traces_match() {
+  let log = run_all::<u32, _>(&TRAP_PROGRAM, |_| ());
+  let lines: Vec<&str> = log.lines().collect();
+  assert_eq!(lines[0], "core   0: 3 0x00000000 (0x01c00293) x5  0x0000001c");
+  assert_eq!(lines[3], "core   0: exception trap_load_address_misaligned, epc 0x0000000c");
+  assert_eq!(lines[4], "core   0:           tval 0x00000003");
+  assert_eq!(lines.last(), Some(&"core   0: 3 0x00000014 (0x00700613) x12 0x00000007"));
+}
+
+// test/privilege.asm, where M mode starts an S mode kernel that runs a U mode program, whose
+// ecall is delegated to the kernel and whose read of mstatus traps to M mode
+#[test]
+fn test_privilege() {
+  use crate::program_state::{Status, Privilege};
+  let program = [
+    0x06400293, // li t0, 0x64
+    0x30529073, // csrw mtvec, t0
+    0x04c00293, // li t0, 0x4c
+    0x10529073, // csrw stvec, t0
+    0x10000293, // li t0, 0x100
+    0x30229073, // csrw medeleg, t0
+    0x000012b7, // lui t0, 1
+    0x3002b073, // csrc mstatus, t0
+    0x02c00293, // li t0, 0x2c
+    0x34129073, // csrw mepc, t0
+    0x30200073, // mret
+    0x10500073, // kernel: wfi
+    0x03c00293, // li t0, 0x3c
+    0x14129073, // csrw sepc, t0
+    0x10200073, // sret
+    0x00500513, // user: li a0, 5
+    0x00000073, // ecall
+    0x300025f3, // csrr a1, mstatus
+    0x00700613, // li a2, 7
+    0x14202473, // strap: csrr s0, scause
+    0x14102373, // csrr t1, sepc
+    0x00430313, // addi t1, t1, 4
+    0x14131073, // csrw sepc, t1
+    0x00150513, // addi a0, a0, 1
+    0x10200073, // sret
+    0x342024f3, // mtrap: csrr s1, mcause
+    0x30002973, // csrr s2, mstatus
+    crate::instr::InstrType::halt_val(),
+  ];
+  let log = run_all::<u32, _>(&program, |ps| {
+    assert_eq!((ps.status, ps.privilege), (Status::Done, Privilege::Machine));
+    assert_eq!([ps.regs[10], ps.regs[12], ps.regs[8], ps.regs[9], ps.regs[18]], [6, 0, 8, 2, 0x20]);
+    assert_eq!((ps.csrs.mepc, ps.csrs.mtval), (0x44, 0x300025f3));
+  });
+  let lines: Vec<&str> = log.lines().collect();
+  assert_eq!(lines[10], "core   0: 3 0x00000028 (0x30200073)");
+  assert_eq!(lines[11], "core   0: 1 0x0000002c (0x10500073)");
+  assert_eq!(lines[15], "core   0: 0 0x0000003c (0x00500513) x10 0x00000005");
+  assert_eq!(lines[16], "core   0: exception trap_user_ecall, epc 0x00000040");
+}
+
+#[test]
+fn test_rv64() {
+  use crate::program_state::{Status, Exceptions};
+  let program = [
+    0xfff00513, // li a0, -1
+    0x02055593, // srli a1, a0, 32
+    0x02459613, // slli a2, a1, 36
+    0x0015869b, // addiw a3, a1, 1
+    0x00b5873b, // addw a4, a1, a1
+    0x08000293, // li t0, 128
+    0x00c2b023, // sd a2, 0(t0)
+    0x0002b783, // ld a5, 0(t0)
+    0x0042a803, // lw a6, 4(t0)
+    0x0042e883, // lwu a7, 4(t0)
+    0x4048541b, // sraiw s0, a6, 4
+    0x800004b7, // lui s1, 0x80000
+    0x40b0093b, // subw s2, zero, a1
+    0x02b589bb, // mulw s3, a1, a1
+    0x00b55a33, // srl s4, a0, a1
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u64, _>(&program, |ps| {
+    assert_eq!(ps.status, Status::Done);
+    assert_eq!(regs(&ps, 8..=20), [
+      u64::MAX, 0xffffffff80000000, // s0, s1
+      u64::MAX, 0xffffffff, 0xfffffff000000000, 0, u64::MAX - 1, 0xfffffff000000000, u64::MAX - 15,
+      0xfffffff0, // a0-a7
+      1, 1, 1, // s2-s4
+    ]);
+  });
+  // shifting by 32 is already illegal in RV32
+  let ps = normal(load_words_in::<u32>(&program, 0x100)).unwrap();
+  assert_eq!(ps.status, Status::Exception(Exceptions::IllegalInstruction));
+  assert_eq!(ps.csrs.instret, 1);
+}
+
+// test/llsc.asm, where sc.w writes 0 to rd when it stores and 1 when it fails
+#[test]
+fn test_llsc() {
+  use crate::mem::Size;
+  let program = [
+    0x02a00293, // li t0, 42
+    0x40000413, // li s0, 1024
+    0x185422af, // sc.w t0, t0, (s0)
+    0x00042283, // lw t0, 0(s0)
+    0x02a00293, // li t0, 42
+    0x00542223, // sw t0, 4(s0)
+    0x00440493, // addi s1, s0, 4
+    0x1004a2af, // lr.w t0, (s1)
+    0x04500293, // li t0, 69
+    0x1854a2af, // sc.w t0, t0, (s1)
+    0x00442303, // lw t1, 4(s0)
+    0x02a00293, // li t0, 42
+    0x1854a2af, // sc.w t0, t0, (s1)
+    0x00442383, // lw t2, 4(s0)
+    0x00840913, // addi s2, s0, 8
+    0x00c40993, // addi s3, s0, 12
+    0x00642423, // sw t1, 8(s0)
+    0x1009232f, // lr.w t1, (s2)
+    0x1869a32f, // sc.w t1, t1, (s3)
+    0x00c42e03, // lw t3, 12(s0)
+    0x00d00293, // li t0, 13
+    0x00542023, // sw t0, 0(s0)
+    0x10042eaf, // lr.w t4, (s0)
+    0x1004af2f, // lr.w t5, (s1)
+    0x19e42f2f, // sc.w t5, t5, (s0)
+    0x19d4aeaf, // sc.w t4, t4, (s1)
+    0x100923af, // lr.w t2, (s2)
+    0x00100f93, // li t6, 1
+    0x01f41423, // sh t6, 8(s0)
+    0x187923af, // sc.w t2, t2, (s2)
+    0x10092faf, // lr.w t6, (s2)
+    0x007405a3, // sb t2, 11(s0)
+    0x19f92faf, // sc.w t6, t6, (s2)
+    0xffb00513, // li a0, -5
+    0x0ca925af, // amoswap.w.aq a1, a0, (s2)
+    0x00b9262f, // amoadd.w a2, a1, (s2)
+    0x82a926af, // amomin.w.rl a3, a0, (s2)
+    0xe6a9272f, // amomaxu.w.aqrl a4, a0, (s2)
+    0x00842783, // lw a5, 8(s0)
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u32, _>(&program, |ps| {
+    assert_eq!(regs(&ps, 5..=7), [13, 1, 1]); // t0-t2
+    assert_eq!(regs(&ps, 28..=31), [0, 1, 1, 1]); // t3-t6
+    assert_eq!(regs(&ps, 10..=15), [-5i32 as u32, 0x01000001, -5i32 as u32, 0x00fffffc,
+      -5i32 as u32, -5i32 as u32]); // a0-a5
+    let word = |loc: usize| ps.mem.read(1024 + loc, Size::WORD).unwrap();
+    assert_eq!([word(0), word(4), word(12)], [13, 69, 0]);
+  });
+}
+
+// Fences retire without doing anything, as one hart always sees its own memory in order
+#[test]
+fn test_fence() {
+  use crate::program_state::Status;
+  let program = [
+    0x02a00293, // li t0, 42
+    0x10502023, // sw t0, 256(zero)
+    0x0ff0000f, // fence
+    0x10002303, // lw t1, 256(zero)
+    0x0330000f, // fence rw, rw
+    0x0000100f, // fence.i
+    0x00130313, // addi t1, t1, 1
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u32, _>(&program, |ps| {
+    assert_eq!((ps.status, ps.regs[6]), (Status::Done, 43));
+    assert_eq!(ps.csrs.instret, 7);
+  });
+}
+
+#[test]
+fn test_float() {
+  let program = [
+    0x40000413, // li s0, 1024
+    0x00300293, // li t0, 3
+    0xd2028553, // fcvt.d.w fa0, t0
+    0x00100293, // li t0, 1
+    0xd20285d3, // fcvt.d.w fa1, t0
+    0x1aa5f653, // fdiv.d fa2, fa1, fa0
+    0x00c43027, // fsd fa2, 0(s0)
+    0x00042503, // lw a0, 0(s0)
+    0x00442583, // lw a1, 4(s0)
+    0x00102673, // csrr a2, fflags
+    0x00101073, // csrw fflags, zero
+    0x5a0576d3, // fsqrt.d fa3, fa0
+    0x12d6f753, // fmul.d fa4, fa3, fa3
+    0xa2a726d3, // feq.d a3, fa4, fa0
+    0xa2e50753, // fle.d a4, fa0, fa4
+    0x401677d3, // fcvt.s.d fa5, fa2
+    0x00f42427, // fsw fa5, 8(s0)
+    0x00842807, // flw fa6, 8(s0)
+    0xe00807d3, // fmv.x.w a5, fa6
+    0x0020d073, // csrwi frm, 1
+    0xc2077853, // fcvt.w.d a6, fa4
+    0xff900313, // li t1, -7
+    0xd0037053, // fcvt.s.w ft0, t1
+    0x78f070c3, // fmadd.s ft1, ft0, fa5, fa5
+    0xc00088d3, // fcvt.w.s a7, ft1, rne
+    0xe00014d3, // fclass.s s1, ft0
+    0x20001153, // fneg.s ft2, ft0
+    0xc0117953, // fcvt.wu.s s2, ft2
+    0x282001d3, // fmin.s ft3, ft0, ft2
+    0xe00189d3, // fmv.x.w s3, ft3
+    0x00302a73, // csrr s4, fcsr
+    0x00043407, // fld fs0, 0(s0)
+    0xa2c42ad3, // feq.d s5, fs0, fa2
+    0x00101073, // csrw fflags, zero
+    0x08007253, // fsub.s ft4, ft0, ft0
+    0x184272d3, // fdiv.s ft5, ft4, ft4
+    0xa0029b53, // flt.s s6, ft5, ft0
+    0xa052acd3, // feq.s s9, ft5, ft5
+    0x00102bf3, // csrr s7, fflags
+    0xe0028c53, // fmv.x.w s8, ft5
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u32, _>(&program, |ps| {
+    // 1/3 stored as a double, and sqrt(3) squared coming out just below 3
+    assert_eq!(regs(&ps, 10..=17),
+      [0x55555555, 0x3fd55555, 1, 0, 0, 0x3eaaaaab, 2, -2i32 as u32]); // a0-a7
+    assert_eq!(regs(&ps, 18..=25), [7, 0xc0e00000, 0x21, 1, 0, 0x10, 0x7fc00000, 0]); // s2-s9
+    assert_eq!(ps.regs[9], 1 << 1); // s1, a negative normal number
+    assert_eq!(ps.fregs[14], 0x4007ffffffffffff); // fa4
+    assert_eq!(ps.fregs[1], 0xffffffff_c0000000); // ft1, boxed -2.0
+  });
+}
+
+#[test]
+fn test_float_traps() {
+  let program = [
+    0x01c00293, // li t0, handler
+    0x30529073, // csrw mtvec, t0
+    0x0022d073, // csrwi frm, 5
+    0x00007053, // fadd.s ft0, ft0, ft0, with a reserved rounding mode in frm
+    0x00200513, // li a0, 2
+    0x00152087, // flw ft1, 1(a0)
+    crate::instr::InstrType::halt_val(),
+  ];
+  let program: Vec<u32> = program.iter().chain(TRAP_PROGRAM[7..].iter()).copied().collect();
+  run_all::<u32, _>(&program, |ps| {
+    // the illegal instruction reports its encoding and the load its address
+    assert_eq!([ps.regs[8], ps.regs[9]], [2 + 4, 0x00007053 + 3]);
+  });
+}
+
+#[test]
+fn test_bitmanip() {
+  let program = [
+    0x00001537, // lui a0, 1
+    0x23450513, // addi a0, a0, 564
+    0xffd00593, // li a1, -3
+    0x20a54633, // sh2add a2, a0, a0
+    0x0aa5c6b3, // min a3, a1, a0
+    0x0aa5d733, // minu a4, a1, a0
+    0x60259793, // cpop a5, a1
+    0x60151813, // ctz a6, a0
+    0x69855893, // rev8 a7, a0
+    0x60455293, // rori t0, a0, 4
+    0x29f01313, // bseti t1, zero, 31
+    0x4905d3b3, // bext t2, a1, a6
+    0x0aa51e33, // clmul t3, a0, a0
+    0x28765e93, // orc.b t4, a2
+    0x40a5ff33, // andn t5, a1, a0
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u32, _>(&program, |ps| {
+    assert_eq!(regs(&ps, 10..=17),
+      [0x1234, 0xffff_fffd, 0x5b04, 0xffff_fffd, 0x1234, 31, 2, 0x3412_0000]); // a0-a7
+    assert_eq!(regs(&ps, 5..=7), [0x4000_0123, 0x8000_0000, 1]); // t0-t2
+    assert_eq!(regs(&ps, 28..=30), [0x0104_0510, 0xffff, 0xffff_edc9]); // t3-t5
+    assert_eq!(ps.csrs.instret, 15);
+  });
+}
+
+#[test]
+fn test_compressed() {
+  // halfwords packed two to a word, so the addi and auipc straddle word boundaries
+  let program = [
+    0x45a94501, // c.li a0, 0; c.li a1, 10
+    0x15fd952e, // loop: c.add a0, a1; c.addi a1, -1
+    0x0113fdf5, // c.bnez a1, loop; addi sp, zero, 1024 ...
+    0x28194000, // ... ; c.jal func
+    0x06450613, // addi a2, a0, 100
+    0xc22a8686, // c.mv a3, ra; c.swsp a0, 4(sp)
+    0x02974712, // c.lwsp a4, 4(sp); auipc t0, 0 ...
+    0x02b90000, // ... ; c.addi t0, 14
+    0xa0299282, // c.jalr t0; c.j end
+    0x80820506, // func: c.slli a0, 1; c.jr ra
+    0x87828786, // c.mv a5, ra; c.jr a5
+    0x00010001, // end: c.nop; c.nop to align the halt
+    crate::instr::InstrType::halt_val(),
+  ];
+  run_all::<u32, _>(&program, |ps| {
+    // links point 2 bytes past c.jal and c.jalr
+    assert_eq!(regs(&ps, 10..=15), [110, 0, 210, 0x10, 110, 0x22]); // a0-a5
+    assert_eq!(ps.regs[5], 0x28); // t0
+    assert_eq!(ps.regs.pc(), 0x30);
+    assert_eq!(ps.csrs.instret, 48);
+  });
+}
@@ -1,79 +1,163 @@
 use crate::mem;
+use crate::rvc;
+use crate::bitmanip;
 use crate::program_state::{ProgramState, Status, Exceptions};
 use crate::reg::{RegData};
 use crate::instr::{self, InstrType};
+use crate::trace::{mem_access, MemAccess, Retired};
 
+#[allow(clippy::result_unit_err)]
 pub fn execute<T : RegData>(mut ps: ProgramState<T>) -> Result<ProgramState<T>, ()> {
-  while ps.status == Status::Running { ps = run_instr(ps); }
+  while ps.status == Status::Running { step(&mut ps); }
   Ok(ps)
 }
 
-fn run_instr<T : RegData>(mut ps: ProgramState<T>) -> ProgramState<T> {
-  let raw = ps.mem.read_instr(ps.regs.pc().as_usize())
-    .unwrap_or_else(|_| panic!("Failed to read instr at {}", ps.regs.pc()));
-  let instr = instr::decode(raw).unwrap();
-  // println!("{:?}", instr);
+// Runs a single instruction, which takes one cycle, returning what it retired
+pub fn step<T : RegData>(ps: &mut ProgramState<T>) -> Option<Retired<T>> {
+  let retired = run_instr(ps);
+  ps.csrs.cycle += 1;
+  retired
+}
+
+fn run_instr<T : RegData>(ps: &mut ProgramState<T>) -> Option<Retired<T>> {
+  let pc = ps.regs.pc();
+  match fetch_and_exec(ps, pc) {
+    Ok(retired) => retired,
+    Err((e, tval)) => {
+      ps.trap(e, pc, tval);
+      Some(Retired::Exception{ e, epc: pc, tval })
+    },
+  }
+}
+
+// Runs and retires the instruction at pc, halting does not retire anything
+fn fetch_and_exec<T : RegData>(ps: &mut ProgramState<T>, pc: T)
+  -> Result<Option<Retired<T>>, (Exceptions, T)> {
+  let raw = ps.mem.read_instr(pc.as_usize()).map_err(|_| (Exceptions::InstrAccessFault, pc))?;
+  ps.fetch_latency(pc);
+  // illegal instructions report their encoding
+  let illegal = (Exceptions::IllegalInstruction, T::from(raw));
+  let instr = instr::decode_xlen(raw, T::BYTE_SIZE * 8).map_err(|_| illegal)?;
+  let access = mem_access(&instr, |r| ps.regs[r]);
+  let prv = ps.privilege;
+  // while executing the pc already points to the next instruction, jumps overwrite it
+  ps.regs.inc_pc(rvc::instr_len(raw));
+  let access = exec(ps, pc, instr)
+    .map_err(|(e, tval)| if e == illegal.0 { illegal } else { (e, tval) })?.or(access);
+  if let Some(access) = access { ps.data_latency(access); };
+  if ps.status == Status::Done { return Ok(None) };
+  ps.csrs.instret += 1;
+  Ok(Some(ps.commit(pc, raw, prv, &instr, access)))
+}
+
+// Executes a single instruction at pc, faults return the exception and its mtval without
+// having changed any register or memory. Atomics and FP loads and stores return the memory they
+// accessed, which is only known once they have run.
+fn exec<T : RegData>(ps: &mut ProgramState<T>, pc: T, instr: InstrType)
+  -> Result<Option<MemAccess<T>>, (Exceptions, T)> {
+  // control transfers must land on an instruction boundary
+  let jump = |ps: &mut ProgramState<T>, target: T| {
+    if !target.as_usize().is_multiple_of(rvc::IALIGN) { return Err((Exceptions::InstrMisaligned, target)) };
+    ps.regs.assign_pc(target);
+    Ok(())
+  };
   match instr {
     instr::InstrType::Halt => {
+      ps.regs.assign_pc(pc);
       ps.status = Status::Done;
-      return ps
     },
     instr::InstrType::R{ var: r, rs1, rs2, rd } => {
       use crate::instr::RInstr;
       let result = match r {
-        RInstr::ADD => T::from_signed(ps.sx(ps.regs[rs1]) + ps.sx(ps.regs[rs2])),
-        RInstr::SUB => T::from_signed(ps.sx(ps.regs[rs1]) - ps.sx(ps.regs[rs2])),
-        RInstr::SLL => ps.zx(ps.regs[rs1]) << ps.regs[rs2],
+        RInstr::ADD => ps.regs[rs1].wrapping_add(&ps.regs[rs2]),
+        RInstr::SUB => ps.regs[rs1].wrapping_sub(&ps.regs[rs2]),
+        RInstr::SLL => ps.zx(ps.regs[rs1]) << ps.regs[rs2].shamt(),
         RInstr::SLT => if ps.sx(ps.regs[rs1]) < ps.sx(ps.regs[rs2]) {T::one()} else {T::zero()},
         RInstr::SLTU => if ps.zx(ps.regs[rs1]) < ps.zx(ps.regs[rs2]) {T::one()} else {T::zero()},
         RInstr::XOR => ps.zx(ps.regs[rs1]) ^ ps.zx(ps.regs[rs2]),
-        RInstr::SRL => ps.zx(ps.regs[rs1]) >> ps.regs[rs2],
-        RInstr::SRA => T::from_signed(ps.sx(ps.regs[rs1]) >> ps.regs[rs2].to_signed()),
+        RInstr::SRL => ps.zx(ps.regs[rs1]) >> ps.regs[rs2].shamt(),
+        RInstr::SRA => T::from_signed(ps.sx(ps.regs[rs1]) >> ps.regs[rs2].shamt().to_signed()),
         RInstr::OR => ps.zx(ps.regs[rs1]) | ps.zx(ps.regs[rs2]),
         RInstr::AND => ps.zx(ps.regs[rs1]) & ps.zx(ps.regs[rs2]),
         RInstr::SLLI => ps.zx(ps.regs[rs1]) << T::from(rs2),
         RInstr::SRLI => ps.zx(ps.regs[rs1]) >> T::from(rs2),
         RInstr::SRAI => T::from_signed(ps.sx(ps.regs[rs1]) >> T::from(rs2).to_signed()),
+        RInstr::MUL => ps.regs[rs1].mul_lo(ps.regs[rs2]),
+        RInstr::MULH => ps.regs[rs1].mulh(ps.regs[rs2]),
+        RInstr::MULHSU => ps.regs[rs1].mulhsu(ps.regs[rs2]),
+        RInstr::MULHU => ps.regs[rs1].mulhu(ps.regs[rs2]),
+        RInstr::DIV => ps.regs[rs1].div_s(ps.regs[rs2]),
+        RInstr::DIVU => ps.regs[rs1].div_u(ps.regs[rs2]),
+        RInstr::REM => ps.regs[rs1].rem_s(ps.regs[rs2]),
+        RInstr::REMU => ps.regs[rs1].rem_u(ps.regs[rs2]),
+        RInstr::ADDW => ps.regs[rs1].wrapping_add(&ps.regs[rs2]).sext_w(),
+        RInstr::SUBW => ps.regs[rs1].wrapping_sub(&ps.regs[rs2]).sext_w(),
+        RInstr::SLLW => (ps.regs[rs1] << ps.regs[rs2].shamt_w()).sext_w(),
+        RInstr::SRLW => (ps.regs[rs1].zext_w() >> ps.regs[rs2].shamt_w()).sext_w(),
+        RInstr::SRAW => T::from_signed(ps.sx(ps.regs[rs1].sext_w()) >> ps.regs[rs2].shamt_w().to_signed()),
+        RInstr::SLLIW => (ps.regs[rs1] << T::from(rs2)).sext_w(),
+        RInstr::SRLIW => (ps.regs[rs1].zext_w() >> T::from(rs2)).sext_w(),
+        RInstr::SRAIW => T::from_signed(ps.sx(ps.regs[rs1].sext_w()) >> T::from(rs2).to_signed()),
+        RInstr::MULW => ps.regs[rs1].mul_lo(ps.regs[rs2]).sext_w(),
+        RInstr::DIVW => ps.regs[rs1].sext_w().div_s(ps.regs[rs2].sext_w()).sext_w(),
+        RInstr::DIVUW => ps.regs[rs1].zext_w().div_u(ps.regs[rs2].zext_w()).sext_w(),
+        RInstr::REMW => ps.regs[rs1].sext_w().rem_s(ps.regs[rs2].sext_w()).sext_w(),
+        RInstr::REMUW => ps.regs[rs1].zext_w().rem_u(ps.regs[rs2].zext_w()).sext_w(),
+        RInstr::Zb(zb) => {
+          // unary and immediate forms keep something other than a register in rs2
+          let b = if zb.unary() || zb.immediate() { T::zero() } else { ps.regs[rs2] };
+          bitmanip::exec(zb, ps.regs[rs1], b, rs2)
+        },
       };
       ps.regs.force_assign(rd, result);
     },
-    InstrType::I{ var: i, rs1, rd, sx_imm: sx, zx_imm: zx } => {
+    InstrType::I{ var: i, rs1, rd, sx_imm: sx, zx_imm: csr } => {
       use crate::instr::IInstr;
-      let (sx_imm, zx_imm) = (T::Signed::from(sx), T::from(zx));
+      let sx_imm = T::Signed::from(sx);
+      // the immediate is sign extended even for unsigned comparisons and logical ops
+      let imm = T::from_signed(sx_imm);
+      let addr = ps.regs[rs1].offset(sx_imm);
+      let load_fault = |e| (e, addr);
       let result = match i {
-        IInstr::ADDI => T::from_signed(ps.sx(ps.regs[rs1]) + sx_imm),
+        IInstr::ADDI => ps.regs[rs1].offset(sx_imm),
         IInstr::SLTI => if ps.sx(ps.regs[rs1]) < sx_imm { T::one() } else { T::zero() },
-        IInstr::SLTIU => if ps.zx(ps.regs[rs1]) < zx_imm { T::one() } else { T::zero() },
-        IInstr::XORI => ps.zx(ps.regs[rs1]) ^ zx_imm,
-        IInstr::ORI => ps.zx(ps.regs[rs1]) | zx_imm,
-        IInstr::ANDI => ps.zx(ps.regs[rs1]) & zx_imm,
+        IInstr::SLTIU => if ps.zx(ps.regs[rs1]) < imm { T::one() } else { T::zero() },
+        IInstr::XORI => ps.zx(ps.regs[rs1]) ^ imm,
+        IInstr::ORI => ps.zx(ps.regs[rs1]) | imm,
+        IInstr::ANDI => ps.zx(ps.regs[rs1]) & imm,
+        IInstr::ADDIW => ps.regs[rs1].offset(sx_imm).sext_w(),
         IInstr::JALR => {
-          let curr_pc = ps.regs.pc();
-          ps.regs.assign_pc(
-            T::from_signed((ps.sx(ps.regs[rs1]) + sx_imm) & T::Signed::from(-2))
-          );
-          curr_pc
+          let link = ps.regs.pc();
+          jump(ps, addr & T::from_signed(T::Signed::from(-2)))?;
+          link
+        },
+        IInstr::LD => ps.load(addr, mem::Size::DOUBLE, false).map_err(load_fault)?,
+        IInstr::LWU => ps.load(addr, mem::Size::WORD, false).map_err(load_fault)?,
+        IInstr::LW => ps.load(addr, mem::Size::WORD, true).map_err(load_fault)?,
+        IInstr::LHU => ps.load(addr, mem::Size::HALF, false).map_err(load_fault)?,
+        IInstr::LBU => ps.load(addr, mem::Size::BYTE, false).map_err(load_fault)?,
+        IInstr::LH => ps.load(addr, mem::Size::HALF, true).map_err(load_fault)?,
+        IInstr::LB => ps.load(addr, mem::Size::BYTE, true).map_err(load_fault)?,
+        IInstr::ECALL => {
+          ps.ecall().map_err(|e| (e, T::zero()))?;
+          ps.regs[rd]
         },
-        IInstr::LW =>
-          ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::WORD)
-            .unwrap_or_else(|_| ps.regs[rd]),
-        IInstr::LH =>
-          ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::HALF)
-            .unwrap_or_else(|_| ps.regs[rd]),
-        IInstr::LB =>
-          ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::BYTE)
-            .unwrap_or_else(|_| ps.regs[rd]),
-        IInstr::LHU =>
-          ps.mem.read_signed(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm)
-            .as_usize(), mem::Size::HALF)
-            .map(|s| T::from_signed(s))
-            .unwrap_or_else(|_| ps.regs[rd]),
-        IInstr::LBU =>
-          ps.mem.read_signed(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm)
-          .as_usize(), mem::Size::BYTE)
-            .map(|s| T::from_signed(s))
-            .unwrap_or_else(|_| ps.regs[rd]),
-        v => panic!("Unimplemented {:?}", v),
+        IInstr::EBREAK => return Err((Exceptions::Breakpoint, pc)),
+        // memory is only ever accessed in program order
+        IInstr::FENCE | IInstr::FENCEI => ps.regs[rd],
+        IInstr::MRET | IInstr::SRET | IInstr::WFI | IInstr::SFENCEVMA => {
+          let ret = match i {
+            IInstr::MRET => ps.mret(),
+            IInstr::SRET => ps.sret(),
+            IInstr::WFI => ps.wfi(),
+            _ => ps.sfence_vma(),
+          };
+          ret.map_err(|e| (e, T::zero()))?;
+          ps.regs[rd]
+        },
+        IInstr::CSRRW | IInstr::CSRRS | IInstr::CSRRC
+          | IInstr::CSRRWI | IInstr::CSRRSI | IInstr::CSRRCI =>
+          ps.csr_op(i, csr, rs1, ps.regs[rs1]).map_err(|e| (e, T::zero()))?,
       };
       ps.regs.force_assign(rd, result);
     },
@@ -83,12 +167,27 @@ fn run_instr<T : RegData>(mut ps: ProgramState<T>) -> ProgramState<T> {
         SInstr::SB => mem::Size::BYTE,
         SInstr::SH => mem::Size::HALF,
         SInstr::SW => mem::Size::WORD,
+        SInstr::SD => mem::Size::DOUBLE,
       };
-      if let Err(e) = ps.mem.write((ps.regs[rs1] + T::from(imm)).as_usize(),
-        ps.regs[rs2], size) {
-          println!("{:?}", e);
-          ps.status = Status::Exception(Exceptions::Mem);
-      };
+      let addr = ps.regs[rs1].offset(T::Signed::from(imm));
+      let loc = ps.store_addr(addr, size).map_err(|e| (e, addr))?;
+      ps.store(loc, ps.regs[rs2], size);
+    },
+    InstrType::A{ var, rs1, rs2, rd, size, .. } => {
+      let addr = ps.regs[rs1];
+      let (result, access) = ps.atomic(var, addr, size, ps.regs[rs2]).map_err(|e| (e, addr))?;
+      ps.regs.force_assign(rd, result);
+      return Ok(access)
+    },
+    InstrType::F{ rd, rs1, .. } => {
+      let (result, access) = ps.float(&instr, ps.regs[rs1])?;
+      if let Some(v) = result { ps.regs.force_assign(rd, v) };
+      return Ok(access)
+    },
+    // x registers are read whether or not the instruction has them as sources
+    InstrType::V{ vd, rs1, vs2, .. } => {
+      let result = ps.vector(&instr, ps.regs[rs1], ps.regs[vs2])?;
+      if let Some(v) = result { ps.regs.force_assign(vd, v) };
     },
     InstrType::B{ var: b, rs1, rs2, imm } => {
       use crate::instr::BInstr;
@@ -100,16 +199,14 @@ fn run_instr<T : RegData>(mut ps: ProgramState<T>) -> ProgramState<T> {
         BInstr::BLTU => ps.zx(ps.regs[rs1]) < ps.zx(ps.regs[rs2]),
         BInstr::BGEU => ps.zx(ps.regs[rs1]) >= ps.zx(ps.regs[rs2]),
       };
-      if branch {
-        ps.regs.assign_pc(ps.regs.pc().offset(T::Signed::from(imm))
-          - T::from(mem::WORD_SIZE as u32));
-      };
+      if branch { jump(ps, pc.offset(T::Signed::from(imm)))? };
     },
     InstrType::U{ var: u, rd, imm } => {
       use crate::instr::UInstr;
       let result = match u {
-        UInstr::LUI => T::from(imm),
-        UInstr::AUIPC => T::from(imm) + ps.regs.pc(),
+        // the upper immediate is sign extended in RV64
+        UInstr::LUI => T::from_signed(T::Signed::from(imm as i32)),
+        UInstr::AUIPC => pc.offset(T::Signed::from(imm as i32)),
       };
       ps.regs.force_assign(rd, result);
     },
@@ -117,15 +214,149 @@ fn run_instr<T : RegData>(mut ps: ProgramState<T>) -> ProgramState<T> {
       use crate::instr::JInstr;
       match j {
         JInstr::JAL => {
-          let pc = ps.regs.pc();
-          ps.regs.force_assign(rd, pc);
-          ps.regs.assign_pc(pc.offset(T::Signed::from(offset))
-            - T::from(mem::WORD_SIZE as u32))
+          let link = ps.regs.pc();
+          jump(ps, pc.offset(T::Signed::from(offset)))?;
+          ps.regs.force_assign(rd, link);
         },
       };
     },
   };
-  ps.regs.inc_pc();
-  return ps;
+  Ok(None)
+}
+
+#[cfg(test)]
+fn run_words(program: &[u32]) -> ProgramState<u32> { execute(super::load_words(program)).unwrap() }
+
+#[test]
+fn test_m_extension() {
+  let program = [
+    0x00700513, // li a0, 7
+    0xffe00593, // li a1, -2
+    0x02b50633, // mul a2, a0, a1
+    0x02b516b3, // mulh a3, a0, a1
+    0x02b54733, // div a4, a0, a1
+    0x02b567b3, // rem a5, a0, a1
+    0x02055833, // divu a6, a0, zero
+    0x020578b3, // remu a7, a0, zero
+    InstrType::halt_val(),
+  ];
+  let ps = run_words(&program);
+  assert_eq!(ps.status, Status::Done);
+  assert_eq!(ps.regs[12], u32::from_signed(-14));
+  assert_eq!(ps.regs[13], u32::MAX);
+  assert_eq!(ps.regs[14], u32::from_signed(-3));
+  assert_eq!(ps.regs[15], 1);
+  assert_eq!(ps.regs[16], u32::MAX);
+  assert_eq!(ps.regs[17], 7);
+}
+
+#[test]
+fn test_calls_and_negative_offsets() {
+  let program = [
+    0x01c000ef, // jal ra, f
+    0x04000513, // li a0, 64
+    0xfff00293, // li t0, -1
+    0xfe552e23, // sw t0, -4(a0)
+    0xffc54583, // lbu a1, -4(a0)
+    0xffc50603, // lb a2, -4(a0)
+    InstrType::halt_val(),
+    0x00300693, // f: li a3, 3
+    0x00008067, // ret
+  ];
+  let ps = run_words(&program);
+  assert_eq!(ps.status, Status::Done);
+  assert_eq!(ps.regs[1], 4);
+  assert_eq!(ps.regs[11], 0xff);
+  assert_eq!(ps.regs[12], u32::MAX);
+  assert_eq!(ps.regs[13], 3);
+  assert_eq!(ps.regs.pc(), 24);
 }
 
+#[test]
+fn test_ecall_exit() {
+  let program = [
+    0x00500513, // li a0, 5
+    0x05d00893, // li a7, 93
+    0x00000073, // ecall
+    InstrType::halt_val(),
+  ];
+  let mut ps = super::load_words(&program);
+  ps.syscalls = Some(Box::new(crate::syscall::LinuxSyscalls::new(0x100, None)));
+  assert_eq!(execute(ps).unwrap().status, Status::Exit(5));
+  assert_eq!(run_words(&program).status, Status::Exception(Exceptions::EcallM));
+}
+
+#[test]
+fn test_csr_counters() {
+  let program = [
+    0x00000013, // nop
+    0xc0002573, // rdcycle a0
+    0xc02025f3, // rdinstret a1
+    0x3402d073, // csrwi mscratch, 5
+    0x34006673, // csrrsi a2, mscratch, 0
+    0xf1401073, // csrw mhartid, zero
+    InstrType::halt_val(),
+  ];
+  let ps = run_words(&program);
+  assert_eq!(ps.status, Status::Exception(Exceptions::IllegalInstruction));
+  assert_eq!(ps.regs.pc(), 20);
+  assert_eq!(ps.regs[10], 1);
+  assert_eq!(ps.regs[11], 2);
+  assert_eq!(ps.regs[12], 5);
+  assert_eq!(ps.csrs.instret, 5);
+}
+
+#[test]
+fn test_trap_handler() {
+  let ps = run_words(&super::TRAP_PROGRAM);
+  assert_eq!(ps.status, Status::Done);
+  assert_eq!(ps.regs[8], 4 + 2);
+  assert_eq!(ps.regs[9], 3);
+  assert_eq!(ps.regs[11], 0);
+  assert_eq!(ps.regs[12], 7);
+  assert_eq!(ps.csrs.mepc, 20);
+}
+
+#[test]
+fn test_vector() {
+  let program = [
+    0x07000513, // li a0, 112
+    0x00800293, // li t0, 8
+    0x0d12f357, // vsetvli t1, t0, e32, m2, ta, ma
+    0x02056407, // vle32.v v8, (a0)
+    0x028fb557, // vadd.vi v10, v8, -1
+    0x96a42657, // vmul.vv v12, v10, v8
+    0x00500393, // li t2, 5
+    0x6e83c057, // vmslt.vx v0, v8, t2
+    0x5e003757, // vmv.v.i v14, 0
+    0x00c40757, // vadd.vv v14, v12, v8, v0.t
+    0x42006857, // vmv.s.x v16, zero
+    0x02e82857, // vredsum.vs v16, v14, v16
+    0x43002657, // vmv.x.s a2, v16
+    0x420826d7, // vcpop.m a3, v0
+    0x7e82b0d7, // vmsgt.vi v1, v8, 5
+    0x4218a757, // vfirst.m a4, v1
+    0x0a000593, // li a1, 160
+    0x00800e13, // li t3, 8
+    0xcd027057, // vsetivli zero, 4, e32, m1, ta, ma
+    0x0bc5e627, // vsse32.v v12, (a1), t3
+    0x0bc5e907, // vlse32.v v18, (a1), t3
+    0x0205e427, // vse32.v v8, (a1)
+    0x0c0077d7, // vsetvli a5, zero, e8, m1, ta, ma
+    0xc2002873, // csrr a6, vl
+    0xc22028f3, // csrr a7, vlenb
+    InstrType::halt_val(),
+    0, 0,
+    1, 2, 3, 4, 5, 6, 7, 8,
+  ];
+  let ps = run_words(&program);
+  assert_eq!(ps.status, Status::Done);
+  assert_eq!(ps.regs[6], 8);
+  // 1 * 1 + 2 * 2 + 3 * 3 + 4 * 4 for the elements under 5
+  assert_eq!(ps.regs[12], 30);
+  assert_eq!([ps.regs[13], ps.regs[14]], [4, 5]);
+  assert_eq!([ps.regs[15], ps.regs[16], ps.regs[17]], [16, 16, 16]);
+  let word = |loc| ps.mem.read(loc, crate::mem::Size::WORD).unwrap();
+  assert_eq!([word(0xa0), word(0xa4), word(0xa8), word(0xac), word(0xb0), word(0xb8)], [1, 2, 3, 4, 6, 12]);
+  assert_eq!((0..4).map(|i| ps.vregs.get(18, i, 4)).collect::<Vec<_>>(), [0, 2, 6, 12]);
+}
@@ -1,224 +1,967 @@
-use std::collections::{VecDeque, BinaryHeap, HashSet, HashMap};
-use crate::instr::{InstrType, decode};
-use crate::program_state::{ProgramState, Status, Exceptions};
-use crate::reg::{RegData};
-use std::cmp::Ordering;
+// Tomasulo style out of order core. Instructions are fetched down the predicted path, renamed
+// onto reorder buffer entries and wait in reservation stations until their operands are
+// broadcast on the common data bus. They commit in order from the reorder buffer, which is
+// where exceptions are taken, stores write memory and system instructions run. Everything
+// dispatched past an unresolved jump or branch carries its bit in a branch mask, so a mispredict
+// squashes exactly the work that depends on it and restores the rename table saved alongside it.
+// Loads and stores also go through load and store queues. Loads take their value from the
+// youngest older store to the same bytes, and run ahead of stores whose address is not known yet
+// unless the store sets predict they depend on one. A store that turns out to write what a
+// younger load already read replays the load and everything after it.
+// Atomics read and write memory as they commit, once everything older has, and wait in the store
+// queue until then so that younger loads of the same bytes wait for them. Loads younger than an
+// atomic with aq set do not issue before it commits.
+// F and D instructions run as they commit, where the f registers and fflags are kept, without
+// holding up dispatch. FP stores work out their address in a station first so that they wait in
+// the store queue like atomics.
+use std::collections::VecDeque;
+use crate::instr::{decode_xlen, InstrType, RInstr, IInstr, BInstr, JInstr, SInstr, UInstr, AInstr, FInstr};
 use crate::mem;
+use crate::program_state::{ProgramState, Status, Exceptions};
+use crate::reg::RegData;
+use crate::rvc;
+use crate::bitmanip;
+use crate::trace::MemAccess;
 
-#[derive(Hash, PartialEq, Eq, Debug)]
-enum OutputDirective<T : RegData> {
-  PC(T),
-  Reg(u32, T),
-  Exception(Exceptions),
-  MemStore(T, usize, mem::Size),
-  Nop,
-  Halt,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfOrderConfig {
+  // instructions fetched, dispatched, issued, broadcast and committed each cycle
+  pub width: usize,
+  pub rob_size: usize,
+  // reservation stations, shared by every functional unit
+  pub stations: usize,
+  // jumps and branches that can be unresolved at once, each needs a bit of the branch masks
+  pub branches: usize,
+  pub load_queue: usize,
+  pub store_queue: usize,
 }
 
-#[derive(Eq)]
-struct OutputArtifact<T : RegData> {
-  src_pc: T,
-  finish: HashSet<OutputDirective<T>>
+impl Default for OutOfOrderConfig {
+  fn default() -> Self { OutOfOrderConfig{ width: 4, rob_size: 32, stations: 16, branches: 8,
+    load_queue: 8, store_queue: 8 } }
 }
 
-impl <T : RegData> Ord for OutputArtifact<T> {
-  fn cmp(&self, other: &Self) -> Ordering { self.src_pc.cmp(&other.src_pc).reverse() }
+// Age of an instruction, and the name its result goes by until it commits
+type Tag = u64;
+// A bit for each unresolved jump or branch an instruction was fetched past
+type Mask = u64;
+pub const MAX_BRANCHES: usize = Mask::BITS as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand<T : RegData> { Value(T), Wait(Tag) }
+
+impl <T : RegData> Operand<T> {
+  fn value(self) -> Option<T> { if let Operand::Value(v) = self { Some(v) } else { None } }
 }
 
-impl <T : RegData> PartialOrd for OutputArtifact<T> {
-  fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+// An instruction waiting to be dispatched, and where fetch went after it. Instructions that
+// could not be fetched have no encoding.
+#[derive(Clone, Copy, Debug)]
+struct Fetched<T : RegData> {
+  pc: T,
+  raw: Option<u32>,
+  predicted: T,
 }
 
-impl <T : RegData> PartialEq for OutputArtifact<T> {
-  fn eq(&self, other: &Self) -> bool { self.src_pc == other.src_pc }
+// What an instruction did, known once it has executed
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Outcome<T : RegData> {
+  result: Option<T>,
+  access: Option<MemAccess<T>>,
+  // where control went, if not to the next instruction
+  target: Option<T>,
+  // with mtval
+  exception: Option<(Exceptions, T)>,
 }
 
+impl <T : RegData> Outcome<T> {
+  fn new() -> Self { Outcome{ result: None, access: None, target: None, exception: None } }
+  fn exception(e: Exceptions, tval: T) -> Self { Outcome{ exception: Some((e, tval)), ..Self::new() } }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RobEntry<T : RegData> {
+  tag: Tag,
+  pc: T,
+  raw: u32,
+  // None if it could not be fetched or decoded, the outcome holds the exception
+  instr: Option<InstrType>,
+  predicted: T,
+  outcome: Option<Outcome<T>>,
+  mask: Mask,
+  // the bit given to a jump or branch until it resolves
+  branch: Option<usize>,
+}
+
+impl <T : RegData> RobEntry<T> {
+  // Whether younger instructions have to wait before they are dispatched, as system
+  // instructions can read and write anything when they commit
+  fn blocks(&self) -> bool { self.instr.is_some_and(|i| i.serializes()) }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Station<T : RegData> {
+  tag: Tag,
+  pc: T,
+  raw: u32,
+  instr: InstrType,
+  ops: [Operand<T>; 2],
+  mask: Mask,
+  // the store a load is predicted to read from, which it waits to have its address
+  after: Option<Tag>,
+}
+
+// An instruction in a functional unit, with its outcome on the bus from cycle done
+#[derive(Clone, Copy, Debug)]
+struct Executing<T : RegData> {
+  tag: Tag,
+  done: u64,
+  outcome: Outcome<T>,
+  mask: Mask,
+}
+
+#[derive(Debug)]
+struct Core<T : RegData> {
+  config: OutOfOrderConfig,
+  fetch_pc: T,
+  // stops after a halt or a fault, until fetch is redirected
+  fetching: bool,
+  // the instruction fetch is waiting on the I-cache for, and when it arrives
+  waiting: Option<(T, u64)>,
+  fetched: VecDeque<Fetched<T>>,
+  rob: VecDeque<RobEntry<T>>,
+  next_tag: Tag,
+  // the entry that will write each register, or None when the register file has its value
+  rename: [Option<Tag>; 32],
+  stations: Vec<Station<T>>,
+  executing: Vec<Executing<T>>,
+  // bits of the branch masks in use, and the rename table as it was after each of their jumps
+  // and branches dispatched
+  unresolved: Mask,
+  checkpoints: Vec<[Option<Tag>; 32]>,
+  // in program order, with the memory they access once they have issued
+  loads: VecDeque<Queued<T>>,
+  stores: VecDeque<Queued<T>>,
+  store_sets: StoreSets,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Queued<T : RegData> {
+  tag: Tag,
+  pc: T,
+  access: Option<MemAccess<T>>,
+  // the store a load took its value from, rather than memory
+  forwarded: Option<Tag>,
+}
 
+const STORE_SET_BITS: usize = 10;
+const STORE_SETS: usize = 64;
 
-pub fn execute<T : RegData>(mut ps: ProgramState<T>) -> Result<ProgramState<T>, ()> {
-  use OutputDirective::*;
-  let mut instr_queue : VecDeque<(T, InstrType, Option<T>)> = VecDeque::new();
-  let mut unprocessed = BinaryHeap::new();
+// Memory dependence predictor. Loads and stores that have conflicted are put in the same set, and
+// a load waits for the last store of its set dispatched before it.
+#[derive(Debug)]
+struct StoreSets {
+  // the set of each instruction, indexed by pc
+  ids: Vec<Option<usize>>,
+  // the last store dispatched in each set
+  last: Vec<Option<Tag>>,
+  next_id: usize,
+}
+
+impl StoreSets {
+  fn new() -> Self {
+    StoreSets{ ids: vec![None; 1 << STORE_SET_BITS], last: vec![None; STORE_SETS], next_id: 0 }
+  }
+  fn index<T : RegData>(pc: T) -> usize { (pc.as_usize() / mem::WORD_SIZE) & ((1 << STORE_SET_BITS) - 1) }
+  fn set<T : RegData>(&self, pc: T) -> Option<usize> { self.ids[Self::index(pc)] }
+  // A load read memory before a store older than it wrote there
+  fn conflict<T : RegData>(&mut self, load: T, store: T) {
+    let id = self.set(load).or(self.set(store)).unwrap_or_else(|| {
+      self.next_id = (self.next_id + 1) % STORE_SETS;
+      self.next_id
+    });
+    self.ids[Self::index(load)] = Some(id);
+    self.ids[Self::index(store)] = Some(id);
+  }
+}
+
+fn overlaps<T : RegData>(a: MemAccess<T>, b: MemAccess<T>) -> bool {
+  let (a_start, b_start) = (a.addr.as_usize(), b.addr.as_usize());
+  a_start < b_start + b.size.bytes() && b_start < a_start + a.size.bytes()
+}
+
+// The value a load reads from what a store writes, if the store covers all of it
+fn forward<T : RegData>(store: MemAccess<T>, load: MemAccess<T>, signed: bool) -> Option<T> {
+  let (start, addr) = (store.addr.as_usize(), load.addr.as_usize());
+  if addr < start || addr + load.size.bytes() > start + store.size.bytes() { return None };
+  // shifts the bytes read to the top, and back down to extend them
+  let unused = T::from(((T::BYTE_SIZE - load.size.bytes()) * 8) as u32);
+  let v = T::from_u64(store.store? >> ((addr - start) * 8)) << unused;
+  Some(if signed { T::from_signed(v.to_signed() >> unused.to_signed()) } else { v >> unused })
+}
+
+fn is_control(instr: &InstrType) -> bool {
+  matches!(instr, InstrType::B{ .. } | InstrType::J{ .. } | InstrType::I{ var: IInstr::JALR, .. })
+}
+
+fn is_load(instr: &InstrType) -> bool {
+  use IInstr::*;
+  matches!(instr, InstrType::I{ var: LB | LH | LW | LBU | LHU | LWU | LD, .. })
+}
+
+// Stores, FP stores, and every atomic other than LR
+fn writes_memory(instr: &InstrType) -> bool {
+  match instr {
+    InstrType::S{ .. } | InstrType::F{ var: FInstr::FS, .. } => true,
+    InstrType::A{ var, .. } => *var != AInstr::LR,
+    _ => false,
+  }
+}
+
+// Instructions that need a functional unit, rather than running as they commit
+fn executes(instr: &InstrType) -> bool {
+  match instr {
+    InstrType::F{ var, .. } => *var == FInstr::FS,
+    InstrType::Halt => false,
+    _ => !instr.serializes(),
+  }
+}
+
+// Cycles from issue until the result is on the bus
+fn latency(instr: &InstrType) -> u64 {
+  use RInstr::*;
+  match instr {
+    InstrType::R{ var: MUL | MULH | MULHSU | MULHU | MULW, .. } => 3,
+    InstrType::R{ var: DIV | DIVU | REM | REMU | DIVW | DIVUW | REMW | REMUW, .. } => 10,
+    _ if is_load(instr) => 2,
+    _ => 1,
+  }
+}
+
+#[allow(clippy::result_unit_err)]
+pub fn execute<T : RegData>(ps: ProgramState<T>) -> Result<ProgramState<T>, ()> {
+  execute_with(ps, OutOfOrderConfig::default())
+}
+
+#[allow(clippy::result_unit_err)]
+pub fn execute_with<T : RegData>(mut ps: ProgramState<T>, config: OutOfOrderConfig)
+  -> Result<ProgramState<T>, ()> {
+  let mut core = Core::new(ps.regs.pc(), config);
+  // each stage runs before the one feeding it, so instructions take a cycle per stage
   while ps.status == Status::Running {
-    let curr_pc = ps.regs.pc();
-    (0..10)
-      .map(|i| curr_pc + T::from(i * mem::WORD_SIZE as u32))
-      .filter_map(|i| ps.mem.read_instr(i.as_usize()).ok().map(|raw| (raw, i)))
-      .filter_map(|(raw, pc)| match decode(raw) {
-        Ok(instr) => Some((instr, pc)),
-        Err(_) => None,
-      })
-      .for_each(|(instr, pc): (InstrType, T)| {
-        let dependent = instr_queue.iter().find(|(_, i, _)| instr.depends_on(i));
-        match dependent {
-          Some(&(max_pc,_,_)) => instr_queue.push_back((pc,instr,Some(max_pc))),
-          None => instr_queue.push_back((pc, instr, None)),
-        };
-      });
-
-    let (mut runnable, mut pending) = (HashMap::new(), VecDeque::new());
-    instr_queue
-      .into_iter()
-      .for_each(|v| {
-        let (pc, instr, dependency) = v;
-        match dependency {
-          None => { runnable.insert(pc, instr); },
-          Some(dependent_pc) =>
-            if runnable.contains_key(&dependent_pc) { runnable.insert(pc, instr); }
-            else { pending.push_back(v); },
-        };
-      });
-    instr_queue = pending;
-
-    runnable
-      .into_iter()
-      .map(|(pc, instr)| OutputArtifact{
-        src_pc: pc,
-        finish: OutputDirective::from(pc, instr, &ps),
-      })
-      .for_each(|v| unprocessed.push(v));
-
-    while let Some(artifact) = unprocessed.pop() {
-      if artifact.src_pc == ps.regs.pc() {
-        artifact.finish
-          .iter()
-          .for_each(|directive| {
-            println!("{:?}", directive);
-            match directive {
-              PC(new_pc) => ps.regs.assign_pc(*new_pc),
-              Exception(e) => ps.status = Status::Exception(*e),
-              Reg(rd, val) => ps.regs.force_assign(*rd, *val),
-              MemStore(val, loc, sz) => if let Err(_) = ps.mem.write(*loc, *val, *sz) {
-                ps.status = Status::Exception(Exceptions::Mem)
-              },
-              Halt => ps.status = Status::Done,
-              Nop => (),
-            };
-          });
-        if ps.status != Status::Running { break }
-        ps.regs.inc_pc();
-      } else {
-        unprocessed.push(artifact);
-        break
-      }
-    }
+    ps.csrs.cycle += 1;
+    core.commit(&mut ps);
+    if ps.status != Status::Running { break }
+    core.writeback(&mut ps);
+    core.issue(&mut ps);
+    core.dispatch(&mut ps);
+    core.fetch(&mut ps);
   };
   Ok(ps)
 }
 
-impl <T : RegData> OutputDirective<T> {
-  // takes an instr and pc and returns a set of commands to run in random order
-  fn from(pc: T, instr: InstrType, ps: &ProgramState<T>) -> HashSet<Self> {
-    use crate::instr::InstrType::*;
-    use crate::instr::{RInstr, IInstr, BInstr, JInstr, SInstr, UInstr};
-    use OutputDirective::*;
-    let mut out = HashSet::new();
-    let action = match instr {
-      R{ var, rs1, rs2, rd } => Reg(rd, match var {
-        RInstr::ADD => T::from_signed(ps.sx(ps.regs[rs1]) + ps.sx(ps.regs[rs2])),
-        RInstr::SUB => T::from_signed(ps.sx(ps.regs[rs1]) - ps.sx(ps.regs[rs2])),
-        RInstr::SLL => ps.zx(ps.regs[rs1]) << ps.regs[rs2],
-        RInstr::SLT => if ps.sx(ps.regs[rs1]) < ps.sx(ps.regs[rs2]) {T::one()} else {T::zero()},
-        RInstr::SLTU => if ps.zx(ps.regs[rs1]) < ps.zx(ps.regs[rs2]) {T::one()} else {T::zero()},
-        RInstr::XOR => ps.zx(ps.regs[rs1]) ^ ps.zx(ps.regs[rs2]),
-        RInstr::SRL => ps.zx(ps.regs[rs1]) >> ps.regs[rs2],
-        RInstr::SRA => T::from_signed(ps.sx(ps.regs[rs1]) >> ps.regs[rs2].to_signed()),
-        RInstr::OR => ps.zx(ps.regs[rs1]) | ps.zx(ps.regs[rs2]),
-        RInstr::AND => ps.zx(ps.regs[rs1]) & ps.zx(ps.regs[rs2]),
-        RInstr::SLLI => ps.zx(ps.regs[rs1]) << T::from(rs2),
-        RInstr::SRLI => ps.zx(ps.regs[rs1]) >> T::from(rs2),
-        RInstr::SRAI => T::from_signed(ps.sx(ps.regs[rs1]) >> T::from(rs2).to_signed()),
+impl <T : RegData> Core<T> {
+  fn new(pc: T, config: OutOfOrderConfig) -> Self {
+    Core{ config, fetch_pc: pc, fetching: true, waiting: None, fetched: VecDeque::new(), rob: VecDeque::new(),
+      next_tag: 0, rename: [None; 32], stations: Vec::new(), executing: Vec::new(), unresolved: 0,
+      checkpoints: vec![[None; 32]; config.branches.min(MAX_BRANCHES)], loads: VecDeque::new(),
+      stores: VecDeque::new(), store_sets: StoreSets::new() }
+  }
+
+  // Tags skip over the entries that were squashed
+  fn entry(&mut self, tag: Tag) -> Option<&mut RobEntry<T>> {
+    let i = self.rob.binary_search_by_key(&tag, |e| e.tag).ok()?;
+    self.rob.get_mut(i)
+  }
+
+  // Throws away what was fetched, and fetches again from pc
+  fn redirect(&mut self, pc: T) {
+    self.fetched.clear();
+    self.fetch_pc = pc;
+    self.fetching = true;
+  }
+
+  // Drops everything in flight, and fetches again from pc
+  fn flush(&mut self, ps: &mut ProgramState<T>, pc: T) {
+    ps.stats.squashed += self.rob.len() as u64;
+    ps.stats.flushes += 1;
+    self.rob.clear();
+    self.stations.clear();
+    self.executing.clear();
+    self.rename = [None; 32];
+    self.unresolved = 0;
+    self.loads.clear();
+    self.stores.clear();
+    self.redirect(pc);
+  }
+
+  // Drops the loads and stores that were squashed from the reorder buffer
+  fn trim_queues(&mut self) {
+    let last = self.rob.back().map(|e| e.tag);
+    self.loads.retain(|l| last.is_some_and(|last| l.tag <= last));
+    self.stores.retain(|s| last.is_some_and(|last| s.tag <= last));
+  }
+
+  // Throws away the load at tag and everything younger, which read memory too early, and fetches
+  // them again
+  fn replay(&mut self, ps: &mut ProgramState<T>, tag: Tag, pc: T) {
+    let before = self.rob.len();
+    let freed = self.rob.iter().filter(|e| e.tag >= tag).filter_map(|e| e.branch)
+      .fold(0, |freed: Mask, b| freed | 1 << b);
+    self.unresolved &= !freed;
+    self.rob.retain(|e| e.tag < tag);
+    self.stations.retain(|s| s.tag < tag);
+    self.executing.retain(|e| e.tag < tag);
+    ps.stats.squashed += (before - self.rob.len()) as u64;
+    ps.stats.flushes += 1;
+    // no jump or branch was dispatched just before the load, so the rename table is rebuilt from
+    // what is left
+    self.rename = [None; 32];
+    for e in self.rob.iter() {
+      if let Some(rd) = e.instr.and_then(|i| i.dest()) { self.rename[rd as usize] = Some(e.tag) };
+    };
+    self.trim_queues();
+    self.redirect(pc);
+  }
+
+  // A jump or branch went where fetch expected, so what was fetched past it is no longer
+  // speculative on it
+  fn confirm(&mut self, bit: usize) {
+    let clear = !(1 << bit);
+    self.rob.iter_mut().for_each(|e| e.mask &= clear);
+    self.stations.iter_mut().for_each(|s| s.mask &= clear);
+    self.executing.iter_mut().for_each(|e| e.mask &= clear);
+    self.unresolved &= clear;
+  }
+
+  // A jump or branch went somewhere else, so everything fetched past it is thrown away
+  fn squash(&mut self, ps: &mut ProgramState<T>, bit: usize, pc: T) {
+    let wrong = |mask: Mask| mask & (1 << bit) != 0;
+    let before = self.rob.len();
+    // the jumps and branches on the wrong path give their bits back
+    let freed = self.rob.iter().filter(|e| wrong(e.mask)).filter_map(|e| e.branch)
+      .fold(0, |freed: Mask, b| freed | 1 << b);
+    self.unresolved &= !freed;
+    self.rob.retain(|e| !wrong(e.mask));
+    self.stations.retain(|s| !wrong(s.mask));
+    self.executing.retain(|e| !wrong(e.mask));
+    ps.stats.squashed += (before - self.rob.len()) as u64;
+    ps.stats.flushes += 1;
+    self.rename = self.checkpoints[bit];
+    self.confirm(bit);
+    self.trim_queues();
+    self.redirect(pc);
+  }
+
+  fn fetch(&mut self, ps: &mut ProgramState<T>) {
+    for _ in 0..self.config.width {
+      if !self.fetching || self.fetched.len() >= 2 * self.config.width { return };
+      let pc = self.fetch_pc;
+      if !ps.instr_arrived(&mut self.waiting, pc) { return };
+      let raw = ps.mem.read_instr(pc.as_usize()).ok();
+      let predicted = raw.map_or(pc, |raw| ps.predict_next(pc, raw));
+      self.fetched.push_back(Fetched{ pc, raw, predicted });
+      // nothing past the end of the program is worth fetching unless something jumps away
+      if raw.is_none() || raw == Some(InstrType::halt_val()) { self.fetching = false };
+      self.fetch_pc = predicted;
+    };
+  }
+
+  // The value of a source register, or the entry that will produce it
+  fn operand(&mut self, ps: &ProgramState<T>, r: Option<u32>) -> Operand<T> {
+    let r = match r { Some(r) => r, None => return Operand::Value(T::zero()) };
+    // a restored rename table can name entries that have since committed
+    let committed = |tag: Tag| self.rob.front().is_none_or(|head| tag < head.tag);
+    match self.rename[r as usize] {
+      Some(tag) if !committed(tag) => match self.entry(tag).and_then(|e| e.outcome).and_then(|o| o.result) {
+        Some(v) => Operand::Value(v),
+        None => Operand::Wait(tag),
+      },
+      _ => Operand::Value(ps.regs[r]),
+    }
+  }
+
+  // Renames instructions in program order, placing them in the reorder buffer and stations
+  fn dispatch(&mut self, ps: &mut ProgramState<T>) {
+    for _ in 0..self.config.width {
+      let Fetched{ pc, raw, predicted } = match self.fetched.front() { Some(&f) => f, None => return };
+      // vector instructions are only run by the normal simulator
+      let instr = raw.and_then(|raw| decode_xlen(raw, T::BYTE_SIZE * 8).ok())
+        .filter(|i| !matches!(i, InstrType::V{ .. }));
+      let executes = instr.is_some_and(|i| executes(&i));
+      let (load, store) = match instr {
+        Some(i) => (is_load(&i), writes_memory(&i)),
+        None => (false, false),
+      };
+      let control = instr.is_some_and(|i| is_control(&i));
+      let branch = (0..self.checkpoints.len()).find(|b| self.unresolved & (1 << b) == 0).filter(|_| control);
+      // counts the cycle against whatever dispatch waits on first
+      let stats = &mut ps.stats;
+      let stall = if self.rob.iter().any(RobEntry::blocks) { Some(&mut stats.serializing_stalls) }
+        else if self.rob.len() >= self.config.rob_size { Some(&mut stats.rob_stalls) }
+        else if executes && self.stations.len() >= self.config.stations { Some(&mut stats.station_stalls) }
+        else if (load && self.loads.len() >= self.config.load_queue)
+          || (store && self.stores.len() >= self.config.store_queue) { Some(&mut stats.queue_stalls) }
+        else if control && branch.is_none() { Some(&mut stats.branch_mask_stalls) }
+        else { None };
+      if let Some(cycles) = stall {
+        *cycles += 1;
+        return
+      };
+      let mask = self.unresolved;
+      self.fetched.pop_front();
+      let tag = self.next_tag;
+      self.next_tag += 1;
+      let outcome = match (raw, instr) {
+        (None, _) => Some(Outcome::exception(Exceptions::InstrAccessFault, pc)),
+        (Some(raw), None) => Some(Outcome::exception(Exceptions::IllegalInstruction, T::from(raw))),
+        (_, Some(InstrType::Halt)) => Some(Outcome::new()),
+        _ => None,
+      };
+      self.rob.push_back(RobEntry{ tag, pc, raw: raw.unwrap_or(0), instr, predicted, outcome, mask, branch });
+      let instr = match instr { Some(instr) => instr, None => continue };
+      let set = self.store_sets.set(pc);
+      let after = if load { set.and_then(|set| self.store_sets.last[set]) } else { None };
+      if load { self.loads.push_back(Queued{ tag, pc, access: None, forwarded: None }) };
+      if store {
+        self.stores.push_back(Queued{ tag, pc, access: None, forwarded: None });
+        if let Some(set) = set { self.store_sets.last[set] = Some(tag) };
+      };
+      if executes {
+        let [rs1, rs2] = instr.sources();
+        let ops = [self.operand(ps, rs1), self.operand(ps, rs2)];
+        self.stations.push(Station{ tag, pc, raw: raw.unwrap_or(0), instr, ops, mask, after });
+      };
+      if let Some(rd) = instr.dest() { self.rename[rd as usize] = Some(tag) };
+      if let Some(b) = branch {
+        self.unresolved |= 1 << b;
+        self.checkpoints[b] = self.rename;
+      };
+    };
+  }
+
+  // Where a load gets its value from. Memory is read unless the youngest older store known to
+  // write any of the same bytes covers all of them, and a store that only covers some of them
+  // has to write memory first.
+  fn load_source(&self, tag: Tag, access: MemAccess<T>, signed: bool) -> Result<Option<(Tag, T)>, ()> {
+    let store = self.stores.iter().rev()
+      .filter(|s| s.tag < tag)
+      .find_map(|s| s.access.filter(|&a| overlaps(a, access)).map(|a| (s.tag, a)));
+    match store {
+      None => Ok(None),
+      Some((store, a)) => forward(a, access, signed).map(|v| Some((store, v))).ok_or(()),
+    }
+  }
+
+  // Starts the oldest instructions whose operands are ready. Loads also wait for the store the
+  // store sets predict they depend on to know its address, and for older atomics with aq set to
+  // commit.
+  fn issue(&mut self, ps: &mut ProgramState<T>) {
+    let mut issued = 0;
+    let mut i = 0;
+    while i < self.stations.len() && issued < self.config.width {
+      let s = self.stations[i];
+      let acquiring = |e: &RobEntry<T>| e.tag < s.tag && matches!(e.instr, Some(InstrType::A{ aq: true, .. }));
+      let waiting = s.after.is_some_and(|store| self.stations.iter().any(|s| s.tag == store))
+        || (is_load(&s.instr) && self.rob.iter().any(acquiring));
+      let (a, b) = match s.ops.map(Operand::value) {
+        [Some(a), Some(b)] if !waiting => (a, b),
+        _ => { i += 1; continue },
+      };
+      let mut outcome = ps.run(s.pc, s.raw, &s.instr, a, b);
+      let mut forwarded = None;
+      if let (true, Some(access)) = (is_load(&s.instr), outcome.access) {
+        let signed = matches!(s.instr, InstrType::I{ var: IInstr::LB | IInstr::LH | IInstr::LW, .. });
+        match self.load_source(s.tag, access, signed) {
+          Ok(source) => forwarded = source,
+          Err(()) => { i += 1; continue },
+        };
+      };
+      self.stations.remove(i);
+      let mut done = ps.csrs.cycle + latency(&s.instr);
+      match (forwarded, outcome.access) {
+        (Some((_, v)), _) => {
+          outcome.result = Some(v);
+          ps.stats.forwarded_loads += 1;
+        },
+        // stores go to the D-cache when they commit
+        (None, Some(access)) if is_load(&s.instr) =>
+          done += ps.data_latency(access).saturating_sub(1),
+        _ => (),
+      };
+      if let Some(l) = self.loads.iter_mut().find(|l| l.tag == s.tag) {
+        l.access = outcome.access;
+        l.forwarded = forwarded.map(|(store, _)| store);
+      };
+      self.executing.push(Executing{ tag: s.tag, done, outcome, mask: s.mask });
+      issued += 1;
+      if let (true, Some(access)) = (writes_memory(&s.instr), outcome.access) {
+        if let Some(st) = self.stores.iter_mut().find(|st| st.tag == s.tag) { st.access = Some(access) };
+        // the oldest younger load that read any of these bytes from somewhere older
+        let violation = self.loads.iter().find(|l| l.tag > s.tag
+          && l.access.is_some_and(|a| overlaps(a, access))
+          && l.forwarded.is_none_or(|f| f < s.tag)).copied();
+        if let Some(load) = violation {
+          ps.stats.order_violations += 1;
+          self.store_sets.conflict(load.pc, s.pc);
+          return self.replay(ps, load.tag, load.pc);
+        };
+      };
+    };
+  }
+
+  // Broadcasts the oldest finished outcomes on the common data bus, to the stations waiting for
+  // them and the reorder buffer. Jumps and branches resolve here.
+  fn writeback(&mut self, ps: &mut ProgramState<T>) {
+    self.executing.sort_by_key(|e| e.tag);
+    let mut finished: Vec<Executing<T>> = Vec::new();
+    let width = self.config.width;
+    self.executing.retain(|e| {
+      let take = e.done <= ps.csrs.cycle && finished.len() < width;
+      if take { finished.push(*e) };
+      !take
+    });
+    for Executing{ tag, outcome, .. } in finished {
+      // squashed by an older jump or branch that finished in the same cycle
+      let entry = match self.entry(tag) { Some(entry) => entry, None => continue };
+      entry.outcome = Some(outcome);
+      let (pc, raw, predicted, branch) = (entry.pc, entry.raw, entry.predicted, entry.branch);
+      if let Some(v) = outcome.result { self.broadcast(tag, v) };
+      let bit = match branch { Some(bit) => bit, None => continue };
+      let next = outcome.target.unwrap_or_else(|| rvc::next_pc(pc, raw));
+      // a jump that traps flushes everything when it commits anyway
+      if next != predicted && outcome.exception.is_none() { self.squash(ps, bit, next) }
+      else { self.confirm(bit) };
+    };
+  }
+
+  // Hands the result of tag to the stations waiting for it
+  fn broadcast(&mut self, tag: Tag, v: T) {
+    self.stations.iter_mut().flat_map(|s| s.ops.iter_mut())
+      .filter(|op| **op == Operand::Wait(tag))
+      .for_each(|op| *op = Operand::Value(v));
+  }
+
+  // Retires finished instructions from the head of the reorder buffer in program order
+  fn commit(&mut self, ps: &mut ProgramState<T>) {
+    for _ in 0..self.config.width {
+      let head = match self.rob.front() { Some(&head) => head, None => return };
+      // the level it runs at, before an mret or sret changes it
+      let prv = ps.privilege;
+      let outcome = match (head.outcome, head.instr) {
+        (Some(outcome), _) => outcome,
+        // everything older has committed, so system instructions see the state they expect
+        (None, Some(instr)) if instr.serializes() => ps.system(head.pc, &instr),
+        (None, Some(InstrType::F{ .. })) => Outcome::new(),
+        (None, _) => return,
+      };
+      // the register file now holds the operands of an atomic, and what it returns is broadcast
+      // as it would have been from a functional unit
+      let outcome = match head.instr {
+        Some(InstrType::A{ var, rs1, rs2, size, .. }) if outcome.exception.is_none() => {
+          let addr = ps.regs[rs1];
+          match ps.atomic(var, addr, size, ps.regs[rs2]) {
+            Ok((v, access)) => {
+              self.broadcast(head.tag, v);
+              if let Some(access) = access { ps.data_latency(access); };
+              Outcome{ result: Some(v), access, ..outcome }
+            },
+            Err(e) => Outcome::exception(e, addr),
+          }
+        },
+        Some(instr @ InstrType::F{ rs1, .. }) if outcome.exception.is_none() =>
+          match ps.float(&instr, ps.regs[rs1]) {
+            Ok((result, access)) => {
+              if let Some(v) = result { self.broadcast(head.tag, v) };
+              if let Some(access) = access { ps.data_latency(access); };
+              Outcome{ result, access, ..outcome }
+            },
+            Err((Exceptions::IllegalInstruction, _)) =>
+              Outcome::exception(Exceptions::IllegalInstruction, T::from(head.raw)),
+            Err((e, tval)) => Outcome::exception(e, tval),
+          },
+        _ => outcome,
+      };
+      self.rob.pop_front();
+      if let Some((e, tval)) = outcome.exception {
+        ps.trap(e, head.pc, tval);
+        self.flush(ps, ps.regs.pc());
+        return
+      };
+      let instr = head.instr.expect("Only exceptions have no instruction");
+      match (instr, outcome.access) {
+        (InstrType::Halt, _) => {
+          ps.status = Status::Done;
+          return
+        },
+        (InstrType::S{ .. }, Some(access @ MemAccess{ addr, size, store: Some(v) })) => {
+          ps.store(addr.as_usize(), T::from_u64(v), size);
+          ps.data_latency(access);
+          self.stores.pop_front();
+        },
+        _ if is_load(&instr) => { self.loads.pop_front(); },
+        _ if writes_memory(&instr) => { self.stores.pop_front(); },
+        _ => (),
+      };
+      if let Some(rd) = instr.dest() {
+        if let Some(v) = outcome.result { ps.regs.force_assign(rd, v) };
+        if self.rename[rd as usize] == Some(head.tag) { self.rename[rd as usize] = None };
+      };
+      let next = outcome.target.unwrap_or_else(|| rvc::next_pc(head.pc, head.raw));
+      // predictors learn from the path that was really taken
+      if is_control(&instr) { ps.resolved(head.pc, head.raw, outcome.target, head.predicted); };
+      ps.regs.assign_pc(next);
+      ps.csrs.instret += 1;
+      ps.commit(head.pc, head.raw, prv, &instr, outcome.access);
+      if ps.status != Status::Running { return };
+      // fetch carried on past an mret or sret
+      if let InstrType::I{ var: IInstr::MRET | IInstr::SRET, .. } = instr {
+        ps.stats.flushes += 1;
+        self.redirect(next);
+      };
+    };
+  }
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Executes an instruction in a functional unit, given its operands
+  fn run(&self, pc: T, raw: u32, instr: &InstrType, a: T, b: T) -> Outcome<T> {
+    let mut out = Outcome::new();
+    let link = rvc::next_pc(pc, raw);
+    let jump = |out: &mut Outcome<T>, target: T| if target.as_usize().is_multiple_of(rvc::IALIGN) {
+      out.target = Some(target);
+    } else {
+      *out = Outcome::exception(Exceptions::InstrMisaligned, target);
+    };
+    match *instr {
+      InstrType::R{ var, rs2: shamt, .. } => out.result = Some(match var {
+        RInstr::ADD => a.wrapping_add(&b),
+        RInstr::SUB => a.wrapping_sub(&b),
+        RInstr::SLL => self.zx(a) << b.shamt(),
+        RInstr::SLT => if self.sx(a) < self.sx(b) {T::one()} else {T::zero()},
+        RInstr::SLTU => if self.zx(a) < self.zx(b) {T::one()} else {T::zero()},
+        RInstr::XOR => self.zx(a) ^ self.zx(b),
+        RInstr::SRL => self.zx(a) >> b.shamt(),
+        RInstr::SRA => T::from_signed(self.sx(a) >> b.shamt().to_signed()),
+        RInstr::OR => self.zx(a) | self.zx(b),
+        RInstr::AND => self.zx(a) & self.zx(b),
+        RInstr::SLLI => self.zx(a) << T::from(shamt),
+        RInstr::SRLI => self.zx(a) >> T::from(shamt),
+        RInstr::SRAI => T::from_signed(self.sx(a) >> T::from(shamt).to_signed()),
+        RInstr::MUL => a.mul_lo(b),
+        RInstr::MULH => a.mulh(b),
+        RInstr::MULHSU => a.mulhsu(b),
+        RInstr::MULHU => a.mulhu(b),
+        RInstr::DIV => a.div_s(b),
+        RInstr::DIVU => a.div_u(b),
+        RInstr::REM => a.rem_s(b),
+        RInstr::REMU => a.rem_u(b),
+        RInstr::ADDW => a.wrapping_add(&b).sext_w(),
+        RInstr::SUBW => a.wrapping_sub(&b).sext_w(),
+        RInstr::SLLW => (a << b.shamt_w()).sext_w(),
+        RInstr::SRLW => (a.zext_w() >> b.shamt_w()).sext_w(),
+        RInstr::SRAW => T::from_signed(self.sx(a.sext_w()) >> b.shamt_w().to_signed()),
+        RInstr::SLLIW => (a << T::from(shamt)).sext_w(),
+        RInstr::SRLIW => (a.zext_w() >> T::from(shamt)).sext_w(),
+        RInstr::SRAIW => T::from_signed(self.sx(a.sext_w()) >> T::from(shamt).to_signed()),
+        RInstr::MULW => a.mul_lo(b).sext_w(),
+        RInstr::DIVW => a.sext_w().div_s(b.sext_w()).sext_w(),
+        RInstr::DIVUW => a.zext_w().div_u(b.zext_w()).sext_w(),
+        RInstr::REMW => a.sext_w().rem_s(b.sext_w()).sext_w(),
+        RInstr::REMUW => a.zext_w().rem_u(b.zext_w()).sext_w(),
+        RInstr::Zb(zb) => bitmanip::exec(zb, a, b, shamt),
       }),
-      I{ var, rs1, rd, sx_imm, zx_imm } => {
-        let (sx_imm, zx_imm) = (T::Signed::from(sx_imm), T::from(zx_imm));
-        match var {
-          IInstr::ADDI => Reg(rd, T::from_signed(ps.sx(ps.regs[rs1]) + sx_imm)),
+      InstrType::I{ var, sx_imm, .. } => {
+        let sx_imm = T::Signed::from(sx_imm);
+        // the immediate is sign extended even for unsigned comparisons and logical ops
+        let imm = T::from_signed(sx_imm);
+        let (size, signed) = match var {
+          IInstr::ADDI => return Outcome{ result: Some(a.offset(sx_imm)), ..out },
           IInstr::SLTI =>
-            Reg(rd, if ps.sx(ps.regs[rs1]) < sx_imm { T::one() } else { T::zero() }),
+            return Outcome{ result: Some(if self.sx(a) < sx_imm {T::one()} else {T::zero()}), ..out },
           IInstr::SLTIU =>
-            Reg(rd, if ps.zx(ps.regs[rs1]) < zx_imm { T::one() } else { T::zero() }),
-          IInstr::XORI => Reg(rd, ps.zx(ps.regs[rs1]) ^ zx_imm),
-          IInstr::ORI => Reg(rd, ps.zx(ps.regs[rs1]) | zx_imm),
-          IInstr::ANDI => Reg(rd, ps.zx(ps.regs[rs1]) & zx_imm),
+            return Outcome{ result: Some(if self.zx(a) < imm {T::one()} else {T::zero()}), ..out },
+          IInstr::XORI => return Outcome{ result: Some(self.zx(a) ^ imm), ..out },
+          IInstr::ORI => return Outcome{ result: Some(self.zx(a) | imm), ..out },
+          IInstr::ANDI => return Outcome{ result: Some(self.zx(a) & imm), ..out },
+          IInstr::ADDIW => return Outcome{ result: Some(a.offset(sx_imm).sext_w()), ..out },
+          // loads already wait on older stores to the same address
+          IInstr::FENCE | IInstr::FENCEI => return out,
           IInstr::JALR => {
-            out.insert(PC(T::from_signed((ps.sx(ps.regs[rs1]) + sx_imm) & T::Signed::from(-2))));
-            Reg(rd, pc)
+            out.result = Some(link);
+            jump(&mut out, a.offset(sx_imm) & T::from_signed(T::Signed::from(-2)));
+            return out
           },
-          IInstr::LW =>
-            ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::WORD)
-              .map(|t| Reg(rd, t))
-              .unwrap_or(Exception(Exceptions::Mem)),
-          IInstr::LH =>
-            ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::HALF)
-              .map(|t| Reg(rd, t))
-              .unwrap_or(Exception(Exceptions::Mem)),
-          IInstr::LB =>
-            ps.mem.read(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm).as_usize(), mem::Size::BYTE)
-              .map(|t| Reg(rd, t))
-              .unwrap_or(Exception(Exceptions::Mem)),
-          IInstr::LHU =>
-            ps.mem.read_signed(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm)
-              .as_usize(), mem::Size::HALF)
-              .map(|s| T::from_signed(s))
-              .map(|t| Reg(rd, t))
-              .unwrap_or(Exception(Exceptions::Mem)),
-          IInstr::LBU =>
-            ps.mem.read_signed(T::from_signed(ps.sx(ps.regs[rs1])+sx_imm)
-            .as_usize(), mem::Size::BYTE)
-              .map(|s| T::from_signed(s))
-              .map(|t| Reg(rd, t))
-              .unwrap_or(Exception(Exceptions::Mem)),
-          v => panic!("Unimplemented {:?}", v),
-        }
+          IInstr::LD => (mem::Size::DOUBLE, false),
+          IInstr::LWU => (mem::Size::WORD, false),
+          IInstr::LW => (mem::Size::WORD, true),
+          IInstr::LHU => (mem::Size::HALF, false),
+          IInstr::LBU => (mem::Size::BYTE, false),
+          IInstr::LH => (mem::Size::HALF, true),
+          IInstr::LB => (mem::Size::BYTE, true),
+          _ => panic!("run() called with system instruction {:?}", instr),
+        };
+        let addr = a.offset(sx_imm);
+        match self.load(addr, size, signed) {
+          Ok(v) => {
+            out.result = Some(v);
+            out.access = Some(MemAccess::load(addr, size));
+          },
+          Err(e) => out = Outcome::exception(e, addr),
+        };
       },
-      S{ var, rs1, rs2, imm } => {
+      InstrType::S{ var, imm, .. } => {
         let size = match var {
           SInstr::SB => mem::Size::BYTE,
           SInstr::SH => mem::Size::HALF,
           SInstr::SW => mem::Size::WORD,
+          SInstr::SD => mem::Size::DOUBLE,
+        };
+        let addr = a.offset(T::Signed::from(imm));
+        match self.store_addr(addr, size) {
+          Ok(_) => out.access = Some(MemAccess::store(addr, size, b)),
+          Err(e) => out = Outcome::exception(e, addr),
         };
-        MemStore(ps.regs[rs2], (ps.regs[rs1] + T::from(imm)).as_usize(), size)
       },
-      B{ var, rs1, rs2, imm } => {
-        let branch = match var {
-          BInstr::BEQ => ps.regs[rs1] == ps.regs[rs2],
-          BInstr::BNE => ps.regs[rs1] != ps.regs[rs2],
-          BInstr::BLT => ps.sx(ps.regs[rs1]) < ps.sx(ps.regs[rs2]),
-          BInstr::BGE => ps.sx(ps.regs[rs1]) >= ps.sx(ps.regs[rs2]),
-          BInstr::BLTU => ps.zx(ps.regs[rs1]) < ps.zx(ps.regs[rs2]),
-          BInstr::BGEU => ps.zx(ps.regs[rs1]) >= ps.zx(ps.regs[rs2]),
+      InstrType::B{ var, imm, .. } => {
+        let taken = match var {
+          BInstr::BEQ => a == b,
+          BInstr::BNE => a != b,
+          BInstr::BLT => self.sx(a) < self.sx(b),
+          BInstr::BGE => self.sx(a) >= self.sx(b),
+          BInstr::BLTU => self.zx(a) < self.zx(b),
+          BInstr::BGEU => self.zx(a) >= self.zx(b),
         };
-        if branch { PC(pc.offset(T::Signed::from(imm)) - T::from(mem::WORD_SIZE as u32)) }
-        else { Nop }
+        if taken { jump(&mut out, pc.offset(T::Signed::from(imm))) };
       },
-      U{ var, rd, imm } => Reg(rd, match var {
-        UInstr::LUI => T::from(imm),
-        UInstr::AUIPC => T::from(imm) + pc,
+      InstrType::U{ var, imm, .. } => out.result = Some(match var {
+        UInstr::LUI => T::from_signed(T::Signed::from(imm as i32)),
+        UInstr::AUIPC => pc.offset(T::Signed::from(imm as i32)),
       }),
-      J{ var, rd, offset } => match var {
-        JInstr::JAL => {
-          out.insert(Reg(rd, pc));
-          PC(pc.offset(T::Signed::from(offset)) - T::from(mem::WORD_SIZE as u32))
-        },
+      InstrType::J{ var: JInstr::JAL, offset, .. } => {
+        out.result = Some(link);
+        jump(&mut out, pc.offset(T::Signed::from(offset)));
+      },
+      // only the address is checked here, memory is accessed as the atomic commits
+      InstrType::A{ var, size, .. } => match self.atomic_addr(var, a, size) {
+        Ok(_) => out.access = Some(MemAccess::load(a, size)),
+        Err(e) => out = Outcome::exception(e, a),
       },
-      InstrType::Halt => OutputDirective::Halt,
+      // the bits stored are read from the f registers when it commits
+      InstrType::F{ var: FInstr::FS, imm, double, .. } => {
+        let (addr, size) = (a.offset(T::Signed::from(imm)), if double { mem::Size::DOUBLE } else { mem::Size::WORD });
+        match self.store_addr(addr, size) {
+          Ok(_) => out.access = Some(MemAccess::load(addr, size)),
+          Err(e) => out = Outcome::exception(e, addr),
+        };
+      },
+      InstrType::F{ .. } | InstrType::V{ .. } => panic!("run() called with {:?}", instr),
+      InstrType::Halt => panic!("run() called with halt"),
     };
-    out.insert(action);
     out
   }
-}
-
-
-
-
-
-
-
-
-
-
 
+  // Runs a system instruction as it commits
+  fn system(&mut self, pc: T, instr: &InstrType) -> Outcome<T> {
+    let raw = self.mem.read_instr(pc.as_usize()).unwrap_or(0);
+    match *instr {
+      InstrType::I{ var: IInstr::ECALL, .. } => match self.ecall() {
+        Ok(()) => Outcome::new(),
+        Err(e) => Outcome::exception(e, T::zero()),
+      },
+      InstrType::I{ var: IInstr::EBREAK, .. } => Outcome::exception(Exceptions::Breakpoint, pc),
+      InstrType::I{ var: var @ (IInstr::MRET | IInstr::SRET), .. } => {
+        match if var == IInstr::MRET { self.mret() } else { self.sret() } {
+          Ok(()) => Outcome{ target: Some(self.regs.pc()), ..Outcome::new() },
+          Err(e) => Outcome::exception(e, T::from(raw)),
+        }
+      },
+      InstrType::I{ var: var @ (IInstr::WFI | IInstr::SFENCEVMA), .. } =>
+        match if var == IInstr::WFI { self.wfi() } else { self.sfence_vma() } {
+          Ok(()) => Outcome::new(),
+          Err(e) => Outcome::exception(e, T::from(raw)),
+        },
+      InstrType::I{ var, rs1, zx_imm, .. } => match self.csr_op(var, zx_imm, rs1, self.regs[rs1]) {
+        Ok(old) => Outcome{ result: Some(old), ..Outcome::new() },
+        Err(e) => Outcome::exception(e, T::from(raw)),
+      },
+      _ => panic!("system() called with {:?}", instr),
+    }
+  }
+}
 
+#[cfg(test)]
+use crate::cache::Cache;
 
+#[test]
+fn test_out_of_order() {
+  use super::Lockstep;
+  let check = |program: &[u32], config| {
+    let mut ps = super::load_words(program);
+    let (checker, divergence) = Lockstep::new(&ps);
+    ps.hooks.push(Box::new(checker));
+    let ps = execute_with(ps, config).unwrap();
+    assert_eq!(*divergence.borrow(), None);
+    assert_eq!(ps.status, Status::Done);
+    ps
+  };
+  // a chain of dependent instructions, with independent ones to run around it
+  let program = [
+    0x00a00513, // li a0, 10
+    0x02a50533, // mul a0, a0, a0
+    0x00150513, // addi a0, a0, 1
+    0x00100593, // li a1, 1
+    0x00158593, // addi a1, a1, 1
+    0x00b50633, // add a2, a0, a1
+    0x04c02023, // sw a2, 64(zero)
+    0x04002683, // lw a3, 64(zero)
+    0x00d686b3, // add a3, a3, a3
+    InstrType::halt_val(),
+  ];
+  for &(width, rob_size) in [(1, 4), (2, 8), (4, 32)].iter() {
+    let ps = check(&program, OutOfOrderConfig{ width, rob_size, ..Default::default() });
+    assert_eq!((ps.regs[10], ps.regs[11], ps.regs[12], ps.regs[13]), (101, 2, 103, 206));
+  };
+  // the li a1 and addi ran while the mul was busy
+  let narrow = check(&program, OutOfOrderConfig{ width: 1, rob_size: 2, ..Default::default() });
+  let wide = check(&program, OutOfOrderConfig::default());
+  assert!(wide.csrs.cycle < narrow.csrs.cycle);
+  // exceptions are taken in order, with nothing after them having happened
+  let ps = check(&super::TRAP_PROGRAM, OutOfOrderConfig::default());
+  assert_eq!((ps.regs[8], ps.regs[9], ps.regs[12]), (4 + 2, 3, 7));
+  // a load that misses in the D-cache finishes once memory answers
+  let load = [
+    0x04002503, // lw a0, 64(zero)
+    0x00150513, // addi a0, a0, 1
+    InstrType::halt_val(),
+  ];
+  let fast = execute(super::load_words(&load)).unwrap();
+  let mut ps = super::load_words(&load);
+  ps.caches.dcache = Some(Cache::l1("size=256,line=16,latency=2"));
+  ps.caches.dram_latency = 10;
+  let slow = execute(ps).unwrap();
+  assert_eq!(slow.regs[10], 1);
+  assert_eq!(slow.csrs.cycle, fast.csrs.cycle + 2 + 10 - 1);
+  // the second load of the word hits in a D-cache with no latency
+  let twice = [
+    0x00002503, // lw a0, 0(zero)
+    0x00002583, // lw a1, 0(zero)
+    InstrType::halt_val(),
+  ];
+  let mut ps = super::load_words(&twice);
+  ps.caches.icache = Some(Cache::l1("size=256,line=16,latency=0"));
+  ps.caches.dcache = Some(Cache::l1("size=256,line=16,latency=0"));
+  ps.caches.dram_latency = 0;
+  let ps = execute(ps).unwrap();
+  assert_eq!((ps.status, ps.regs[11]), (Status::Done, 0x00002503));
+  assert_eq!(ps.csrs.cycle, execute(super::load_words(&twice)).unwrap().csrs.cycle);
+}
 
+#[test]
+fn test_speculation() {
+  use super::Lockstep;
+  let run = |predictor: &str, config| {
+    let mut ps = super::load_words(&[
+      0x00000513, // li a0, 0
+      0x00a00593, // li a1, 10
+      0x00150513, // loop: addi a0, a0, 1
+      0x02a50633, // mul a2, a0, a0
+      0xfeb51ce3, // bne a0, a1, loop
+      0x00160693, // addi a3, a2, 1
+      InstrType::halt_val(),
+    ]);
+    ps.predictor = crate::predictor::from_name(predictor).unwrap();
+    let (checker, divergence) = Lockstep::new(&ps);
+    ps.hooks.push(Box::new(checker));
+    let ps = execute_with(ps, config).unwrap();
+    assert_eq!(*divergence.borrow(), None);
+    assert_eq!(ps.regs[13], 101);
+    assert_eq!(ps.stats.branches, 10);
+    ps
+  };
+  // falling through runs ahead into the addi and halt every time round
+  let not_taken = run("not-taken", OutOfOrderConfig::default());
+  assert_eq!(not_taken.stats.mispredicts, 9);
+  assert!(not_taken.stats.squashed >= 9 * 2);
+  // going round again only mispredicts on the way out, past more copies of the loop
+  let btfn = run("btfn", OutOfOrderConfig::default());
+  assert_eq!(btfn.stats.mispredicts, 1);
+  assert!(btfn.stats.squashed > 0);
+  assert!(btfn.csrs.cycle < not_taken.csrs.cycle);
+  // with a single branch in flight fetch stops at the second copy of the bne
+  let one = run("btfn", OutOfOrderConfig{ branches: 1, ..Default::default() });
+  assert!(one.csrs.cycle > btfn.csrs.cycle);
+}
 
+#[test]
+fn test_memory_order() {
+  use super::Lockstep;
+  let run = |ps: ProgramState<u32>| {
+    let mut ps = ps;
+    ps.predictor = crate::predictor::from_name("btfn").unwrap();
+    let (checker, divergence) = Lockstep::new(&ps);
+    ps.hooks.push(Box::new(checker));
+    let ps = execute(ps).unwrap();
+    assert_eq!(*divergence.borrow(), None);
+    assert_eq!(ps.status, Status::Done);
+    ps
+  };
+  // test/lwsw.asm
+  let ps = run(super::load_words(&[
+    0x01400293, // li t0, 20
+    0x02800393, // li t2, 40
+    0x0002a303, // lw t1, 0(t0)
+    0x0063a023, // sw t1, 0(t2)
+    InstrType::halt_val(),
+    0x04, 0x08, 0x12, 0x16, 0x16, 0x16,
+  ]));
+  assert_eq!(ps.mem.read(40, mem::Size::WORD), Ok(4));
+  // test/save.asm, where loads read parts of the stores just before them
+  let ps = run(super::load_words_in(&[
+    0x00128293, // addi t0, t0, 1
+    0x40030313, // addi t1, t1, 1024
+    0x00530023, // sb t0, 0(t1)
+    0x00034283, // lbu t0, 0(t1)
+    0x0002f293, // andi t0, t0, 0
+    0x000042b7, // lui t0, 4
+    0x32128293, // addi t0, t0, 801
+    0x00530023, // sb t0, 0(t1)
+    0x00531123, // sh t0, 2(t1)
+    0x00532223, // sw t0, 4(t1)
+    0x00034403, // lbu s0, 0(t1)
+    0x00134483, // lbu s1, 1(t1)
+    0x00234903, // lbu s2, 2(t1)
+    0x00334983, // lbu s3, 3(t1)
+    0x00435a03, // lhu s4, 4(t1)
+    0x00635a83, // lhu s5, 6(t1)
+    0x000013b7, // lui t2, 1
+    0x11138393, // addi t2, t2, 273
+    0x098763b7, // lui t2, 39030
+    0x00321e37, // lui t3, 801
+    0x000013b7, // lui t2, 1
+    0x23438393, // addi t2, t2, 564
+    0x00731423, // sh t2, 8(t1)
+    0x00835e83, // lhu t4, 8(t1)
+    0x00a35f03, // lhu t5, 10(t1)
+    0x00832f83, // lw t6, 8(t1)
+    InstrType::halt_val(),
+  ], 0x800));
+  assert_eq!(super::regs(&ps, 8..=9), [0x21, 0]);
+  assert_eq!(super::regs(&ps, 18..=21), [0x21, 0x43, 0x4321, 0]);
+  assert_eq!(super::regs(&ps, 29..=31), [0x1234, 0, 0x1234]);
+  assert!(ps.stats.forwarded_loads > 0);
+  // the load runs ahead of the store while the mul works out its address, which the store sets
+  // only allow once
+  let ps = run(super::load_words(&[
+    0x00300713, // li a4, 3
+    0x00800513, // loop: li a0, 8
+    0x02a505b3, // mul a1, a0, a0
+    0x00e5a023, // sw a4, 0(a1)
+    0x04002683, // lw a3, 64(zero)
+    0xfff70713, // addi a4, a4, -1
+    0xfe0716e3, // bnez a4, loop
+    InstrType::halt_val(),
+  ]));
+  assert_eq!(ps.regs[13], 1);
+  assert_eq!(ps.stats.order_violations, 1);
+}
@@ -0,0 +1,214 @@
+// Counts of where the simulators spent their cycles, and the report printed at the end of a run
+use std::fmt;
+use crate::cache::CacheStats;
+use crate::instr::{InstrType, RInstr, IInstr, FInstr, VInstr};
+use crate::program_state::ProgramState;
+use crate::reg::RegData;
+
+// Retired instructions by the kind of unit they need
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct InstrMix {
+  pub alu: u64,
+  pub mul_div: u64,
+  pub loads: u64,
+  pub stores: u64,
+  // LR, SC and AMOs
+  pub atomics: u64,
+  // F and D instructions other than loads and stores
+  pub fp: u64,
+  // V instructions other than loads and stores
+  pub vector: u64,
+  pub branches: u64,
+  pub jumps: u64,
+  // system calls, CSR accesses and returns from traps
+  pub system: u64,
+}
+
+impl InstrMix {
+  pub(crate) fn count(&mut self, instr: &InstrType) {
+    use RInstr::*;
+    let class = match instr {
+      _ if instr.serializes() => &mut self.system,
+      InstrType::R{ var: MUL | MULH | MULHSU | MULHU | DIV | DIVU | REM | REMU
+        | MULW | DIVW | DIVUW | REMW | REMUW, .. } => &mut self.mul_div,
+      InstrType::I{ var: IInstr::LB | IInstr::LH | IInstr::LW | IInstr::LBU | IInstr::LHU
+        | IInstr::LWU | IInstr::LD, .. } => &mut self.loads,
+      InstrType::S{ .. } | InstrType::F{ var: FInstr::FS, .. } => &mut self.stores,
+      InstrType::F{ var: FInstr::FL, .. } => &mut self.loads,
+      InstrType::A{ .. } => &mut self.atomics,
+      InstrType::F{ .. } => &mut self.fp,
+      InstrType::V{ var: VInstr::VLE | VInstr::VLSE, .. } => &mut self.loads,
+      InstrType::V{ var: VInstr::VSE | VInstr::VSSE, .. } => &mut self.stores,
+      InstrType::V{ .. } => &mut self.vector,
+      InstrType::B{ .. } => &mut self.branches,
+      InstrType::J{ .. } | InstrType::I{ var: IInstr::JALR, .. } => &mut self.jumps,
+      _ => &mut self.alu,
+    };
+    *class += 1;
+  }
+  fn fields(&self) -> [(&'static str, u64); 10] {
+    [("alu", self.alu), ("mul_div", self.mul_div), ("loads", self.loads), ("stores", self.stores),
+      ("atomics", self.atomics), ("fp", self.fp), ("vector", self.vector), ("branches", self.branches),
+      ("jumps", self.jumps), ("system", self.system)]
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+  pub mix: InstrMix,
+  // cycles decode waited on a load, or another result only known after MEM
+  pub load_use_stalls: u64,
+  // cycles decode waited on any other result that could not be forwarded in time
+  pub raw_stalls: u64,
+  // cycles fetch waited on the I-cache, and the in order MEM stage on the D-cache
+  pub fetch_stalls: u64,
+  pub memory_stalls: u64,
+  // cycles the out of order core could not dispatch because the reorder buffer, reservation
+  // stations, a load or store queue or the branch masks were full, or a system instruction had
+  // to commit first
+  pub rob_stalls: u64,
+  pub station_stalls: u64,
+  pub queue_stalls: u64,
+  pub branch_mask_stalls: u64,
+  pub serializing_stalls: u64,
+  // times younger instructions were thrown away, after a mispredict, trap, mret or replay
+  pub flushes: u64,
+  // jumps and branches resolved, and those that went somewhere other than where fetch carried on
+  pub branches: u64,
+  pub mispredicts: u64,
+  // cycles fetched down the wrong path before them
+  pub mispredict_penalty: u64,
+  // instructions the out of order core dispatched and then threw away, past a mispredict or trap
+  pub squashed: u64,
+  // loads the out of order core gave the value of an older store still in its store queue, and
+  // loads it replayed because an older store wrote what they had already read
+  pub forwarded_loads: u64,
+  pub order_violations: u64,
+}
+
+impl Stats {
+  // Percentage of jumps and branches fetch followed correctly
+  pub fn accuracy(&self) -> f64 {
+    if self.branches == 0 { return 100.0 };
+    100.0 * (self.branches - self.mispredicts) as f64 / self.branches as f64
+  }
+  fn stalls(&self) -> [(&'static str, u64); 9] {
+    [("load_use", self.load_use_stalls), ("raw", self.raw_stalls), ("fetch", self.fetch_stalls),
+      ("memory", self.memory_stalls), ("rob_full", self.rob_stalls), ("stations_full", self.station_stalls),
+      ("queue_full", self.queue_stalls), ("branch_masks", self.branch_mask_stalls),
+      ("serializing", self.serializing_stalls)]
+  }
+}
+
+// Everything counted over a run, for comparing configurations
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+  pub cycles: u64,
+  pub instret: u64,
+  pub stats: Stats,
+  // each cache from L1 down, by name
+  pub caches: Vec<(&'static str, CacheStats)>,
+}
+
+impl Report {
+  pub fn new<T : RegData>(ps: &ProgramState<T>) -> Self {
+    let c = &ps.caches;
+    let l1 = [("L1I", &c.icache), ("L1D", &c.dcache)].iter()
+      .filter_map(|&(name, cache)| cache.as_ref().map(|cache| (name, cache.stats)))
+      .collect::<Vec<_>>();
+    let lower = c.lower.iter().zip(["L2", "L3"].iter()).map(|(cache, &name)| (name, cache.stats));
+    Report{ cycles: ps.csrs.cycle, instret: ps.csrs.instret, stats: ps.stats, caches: l1.into_iter().chain(lower).collect() }
+  }
+
+  // Cycles per retired instruction
+  pub fn cpi(&self) -> f64 {
+    if self.instret == 0 { return 0.0 };
+    self.cycles as f64 / self.instret as f64
+  }
+
+  // The report as a single JSON object
+  pub fn json(&self) -> String {
+    let object = |fields: &[(&str, String)]| {
+      let fields: Vec<String> = fields.iter().map(|(k, v)| format!("\"{}\":{}", k, v)).collect();
+      format!("{{{}}}", fields.join(","))
+    };
+    let counts = |fields: &[(&str, u64)]| {
+      object(&fields.iter().map(|&(k, v)| (k, v.to_string())).collect::<Vec<_>>())
+    };
+    let s = &self.stats;
+    let caches: Vec<(&str, String)> = self.caches.iter().map(|(name, c)| (*name, object(&[
+      ("reads", c.reads.to_string()), ("read_misses", c.read_misses.to_string()),
+      ("writes", c.writes.to_string()), ("write_misses", c.write_misses.to_string()),
+      ("writebacks", c.writebacks.to_string()), ("write_throughs", c.write_throughs.to_string()),
+      ("hit_rate", format!("{:.4}", c.hit_rate())),
+    ]))).collect();
+    object(&[
+      ("cycles", self.cycles.to_string()),
+      ("instret", self.instret.to_string()),
+      ("cpi", format!("{:.4}", self.cpi())),
+      ("mix", counts(&s.mix.fields())),
+      ("stalls", counts(&s.stalls())),
+      ("flushes", s.flushes.to_string()),
+      ("squashed", s.squashed.to_string()),
+      ("branches", object(&[
+        ("resolved", s.branches.to_string()), ("mispredicts", s.mispredicts.to_string()),
+        ("accuracy", format!("{:.4}", s.accuracy())), ("penalty", s.mispredict_penalty.to_string()),
+      ])),
+      ("forwarded_loads", s.forwarded_loads.to_string()),
+      ("order_violations", s.order_violations.to_string()),
+      ("caches", object(&caches)),
+    ])
+  }
+}
+
+// A table with a row per counter, nesting the breakdowns under their totals
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let row = |f: &mut fmt::Formatter, name: &str, v: &dyn fmt::Display| writeln!(f, "{:<24}{:>12}", name, v);
+    let s = &self.stats;
+    row(f, "cycles", &self.cycles)?;
+    row(f, "instructions", &self.instret)?;
+    row(f, "CPI", &format!("{:.3}", self.cpi()))?;
+    s.mix.fields().iter().try_for_each(|(name, v)| row(f, &format!("  {}", name), v))?;
+    row(f, "stalls", &s.stalls().iter().map(|(_, v)| v).sum::<u64>())?;
+    s.stalls().iter().filter(|(_, v)| *v > 0).try_for_each(|(name, v)| row(f, &format!("  {}", name), v))?;
+    row(f, "flushes", &s.flushes)?;
+    row(f, "  squashed", &s.squashed)?;
+    row(f, "branches", &s.branches)?;
+    row(f, "  mispredicts", &s.mispredicts)?;
+    row(f, "  accuracy", &format!("{:.2}%", s.accuracy()))?;
+    row(f, "  penalty", &s.mispredict_penalty)?;
+    row(f, "forwarded loads", &s.forwarded_loads)?;
+    row(f, "order violations", &s.order_violations)?;
+    for (name, c) in self.caches.iter() {
+      row(f, &format!("{} accesses", name), &c.accesses())?;
+      row(f, "  misses", &c.misses())?;
+      row(f, "  hit rate", &format!("{:.2}%", c.hit_rate()))?;
+      row(f, "  writebacks", &c.writebacks)?;
+      row(f, "  write throughs", &c.write_throughs)?;
+    };
+    Ok(())
+  }
+}
+
+#[test]
+fn test_report() {
+  use crate::cache::Cache;
+  let mut ps = crate::program_state::ProgramState::<u32>::new(crate::mem::Memory::new(0x100));
+  ps.caches.dcache = Some(Cache::l1("size=256,line=16"));
+  ps.caches.dcache.as_mut().unwrap().access(0, false);
+  ps.csrs.cycle = 30;
+  ps.csrs.instret = 20;
+  ps.stats.mix.count(&crate::instr::decode(0x02a50533).unwrap()); // mul a0, a0, a0
+  ps.stats.load_use_stalls = 2;
+  let report = Report::new(&ps);
+  assert_eq!(report.cpi(), 1.5);
+  let table = report.to_string();
+  assert!(table.contains("CPI                            1.500\n"));
+  assert!(table.contains("  mul_div                          1\n"));
+  assert!(table.contains("  load_use                         2\n"));
+  assert!(table.contains("L1D accesses                       1\n"));
+  let json = report.json();
+  assert!(json.starts_with("{\"cycles\":30,\"instret\":20,\"cpi\":1.5000,\"mix\":{\"alu\":0,\"mul_div\":1,"));
+  assert!(json.contains("\"caches\":{\"L1D\":{\"reads\":1,\"read_misses\":1,"));
+}
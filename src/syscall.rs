@@ -0,0 +1,262 @@
+// System calls made through ECALL, following the RISC-V Linux ABI:
+// a7 holds the call number, a0-a5 the arguments and the result is returned in a0
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use crate::program_state::{ProgramState, Status, Exceptions, Privilege};
+use crate::reg::RegData;
+
+pub const A0: u32 = 10;
+pub const A7: u32 = 17;
+
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_BRK: u32 = 214;
+
+const AT_FDCWD: i32 = -100;
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+// longest path openat will read out of simulated memory
+const PATH_MAX: usize = 4096;
+
+pub trait SyscallHandler<T : RegData> : std::fmt::Debug {
+  // Called when an ECALL executes, with the handler itself removed from the state
+  fn ecall(&mut self, ps: &mut ProgramState<T>);
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Dispatches an ECALL to the installed handler, without one it is an exception for the
+  // guest to handle. ECALLs from S and U mode are always left to the guest's own kernel.
+  pub fn ecall(&mut self) -> Result<(), Exceptions> {
+    let e = match self.privilege {
+      Privilege::User => Exceptions::EcallU,
+      Privilege::Supervisor => Exceptions::EcallS,
+      Privilege::Machine => Exceptions::EcallM,
+    };
+    if self.privilege != Privilege::Machine { return Err(e) };
+    let mut handler = self.syscalls.take().ok_or(e)?;
+    handler.ecall(self);
+    self.syscalls = Some(handler);
+    Ok(())
+  }
+}
+
+// The subset of Linux needed by newlib: console io, exit, brk and files inside a host directory
+#[derive(Debug)]
+pub struct LinuxSyscalls {
+  // start of the heap and the current program break
+  brk_start: usize,
+  brk: usize,
+  // host directory that openat paths are resolved against, files are inaccessible without one
+  sandbox: Option<PathBuf>,
+  files: HashMap<i32, File>,
+  next_fd: i32,
+}
+
+impl LinuxSyscalls {
+  pub fn new(brk: usize, sandbox: Option<PathBuf>) -> Self {
+    LinuxSyscalls{ brk_start: brk, brk, sandbox, files: HashMap::new(), next_fd: 3 }
+  }
+
+  // Maps a guest path into the sandbox, refusing anything that could climb out of it. Symlinks
+  // inside the sandbox may point anywhere, so the check is on the real path, and files that do
+  // not exist yet are checked through their directory.
+  fn resolve(&self, path: &str) -> Result<PathBuf, i32> {
+    let root = self.sandbox.as_ref().ok_or(EACCES)?.canonicalize().map_err(errno)?;
+    let rel = Path::new(path.trim_start_matches('/'));
+    let escapes = rel.components()
+      .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes { return Err(EACCES) };
+    let joined = root.join(rel);
+    let real = match joined.canonicalize() {
+      Ok(real) => real,
+      // a dangling symlink would be followed when the file is created
+      Err(_) if joined.symlink_metadata().is_ok() => return Err(EACCES),
+      Err(_) => {
+        let name = joined.file_name().ok_or(ENOENT)?;
+        joined.parent().ok_or(ENOENT)?.canonicalize().map_err(errno)?.join(name)
+      },
+    };
+    if real.starts_with(&root) { Ok(real) } else { Err(EACCES) }
+  }
+
+  fn openat<T : RegData>(&mut self, ps: &ProgramState<T>, args: [usize; 6]) -> Result<usize, i32> {
+    if args[0] as i32 != AT_FDCWD { return Err(EBADF) };
+    let path = read_c_str(ps, args[1]).ok_or(EFAULT)?;
+    let path = self.resolve(&path)?;
+    let flags = args[2];
+    let mut opts = OpenOptions::new();
+    match flags & 0b11 {
+      0 => opts.read(true),
+      1 => opts.write(true),
+      2 => opts.read(true).write(true),
+      _ => return Err(EINVAL),
+    };
+    opts.create(flags & 0o100 != 0).truncate(flags & 0o1000 != 0).append(flags & 0o2000 != 0);
+    let file = opts.open(path).map_err(errno)?;
+    let fd = self.next_fd;
+    self.next_fd += 1;
+    self.files.insert(fd, file);
+    Ok(fd as usize)
+  }
+
+  fn read<T : RegData>(&mut self, ps: &mut ProgramState<T>, args: [usize; 6])
+    -> Result<usize, i32> {
+    // checked before reading so a bad buffer neither loses input nor sizes the allocation
+    if !ps.mem.in_bounds(args[1], args[2]) { return Err(EFAULT) };
+    let mut buf = vec![0; args[2]];
+    let n = match args[0] as i32 {
+      0 => io::stdin().read(&mut buf),
+      fd => self.files.get_mut(&fd).ok_or(EBADF)?.read(&mut buf),
+    }.map_err(errno)?;
+    ps.mem.write_bytes(args[1], &buf[..n]).map_err(|_| EFAULT)?;
+    Ok(n)
+  }
+
+  fn write<T : RegData>(&mut self, ps: &ProgramState<T>, args: [usize; 6]) -> Result<usize, i32> {
+    let buf = ps.mem.read_bytes(args[1], args[2]).map_err(|_| EFAULT)?;
+    match args[0] as i32 {
+      1 => io::stdout().write(buf).and_then(|n| io::stdout().flush().map(|_| n)),
+      2 => io::stderr().write(buf),
+      fd => self.files.get_mut(&fd).ok_or(EBADF)?.write(buf),
+    }.map_err(errno)
+  }
+
+  // brk(0) queries the break, otherwise it moves as long as it stays between the end of the
+  // program and the stack, and the current break is returned either way
+  fn brk<T : RegData>(&mut self, ps: &ProgramState<T>, addr: usize) -> usize {
+    let sp = ps.regs[2].as_usize();
+    if addr >= self.brk_start && addr < sp { self.brk = addr };
+    self.brk
+  }
+}
+
+impl <T : RegData> SyscallHandler<T> for LinuxSyscalls {
+  fn ecall(&mut self, ps: &mut ProgramState<T>) {
+    let mut args = [0; 6];
+    (0..6).for_each(|i| args[i] = ps.regs[A0 + i as u32].as_usize());
+    let number = ps.regs[A7].as_usize() as u32;
+    let result = match number {
+      SYS_OPENAT => self.openat(ps, args),
+      SYS_CLOSE => self.files.remove(&(args[0] as i32)).map(|_| 0).ok_or(EBADF),
+      SYS_READ => self.read(ps, args),
+      SYS_WRITE => self.write(ps, args),
+      SYS_EXIT | SYS_EXIT_GROUP => {
+        ps.status = Status::Exit(args[0] as i32);
+        return
+      },
+      SYS_BRK => Ok(self.brk(ps, args[0])),
+      _ => Err(ENOSYS),
+    };
+    let ret = match result {
+      Ok(v) => T::from_u64(v as u64),
+      Err(e) => T::from_signed(T::Signed::from(-e)),
+    };
+    ps.regs.force_assign(A0, ret);
+  }
+}
+
+fn errno(e: io::Error) -> i32 { e.raw_os_error().unwrap_or(EINVAL) }
+
+// Strings have to end inside both memory and the first PATH_MAX bytes
+fn read_c_str<T : RegData>(ps: &ProgramState<T>, addr: usize) -> Option<String> {
+  let len = ps.mem.end().saturating_sub(addr).min(PATH_MAX);
+  let bytes = ps.mem.read_bytes(addr, len).ok()?;
+  let end = bytes.iter().position(|&b| b == 0)?;
+  String::from_utf8(bytes[..end].to_vec()).ok()
+}
+
+#[cfg(test)]
+fn test_state(sandbox: Option<PathBuf>) -> ProgramState<u32> {
+  let mut ps = ProgramState::new(crate::mem::Memory::new(0x1000));
+  ps.regs.force_assign(2, 0x1000);
+  ps.syscalls = Some(Box::new(LinuxSyscalls::new(0x800, sandbox)));
+  ps
+}
+
+#[cfg(test)]
+fn call(ps: &mut ProgramState<u32>, number: u32, args: &[u32]) -> i32 {
+  ps.regs.force_assign(A7, number);
+  args.iter().enumerate().for_each(|(i, &v)| ps.regs.force_assign(A0 + i as u32, v));
+  ps.ecall().unwrap();
+  ps.regs[A0] as i32
+}
+
+#[test]
+fn test_exit_and_unknown() {
+  let mut ps = test_state(None);
+  assert_eq!(call(&mut ps, 1234, &[]), -ENOSYS);
+  assert_eq!(ps.status, Status::Running);
+  call(&mut ps, SYS_EXIT, &[3]);
+  assert_eq!(ps.status, Status::Exit(3));
+
+  let mut bare = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  assert_eq!(bare.ecall(), Err(Exceptions::EcallM));
+  // the handler only sees M mode ecalls
+  ps.privilege = Privilege::User;
+  assert_eq!(ps.ecall(), Err(Exceptions::EcallU));
+}
+
+#[test]
+fn test_brk() {
+  let mut ps = test_state(None);
+  assert_eq!(call(&mut ps, SYS_BRK, &[0]), 0x800);
+  assert_eq!(call(&mut ps, SYS_BRK, &[0x900]), 0x900);
+  // cannot shrink below the program or grow into the stack
+  assert_eq!(call(&mut ps, SYS_BRK, &[0x100]), 0x900);
+  assert_eq!(call(&mut ps, SYS_BRK, &[0x1000]), 0x900);
+}
+
+#[test]
+fn test_sandboxed_files() {
+  let dir = std::env::temp_dir().join(format!("riscv-syscall-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let mut ps = test_state(Some(dir.clone()));
+  ps.mem.write_bytes(0x100, b"out.txt\0../escape\0hello").unwrap();
+  let fd = call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0x100, 0o1101]);
+  assert_eq!(fd, 3);
+  assert_eq!(call(&mut ps, SYS_WRITE, &[3, 0x112, 5]), 5);
+  assert_eq!(call(&mut ps, SYS_CLOSE, &[3]), 0);
+  assert_eq!(call(&mut ps, SYS_CLOSE, &[3]), -EBADF);
+  assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"hello");
+  assert_eq!(call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0x108, 0]), -EACCES);
+
+  let fd = call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0x100, 0]);
+  // a buffer outside memory fails without reading anything
+  assert_eq!(call(&mut ps, SYS_READ, &[fd as u32, 0x200, u32::MAX]), -EFAULT);
+  assert_eq!(call(&mut ps, SYS_READ, &[fd as u32, 0xffc, 16]), -EFAULT);
+  assert_eq!(call(&mut ps, SYS_READ, &[fd as u32, 0x200, 16]), 5);
+  assert_eq!(ps.mem.read_bytes(0x200, 5).unwrap(), b"hello");
+  // paths running off the end of memory
+  assert_eq!(call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, u32::MAX, 0]), -EFAULT);
+  ps.mem.write_bytes(0xffc, b"abcd").unwrap();
+  assert_eq!(call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0xffc, 0]), -EFAULT);
+  assert_eq!(read_c_str(&ps, usize::MAX), None);
+
+  // symlinks cannot lead out of the sandbox, whether or not their target exists
+  #[cfg(unix)] {
+    let outside = std::env::temp_dir().join(format!("riscv-outside-{}", std::process::id()));
+    std::fs::write(&outside, b"secret").unwrap();
+    std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+    let missing = outside.with_extension("missing");
+    std::os::unix::fs::symlink(&missing, dir.join("dangling")).unwrap();
+    ps.mem.write_bytes(0x300, b"link\0dangling\0").unwrap();
+    assert_eq!(call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0x300, 0]), -EACCES);
+    assert_eq!(call(&mut ps, SYS_OPENAT, &[AT_FDCWD as u32, 0x305, 0o101]), -EACCES);
+    assert!(!missing.exists());
+    std::fs::remove_file(&outside).unwrap();
+  };
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  let mut closed = test_state(None);
+  assert_eq!(call(&mut closed, SYS_OPENAT, &[AT_FDCWD as u32, 0x100, 0]), -EACCES);
+}
@@ -0,0 +1,214 @@
+// Log of retired instructions, in the format of spike's --log-commits so that runs can be
+// diffed against it
+use std::fmt;
+use std::io::Write;
+use crate::disasm::Syntax;
+use crate::instr::{InstrType, IInstr, SInstr};
+use crate::mem::Size;
+use crate::program_state::{ProgramState, Exceptions, Privilege};
+use crate::reg::RegData;
+use crate::rvc;
+
+// Memory touched by an instruction, stores also carry the value written. It is kept as 64 bits
+// since FSD stores a double even when registers are narrower.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct MemAccess<T : RegData> {
+  pub addr: T,
+  pub size: Size,
+  pub store: Option<u64>,
+}
+
+impl <T : RegData> MemAccess<T> {
+  pub fn load(addr: T, size: Size) -> Self { MemAccess{ addr, size, store: None } }
+  // Only the bytes that are stored are kept from v
+  pub fn store(addr: T, size: Size, v: T) -> Self { Self::store_bits(addr, size, v.as_usize() as u64) }
+  pub fn store_bits(addr: T, size: Size, v: u64) -> Self {
+    let bits = size.bytes() * 8;
+    let mask = if bits >= 64 { !0 } else { (1 << bits) - 1 };
+    MemAccess{ addr, size, store: Some(v & mask) }
+  }
+}
+
+// What an engine did when an instruction left the pipeline, in program order
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Retired<T : RegData> {
+  // the register written and the memory accessed by an instruction that completed, and the
+  // level it ran at, which for mret and sret is the one they returned from
+  Commit{ pc: T, raw: u32, prv: Privilege, rd: Option<(u32, T)>, frd: Option<(u32, u64)>,
+    access: Option<MemAccess<T>> },
+  Exception{ e: Exceptions, epc: T, tval: T },
+}
+
+// Formats as the lines spike logs for the event
+impl <T : RegData> fmt::Display for Retired<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let xlen = T::BYTE_SIZE * 2;
+    match *self {
+      Retired::Commit{ pc, raw, prv, rd, frd, access } => {
+        write!(f, "core   0: {} 0x{:0w$x} (0x{:0i$x})", prv as u32, pc, raw, w = xlen,
+          i = rvc::instr_len(raw) * 2)?;
+        if let Some((rd, v)) = rd { write!(f, " x{:<2} 0x{:0w$x}", rd, v, w = xlen)? };
+        if let Some((rd, v)) = frd { write!(f, " f{:<2} 0x{:016x}", rd, v)? };
+        if let Some(MemAccess{ addr, size, store }) = access {
+          write!(f, " mem 0x{:0w$x}", addr, w = xlen)?;
+          if let Some(v) = store { write!(f, " 0x{:0w$x}", v, w = size.bytes() * 2)? };
+        };
+        Ok(())
+      },
+      Retired::Exception{ e, epc, tval } => {
+        let name = match e {
+          Exceptions::InstrMisaligned => "trap_instruction_address_misaligned",
+          Exceptions::InstrAccessFault => "trap_instruction_access_fault",
+          Exceptions::IllegalInstruction => "trap_illegal_instruction",
+          Exceptions::Breakpoint => "trap_breakpoint",
+          Exceptions::LoadMisaligned => "trap_load_address_misaligned",
+          Exceptions::LoadAccessFault => "trap_load_access_fault",
+          Exceptions::StoreMisaligned => "trap_store_address_misaligned",
+          Exceptions::StoreAccessFault => "trap_store_access_fault",
+          Exceptions::EcallU => "trap_user_ecall",
+          Exceptions::EcallS => "trap_supervisor_ecall",
+          Exceptions::EcallM => "trap_machine_ecall",
+        };
+        writeln!(f, "core   0: exception {}, epc 0x{:0w$x}", name, epc, w = xlen)?;
+        write!(f, "core   0:           tval 0x{:0w$x}", tval, w = xlen)
+      },
+    }
+  }
+}
+
+// Sees everything an engine retires. Hooks are removed from the state while they run, and may
+// stop the engine by changing its status.
+pub trait RetireHook<T : RegData> : fmt::Debug {
+  fn retire(&mut self, ps: &mut ProgramState<T>, event: &Retired<T>);
+}
+
+pub struct Tracer {
+  out: Box<dyn Write>,
+  // also log the disassembly of each instruction, like spike -l
+  disasm: bool,
+}
+
+impl fmt::Debug for Tracer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Tracer").field("disasm", &self.disasm).finish_non_exhaustive()
+  }
+}
+
+impl Tracer {
+  pub fn new(out: Box<dyn Write>, disasm: bool) -> Self { Tracer{ out, disasm } }
+
+  fn log<T : RegData>(&mut self, event: &Retired<T>) -> std::io::Result<()> {
+    if let (true, Retired::Commit{ pc, raw, .. }) = (self.disasm, event) {
+      let text = crate::disasm::disassemble(*raw, *pc, Syntax::default());
+      writeln!(self.out, "core   0: 0x{:0w$x} (0x{:0i$x}) {}", pc, raw, text, w = T::BYTE_SIZE * 2,
+        i = rvc::instr_len(*raw) * 2)?;
+    };
+    writeln!(self.out, "{}", event)
+  }
+}
+
+impl <T : RegData> RetireHook<T> for Tracer {
+  fn retire(&mut self, _: &mut ProgramState<T>, event: &Retired<T>) {
+    self.log(event).expect("Failed to write trace");
+  }
+}
+
+// Memory accessed by a load or store, given how to read its source registers
+pub(crate) fn mem_access<T : RegData>(instr: &InstrType, reg: impl Fn(u32) -> T)
+  -> Option<MemAccess<T>> {
+  match *instr {
+    InstrType::I{ var, rs1, sx_imm, .. } => {
+      let size = match var {
+        IInstr::LD => Size::DOUBLE,
+        IInstr::LW | IInstr::LWU => Size::WORD,
+        IInstr::LH | IInstr::LHU => Size::HALF,
+        IInstr::LB | IInstr::LBU => Size::BYTE,
+        _ => return None,
+      };
+      Some(MemAccess::load(reg(rs1).offset(T::Signed::from(sx_imm)), size))
+    },
+    InstrType::S{ var, rs1, rs2, imm } => {
+      let size = match var {
+        SInstr::SD => Size::DOUBLE, SInstr::SW => Size::WORD, SInstr::SH => Size::HALF, SInstr::SB => Size::BYTE,
+      };
+      Some(MemAccess::store(reg(rs1).offset(T::Signed::from(imm)), size, reg(rs2)))
+    },
+    _ => None,
+  }
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Whether anything is watching retired instructions, so engines can skip working out what
+  // they accessed
+  pub fn tracing(&self) -> bool { !self.hooks.is_empty() }
+
+  // Retires an instruction that completed at level prv, reading what it wrote from the register
+  // file
+  pub(crate) fn commit(&mut self, pc: T, raw: u32, prv: Privilege, instr: &InstrType,
+    access: Option<MemAccess<T>>) -> Retired<T> {
+    self.stats.mix.count(instr);
+    let rd = instr.dest().map(|rd| (rd, self.regs[rd]));
+    let frd = instr.fdest().map(|rd| (rd, self.fregs[rd]));
+    let event = Retired::Commit{ pc, raw, prv, rd, frd, access };
+    self.retire(&event);
+    event
+  }
+
+  pub(crate) fn retire(&mut self, event: &Retired<T>) {
+    if self.hooks.is_empty() { return };
+    let mut hooks = std::mem::take(&mut self.hooks);
+    hooks.iter_mut().for_each(|hook| hook.retire(self, event));
+    self.hooks = hooks;
+  }
+}
+
+// Clones what is written so tests can read the log back
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(pub std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuf {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.borrow_mut().write(buf) }
+  fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+impl SharedBuf {
+  pub fn text(&self) -> String { String::from_utf8(self.0.borrow().clone()).unwrap() }
+}
+
+#[test]
+fn test_commit_format() {
+  let buf = SharedBuf::default();
+  let mut tracer = Tracer::new(Box::new(buf.clone()), true);
+  let m = Privilege::Machine;
+  tracer.log(&Retired::Commit{ pc: 0x80000000u32, raw: 0x00000297, prv: m, rd: Some((5, 0x80000000)),
+    frd: None, access: None }).unwrap();
+  let store = MemAccess::store(0x40u32, Size::HALF, 0xabcd1234);
+  tracer.log(&Retired::Commit{ pc: 0x4u32, raw: 0x00a51023, prv: m, rd: None, frd: None,
+    access: Some(store) })
+    .unwrap();
+  // U mode instructions log 0
+  let load = MemAccess::load(0x40u64, Size::WORD);
+  tracer.log(&Retired::Commit{ pc: 0x8u64, raw: 0x00052583, prv: Privilege::User, rd: Some((11, 7)),
+    frd: None, access: Some(load) })
+    .unwrap();
+  tracer.log(&Retired::Commit{ pc: 0xcu32, raw: 0x0505, prv: m, rd: Some((10, 1)), frd: None,
+    access: None })
+    .unwrap();
+  tracer.log(&Retired::Exception{ e: Exceptions::IllegalInstruction, epc: 0xeu32, tval: 0 })
+    .unwrap();
+  assert_eq!(buf.text(), "\
+core   0: 0x80000000 (0x00000297) auipc t0, 0x0
+core   0: 3 0x80000000 (0x00000297) x5  0x80000000
+core   0: 0x00000004 (0x00a51023) sh a0, 0(a0)
+core   0: 3 0x00000004 (0x00a51023) mem 0x00000040 0x1234
+core   0: 0x0000000000000008 (0x00052583) lw a1, 0(a0)
+core   0: 0 0x0000000000000008 (0x00052583) x11 0x0000000000000007 mem 0x0000000000000040
+core   0: 0x0000000c (0x0505) addi a0, a0, 1
+core   0: 3 0x0000000c (0x0505) x10 0x00000001
+core   0: exception trap_illegal_instruction, epc 0x0000000e
+core   0:           tval 0x00000000
+");
+}
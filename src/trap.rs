@@ -0,0 +1,174 @@
+// Trap entry, delegated to S mode through medeleg, and the xRET, WFI and SFENCE.VMA instructions
+use crate::csr::{MSTATUS_SIE, MSTATUS_MIE, MSTATUS_SPIE, MSTATUS_MPIE, MSTATUS_SPP, MSTATUS_MPP,
+  MSTATUS_MPP_SHIFT, MSTATUS_MPRV, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW};
+use crate::program_state::{ProgramState, Status, Exceptions, Privilege};
+use crate::reg::RegData;
+use crate::trace::Retired;
+
+impl <T : RegData> ProgramState<T> {
+  // Takes an exception raised by the instruction at epc, every older instruction must have
+  // completed and no younger one may have had an effect. Exceptions below M mode whose bit is
+  // set in medeleg go to the S mode handler. Until the program installs a handler the
+  // simulation stops at the faulting instruction instead.
+  pub fn trap(&mut self, e: Exceptions, epc: T, tval: T) {
+    let delegated = self.privilege != Privilege::Machine
+      && (self.csrs.medeleg >> T::from(e.cause())) & T::one() == T::one();
+    let tvec = if delegated { self.csrs.stvec } else { self.csrs.mtvec };
+    let base = tvec & !T::from(0b11u32);
+    if !self.csrs.handler_installed {
+      self.regs.assign_pc(epc);
+      self.status = Status::Exception(e);
+      self.retire(&Retired::Exception{ e, epc, tval });
+      return
+    };
+    // interrupts are disabled in the handler, and xPIE remembers if they were enabled, along
+    // with the level the trap came from in xPP
+    let status = self.csrs.mstatus;
+    let set = |bits: u32, on: bool| if on { T::from(bits) } else { T::zero() };
+    if delegated {
+      self.csrs.sepc = epc;
+      self.csrs.scause = T::from(e.cause());
+      self.csrs.stval = tval;
+      let sie = (status & T::from(MSTATUS_SIE)) != T::zero();
+      self.csrs.mstatus = (status & !T::from(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP))
+        | set(MSTATUS_SPIE, sie) | set(MSTATUS_SPP, self.privilege == Privilege::Supervisor);
+      self.privilege = Privilege::Supervisor;
+    } else {
+      self.csrs.mepc = epc;
+      self.csrs.mcause = T::from(e.cause());
+      self.csrs.mtval = tval;
+      let mie = (status & T::from(MSTATUS_MIE)) != T::zero();
+      self.csrs.mstatus = (status & !T::from(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP))
+        | set(MSTATUS_MPIE, mie) | T::from((self.privilege as u32) << MSTATUS_MPP_SHIFT);
+      self.privilege = Privilege::Machine;
+    };
+    // vectored mode only applies to interrupts, exceptions always go to the base
+    self.regs.assign_pc(base);
+    self.retire(&Retired::Exception{ e, epc, tval });
+  }
+
+  // MRET, returning to mepc at the level in MPP and restoring the interrupt enable from before
+  // the trap. Only M mode can run it.
+  pub fn mret(&mut self) -> Result<(), Exceptions> {
+    if self.privilege != Privilege::Machine { return Err(Exceptions::IllegalInstruction) };
+    let status = self.csrs.mstatus;
+    let mpp = self.csrs.mpp();
+    let mpie = (status & T::from(MSTATUS_MPIE)) != T::zero();
+    // MPP is left at the lowest level, and returning below M mode clears MPRV
+    let mut clear = MSTATUS_MIE | MSTATUS_MPP;
+    if mpp != Privilege::Machine { clear |= MSTATUS_MPRV };
+    self.csrs.mstatus = (status & !T::from(clear)) | T::from(MSTATUS_MPIE);
+    if mpie { self.csrs.mstatus = self.csrs.mstatus | T::from(MSTATUS_MIE) };
+    self.privilege = mpp;
+    self.regs.assign_pc(self.csrs.mepc);
+    Ok(())
+  }
+
+  // SRET, the S mode version of MRET. U mode cannot run it, and neither can S mode while
+  // mstatus.TSR is set.
+  pub fn sret(&mut self) -> Result<(), Exceptions> {
+    let status = self.csrs.mstatus;
+    let tsr = (status & T::from(MSTATUS_TSR)) != T::zero();
+    if self.privilege == Privilege::User || (self.privilege == Privilege::Supervisor && tsr) {
+      return Err(Exceptions::IllegalInstruction)
+    };
+    let spp = (status & T::from(MSTATUS_SPP)) != T::zero();
+    let spie = (status & T::from(MSTATUS_SPIE)) != T::zero();
+    self.csrs.mstatus = (status & !T::from(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV))
+      | T::from(MSTATUS_SPIE);
+    if spie { self.csrs.mstatus = self.csrs.mstatus | T::from(MSTATUS_SIE) };
+    self.privilege = if spp { Privilege::Supervisor } else { Privilege::User };
+    self.regs.assign_pc(self.csrs.sepc);
+    Ok(())
+  }
+
+  // WFI, which completes at once as nothing raises interrupts. As in spike it is illegal in U
+  // mode, and in S mode while mstatus.TW is set.
+  pub fn wfi(&self) -> Result<(), Exceptions> {
+    let tw = (self.csrs.mstatus & T::from(MSTATUS_TW)) != T::zero();
+    match self.privilege {
+      Privilege::User => Err(Exceptions::IllegalInstruction),
+      Privilege::Supervisor if tw => Err(Exceptions::IllegalInstruction),
+      _ => Ok(()),
+    }
+  }
+  // SFENCE.VMA, which has no TLB to flush. Like satp it is illegal in U mode, and in S mode
+  // while mstatus.TVM is set.
+  pub fn sfence_vma(&self) -> Result<(), Exceptions> {
+    let tvm = (self.csrs.mstatus & T::from(MSTATUS_TVM)) != T::zero();
+    match self.privilege {
+      Privilege::User => Err(Exceptions::IllegalInstruction),
+      Privilege::Supervisor if tvm => Err(Exceptions::IllegalInstruction),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[test]
+fn test_trap_and_mret() {
+  let mut ps = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  ps.trap(Exceptions::LoadAccessFault, 0x8, 0x1234);
+  assert_eq!(ps.status, Status::Exception(Exceptions::LoadAccessFault));
+  assert_eq!(ps.regs.pc(), 0x8);
+
+  let mut ps = ProgramState::<u32>::new(crate::mem::Memory::new(0x10));
+  ps.csrs.write(crate::csr::MTVEC, 0x101).unwrap();
+  ps.csrs.mstatus = MSTATUS_MPP | MSTATUS_MIE;
+  ps.trap(Exceptions::IllegalInstruction, 0x8, 0xffff);
+  assert_eq!(ps.status, Status::Running);
+  assert_eq!(ps.regs.pc(), 0x100);
+  assert_eq!((ps.csrs.mepc, ps.csrs.mcause, ps.csrs.mtval), (0x8, 2, 0xffff));
+  assert_eq!(ps.csrs.mstatus, MSTATUS_MPP | MSTATUS_MPIE);
+  ps.csrs.mepc = 0xc;
+  assert_eq!(ps.mret(), Ok(()));
+  assert_eq!(ps.regs.pc(), 0xc);
+  // MPP goes back to U once it has been used
+  assert_eq!(ps.csrs.mstatus, MSTATUS_MPIE | MSTATUS_MIE);
+  assert_eq!(ps.privilege, Privilege::Machine);
+
+  // a handler at address 0 is as good as any other
+  ps.csrs.write(crate::csr::MTVEC, 0).unwrap();
+  ps.trap(Exceptions::Breakpoint, 0xc, 0xc);
+  assert_eq!((ps.status, ps.regs.pc(), ps.csrs.mepc), (Status::Running, 0, 0xc));
+}
+
+#[test]
+fn test_privilege_changes() {
+  let mut ps = ProgramState::<u64>::new(crate::mem::Memory::new(0x10));
+  ps.csrs.mtvec = 0x100;
+  ps.csrs.stvec = 0x200;
+  ps.csrs.handler_installed = true;
+  ps.csrs.medeleg = 1 << Exceptions::EcallU.cause() | 1 << Exceptions::Breakpoint.cause();
+  // mret to S mode, with MPRV cleared on the way
+  ps.csrs.mstatus = (Privilege::Supervisor as u64) << MSTATUS_MPP_SHIFT | MSTATUS_MPRV as u64;
+  ps.csrs.mepc = 0x40;
+  assert_eq!(ps.mret(), Ok(()));
+  assert_eq!((ps.privilege, ps.regs.pc(), ps.csrs.mstatus),
+    (Privilege::Supervisor, 0x40, MSTATUS_MPIE as u64));
+  assert_eq!(ps.mret(), Err(Exceptions::IllegalInstruction));
+  // a delegated exception in S mode stays in S mode
+  ps.csrs.mstatus |= MSTATUS_SIE as u64;
+  ps.trap(Exceptions::Breakpoint, 0x44, 0x44);
+  assert_eq!((ps.privilege, ps.regs.pc(), ps.csrs.sepc, ps.csrs.scause),
+    (Privilege::Supervisor, 0x200, 0x44, 3));
+  assert_eq!(ps.csrs.mstatus, (MSTATUS_MPIE | MSTATUS_SPIE | MSTATUS_SPP) as u64);
+  // sret to U mode, where an ecall is delegated and an illegal instruction is not
+  ps.csrs.mstatus &= !(MSTATUS_SPP as u64);
+  ps.csrs.sepc = 0x80;
+  assert_eq!(ps.sret(), Ok(()));
+  assert_eq!((ps.privilege, ps.regs.pc()), (Privilege::User, 0x80));
+  assert_eq!(ps.csrs.mstatus, (MSTATUS_MPIE | MSTATUS_SPIE | MSTATUS_SIE) as u64);
+  let illegal = Err(Exceptions::IllegalInstruction);
+  assert_eq!((ps.sret(), ps.wfi(), ps.sfence_vma()), (illegal, illegal, illegal));
+  ps.trap(Exceptions::EcallU, 0x84, 0);
+  assert_eq!((ps.privilege, ps.regs.pc(), ps.csrs.scause), (Privilege::Supervisor, 0x200, 8));
+  assert_eq!(ps.csrs.mstatus & (MSTATUS_SPP | MSTATUS_SIE) as u64, 0);
+  ps.trap(Exceptions::IllegalInstruction, 0x204, 0);
+  assert_eq!((ps.privilege, ps.regs.pc(), ps.csrs.mepc, ps.csrs.mpp()),
+    (Privilege::Machine, 0x100, 0x204, Privilege::Supervisor));
+  // WFI, SRET and SFENCE.VMA in S mode are up to TW, TSR and TVM
+  ps.privilege = Privilege::Supervisor;
+  assert_eq!((ps.wfi(), ps.sfence_vma()), (Ok(()), Ok(())));
+  ps.csrs.mstatus |= (MSTATUS_TW | MSTATUS_TSR | MSTATUS_TVM) as u64;
+  assert_eq!((ps.sret(), ps.wfi(), ps.sfence_vma()), (illegal, illegal, illegal));
+}
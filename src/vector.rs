@@ -0,0 +1,333 @@
+// V extension, the vector register file and the integer subset of RVV 1.0. Elements are kept
+// little-endian, and a group of registers is just the registers after the first, so element i of
+// a group is i * SEW bits on from the start of its first register.
+use std::fmt;
+use crate::instr::{InstrType, VInstr, VSrc};
+use crate::mem::Size;
+use crate::program_state::{ProgramState, Exceptions};
+use crate::reg::RegData;
+use crate::trace::MemAccess;
+
+// Bits in a vector register unless --vlen says otherwise
+pub const DEFAULT_VLEN: usize = 128;
+// Widest element, in bits
+pub const ELEN: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct VRegister {
+  vlenb: usize,
+  data: Vec<u8>,
+}
+
+impl Default for VRegister {
+  fn default() -> Self { VRegister::new(DEFAULT_VLEN).unwrap() }
+}
+
+impl VRegister {
+  // vlen is in bits, a power of two from ELEN up to the most the spec allows
+  pub fn new(vlen: usize) -> Result<Self, String> {
+    if !vlen.is_power_of_two() || !(ELEN..=65536).contains(&vlen) {
+      return Err(format!("VLEN must be a power of two from {} to 65536, not {}", ELEN, vlen));
+    };
+    Ok(VRegister{ vlenb: vlen / 8, data: vec![0; 32 * vlen / 8] })
+  }
+  pub fn vlenb(&self) -> usize { self.vlenb }
+  // Element i, sew bytes wide, of the group starting at v
+  pub fn get(&self, v: u32, i: usize, sew: usize) -> u64 {
+    let at = v as usize * self.vlenb + i * sew;
+    let mut bytes = [0u8; 8];
+    bytes[..sew].copy_from_slice(&self.data[at..at + sew]);
+    u64::from_le_bytes(bytes)
+  }
+  // Only the low sew bytes of x are kept
+  pub fn set(&mut self, v: u32, i: usize, sew: usize, x: u64) {
+    let at = v as usize * self.vlenb + i * sew;
+    self.data[at..at + sew].copy_from_slice(&x.to_le_bytes()[..sew]);
+  }
+  // Bit i of the mask held in v
+  pub fn mask(&self, v: u32, i: usize) -> bool {
+    self.data[v as usize * self.vlenb + i / 8] >> (i % 8) & 1 == 1
+  }
+  pub fn set_mask(&mut self, v: u32, i: usize, bit: bool) {
+    let byte = &mut self.data[v as usize * self.vlenb + i / 8];
+    *byte = *byte & !(1 << (i % 8)) | (bit as u8) << (i % 8);
+  }
+}
+
+// Each register is shown as one number, most significant byte first
+impl fmt::Display for VRegister {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, reg) in self.data.chunks(self.vlenb).enumerate() {
+      write!(f, "[ v{:02}: ", i)?;
+      for byte in reg.iter().rev() { write!(f, "{:02x}", byte)? };
+      writeln!(f, " ]")?;
+    };
+    Ok(())
+  }
+}
+
+// SEW in bytes and LMUL in eighths, so that fractional LMULs are whole numbers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VType {
+  pub sew: usize,
+  pub lmul8: usize,
+}
+
+impl VType {
+  // The settings asked for by a vtype, None for reserved ones, which include vill, and for SEW
+  // being wider than LMUL * ELEN. vta and vma need nothing, as tail and masked off elements are
+  // always left undisturbed.
+  pub fn new(vtype: u64) -> Option<VType> {
+    let (vsew, vlmul) = ((vtype >> 3) & 0b111, vtype & 0b111);
+    if vtype >> 8 != 0 || vsew > 3 || vlmul == 4 { return None };
+    let lmul8 = if vlmul < 4 { 8 << vlmul } else { 1 << (vlmul - 5) };
+    let sew = 1 << vsew;
+    if sew * 8 * 8 > lmul8 * ELEN { return None };
+    Some(VType{ sew, lmul8 })
+  }
+  // The most elements an instruction can work on
+  pub fn vlmax(self, vlenb: usize) -> usize { vlenb * self.lmul8 / 8 / self.sew }
+  // Registers in a group of elements eew bytes wide, fractional groups still taking a whole
+  // register. None if the group would be more than 8 registers or less than an eighth of one.
+  fn regs(self, eew: usize) -> Option<u32> {
+    let emul8 = self.lmul8 * eew / self.sew;
+    if !(1..=64).contains(&emul8) { return None };
+    Some((emul8 / 8).max(1) as u32)
+  }
+}
+
+// Integer operation between x from vs2 and y, the other operand, both bits wide. Compares give 1
+// or 0, and results are cut down to bits wide when stored.
+fn alu(var: VInstr, x: u64, y: u64, bits: usize) -> u64 {
+  use VInstr::*;
+  let sx = |v: u64| (v << (64 - bits)) as i64 >> (64 - bits);
+  let shamt = y as u32 & (bits as u32 - 1);
+  match var {
+    VADD | VREDSUM => x.wrapping_add(y),
+    VSUB => x.wrapping_sub(y),
+    VRSUB => y.wrapping_sub(x),
+    VAND | VREDAND => x & y,
+    VOR | VREDOR => x | y,
+    VXOR | VREDXOR => x ^ y,
+    VSLL => x << shamt,
+    VSRL => x >> shamt,
+    VSRA => (sx(x) >> shamt) as u64,
+    VMINU | VREDMINU => x.min(y),
+    VMIN | VREDMIN => if sx(x) < sx(y) { x } else { y },
+    VMAXU | VREDMAXU => x.max(y),
+    VMAX | VREDMAX => if sx(x) > sx(y) { x } else { y },
+    VMUL => x.wrapping_mul(y),
+    VMULH => ((sx(x) as i128 * sx(y) as i128) >> bits) as u64,
+    VMULHU => ((x as u128 * y as u128) >> bits) as u64,
+    VMULHSU => ((sx(x) as i128 * y as i128) >> bits) as u64,
+    VMV => y,
+    VMSEQ => (x == y) as u64,
+    VMSNE => (x != y) as u64,
+    VMSLTU => (x < y) as u64,
+    VMSLT => (sx(x) < sx(y)) as u64,
+    VMSLEU => (x <= y) as u64,
+    VMSLE => (sx(x) <= sx(y)) as u64,
+    VMSGTU => (x > y) as u64,
+    VMSGT => (sx(x) > sx(y)) as u64,
+    _ => panic!("alu() called with {:?}", var),
+  }
+}
+
+impl <T : RegData> ProgramState<T> {
+  // Runs a V instruction given the values of its x register sources, returning what goes in the
+  // integer rd. Elements from vstart up to vl are worked on and vstart then goes back to 0, unless
+  // a load or store faults, which leaves vstart at the element that faulted so that it can be
+  // restarted from there. Tail and masked off elements are left undisturbed.
+  pub(crate) fn vector(&mut self, instr: &InstrType, a: T, b: T) -> Result<Option<T>, (Exceptions, T)> {
+    use VInstr::*;
+    let (var, vd, rs1, vs2, src, vm, imm) = match *instr {
+      InstrType::V{ var, vd, rs1, vs2, src, vm, imm } => (var, vd, rs1, vs2, src, vm, imm),
+      _ => panic!("vector() called with {:?}", instr),
+    };
+    if let VSETVLI | VSETIVLI | VSETVL = var { return Ok(Some(self.vset(var, vd, rs1, imm, a, b))) };
+    let illegal = (Exceptions::IllegalInstruction, T::zero());
+    let vtype = VType::new(self.csrs.vtype.as_usize() as u64).ok_or(illegal)?;
+    let (sew, bits, xlen) = (vtype.sew, vtype.sew * 8, T::BYTE_SIZE * 8);
+    let (vl, vstart) = (self.csrs.vl.as_usize(), self.csrs.vstart.as_usize());
+    let group = vtype.regs(sew).expect("SEW needs a group");
+    let aligned = |r: u32, regs: u32| r.is_multiple_of(regs);
+    // register groups start at a multiple of their size, and masked instructions cannot
+    // overwrite v0 unless they write a mask or a single element
+    let legal = match var {
+      VLE | VLSE | VSE | VSSE => vtype.regs(imm as usize).is_some_and(|regs| aligned(vd, regs)),
+      _ if var.reduction() => aligned(vs2, group) && vstart == 0,
+      VCPOP | VFIRST => vstart == 0,
+      _ if var.mask_logical() => true,
+      VMVXS | VMVSX => true,
+      _ if var.compare() => aligned(vs2, group) && (src != VSrc::VV || aligned(rs1, group)),
+      _ => aligned(vd, group) && aligned(vs2, group) && (src != VSrc::VV || aligned(rs1, group))
+        && (vm || vd != 0),
+    };
+    if !legal { return Err(illegal) };
+    let trunc = |v: u64| if bits == 64 { v } else { v & ((1 << bits) - 1) };
+    let sx = |v: u64, bits: usize| ((v << (64 - bits)) as i64 >> (64 - bits)) as u64;
+    // x registers are sign extended or truncated to SEW, and immediates are sign extended other
+    // than shift amounts
+    let scalar = trunc(sx(a.as_usize() as u64, xlen));
+    let operand = |vregs: &VRegister, i: usize| match src {
+      VSrc::VV => vregs.get(rs1, i, sew),
+      VSrc::VX => scalar,
+      VSrc::VI if matches!(var, VSLL | VSRL | VSRA) => rs1 as u64,
+      VSrc::VI => trunc(sx(rs1 as u64, 5)),
+    };
+    let active = |vregs: &VRegister, i: usize| vm || vregs.mask(0, i);
+    let result = match var {
+      VLE | VLSE | VSE | VSSE => {
+        let size = match imm { 1 => Size::BYTE, 2 => Size::HALF, 4 => Size::WORD, _ => Size::DOUBLE };
+        let stride = if let VLSE | VSSE = var { b } else { T::from(imm) };
+        for i in vstart..vl {
+          if !active(&self.vregs, i) { continue };
+          let addr = a.wrapping_add(&stride.mul_lo(T::from_u64(i as u64)));
+          let access = match self.vector_access(var, vd, i, size, addr) {
+            Ok(v) => if let VLE | VLSE = var { MemAccess::load(addr, size) }
+              else { MemAccess::store_bits(addr, size, v) },
+            Err(e) => {
+              self.csrs.vstart = T::from_u64(i as u64);
+              return Err((e, addr))
+            },
+          };
+          self.data_latency(access);
+        };
+        None
+      },
+      _ if var.reduction() => {
+        // nothing is written when there are no elements
+        if vl > 0 {
+          let acc = (0..vl).filter(|&i| active(&self.vregs, i))
+            .fold(self.vregs.get(rs1, 0, sew), |acc, i| trunc(alu(var, acc, self.vregs.get(vs2, i, sew), bits)));
+          self.vregs.set(vd, 0, sew, acc);
+        };
+        None
+      },
+      _ if var.mask_logical() => {
+        for i in vstart..vl {
+          let (x, y) = (self.vregs.mask(vs2, i), self.vregs.mask(rs1, i));
+          let bit = match var {
+            VMAND => x & y, VMNAND => !(x & y), VMANDN => x & !y, VMXOR => x ^ y,
+            VMOR => x | y, VMNOR => !(x | y), VMORN => x | !y, _ => !(x ^ y),
+          };
+          self.vregs.set_mask(vd, i, bit);
+        };
+        None
+      },
+      VCPOP => {
+        let count = (0..vl).filter(|&i| active(&self.vregs, i) && self.vregs.mask(vs2, i)).count();
+        Some(T::from_u64(count as u64))
+      },
+      VFIRST => {
+        let first = (0..vl).find(|&i| active(&self.vregs, i) && self.vregs.mask(vs2, i));
+        Some(first.map_or(T::from_signed(T::Signed::from(-1)), |i| T::from_u64(i as u64)))
+      },
+      // element 0 is moved whatever vl is, though only into a body element
+      VMVXS => Some(T::from_u64(sx(self.vregs.get(vs2, 0, sew), bits))),
+      VMVSX => {
+        if vstart < vl { self.vregs.set(vd, 0, sew, scalar) };
+        None
+      },
+      _ => {
+        for i in vstart..vl {
+          let (x, y) = (self.vregs.get(vs2, i, sew), operand(&self.vregs, i));
+          match var {
+            // every element is written, the mask picks which operand it comes from
+            VMERGE => self.vregs.set(vd, i, sew, if self.vregs.mask(0, i) { y } else { x }),
+            _ if !active(&self.vregs, i) => (),
+            _ if var.compare() => self.vregs.set_mask(vd, i, alu(var, x, y, bits) == 1),
+            _ => self.vregs.set(vd, i, sew, alu(var, x, y, bits)),
+          };
+        };
+        None
+      },
+    };
+    self.csrs.vstart = T::zero();
+    Ok(result)
+  }
+
+  // Loads or stores element i of the group at vd, returning its bits
+  fn vector_access(&mut self, var: VInstr, vd: u32, i: usize, size: Size, addr: T) -> Result<u64, Exceptions> {
+    let (loc, eew) = (addr.as_usize(), size.bytes());
+    if let VInstr::VLE | VInstr::VLSE = var {
+      if !loc.is_multiple_of(eew) { return Err(Exceptions::LoadMisaligned) };
+      let bytes = self.mem.read_bytes(loc, eew).map_err(|_| Exceptions::LoadAccessFault)?;
+      let mut v = [0u8; 8];
+      v[..eew].copy_from_slice(bytes);
+      self.vregs.set(vd, i, eew, u64::from_le_bytes(v));
+      return Ok(u64::from_le_bytes(v))
+    };
+    let loc = self.store_addr(addr, size)?;
+    let v = self.vregs.get(vd, i, eew);
+    self.void_reservation(loc, size);
+    self.mem.write_bytes(loc, &v.to_le_bytes()[..eew]).expect("Checked store failed");
+    Ok(v)
+  }
+
+  // vsetvli, vsetivli and vsetvl, returning the new vl. A vtype that cannot be used sets vill
+  // and a vl of 0.
+  fn vset(&mut self, var: VInstr, rd: u32, rs1: u32, imm: u32, a: T, b: T) -> T {
+    let vtype = if var == VInstr::VSETVL { b.as_usize() as u64 } else { imm as u64 };
+    let avl = match var {
+      VInstr::VSETIVLI => rs1 as usize,
+      _ if rs1 != 0 => a.as_usize(),
+      // an rs1 of x0 asks for VLMAX, or to keep vl if rd is x0 too
+      _ if rd != 0 => usize::MAX,
+      _ => self.csrs.vl.as_usize(),
+    };
+    let (vtype, vl) = match VType::new(vtype) {
+      Some(t) => (T::from_u64(vtype), avl.min(t.vlmax(self.vregs.vlenb()))),
+      None => (vill::<T>(), 0),
+    };
+    self.csrs.vtype = vtype;
+    self.csrs.vl = T::from_u64(vl as u64);
+    self.csrs.vstart = T::zero();
+    self.csrs.vl
+  }
+}
+
+// vtype with only vill, its top bit, set
+pub fn vill<T : RegData>() -> T { T::one() << T::from((T::BYTE_SIZE * 8 - 1) as u32) }
+
+#[test]
+fn test_vtype() {
+  // e64, m2 and e8, mf8, then e16, mf8, a reserved vlmul and vill
+  assert_eq!(VType::new(0b011_001), Some(VType{ sew: 8, lmul8: 16 }));
+  assert_eq!(VType::new(0b000_101), Some(VType{ sew: 1, lmul8: 1 }));
+  assert_eq!([VType::new(0b001_101), VType::new(0b100), VType::new(1 << 8)], [None, None, None]);
+  let e32m2 = VType::new(0b010_001).unwrap();
+  assert_eq!(e32m2.vlmax(16), 8);
+  assert_eq!([e32m2.regs(1), e32m2.regs(4), e32m2.regs(8)], [Some(1), Some(2), Some(4)]);
+  assert_eq!(VType::new(0b000_011).unwrap().regs(8), None);
+}
+
+#[test]
+fn test_vector_alu() {
+  use VInstr::*;
+  assert_eq!(alu(VMULH, 0x80, 0x80, 8), 0x40);
+  assert_eq!(alu(VMULHU, u64::MAX, u64::MAX, 64), u64::MAX - 1);
+  assert_eq!(alu(VMULHSU, 0xff, 0xff, 8) & 0xff, 0xff);
+  assert_eq!(alu(VSRA, 0x80, 9, 8) & 0xff, 0xc0);
+  assert_eq!([alu(VMIN, 0xffff, 1, 16), alu(VMINU, 0xffff, 1, 16)], [0xffff, 1]);
+  assert_eq!([alu(VMSLT, 0xff, 0, 8), alu(VMSLTU, 0xff, 0, 8), alu(VMSGT, 0, 0xff, 8)], [1, 0, 1]);
+}
+
+#[test]
+fn test_vector_rv64() {
+  use crate::instr::decode_xlen;
+  let mut ps: ProgramState<u64> = ProgramState::new(crate::mem::Memory::new(0x100));
+  let run = |ps: &mut ProgramState<u64>, raw, a, b| ps.vector(&decode_xlen(raw, 64).unwrap(), a, b);
+  // nothing but vset* runs until vtype is set
+  assert_eq!(run(&mut ps, 0x0205f407, 0xf0, 0), Err((Exceptions::IllegalInstruction, 0)));
+  // vsetvli t1, a0, e64, m8, ta, ma asking for 20 elements gets 16
+  assert_eq!(run(&mut ps, 0x0db57357, 20, 0), Ok(Some(16)));
+  // vle64.v v8, (a1) from 0xf0 faults on the third element, leaving vstart there
+  assert_eq!(run(&mut ps, 0x0205f407, 0xf0, 0), Err((Exceptions::LoadAccessFault, 0x100)));
+  assert_eq!(ps.csrs.vstart, 2);
+  // vadd.vv v9, v8, v8 is not aligned to a group of 8
+  assert_eq!(run(&mut ps, 0x028404d7, 0, 0), Err((Exceptions::IllegalInstruction, 0)));
+  // vsetvl t1, a0, a1 with a reserved vtype sets vill
+  assert_eq!(run(&mut ps, 0x80b57357, 4, 1 << 8), Ok(Some(0)));
+  assert_eq!(ps.csrs.vtype, 1 << 63);
+}
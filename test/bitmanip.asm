@@ -0,0 +1,16 @@
+# Zba, Zbb, Zbs and Zbc
+li a0, 0x1234
+li a1, -3
+sh2add a2, a0, a0  # a2 = 0x5b04
+min a3, a1, a0     # a3 = -3
+minu a4, a1, a0    # a4 = 0x1234
+cpop a5, a1        # a5 = 31
+ctz a6, a0         # a6 = 2
+rev8 a7, a0        # a7 = 0x34120000
+rori t0, a0, 4     # t0 = 0x40000123
+bseti t1, zero, 31 # t1 = 0x80000000
+bext t2, a1, a6    # t2 = 1
+clmul t3, a0, a0   # t3 = 0x01040510
+orc.b t4, a2       # t4 = 0xffff
+andn t5, a1, a0    # t5 = 0xffffedc9
+.word 0xfeedfeed
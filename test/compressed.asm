@@ -0,0 +1,27 @@
+# C instructions mixed with 32 bit ones, which end up straddling word boundaries
+c.li a0, 0
+c.li a1, 10
+loop:
+c.add a0, a1       # a0 = 55 after the loop
+c.addi a1, -1
+c.bnez a1, loop
+addi sp, zero, 0x400
+c.jal func         # ra = the addi, 2 bytes on
+addi a2, a0, 100   # a2 = 210
+c.mv a3, ra
+c.swsp a0, 4(sp)
+c.lwsp a4, 4(sp)   # a4 = 110
+auipc t0, 0
+c.addi t0, 14      # t0 = other
+c.jalr t0
+c.j end
+func:
+c.slli a0, 1       # a0 = 110
+c.jr ra
+other:
+c.mv a5, ra
+c.jr a5
+end:
+c.nop
+.balign 4          # the halt word is only recognised on a word boundary
+.word 0xfeedfeed
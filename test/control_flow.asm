@@ -1,38 +1,38 @@
 # test out various control flows, registers should come out in order
 main:
 li t0, 0
-addi t7, 1
-move t1, t7
+addi a7, a7, 1
+mv t1, a7
 li s1, 1
 
 beg:
 beq t0, zero, hit
-addi t7, 1
-move t3, t7
+addi a7, a7, 1
+mv t3, a7
 li s3, 3
 jal fn
-addi t7, 1
-move t5, t7
+addi a7, a7, 1
+mv t5, a7
 li s5, 5
 j done
 
 hit:
 li t0, -1
-addi t7, 1
-move t2, t7
+addi a7, a7, 1
+mv t2, a7
 li s2, 2
 j beg
 
 fn:
-addi t7, 1
-move t4, t7
+addi a7, a7, 1
+mv t4, a7
 li s4, 4
 jr ra
 
 done:
-addi t7, 1
-move t6, t7
-addi t7, 1
+addi a7, a7, 1
+mv t6, a7
+addi a7, a7, 1
 li s6, 6
 .align 4
 .word 0xfeedfeed
@@ -0,0 +1,45 @@
+# F and D instructions, with the rounding mode and flags they leave in fcsr
+li s0, 1024
+li t0, 3
+fcvt.d.w fa0, t0
+li t0, 1
+fcvt.d.w fa1, t0
+fdiv.d fa2, fa1, fa0
+fsd fa2, 0(s0)
+lw a0, 0(s0)
+lw a1, 4(s0)
+# 1/3 is inexact
+csrr a2, fflags
+csrw fflags, zero
+fsqrt.d fa3, fa0
+fmul.d fa4, fa3, fa3
+feq.d a3, fa4, fa0
+fle.d a4, fa0, fa4
+fcvt.s.d fa5, fa2
+fsw fa5, 8(s0)
+flw fa6, 8(s0)
+fmv.x.w a5, fa6
+# round towards zero from here on
+csrwi frm, 1
+fcvt.w.d a6, fa4
+li t1, -7
+fcvt.s.w ft0, t1
+fmadd.s ft1, ft0, fa5, fa5
+fcvt.w.s a7, ft1, rne
+fclass.s s1, ft0
+fneg.s ft2, ft0
+fcvt.wu.s s2, ft2
+fmin.s ft3, ft0, ft2
+fmv.x.w s3, ft3
+csrr s4, fcsr
+fld fs0, 0(s0)
+feq.d s5, fs0, fa2
+# quiet NaNs only make ordered comparisons invalid
+csrw fflags, zero
+fsub.s ft4, ft0, ft0
+fdiv.s ft5, ft4, ft4
+flt.s s6, ft5, ft0
+feq.s s9, ft5, ft5
+csrr s7, fflags
+fmv.x.w s8, ft5
+.word 0xfeedfeed
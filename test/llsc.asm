@@ -1,43 +1,54 @@
 main:
-# an sc instruction before ll always fails
-li t0, 42       # set t0 to 24
-li s0, 1024     # s0 is the start of memory
-sc t0, 0(s0)   # try sc before any ll, t0 should be 0
-lw t0, 0(s0)   # make sure memory isn't altered
+# an sc before any lr always fails, and sc writes 0 to rd only when it stores
+li t0, 42        # t0 = 42
+li s0, 1024      # s0 is the start of memory
+sc.w t0, t0, (s0)  # t0 = 1
+lw t0, 0(s0)     # t0 = 0, memory isn't altered
 
-# an sc instruction clears any reservation taken out by ll
-li t0, 42       # to = 42
+# an sc clears any reservation taken out by lr
+li t0, 42
 sw t0, 4(s0)
-ll t0, 4(s0)   # t0 = 42
-li t0, 69       # t0 = 69
-sc t0, 4(s0)   # t0 = 1
-lw t1, 4(s0)   # t1 = 69
-li t0, 42       # t0 = 42
-sc t0, 4(s0)   # t0 = 0
-lw t2, 4(s0)   # t2 = 69
+addi s1, s0, 4
+lr.w t0, (s1)    # t0 = 42
+li t0, 69
+sc.w t0, t0, (s1)  # t0 = 0
+lw t1, 4(s0)     # t1 = 69
+li t0, 42
+sc.w t0, t0, (s1)  # t0 = 1
+lw t2, 4(s0)     # t2 = 69
 
-# an sc to a different resAddress always fails
+# an sc to a different address always fails
+addi s2, s0, 8
+addi s3, s0, 12
 sw t1, 8(s0)
-ll t1, 8(s0)      # t1 = 69
-sc t1, 12(s0)     # t1 = 0
-lw t3, 12(s0)     # t3 = 0
+lr.w t1, (s2)    # t1 = 69
+sc.w t1, t1, (s3)  # t1 = 1
+lw t3, 12(s0)    # t3 = 0
 
 # at most one reservation at a time
 li t0, 13
 sw t0, 0(s0)
-ll t4, 0(s0)      # t4 = 13
-ll t5, 4(s0)      # t5 = 69
-sc t5, 0(s0)      # t5 = 0
-sc t4, 4(s0)      # t4 = 0
+lr.w t4, (s0)    # t4 = 13
+lr.w t5, (s1)    # t5 = 69
+sc.w t5, t5, (s0)  # t5 = 1
+sc.w t4, t4, (s1)  # t4 = 1
 
-# a write that overlaps with resAddress voids it
-ll t2, 8(s0)      # t2 = 69
+# a store that overlaps the reservation voids it
+lr.w t2, (s2)    # t2 = 69
 li t6, 1
 sh t6, 8(s0)
-sc t2, 8(s0)      # t2 = 0
+sc.w t2, t2, (s2)  # t2 = 1
 
-ll t6, 8(s0)      # t6 = 0x000100045
+lr.w t6, (s2)    # t6 = 0x00000001
 sb t2, 11(s0)
-sc t6, 8(s0)      # t6 = 0
+sc.w t6, t6, (s2)  # t6 = 1
+
+# atomics return the old value, and order the memory accesses around them
+li a0, -5
+amoswap.w.aq a1, a0, (s2)  # a1 = 0x01000001
+amoadd.w a2, a1, (s2)      # a2 = -5
+amomin.w.rl a3, a0, (s2)   # a3 = 0x00fffffc
+amomaxu.w.aqrl a4, a0, (s2)  # a4 = -5
+lw a5, 8(s0)               # a5 = -5
 done:
 .word 0xfeedfeed
@@ -0,0 +1,30 @@
+# M mode starts an S mode kernel, which runs a U mode program. Its ecall is delegated to the
+# kernel, while reading mstatus is illegal and goes to M mode, which halts.
+li t0, 0x64        # mtrap
+csrw mtvec, t0
+li t0, 0x4c        # strap
+csrw stvec, t0
+li t0, 0x100       # ecalls from U mode
+csrw medeleg, t0
+lui t0, 1          # MPP from M to S
+csrc mstatus, t0
+li t0, 0x2c        # kernel
+csrw mepc, t0
+mret
+wfi                # kernel:
+li t0, 0x3c        # user
+csrw sepc, t0
+sret
+li a0, 5           # user:
+ecall              # a0 = 6
+csrr a1, mstatus
+li a2, 7
+csrr s0, scause    # strap: s0 = 8
+csrr t1, sepc
+addi t1, t1, 4
+csrw sepc, t1
+addi a0, a0, 1
+sret
+csrr s1, mcause    # mtrap: s1 = 2
+csrr s2, mstatus   # s2 = 0x20, SPIE with MPP at U
+.word 0xfeedfeed
@@ -0,0 +1,18 @@
+main:
+li a0, -1
+srli a1, a0, 32
+# a1 = 0x00000000ffffffff
+addiw a2, a1, 1
+# a2 = 0, the sum is taken in 32 bits
+addw a3, a1, a1
+# a3 = 0xfffffffffffffffe
+li t0, 128
+sd a0, 0(t0)
+lw a4, 4(t0)
+# a4 = 0xffffffffffffffff
+lwu a5, 4(t0)
+# a5 = 0x00000000ffffffff
+lui a6, 0x80000
+# a6 = 0xffffffff80000000
+done:
+.word 0xfeedfeed
@@ -0,0 +1,19 @@
+# V, run with the non-pipelined simulator
+li a0, 0x44
+li t0, 8
+vsetvli t1, t0, e32, m2, ta, ma # t1 = 8
+vle32.v v8, (a0)                # v8 = 1 .. 8
+vadd.vi v10, v8, -1
+vmul.vv v12, v10, v8            # v12 = 0, 2, 6, 12, 20, 30, 42, 56
+li t2, 5
+vmslt.vx v0, v8, t2
+vmv.v.i v14, 0
+vadd.vv v14, v12, v8, v0.t      # v14 = 1, 4, 9, 16, 0, 0, 0, 0
+vmv.s.x v16, zero
+vredsum.vs v16, v14, v16
+vmv.x.s a2, v16                 # a2 = 30
+vcpop.m a3, v0                  # a3 = 4
+vmsgt.vi v1, v8, 5
+vfirst.m a4, v1                 # a4 = 5
+.word 0xfeedfeed
+.word 1, 2, 3, 4, 5, 6, 7, 8